@@ -0,0 +1,207 @@
+// Server-side account store. Passwords are kept as Argon2 PHC hashes in
+// accounts.json, and logins are handed a session token signed with a server
+// secret (HMAC-SHA256) so clients can reconnect without the password.
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const TOKEN_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+
+pub struct Accounts {
+    path: PathBuf,
+    users: HashMap<String, String>,
+    secret: Vec<u8>,
+}
+
+impl Accounts {
+    // Loads accounts.json and the token secret from `dir`, creating both if missing
+    pub fn load(dir: &Path) -> io::Result<Accounts> {
+        fs::create_dir_all(dir)?;
+
+        let path = dir.join("accounts.json");
+        let users = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        // Every token issued so far is signed with it, so it is only made when there is
+        // none, never to replace one that can't be read
+        let secret_path = dir.join("token_secret");
+        let secret = match fs::read(&secret_path) {
+            Ok(secret) if secret.len() >= 32 => secret,
+            Ok(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is too short", secret_path.display()))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let mut secret = vec![0u8; 32];
+                rand::thread_rng().fill_bytes(&mut secret);
+                write_private(&secret_path, &secret)?;
+                secret
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(Accounts { path, users, secret })
+    }
+
+    // PHC hash for `username`, None if the name hasn't been claimed yet
+    pub fn password_hash(&self, username: &str) -> Option<String> {
+        self.users.get(username).cloned()
+    }
+
+    // Claims a username. Fails if someone else got there first.
+    pub fn create(&mut self, username: &str, password_hash: String) -> io::Result<bool> {
+        if self.users.contains_key(username) {
+            return Ok(false);
+        }
+        self.users.insert(username.to_string(), password_hash);
+        write_private(&self.path, serde_json::to_string_pretty(&self.users)?.as_bytes())?;
+        Ok(true)
+    }
+
+    pub fn issue_token(&self, username: &str) -> String {
+        let expires = now_secs() + TOKEN_TTL_SECS;
+        let payload = format!("{}.{}", URL_SAFE_NO_PAD.encode(username), expires);
+        let signature = URL_SAFE_NO_PAD.encode(self.sign(&payload));
+        format!("{}.{}", payload, signature)
+    }
+
+    // Returns the username the token was issued to, if it is authentic and not expired
+    pub fn verify_token(&self, token: &str) -> Option<String> {
+        let (payload, signature) = token.rsplit_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature).ok()?;
+
+        let (username, expires) = payload.split_once('.')?;
+        if expires.parse::<u64>().ok()? < now_secs() {
+            return None;
+        }
+        let username = String::from_utf8(URL_SAFE_NO_PAD.decode(username).ok()?).ok()?;
+        self.users.contains_key(&username).then_some(username)
+    }
+
+    fn sign(&self, payload: &str) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(payload.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+// Argon2 is deliberately slow, run these off the async workers (spawn_blocking)
+pub fn hash_password(password: &str) -> Option<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default().hash_password(password.as_bytes(), &salt).ok().map(|h| h.to_string())
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+        Err(_) => false,
+    }
+}
+
+// Readable by the server's user only, written then renamed so a crash never leaves a
+// half written file behind
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_os_string();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    fs::write(&tmp, data)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))?;
+    }
+    fs::rename(tmp, path)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accounts_with(dir: &Path, users: &[&str]) -> Accounts {
+        let mut accounts = Accounts::load(dir).unwrap();
+        for user in users {
+            accounts.create(user, format!("hash of {}", user)).unwrap();
+        }
+        accounts
+    }
+
+    #[test]
+    fn tokens_name_their_user() {
+        let dir = tempfile::tempdir().unwrap();
+        let accounts = accounts_with(dir.path(), &["alice", "bob"]);
+        assert_eq!(accounts.verify_token(&accounts.issue_token("alice")).as_deref(), Some("alice"));
+        assert_eq!(accounts.verify_token(&accounts.issue_token("bob")).as_deref(), Some("bob"));
+        // Not for names nobody claimed
+        assert_eq!(accounts.verify_token(&accounts.issue_token("mallory")), None);
+    }
+
+    #[test]
+    fn forged_and_expired_tokens_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let accounts = accounts_with(dir.path(), &["alice", "bob"]);
+        let token = accounts.issue_token("alice");
+
+        // Bob's name with alice's signature
+        let [_, expires, signature] = token.split('.').collect::<Vec<_>>()[..] else { panic!("{}", token) };
+        let forged = format!("{}.{}.{}", URL_SAFE_NO_PAD.encode("bob"), expires, signature);
+        assert_eq!(accounts.verify_token(&forged), None);
+        assert_eq!(accounts.verify_token("garbage"), None);
+        assert_eq!(accounts.verify_token(&format!("{}x", token)), None);
+
+        let payload = format!("{}.{}", URL_SAFE_NO_PAD.encode("alice"), now_secs() - 1);
+        let expired = format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(accounts.sign(&payload)));
+        assert_eq!(accounts.verify_token(&expired), None);
+
+        // Another server's secret signs different tokens
+        let other = tempfile::tempdir().unwrap();
+        let other = accounts_with(other.path(), &["alice"]);
+        assert_eq!(other.verify_token(&token), None);
+    }
+
+    #[test]
+    fn tokens_and_accounts_survive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let token = accounts_with(dir.path(), &["alice"]).issue_token("alice");
+
+        let reloaded = Accounts::load(dir.path()).unwrap();
+        assert_eq!(reloaded.password_hash("alice").as_deref(), Some("hash of alice"));
+        assert_eq!(reloaded.verify_token(&token).as_deref(), Some("alice"));
+        assert!(!dir.path().join("accounts.json.tmp").exists());
+    }
+
+    #[test]
+    fn short_secret_is_not_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("token_secret"), b"short").unwrap();
+        assert_eq!(Accounts::load(dir.path()).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        assert_eq!(fs::read(dir.path().join("token_secret")).unwrap(), b"short");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn secret_and_accounts_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        accounts_with(dir.path(), &["alice"]);
+        for file in ["token_secret", "accounts.json"] {
+            let mode = fs::metadata(dir.path().join(file)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600, "{}", file);
+        }
+    }
+}
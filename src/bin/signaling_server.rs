@@ -0,0 +1,26 @@
+// The signaling server on its own, configured from the environment. The server itself
+// is signaling_server.rs in the library.
+use p2p_rust::accounts::Accounts;
+use p2p_rust::mailbox::Mailbox;
+use p2p_rust::signaling_server::{run_server, tls_acceptor, MAILBOX_DAYS, MAILBOX_QUOTA_MB};
+use std::path::PathBuf;
+use tokio::net::TcpListener;
+
+#[tokio::main]
+async fn main() {
+    let addr = std::env::var("P2P_SIGNALING_ADDR").unwrap_or_else(|_| "0.0.0.0:8765".to_string());
+    let data_dir = std::env::var("P2P_SERVER_DATA").unwrap_or_else(|_| "server_data".to_string());
+
+    let accounts = Accounts::load(&PathBuf::from(&data_dir)).expect("Failed to load accounts");
+    let env_number = |name: &str, default: u64| std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+    let quota = env_number("P2P_MAILBOX_QUOTA_MB", MAILBOX_QUOTA_MB) * 1024 * 1024;
+    let ttl = env_number("P2P_MAILBOX_DAYS", MAILBOX_DAYS) * 24 * 60 * 60;
+    let mailbox = (quota > 0)
+        .then(|| Mailbox::load(&PathBuf::from(&data_dir).join("mailbox"), quota, ttl).expect("Failed to load the mailbox"));
+    let tls = tls_acceptor().expect("Failed to load TLS certificate");
+    let listener = TcpListener::bind(&addr).await.expect("Can't listen");
+    let scheme = if tls.is_some() { "wss" } else { "ws" };
+    println!("🚀 Signaling server running at {}://{}", scheme, addr);
+    run_server(listener, accounts, mailbox, tls).await;
+}
+
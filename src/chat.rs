@@ -0,0 +1,190 @@
+// Text chat between registered peers. It runs on a signaling connection of its own
+// (chat_listen), so messages keep flowing while a transfer holds the main one.
+// The server routes
+//   chat_message {to, id, text, timestamp}  -> chat_message {from, id, text, timestamp}
+//   chat_ack {to, id}                       -> chat_ack {from, id}
+// and answers chat_failed {id, error} when a message can't be delivered. Every
+// received message is acked right away, so the sender can tick it off.
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+pub enum ChatEvent {
+    Received { from: String, id: String, text: String, timestamp: u64 },
+    Delivered { id: String },
+    Failed { id: String, error: String },
+    // The chat connection is gone, nothing goes out until the next register
+    Closed(String),
+}
+
+#[derive(Clone, PartialEq)]
+pub enum Delivery {
+    Sending,
+    Delivered,
+    Failed(String),
+}
+
+#[derive(Clone)]
+pub struct ChatEntry {
+    pub id: String,
+    pub from_me: bool,
+    pub text: String,
+    // Milliseconds since the epoch, as the sender's clock saw it
+    pub timestamp: u64,
+    pub delivery: Delivery,
+}
+
+// Every conversation, by peer
+#[derive(Default)]
+pub struct Conversations {
+    peers: HashMap<String, Vec<ChatEntry>>,
+    unread: HashMap<String, usize>,
+}
+
+impl Conversations {
+    pub fn get(&self, peer: &str) -> &[ChatEntry] {
+        self.peers.get(peer).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &String> {
+        self.peers.keys()
+    }
+
+    // Kept in arrival order, the two clocks needn't agree
+    pub fn push(&mut self, peer: &str, entry: ChatEntry) {
+        if !entry.from_me {
+            *self.unread.entry(peer.to_string()).or_default() += 1;
+        }
+        self.peers.entry(peer.to_string()).or_default().push(entry);
+    }
+
+    pub fn mark_read(&mut self, peer: &str) {
+        self.unread.remove(peer);
+    }
+
+    pub fn unread(&self) -> usize {
+        self.unread.values().sum()
+    }
+
+    // Updates one of our messages, returns the peer it went to
+    pub fn mark(&mut self, id: &str, delivery: Delivery) -> Option<String> {
+        for (peer, entries) in self.peers.iter_mut() {
+            if let Some(entry) = entries.iter_mut().find(|e| e.from_me && e.id == id) {
+                entry.delivery = delivery;
+                return Some(peer.clone());
+            }
+        }
+        None
+    }
+}
+
+// Queues messages for the chat connection. Dropping it closes the connection.
+pub struct ChatSender {
+    outgoing: mpsc::UnboundedSender<Value>,
+}
+
+impl ChatSender {
+    pub fn send(&self, to: &str, text: &str) -> Result<ChatEntry, String> {
+        let entry = ChatEntry {
+            id: format!("{:016x}", rand::random::<u64>()),
+            from_me: true,
+            text: text.to_string(),
+            timestamp: now_millis(),
+            delivery: Delivery::Sending,
+        };
+        let message = json!({"type": "chat_message", "to": to, "id": entry.id, "text": entry.text, "timestamp": entry.timestamp});
+        self.outgoing.send(message).map_err(|_| "Chat is not connected".to_string())?;
+        Ok(entry)
+    }
+}
+
+pub struct ChatListener {
+    ws_stream: WsStream,
+    outgoing: mpsc::UnboundedReceiver<Value>,
+}
+
+// Registers `ws_stream` as our chat connection. Run the listener to get messages in
+// and out, it stops when the ChatSender is dropped or the connection closes.
+pub async fn open_chat(mut ws_stream: WsStream, token: &str) -> Result<(ChatSender, ChatListener), String> {
+    let listen = json!({"type": "chat_listen", "token": token});
+    ws_stream.send(Message::Text(listen.to_string())).await.map_err(|e| e.to_string())?;
+    while let Some(msg) = ws_stream.next().await {
+        let Message::Text(text) = msg.map_err(|e| e.to_string())? else { continue };
+        let Ok(data) = serde_json::from_str::<Value>(&text) else { continue };
+        match data["type"].as_str() {
+            Some("chat_listening") => {
+                let (tx, rx) = mpsc::unbounded_channel();
+                return Ok((ChatSender { outgoing: tx }, ChatListener { ws_stream, outgoing: rx }));
+            }
+            Some("error") => return Err(data["error"].as_str().unwrap_or("unknown error").to_string()),
+            _ => {}
+        }
+    }
+    Err("Connection closed".to_string())
+}
+
+impl ChatListener {
+    pub async fn run(self, mut on_event: impl FnMut(ChatEvent)) {
+        let ChatListener { ws_stream, mut outgoing } = self;
+        let (mut write, mut read) = ws_stream.split();
+        let error = loop {
+            tokio::select! {
+                message = outgoing.recv() => {
+                    let Some(message) = message else {
+                        let _ = write.close().await;
+                        return;
+                    };
+                    if let Err(e) = write.send(Message::Text(message.to_string())).await {
+                        break e.to_string();
+                    }
+                }
+                msg = read.next() => {
+                    let text = match msg {
+                        Some(Ok(Message::Text(text))) => text,
+                        Some(Ok(_)) => continue,
+                        Some(Err(e)) => break e.to_string(),
+                        None => break "Connection closed".to_string(),
+                    };
+                    let Ok(data) = serde_json::from_str::<Value>(&text) else { continue };
+                    let id = data["id"].as_str().unwrap_or_default().to_string();
+                    match data["type"].as_str() {
+                        Some("chat_message") => {
+                            let from = data["from"].as_str().unwrap_or_default().to_string();
+                            let ack = json!({"type": "chat_ack", "to": from, "id": id});
+                            if let Err(e) = write.send(Message::Text(ack.to_string())).await {
+                                break e.to_string();
+                            }
+                            let text = data["text"].as_str().unwrap_or_default().to_string();
+                            let timestamp = data["timestamp"].as_u64().unwrap_or_else(now_millis);
+                            on_event(ChatEvent::Received { from, id, text, timestamp });
+                        }
+                        Some("chat_ack") => on_event(ChatEvent::Delivered { id }),
+                        Some("chat_failed") => {
+                            let error = data["error"].as_str().unwrap_or("unknown error").to_string();
+                            on_event(ChatEvent::Failed { id, error });
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        };
+        on_event(ChatEvent::Closed(error));
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+// "14:05" in UTC, the UI has no time zone data
+pub fn clock(timestamp: u64) -> String {
+    let minutes = timestamp / 60_000 % (24 * 60);
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
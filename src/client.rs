@@ -0,0 +1,241 @@
+// One user on the signaling server, for programs that want to send and receive files
+// without the rest of the ui:
+//   let client = Client::connect(SignalingConfig::from_env()?, Identity::load_or_create()?).await?;
+//   client.register("alice", Some("secret")).await?;
+//   client.send("bob", Source::File(path)).await?;
+// Transfers report to `events()` as they go, each client only its own. Sending and
+// listing peers open their own connections, so they can run while `receive` holds the
+// registered one. Nothing needs `&mut`, so one client can serve a whole ui from an Rc.
+// `send_udp` goes straight to the address the peer registered instead, through the
+// TURN relay from P2P_TURN_SERVER (see turn.rs) when that doesn't get through.
+use crate::clipboard::Clip;
+use crate::events::{Subscribers, TransferEvent};
+use crate::identity::{Handshake, Identity};
+use crate::multistream::{MAX_STREAMS, MIN_PARALLEL_SIZE};
+use crate::receive::{relay_receive, Destination};
+use crate::send::{relay_send, relay_send_stream};
+use crate::session::{forget_token, load_token, save_token};
+use crate::signaling::{get_clients, get_users, register};
+use crate::tls::{connect_signaling, SignalingConfig};
+use crate::turn::TurnConfig;
+use crate::udp::send_file_direct;
+use futures::channel::mpsc::UnboundedReceiver;
+use serde_json::{json, Value};
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::AsyncRead;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// What to send
+pub enum Source {
+    File(PathBuf),
+    // Everything the reader gives until it ends, under `name`. Its length isn't known
+    // up front, so it goes as a stream (see send.rs).
+    Stream { name: String, reader: Box<dyn AsyncRead + Unpin> },
+}
+
+pub struct Client {
+    config: SignalingConfig,
+    identity: Identity,
+    ws_stream: Arc<Mutex<WsStream>>,
+    // Username and session token, once registered
+    session: RefCell<Option<(String, String)>>,
+    // Connections a large file goes over, 1 sends everything over the first
+    parallel_streams: Cell<usize>,
+    // Where send_udp turns when the peer can't be reached directly
+    turn: RefCell<Option<TurnConfig>>,
+    events: Subscribers,
+}
+
+impl Client {
+    pub async fn connect(config: SignalingConfig, identity: Identity) -> Result<Client, String> {
+        let ws_stream = Arc::new(Mutex::new(connect_signaling(&config).await?));
+        Ok(Client {
+            config,
+            identity,
+            ws_stream,
+            session: RefCell::new(None),
+            parallel_streams: Cell::new(MAX_STREAMS),
+            turn: RefCell::new(TurnConfig::from_env()),
+            events: Subscribers::default(),
+        })
+    }
+
+    // Logs in as `username` like the ui does: with the session token saved last time,
+    // then with `password` if there is none or the server no longer takes it. The new
+    // token is saved for next time. Relay sessions for `username` come to this client
+    // afterwards.
+    pub async fn register(&self, username: &str, password: Option<&str>) -> Result<(), String> {
+        self.register_at(username, password, &Value::Null).await
+    }
+
+    // register, telling peers the addresses they can reach us at over UDP: the ipv4_ip,
+    // ipv4_port, ipv6_ip and ipv6_port of `address`, as found by
+    // signaling::get_pip_port_json_and_sockets
+    pub async fn register_at(&self, username: &str, password: Option<&str>, address: &Value) -> Result<(), String> {
+        let mut payload = json!({"type": "register", "username": username, "identity_key": self.identity.public_key()});
+        for field in ["ipv4_ip", "ipv4_port", "ipv6_ip", "ipv6_port"] {
+            if let Some(value) = address.get(field) {
+                payload[field] = value.clone();
+            }
+        }
+        let mut result = Err("There is no saved session, a password is needed".to_string());
+        if let Some(token) = load_token(username) {
+            payload["token"] = Value::from(token);
+            result = register(&payload, self.ws_stream.clone()).await;
+            if result.is_err() {
+                forget_token(username);
+            }
+        }
+        if result.is_err()
+            && let Some(password) = password
+        {
+            payload["token"] = Value::Null;
+            payload["password"] = Value::from(password);
+            result = register(&payload, self.ws_stream.clone()).await;
+        }
+        let token = result?;
+        save_token(username, &token);
+        *self.session.borrow_mut() = Some((username.to_string(), token));
+        Ok(())
+    }
+
+    pub fn username(&self) -> Option<String> {
+        self.session.borrow().as_ref().map(|(username, _)| username.clone())
+    }
+
+    // For the server requests that have no method here, like rooms and the mailbox
+    pub fn token(&self) -> Option<String> {
+        self.session.borrow().as_ref().map(|(_, token)| token.clone())
+    }
+
+    // The registered connection. `receive` holds it while it runs.
+    pub fn connection(&self) -> Arc<Mutex<WsStream>> {
+        self.ws_stream.clone()
+    }
+
+    pub fn config(&self) -> &SignalingConfig {
+        &self.config
+    }
+
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    // How many connections `send` spreads a large file over, at most MAX_STREAMS
+    pub fn set_parallel_streams(&self, count: usize) {
+        self.parallel_streams.set(count.clamp(1, MAX_STREAMS));
+    }
+
+    // The TURN server send_udp falls back to, None sends direct only
+    pub fn set_turn(&self, turn: Option<TurnConfig>) {
+        *self.turn.borrow_mut() = turn;
+    }
+
+    // Usernames registered right now, alphabetical
+    pub async fn list_peers(&self) -> Result<Vec<String>, String> {
+        let ws_stream = Arc::new(Mutex::new(connect_signaling(&self.config).await?));
+        let text = get_clients(ws_stream).await.map_err(|e| e.to_string())?;
+        serde_json::from_str(&text).map_err(|e| e.to_string())
+    }
+
+    // Sends `source` to `target` over the relay, once `target` proved its identity.
+    // Large files go over several connections at once.
+    pub async fn send(&self, target: &str, source: Source) -> Result<(), String> {
+        let (username, token) = self.session()?;
+        let parallel_streams = self.parallel_streams.get();
+        let ws_stream = Arc::new(Mutex::new(connect_signaling(&self.config).await?));
+        let mut handshake = Handshake::initiator(self.identity.clone(), &username, target);
+        let hello = handshake.hello();
+        let on_identity = move |msg: &Value| handshake.on_message(msg);
+        let transfer = async move {
+            match source {
+                Source::File(path) => {
+                    let mut streams = Vec::new();
+                    if parallel_streams > 1 && std::fs::metadata(&path).is_ok_and(|m| m.len() >= MIN_PARALLEL_SIZE) {
+                        for _ in 0..parallel_streams {
+                            match connect_signaling(&self.config).await {
+                                Ok(ws_stream) => streams.push(ws_stream),
+                                Err(e) => eprintln!("❌ Can't open a parallel stream: {}", e),
+                            }
+                        }
+                    }
+                    relay_send(ws_stream, target.to_string(), token, hello, on_identity, path, streams).await
+                }
+                Source::Stream { name, reader } => {
+                    relay_send_stream(ws_stream, target.to_string(), token, hello, on_identity, &name, reader).await
+                }
+            }
+        };
+        self.events.scope(transfer).await.map_err(|e| e.to_string())
+    }
+
+    // Sends the file at `path` over UDP to the address `target` registered from STUN,
+    // IPv4 when it has one. The target has to be taking files on that socket (see
+    // udp::receive_file_udp).
+    pub async fn send_udp(&self, target: &str, path: &Path) -> Result<(), String> {
+        let ws_stream = Arc::new(Mutex::new(connect_signaling(&self.config).await?));
+        let users = get_users(ws_stream).await?;
+        let user = users.get(target).ok_or_else(|| "Target user not found".to_string())?;
+        let address = |ip: &str, port: &str| {
+            let ip: IpAddr = user.get(ip)?.as_str()?.parse().ok()?;
+            Some(SocketAddr::new(ip, u16::try_from(user.get(port)?.as_u64()?).ok()?))
+        };
+        let peer = address("ipv4_ip", "ipv4_port")
+            .or_else(|| address("ipv6_ip", "ipv6_port"))
+            .ok_or_else(|| format!("{} didn't register a UDP address", target))?;
+        let turn = self.turn.borrow().clone();
+        send_file_direct(path, peer, turn.as_ref()).await.map_err(|e| e.to_string())
+    }
+
+    // Takes what senders send until the connection goes, or with Destination::Stdout
+    // until the first file is through. Senders have to prove their identity first,
+    // room posts and clipboard shares are declined: nobody is there to accept them.
+    pub async fn receive(&self, destination: Destination) -> Result<(), String> {
+        let on_offer = |_: &str, metadata: &Value| {
+            let sent_to_us = metadata.get("room").is_none() && metadata.get("clipboard").is_none();
+            async move { sent_to_us }
+        };
+        self.receive_with(destination, |_| {}, on_offer, |_, _| {}).await
+    }
+
+    // receive, asking the program instead: `on_peer` hears who each sender proved to
+    // be, `on_offer` decides on every file, room post and clipboard share (see
+    // receive.rs) and `on_clipboard` gets the shares taken
+    pub async fn receive_with<F: Future<Output = bool>>(
+        &self,
+        destination: Destination,
+        mut on_peer: impl FnMut(&str),
+        on_offer: impl FnMut(&str, &Value) -> F,
+        on_clipboard: impl FnMut(&str, Clip),
+    ) -> Result<(), String> {
+        let (username, token) = self.session()?;
+        let mut handshake = Handshake::responder(self.identity.clone(), &username);
+        let on_identity = move |msg: &Value| {
+            if let Some(peer) = msg.get("username").and_then(|v| v.as_str()) {
+                on_peer(peer);
+            }
+            handshake.on_message(msg)
+        };
+        let open_stream = || async { connect_signaling(&self.config).await };
+        let transfer =
+            relay_receive(username, token, self.ws_stream.clone(), on_identity, on_offer, on_clipboard, open_stream, destination);
+        self.events.scope(transfer).await.map_err(|e| e.to_string())
+    }
+
+    // Every event of this client's transfers from now on, see events.rs
+    pub fn events(&self) -> UnboundedReceiver<TransferEvent> {
+        self.events.subscribe()
+    }
+
+    fn session(&self) -> Result<(String, String), String> {
+        self.session.borrow().clone().ok_or_else(|| "Not registered".to_string())
+    }
+}
@@ -0,0 +1,129 @@
+// Clipboard shares: the current clipboard content sent to a peer. It travels like a
+// file on a relay session (file_metadata with a "clipboard" kind, chunks, file_end
+// with the hash), so it gets the same identity check, compression and hash check, but
+// the receiver keeps it in memory and only takes it if it opted in to clipboard shares.
+use crate::compression::{ChunkDecoder, Codec};
+use crate::delta::to_hex;
+use arboard::{Clipboard, ImageData};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+
+pub const MAX_CLIPBOARD_SIZE: u64 = 32 * 1024 * 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Clip {
+    Text(String),
+    // RGBA, 4 bytes per pixel
+    Image { width: usize, height: usize, rgba: Vec<u8> },
+}
+
+impl Clip {
+    // Text wins if the clipboard holds both
+    pub fn from_system(clipboard: &mut Clipboard) -> Result<Clip, String> {
+        let clip = match clipboard.get_text() {
+            Ok(text) if !text.is_empty() => Clip::Text(text),
+            _ => match clipboard.get_image() {
+                Ok(image) => Clip::Image { width: image.width, height: image.height, rgba: image.bytes.into_owned() },
+                Err(_) => return Err("The clipboard holds no text or image".to_string()),
+            },
+        };
+        if clip.bytes().len() as u64 > MAX_CLIPBOARD_SIZE {
+            return Err(format!("The clipboard holds more than {} MiB", MAX_CLIPBOARD_SIZE / (1024 * 1024)));
+        }
+        Ok(clip)
+    }
+
+    pub fn to_system(&self, clipboard: &mut Clipboard) -> Result<(), String> {
+        let result = match self {
+            Clip::Text(text) => clipboard.set_text(text.as_str()),
+            Clip::Image { width, height, rgba } => {
+                clipboard.set_image(ImageData { width: *width, height: *height, bytes: Cow::Borrowed(rgba) })
+            }
+        };
+        result.map_err(|e| e.to_string())
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        match self {
+            Clip::Text(text) => text.as_bytes(),
+            Clip::Image { rgba, .. } => rgba,
+        }
+    }
+
+    // "12 characters of text", "800×600 image"
+    pub fn describe(&self) -> String {
+        match self {
+            Clip::Text(text) => format!("{} characters of text", text.chars().count()),
+            Clip::Image { width, height, .. } => format!("{}×{} image", width, height),
+        }
+    }
+
+    // What the sender opens the transfer with
+    pub fn metadata(&self) -> Value {
+        let mut metadata = json!({
+            "type": "file_metadata",
+            "name": "clipboard",
+            "size": self.bytes().len(),
+            "codecs": [Codec::Zstd.name(), Codec::Lz4.name()]
+        });
+        match self {
+            Clip::Text(_) => metadata["clipboard"] = Value::from("text"),
+            Clip::Image { width, height, .. } => {
+                metadata["clipboard"] = Value::from("image");
+                metadata["width"] = Value::from(*width);
+                metadata["height"] = Value::from(*height);
+            }
+        }
+        metadata
+    }
+
+    pub fn sha256(&self) -> String {
+        to_hex(&Sha256::digest(self.bytes()))
+    }
+}
+
+// A clipboard share coming in, collected in memory
+pub struct IncomingClip {
+    metadata: Value,
+    size: u64,
+    decoder: ChunkDecoder,
+    data: Vec<u8>,
+}
+
+impl IncomingClip {
+    pub fn new(metadata: &Value, codec: Codec) -> Result<IncomingClip, String> {
+        let size = metadata.get("size").and_then(|v| v.as_u64()).ok_or("Clipboard share without a size")?;
+        if size > MAX_CLIPBOARD_SIZE {
+            return Err(format!("Clipboard share of {} bytes is over the limit", size));
+        }
+        Ok(IncomingClip { metadata: metadata.clone(), size, decoder: ChunkDecoder::new(codec, size), data: Vec::new() })
+    }
+
+    pub fn write_chunk(&mut self, chunk: &[u8]) -> Result<(), String> {
+        let data = self.decoder.decode(chunk)?;
+        self.data.extend_from_slice(&data);
+        Ok(())
+    }
+
+    pub fn finish(self, sha256: Option<&str>) -> Result<Clip, String> {
+        if self.data.len() as u64 != self.size {
+            return Err(format!("Clipboard share ended after {} of {} bytes", self.data.len(), self.size));
+        }
+        if sha256 != Some(to_hex(&Sha256::digest(&self.data)).as_str()) {
+            return Err("Clipboard share doesn't match its hash".to_string());
+        }
+        match self.metadata.get("clipboard").and_then(|v| v.as_str()) {
+            Some("text") => String::from_utf8(self.data).map(Clip::Text).map_err(|_| "Clipboard text isn't UTF-8".to_string()),
+            Some("image") => {
+                let dimension = |name| self.metadata.get(name).and_then(|v| v.as_u64()).unwrap_or(0) as usize;
+                let (width, height) = (dimension("width"), dimension("height"));
+                if width.checked_mul(height).and_then(|pixels| pixels.checked_mul(4)) != Some(self.data.len()) {
+                    return Err("Clipboard image size doesn't match its dimensions".to_string());
+                }
+                Ok(Clip::Image { width, height, rgba: self.data })
+            }
+            _ => Err("Unknown kind of clipboard share".to_string()),
+        }
+    }
+}
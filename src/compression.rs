@@ -0,0 +1,305 @@
+// Negotiated per-chunk compression for file transfers.
+//
+// The sender lists the codecs it can use in file_metadata ("codecs", in order of
+// preference), the receiver answers with file_accept naming the one it picked, or
+// "none". With a codec every binary chunk starts with a flag byte: 0 = stored as-is,
+// 1 = compressed. Without one chunks are raw bytes like before, so old senders and
+// receivers still interoperate. Compressed lz4 chunks start with their decompressed
+// length (u32, little endian), zstd frames carry it themselves.
+use serde_json::Value;
+use std::path::Path;
+
+pub const CODEC_NONE: &str = "none";
+
+// No chunk ever decompresses to more than this, whatever the declared size
+const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+// A chunk is only sent compressed if that saves at least 1/16 of it
+const MIN_SAVING_DIVISOR: usize = 16;
+
+// After this many chunks in a row that didn't compress, stop trying for a while
+const MISSES_BEFORE_SKIP: u32 = 4;
+const SKIP_CHUNKS: u32 = 64;
+
+const FLAG_STORED: u8 = 0;
+const FLAG_COMPRESSED: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl Codec {
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::None => CODEC_NONE,
+            Codec::Zstd => "zstd",
+            Codec::Lz4 => "lz4",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Codec> {
+        match name {
+            CODEC_NONE => Some(Codec::None),
+            "zstd" => Some(Codec::Zstd),
+            "lz4" => Some(Codec::Lz4),
+            _ => None,
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Codec::None => None,
+            Codec::Zstd => zstd::bulk::compress(data, 3).ok(),
+            Codec::Lz4 => Some(lz4_flex::block::compress_prepend_size(data)),
+        }
+    }
+
+    // Fails instead of producing more than `limit` bytes
+    fn decompress(self, data: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+        match self {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Zstd => zstd::bulk::decompress(data, limit).map_err(|e| e.to_string()),
+            Codec::Lz4 => {
+                let (size, block) = data.split_first_chunk::<4>().ok_or("lz4 chunk without its length")?;
+                let size = u32::from_le_bytes(*size) as usize;
+                if size > limit {
+                    return Err(format!("{} bytes is more than the {} left", size, limit));
+                }
+                let mut out = vec![0u8; size];
+                let n = lz4_flex::block::decompress_into(block, &mut out).map_err(|e| e.to_string())?;
+                if n != size {
+                    return Err(format!("lz4 chunk gave {} of {} bytes", n, size));
+                }
+                Ok(out)
+            }
+        }
+    }
+}
+
+// What a sender puts in file_metadata["codecs"]. Files that are already compressed
+// get no codecs at all.
+pub fn offered_codecs(path: &Path) -> Vec<&'static str> {
+    if looks_compressed(path) {
+        Vec::new()
+    } else {
+        vec![Codec::Zstd.name(), Codec::Lz4.name()]
+    }
+}
+
+// Receiver side: the first codec of the sender's list that we support
+pub fn choose_codec(metadata: &Value) -> Codec {
+    metadata
+        .get("codecs")
+        .and_then(|v| v.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|name| name.as_str().and_then(Codec::from_name))
+        .next()
+        .unwrap_or(Codec::None)
+}
+
+// Sender side: the codec named in a file_accept message
+pub fn accepted_codec(accept: &Value) -> Codec {
+    accept.get("codec").and_then(|v| v.as_str()).and_then(Codec::from_name).unwrap_or(Codec::None)
+}
+
+// Sniffs the magic bytes of archives, compressed media and the like
+pub fn looks_compressed(path: &Path) -> bool {
+    let mut head = [0u8; 12];
+    let n = match std::fs::File::open(path) {
+        Ok(mut file) => std::io::Read::read(&mut file, &mut head).unwrap_or(0),
+        Err(_) => 0,
+    };
+    let head = &head[..n];
+
+    const MAGIC: [&[u8]; 15] = [
+        b"PK\x03\x04",                  // zip, docx, jar, apk
+        b"\x1f\x8b",                    // gzip
+        b"\x28\xb5\x2f\xfd",            // zstd
+        b"\xfd7zXZ\x00",                // xz
+        b"BZh",                         // bzip2
+        b"7z\xbc\xaf\x27\x1c",          // 7z
+        b"Rar!",                        // rar
+        b"\x04\x22\x4d\x18",            // lz4 frame
+        b"\x89PNG",                     // png
+        b"\xff\xd8\xff",                // jpeg
+        b"GIF8",                        // gif
+        b"OggS",                        // ogg, opus
+        b"ID3",                         // mp3
+        b"fLaC",                        // flac
+        b"\x1a\x45\xdf\xa3",            // mkv, webm
+    ];
+    MAGIC.iter().any(|magic| head.starts_with(magic))
+        || head.get(4..8) == Some(b"ftyp")                                      // mp4, mov, heic
+        || (head.starts_with(b"RIFF") && head.get(8..12) == Some(b"WEBP"))
+}
+
+// Frames outgoing chunks, compressing those that are worth it
+pub struct ChunkEncoder {
+    codec: Codec,
+    misses: u32,
+    skip: u32,
+}
+
+impl ChunkEncoder {
+    pub fn new(codec: Codec) -> ChunkEncoder {
+        ChunkEncoder { codec, misses: 0, skip: 0 }
+    }
+
+    pub fn encode(&mut self, chunk: &[u8]) -> Vec<u8> {
+        if self.codec == Codec::None {
+            return chunk.to_vec();
+        }
+        if self.skip > 0 {
+            self.skip -= 1;
+            return frame(FLAG_STORED, chunk);
+        }
+
+        match self.codec.compress(chunk) {
+            Some(compressed) if compressed.len() + chunk.len() / MIN_SAVING_DIVISOR < chunk.len() => {
+                self.misses = 0;
+                frame(FLAG_COMPRESSED, &compressed)
+            }
+            _ => {
+                self.misses += 1;
+                if self.misses >= MISSES_BEFORE_SKIP {
+                    self.misses = 0;
+                    self.skip = SKIP_CHUNKS;
+                }
+                frame(FLAG_STORED, chunk)
+            }
+        }
+    }
+}
+
+fn frame(flag: u8, data: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(data.len() + 1);
+    framed.push(flag);
+    framed.extend_from_slice(data);
+    framed
+}
+
+// Unframes incoming chunks. Never yields more bytes in total than the size the
+// sender declared, which is what stops decompression bombs.
+pub struct ChunkDecoder {
+    codec: Codec,
+    remaining: u64,
+}
+
+impl ChunkDecoder {
+    pub fn new(codec: Codec, declared_size: u64) -> ChunkDecoder {
+        ChunkDecoder { codec, remaining: declared_size }
+    }
+
+    pub fn decode(&mut self, chunk: &[u8]) -> Result<Vec<u8>, String> {
+        let limit = self.remaining.min(MAX_CHUNK_SIZE as u64) as usize;
+        let data = match self.codec {
+            Codec::None => chunk.to_vec(),
+            codec => match chunk.split_first() {
+                Some((&FLAG_STORED, stored)) => stored.to_vec(),
+                Some((&FLAG_COMPRESSED, compressed)) => codec
+                    .decompress(compressed, limit)
+                    .map_err(|e| format!("Chunk doesn't decompress within the declared size: {}", e))?,
+                _ => return Err("Chunk with an unknown compression flag".to_string()),
+            },
+        };
+        if data.len() > limit {
+            return Err("Sender sent more data than it declared".to_string());
+        }
+        self.remaining -= data.len() as u64;
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut bytes = vec![0u8; len];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        bytes
+    }
+
+    // Text-like data that compresses well
+    fn compressible(len: usize) -> Vec<u8> {
+        b"the quick brown fox jumps over the lazy dog ".iter().copied().cycle().take(len).collect()
+    }
+
+    #[test]
+    fn chunks_round_trip_compressed() {
+        for codec in [Codec::Zstd, Codec::Lz4] {
+            let chunk = compressible(64 * 1024);
+            let framed = ChunkEncoder::new(codec).encode(&chunk);
+            assert_eq!(framed[0], FLAG_COMPRESSED, "{:?}", codec);
+            assert!(framed.len() < chunk.len() / 4);
+            assert_eq!(ChunkDecoder::new(codec, chunk.len() as u64).decode(&framed).unwrap(), chunk);
+        }
+    }
+
+    #[test]
+    fn compressed_input_is_stored() {
+        for codec in [Codec::Zstd, Codec::Lz4] {
+            let mut encoder = ChunkEncoder::new(codec);
+            let mut decoder = ChunkDecoder::new(codec, 10 * 64 * 1024);
+            for _ in 0..10 {
+                let chunk = random_bytes(64 * 1024);
+                let framed = encoder.encode(&chunk);
+                assert_eq!(framed[0], FLAG_STORED, "{:?}", codec);
+                assert_eq!(&framed[1..], &chunk[..]);
+                assert_eq!(decoder.decode(&framed).unwrap(), chunk);
+            }
+        }
+    }
+
+    #[test]
+    fn bombs_stop_at_the_declared_size() {
+        for codec in [Codec::Zstd, Codec::Lz4] {
+            let framed = ChunkEncoder::new(codec).encode(&vec![0u8; MAX_CHUNK_SIZE]);
+            assert_eq!(framed[0], FLAG_COMPRESSED);
+            let error = ChunkDecoder::new(codec, 1000).decode(&framed).unwrap_err();
+            assert!(error.contains("declared size"), "{:?}: {}", codec, error);
+        }
+
+        // An lz4 length past what is left is refused before anything is allocated
+        let mut framed = vec![FLAG_COMPRESSED];
+        framed.extend_from_slice(&u32::MAX.to_le_bytes());
+        framed.extend_from_slice(&[0u8; 16]);
+        assert!(ChunkDecoder::new(Codec::Lz4, u64::MAX).decode(&framed).is_err());
+    }
+
+    #[test]
+    fn decoder_counts_across_chunks() {
+        let chunk = compressible(64 * 1024);
+        let framed = ChunkEncoder::new(Codec::Zstd).encode(&chunk);
+        let mut decoder = ChunkDecoder::new(Codec::Zstd, chunk.len() as u64 + 10);
+        assert_eq!(decoder.decode(&framed).unwrap(), chunk);
+        assert!(decoder.decode(&framed).is_err());
+
+        let mut decoder = ChunkDecoder::new(Codec::None, 4);
+        assert_eq!(decoder.decode(b"raw").unwrap(), b"raw");
+        assert_eq!(decoder.decode(b"too much").unwrap_err(), "Sender sent more data than it declared");
+    }
+
+    #[test]
+    fn unknown_flag_is_refused() {
+        for codec in [Codec::Zstd, Codec::Lz4] {
+            let mut decoder = ChunkDecoder::new(codec, 100);
+            assert_eq!(decoder.decode(&[2, 1, 2, 3]).unwrap_err(), "Chunk with an unknown compression flag");
+            assert!(decoder.decode(&[]).is_err());
+        }
+    }
+
+    #[test]
+    fn codecs_are_negotiated_by_name() {
+        let metadata = serde_json::json!({"codecs": ["brotli", "lz4", "zstd"]});
+        assert_eq!(choose_codec(&metadata), Codec::Lz4);
+        assert_eq!(choose_codec(&serde_json::json!({})), Codec::None);
+        assert_eq!(accepted_codec(&serde_json::json!({"codec": "zstd"})), Codec::Zstd);
+        assert_eq!(accepted_codec(&serde_json::json!({"codec": "none"})), Codec::None);
+    }
+}
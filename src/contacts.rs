@@ -0,0 +1,104 @@
+// The user's contacts, by username: a nickname, whether they are a favourite and
+// what to do with their offers. Their identity key isn't copied here, the one pinned
+// in known_peers.json (identity.rs) is shown, so the two can't disagree.
+use crate::store::{load_json, save_json};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const CONTACTS_FILE: &str = "contacts.json";
+
+// What happens to the files, room posts and clipboard shares a contact sends us, once
+// they proved their identity
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AutoAccept {
+    // What everyone gets: files sent to us are taken, room posts wait for
+    // Accept/Decline, clipboard shares follow the checkbox. Saved as "ask" before.
+    #[default]
+    #[serde(alias = "ask")]
+    Default,
+    Always,
+    Never,
+}
+
+impl AutoAccept {
+    pub fn name(self) -> &'static str {
+        match self {
+            AutoAccept::Default => "Default",
+            AutoAccept::Always => "Always",
+            AutoAccept::Never => "Never",
+        }
+    }
+
+    pub fn from_name(name: &str) -> AutoAccept {
+        match name {
+            "Always" => AutoAccept::Always,
+            "Never" => AutoAccept::Never,
+            _ => AutoAccept::Default,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Contact {
+    pub nickname: String,
+    #[serde(default)]
+    pub favorite: bool,
+    #[serde(default)]
+    pub auto_accept: AutoAccept,
+}
+
+pub fn contacts() -> BTreeMap<String, Contact> {
+    load_json(CONTACTS_FILE)
+}
+
+// Adds or replaces `username`
+pub fn save_contact(username: &str, contact: Contact) {
+    let mut contacts = contacts();
+    contacts.insert(username.to_string(), contact);
+    if let Err(e) = save_json(CONTACTS_FILE, &contacts) {
+        eprintln!("❌ Failed to save contacts: {}", e);
+    }
+}
+
+pub fn remove_contact(username: &str) {
+    let mut contacts = contacts();
+    if contacts.remove(username).is_some() {
+        let _ = save_json(CONTACTS_FILE, &contacts);
+    }
+}
+
+// Changes one contact in place, does nothing if `username` isn't one
+pub fn update_contact(username: &str, change: impl FnOnce(&mut Contact)) {
+    let Some(mut contact) = contacts().remove(username) else { return };
+    change(&mut contact);
+    save_contact(username, contact);
+}
+
+pub fn auto_accept(username: &str) -> AutoAccept {
+    contacts().get(username).map_or(AutoAccept::Default, |c| c.auto_accept)
+}
+
+// `usernames` with favourites first (alphabetical), then the rest in their order
+pub fn favorites_first(usernames: Vec<String>) -> Vec<String> {
+    let contacts = contacts();
+    let mut favorites: Vec<String> = contacts.iter().filter(|(_, c)| c.favorite).map(|(name, _)| name.clone()).collect();
+    let rest: Vec<String> = usernames.into_iter().filter(|name| !favorites.contains(name)).collect();
+    favorites.extend(rest);
+    favorites
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policies_saved_as_ask_load_as_default() {
+        let contact: Contact = serde_json::from_str(r#"{"nickname": "Bob", "auto_accept": "ask"}"#).unwrap();
+        assert!(contact.auto_accept == AutoAccept::Default);
+        assert_eq!(serde_json::to_value(AutoAccept::Default).unwrap(), "default");
+        for policy in [AutoAccept::Default, AutoAccept::Always, AutoAccept::Never] {
+            assert!(AutoAccept::from_name(policy.name()) == policy);
+        }
+    }
+}
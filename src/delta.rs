@@ -0,0 +1,383 @@
+// rsync-style delta transfer. The receiver sends signatures of the blocks of the
+// version it already has (a rolling checksum and a strong hash per block); the sender
+// slides a window over the new version and only transmits the bytes that don't
+// match a block, plus instructions to copy the blocks that do.
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+const MIN_BLOCK_SIZE: usize = 2 * 1024;
+const MAX_BLOCK_SIZE: usize = 64 * 1024;
+const READ_SIZE: usize = 1024 * 1024;
+
+// Signatures of the receiver's copy, sent back in file_accept["delta"]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Signature {
+    // Length of the receiver's copy, its last block may be short
+    pub len: u64,
+    pub block_size: usize,
+    // (rolling checksum, base64 of the first 16 bytes of the block's SHA-256)
+    pub blocks: Vec<(u32, String)>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Op {
+    // Blocks `block..block + count` of the receiver's copy
+    Copy { block: usize, count: usize },
+    // Bytes of the new file, read back by the sender when it streams the delta
+    Literal { offset: u64, len: u64 },
+}
+
+impl Signature {
+    // The signature comes from the peer: a block size we would never pick, or a block
+    // list that doesn't cover `len`, would have diff read or index out of bounds
+    pub fn check(&self) -> io::Result<()> {
+        let invalid = |message: String| Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&self.block_size) {
+            return invalid(format!("block size {} is out of range", self.block_size));
+        }
+        if self.blocks.len() as u64 != self.len.div_ceil(self.block_size as u64) {
+            return invalid(format!("{} blocks don't cover {} bytes", self.blocks.len(), self.len));
+        }
+        Ok(())
+    }
+}
+
+// About sqrt(size) like rsync, so small files get fine blocks and huge files don't
+// produce a huge signature
+pub fn block_size_for(len: u64) -> usize {
+    ((len as f64).sqrt() as usize).clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+pub fn signature(path: &Path) -> io::Result<Signature> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let block_size = block_size_for(len);
+    let mut reader = BufReader::new(file);
+    let mut block = vec![0u8; block_size];
+    let mut blocks = Vec::new();
+    loop {
+        let n = read_full(&mut reader, &mut block)?;
+        if n == 0 {
+            break;
+        }
+        blocks.push((Rolling::new(&block[..n]).digest(), strong_hash(&block[..n])));
+        if n < block_size {
+            break;
+        }
+    }
+    Ok(Signature { len, block_size, blocks })
+}
+
+// Reads until `buf` is full or the file ends
+pub fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+fn strong_hash(data: &[u8]) -> String {
+    STANDARD.encode(&Sha256::digest(data)[..16])
+}
+
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; READ_SIZE];
+    loop {
+        match reader.read(&mut buf)? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// rsync's weak checksum, cheap to slide one byte at a time
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(data: &[u8]) -> Rolling {
+        let len = data.len() as u32;
+        let mut a = 0u32;
+        let mut b = 0u32;
+        for (i, &x) in data.iter().enumerate() {
+            a = a.wrapping_add(x as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(x as u32));
+        }
+        Rolling { a: a & 0xffff, b: b & 0xffff, len }
+    }
+
+    fn roll(&mut self, out: u8, inp: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(inp as u32) & 0xffff;
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a) & 0xffff;
+    }
+
+    fn digest(&self) -> u32 {
+        self.a | (self.b << 16)
+    }
+}
+
+// Works out how to build the file at `path` from the receiver's blocks
+pub fn diff(path: &Path, signature: &Signature) -> io::Result<Vec<Op>> {
+    signature.check()?;
+    let block_size = signature.block_size;
+    // Only full blocks can match inside the file, a short last block only at its end
+    let mut by_weak: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (index, (weak, _)) in signature.blocks.iter().enumerate() {
+        if block_len(signature, index) == block_size {
+            by_weak.entry(*weak).or_default().push(index);
+        }
+    }
+
+    let mut ops = Ops::default();
+    let mut reader = File::open(path)?;
+    // buf holds the file from offset buf_start, the window is buf[win..win + block_size]
+    let mut buf: Vec<u8> = Vec::new();
+    let mut buf_start: u64 = 0;
+    let mut win = 0usize;
+    let mut eof = false;
+    let mut rolling: Option<Rolling> = None;
+
+    loop {
+        // Keep one byte past the window around so it can roll forward
+        if !eof && buf.len() < win + block_size + 1 {
+            if win > READ_SIZE {
+                buf.drain(..win);
+                buf_start += win as u64;
+                win = 0;
+            }
+            let old_len = buf.len();
+            buf.resize(old_len + READ_SIZE, 0);
+            let n = read_full(&mut reader, &mut buf[old_len..])?;
+            buf.truncate(old_len + n);
+            eof = n == 0;
+            continue;
+        }
+        if buf.len() < win + block_size {
+            break;
+        }
+
+        let window = &buf[win..win + block_size];
+        let weak = rolling.get_or_insert_with(|| Rolling::new(window)).digest();
+        let matched = by_weak.get(&weak).and_then(|candidates| {
+            let strong = strong_hash(window);
+            candidates
+                .iter()
+                .copied()
+                .find(|&i| signature.blocks[i].1 == strong)
+        });
+
+        match matched {
+            Some(block) => {
+                ops.copy(buf_start + win as u64, block, block_size);
+                win += block_size;
+                rolling = None;
+            }
+            None => {
+                if let (Some(rolling), Some(&inp)) = (rolling.as_mut(), buf.get(win + block_size)) {
+                    rolling.roll(buf[win], inp);
+                } else {
+                    rolling = None;
+                }
+                win += 1;
+            }
+        }
+    }
+
+    // The tail may still be the receiver's short last block
+    let tail = &buf[win.min(buf.len())..];
+    let end = buf_start + buf.len() as u64;
+    if let Some(last) = signature.blocks.len().checked_sub(1)
+        && !tail.is_empty()
+        && block_len(signature, last) == tail.len()
+        && signature.blocks[last].1 == strong_hash(tail)
+    {
+        ops.copy(end - tail.len() as u64, last, tail.len());
+    }
+    ops.finish(end);
+    Ok(ops.ops)
+}
+
+pub fn block_len(signature: &Signature, index: usize) -> usize {
+    let start = (index as u64).saturating_mul(signature.block_size as u64);
+    signature.len.saturating_sub(start).min(signature.block_size as u64) as usize
+}
+
+// Collects ops, merging runs of consecutive blocks and tracking literal ranges
+#[derive(Default)]
+struct Ops {
+    ops: Vec<Op>,
+    // Where the bytes not yet covered by an op start
+    pending: u64,
+}
+
+impl Ops {
+    fn copy(&mut self, offset: u64, block: usize, len: usize) {
+        if offset > self.pending {
+            self.ops.push(Op::Literal { offset: self.pending, len: offset - self.pending });
+        }
+        self.pending = offset + len as u64;
+        if let Some(Op::Copy { block: start, count }) = self.ops.last_mut()
+            && *start + *count == block
+        {
+            *count += 1;
+            return;
+        }
+        self.ops.push(Op::Copy { block, count: 1 });
+    }
+
+    fn finish(&mut self, end: u64) {
+        if end > self.pending {
+            self.ops.push(Op::Literal { offset: self.pending, len: end - self.pending });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut bytes = vec![0u8; len];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        bytes
+    }
+
+    // What the receiver builds from its copy and the ops, like IncomingFile does
+    fn apply(old: &[u8], new: &[u8], signature: &Signature, ops: &[Op]) -> Vec<u8> {
+        let mut built = Vec::new();
+        for op in ops {
+            match *op {
+                Op::Copy { block, count } => {
+                    for index in block..block + count {
+                        let start = index * signature.block_size;
+                        built.extend_from_slice(&old[start..start + block_len(signature, index)]);
+                    }
+                }
+                Op::Literal { offset, len } => built.extend_from_slice(&new[offset as usize..(offset + len) as usize]),
+            }
+        }
+        built
+    }
+
+    fn literal_bytes(ops: &[Op]) -> u64 {
+        ops.iter().map(|op| if let Op::Literal { len, .. } = op { *len } else { 0 }).sum()
+    }
+
+    // The signature of `old` and the ops for `new`, through files like a transfer
+    fn delta(old: &[u8], new: &[u8]) -> (Signature, Vec<Op>) {
+        let dir = tempfile::tempdir().unwrap();
+        let (old_path, new_path) = (dir.path().join("old"), dir.path().join("new"));
+        std::fs::write(&old_path, old).unwrap();
+        std::fs::write(&new_path, new).unwrap();
+        let signature = signature(&old_path).unwrap();
+        let ops = diff(&new_path, &signature).unwrap();
+        (signature, ops)
+    }
+
+    #[test]
+    fn rolling_matches_a_fresh_checksum() {
+        let data = random_bytes(4096);
+        let window = 512;
+        let mut rolling = Rolling::new(&data[..window]);
+        for start in 1..data.len() - window {
+            rolling.roll(data[start - 1], data[start + window - 1]);
+            assert_eq!(rolling.digest(), Rolling::new(&data[start..start + window]).digest(), "window at {}", start);
+        }
+    }
+
+    #[test]
+    fn unchanged_file_is_all_copies() {
+        let old = random_bytes(300 * 1024 + 123);
+        let (signature, ops) = delta(&old, &old);
+
+        assert_eq!(literal_bytes(&ops), 0);
+        assert_eq!(ops, vec![Op::Copy { block: 0, count: signature.blocks.len() }]);
+        assert_eq!(apply(&old, &old, &signature, &ops), old);
+    }
+
+    #[test]
+    fn edits_round_trip_with_little_literal_data() {
+        let old = random_bytes(500 * 1024);
+        let mut new = old.clone();
+        new[1000..1010].copy_from_slice(b"0123456789");
+        new.splice(200_000..200_000, random_bytes(777));
+        new.drain(400_000..401_000);
+        new.extend_from_slice(b"appended at the end");
+        let (signature, ops) = delta(&old, &new);
+
+        assert_eq!(apply(&old, &new, &signature, &ops), new);
+        // Each edit costs about a block, the rest is copied
+        assert!(literal_bytes(&ops) < 5 * signature.block_size as u64, "{} literal bytes", literal_bytes(&ops));
+    }
+
+    #[test]
+    fn unrelated_file_is_all_literal() {
+        let old = random_bytes(64 * 1024);
+        let new = random_bytes(80 * 1024);
+        let (signature, ops) = delta(&old, &new);
+
+        assert_eq!(ops, vec![Op::Literal { offset: 0, len: new.len() as u64 }]);
+        assert_eq!(apply(&old, &new, &signature, &ops), new);
+    }
+
+    #[test]
+    fn short_files_round_trip() {
+        for (old, new) in [(&b""[..], &b"new"[..]), (b"old", b""), (b"same", b"same")] {
+            let (signature, ops) = delta(old, new);
+            assert_eq!(apply(old, new, &signature, &ops), new);
+        }
+    }
+
+    #[test]
+    fn block_size_0_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"data").unwrap();
+        let signature = Signature { len: 4, block_size: 0, blocks: Vec::new() };
+        assert!(diff(&path, &signature).is_err());
+    }
+
+    #[test]
+    fn bad_signatures_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, random_bytes(10 * 1024)).unwrap();
+        let block = |n: usize| vec![(0, String::new()); n];
+        for (len, block_size, blocks) in [
+            (4, usize::MAX, block(1)),
+            (4, MAX_BLOCK_SIZE + 1, block(1)),
+            (4, MIN_BLOCK_SIZE - 1, block(1)),
+            // Too few and too many blocks for the length
+            (10 * 1024, MIN_BLOCK_SIZE, block(4)),
+            (10 * 1024, MIN_BLOCK_SIZE, block(6)),
+            (u64::MAX, MAX_BLOCK_SIZE, block(1)),
+        ] {
+            let signature = Signature { len, block_size, blocks };
+            let error = diff(&path, &signature).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{:?}", signature);
+        }
+        let (signature, _) = delta(&random_bytes(10 * 1024), &[]);
+        assert!(signature.check().is_ok());
+    }
+}
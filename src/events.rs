@@ -0,0 +1,126 @@
+// What the transfers in this process are doing, for a ui or an embedding program to
+// show. Files sent and received over relay sessions report to the subscribers of the
+// process, or to those of the `Client` that started them (see client.rs): a transfer
+// run inside `Subscribers::scope` reports there only.
+use crate::history::Direction;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use std::fmt::Display;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+// Progress goes out at most this often per file, a subscriber can't use more
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransferEvent {
+    // `size` is None for streams, their length is only known at the end
+    Started { direction: Direction, peer: String, name: String, size: Option<u64> },
+    Progress { direction: Direction, peer: String, name: String, bytes: u64, size: Option<u64> },
+    // `path` is where a received file was saved, None for sent files and standard output
+    Finished { direction: Direction, peer: String, name: String, bytes: u64, path: Option<PathBuf> },
+    Failed { direction: Direction, peer: String, name: String, error: String },
+}
+
+#[derive(Clone, Default)]
+pub struct Subscribers(Arc<Mutex<Vec<UnboundedSender<TransferEvent>>>>);
+
+impl Subscribers {
+    // Every event from now on, until the receiver is dropped
+    pub fn subscribe(&self) -> UnboundedReceiver<TransferEvent> {
+        let (tx, rx) = unbounded();
+        self.0.lock().unwrap().push(tx);
+        rx
+    }
+
+    // Runs `transfer` with the files it starts reporting to these subscribers only
+    pub async fn scope<F: Future>(&self, transfer: F) -> F::Output {
+        SCOPE.scope(self.clone(), transfer).await
+    }
+
+    fn emit(&self, event: TransferEvent) {
+        self.0.lock().unwrap().retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+    }
+}
+
+static SUBSCRIBERS: LazyLock<Subscribers> = LazyLock::new(Default::default);
+
+tokio::task_local! {
+    static SCOPE: Subscribers;
+}
+
+// Every event of transfers outside a scope from now on
+pub fn subscribe() -> UnboundedReceiver<TransferEvent> {
+    SUBSCRIBERS.subscribe()
+}
+
+// The events of one file. Dropped unfinished it reports the file as failed, like an
+// unfinished history::Transfer.
+pub struct FileProgress {
+    subscribers: Subscribers,
+    direction: Direction,
+    peer: String,
+    name: String,
+    size: Option<u64>,
+    bytes: u64,
+    reported: Instant,
+    done: bool,
+}
+
+impl FileProgress {
+    pub fn start(direction: Direction, peer: &str, name: &str, size: Option<u64>) -> FileProgress {
+        let (peer, name) = (peer.to_string(), name.to_string());
+        let subscribers = SCOPE.try_with(Subscribers::clone).unwrap_or_else(|_| SUBSCRIBERS.clone());
+        subscribers.emit(TransferEvent::Started { direction, peer: peer.clone(), name: name.clone(), size });
+        FileProgress { subscribers, direction, peer, name, size, bytes: 0, reported: Instant::now(), done: false }
+    }
+
+    // `bytes` is how much of the file is through so far
+    pub fn update(&mut self, bytes: u64) {
+        self.bytes = bytes;
+        let complete = self.size == Some(bytes);
+        if complete || self.reported.elapsed() >= PROGRESS_INTERVAL {
+            self.reported = Instant::now();
+            self.subscribers.emit(TransferEvent::Progress {
+                direction: self.direction,
+                peer: self.peer.clone(),
+                name: self.name.clone(),
+                bytes,
+                size: self.size,
+            });
+        }
+    }
+
+    pub fn finish(mut self, path: Option<&Path>) {
+        self.done = true;
+        let bytes = self.size.unwrap_or(self.bytes);
+        let (peer, name) = (std::mem::take(&mut self.peer), std::mem::take(&mut self.name));
+        self.subscribers.emit(TransferEvent::Finished { direction: self.direction, peer, name, bytes, path: path.map(Path::to_path_buf) });
+    }
+
+    pub fn fail(mut self, error: impl Display) {
+        self.report_failure(error.to_string());
+    }
+
+    pub fn finish_with<T, E: Display>(self, result: &Result<T, E>, path: Option<&Path>) {
+        match result {
+            Ok(_) => self.finish(path),
+            Err(e) => self.fail(e),
+        }
+    }
+
+    fn report_failure(&mut self, error: String) {
+        self.done = true;
+        let (peer, name) = (std::mem::take(&mut self.peer), std::mem::take(&mut self.name));
+        self.subscribers.emit(TransferEvent::Failed { direction: self.direction, peer, name, error });
+    }
+}
+
+impl Drop for FileProgress {
+    fn drop(&mut self) {
+        if !self.done {
+            self.report_failure("Interrupted".to_string());
+        }
+    }
+}
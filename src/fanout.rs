@@ -0,0 +1,133 @@
+// One file sent to several recipients at once. The recipient that is furthest ahead
+// reads the next chunk from disk and the last WINDOW chunks stay around for the
+// others, so in the usual case the file is read (and hashed) once. A recipient that
+// falls further behind reads what it missed from its own file handle instead of
+// holding the others back.
+use crate::delta::{read_full, to_hex};
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const WINDOW: usize = 64;
+
+pub struct SharedFile {
+    path: PathBuf,
+    pub size: u64,
+    chunk_size: usize,
+    state: Mutex<Shared>,
+}
+
+struct Shared {
+    file: File,
+    hasher: Option<Sha256>,
+    sha256: Option<String>,
+    // Index of the first chunk in `recent`
+    first: u64,
+    recent: VecDeque<Arc<Vec<u8>>>,
+    eof: bool,
+}
+
+impl SharedFile {
+    pub fn open(path: &Path, chunk_size: usize) -> io::Result<Arc<SharedFile>> {
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Arc::new(SharedFile {
+            path: path.to_path_buf(),
+            size,
+            chunk_size,
+            state: Mutex::new(Shared {
+                file,
+                hasher: Some(Sha256::new()),
+                sha256: None,
+                first: 0,
+                recent: VecDeque::new(),
+                eof: false,
+            }),
+        }))
+    }
+
+    // Hex SHA-256 of what was read, known once the leading recipient hit the end
+    pub fn sha256(&self) -> Option<String> {
+        self.state.lock().unwrap().sha256.clone()
+    }
+
+    // A recipient's position in the file
+    pub fn reader(self: &Arc<SharedFile>) -> ChunkReader {
+        ChunkReader { shared: self.clone(), next: 0, own: None }
+    }
+}
+
+pub struct ChunkReader {
+    shared: Arc<SharedFile>,
+    next: u64,
+    // Only opened once this recipient fell out of the window
+    own: Option<File>,
+}
+
+impl ChunkReader {
+    // The next chunk, None at the end of the file
+    pub fn next_chunk(&mut self) -> io::Result<Option<Arc<Vec<u8>>>> {
+        let chunk = self.chunk(self.next)?;
+        if chunk.is_some() {
+            self.next += 1;
+        }
+        Ok(chunk)
+    }
+
+    pub fn position(&self) -> u64 {
+        (self.next * self.shared.chunk_size as u64).min(self.shared.size)
+    }
+
+    fn chunk(&mut self, index: u64) -> io::Result<Option<Arc<Vec<u8>>>> {
+        let shared = &self.shared;
+        let mut state = shared.state.lock().unwrap();
+        let end = state.first + state.recent.len() as u64;
+
+        if index >= state.first && index < end {
+            return Ok(Some(state.recent[(index - state.first) as usize].clone()));
+        }
+        if index == end {
+            // We're in front, read for everyone
+            if state.eof {
+                return Ok(None);
+            }
+            let mut buf = vec![0u8; shared.chunk_size];
+            let n = read_full(&mut state.file, &mut buf)?;
+            if n == 0 {
+                state.eof = true;
+                let hasher = state.hasher.take().unwrap_or_default();
+                state.sha256 = Some(to_hex(&hasher.finalize()));
+                return Ok(None);
+            }
+            buf.truncate(n);
+            if let Some(hasher) = state.hasher.as_mut() {
+                hasher.update(&buf);
+            }
+            let chunk = Arc::new(buf);
+            state.recent.push_back(chunk.clone());
+            if state.recent.len() > WINDOW {
+                state.recent.pop_front();
+                state.first += 1;
+            }
+            return Ok(Some(chunk));
+        }
+        drop(state);
+
+        // Fell behind the window
+        let own = match self.own.as_mut() {
+            Some(own) => own,
+            None => self.own.insert(File::open(&shared.path)?),
+        };
+        own.seek(SeekFrom::Start(index * shared.chunk_size as u64))?;
+        let mut buf = vec![0u8; shared.chunk_size];
+        let n = read_full(own, &mut buf)?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.truncate(n);
+        Ok(Some(Arc::new(buf)))
+    }
+}
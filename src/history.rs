@@ -0,0 +1,146 @@
+// A local record of every transfer, kept after the app closes. Each finished (or
+// failed) transfer is one JSON line appended to history.jsonl in the data directory,
+// so a crash mid-write costs at most that one line and the file never needs rewriting.
+use crate::store::data_dir;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+const HISTORY_FILE: &str = "history.jsonl";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+// How the bytes travelled
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Route {
+    Relay,
+    Direct,
+    Lan,
+    // Left on the server for a peer that was offline
+    Mailbox,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FileRecord {
+    pub name: String,
+    pub size: u64,
+    pub sha256: Option<String>,
+    // Where it was sent from or saved to
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Record {
+    // Seconds since the epoch when the transfer started
+    pub started: u64,
+    pub direction: Direction,
+    pub peer: String,
+    pub files: Vec<FileRecord>,
+    pub route: Route,
+    pub duration_ms: u64,
+    // None if it went through
+    pub error: Option<String>,
+}
+
+impl Record {
+    pub fn size(&self) -> u64 {
+        self.files.iter().map(|f| f.size).sum()
+    }
+
+    // Case-insensitive match on the peer and the file names, an empty query matches all
+    pub fn matches(&self, query: &str) -> bool {
+        let query = query.trim().to_lowercase();
+        query.is_empty()
+            || self.peer.to_lowercase().contains(&query)
+            || self.files.iter().any(|f| f.name.to_lowercase().contains(&query))
+    }
+}
+
+// A transfer in progress. It is written to the history when finished, or when dropped
+// unfinished, which is how transfers cut short by an error end up as failed.
+pub struct Transfer {
+    record: Record,
+    clock: Instant,
+    done: bool,
+}
+
+impl Transfer {
+    pub fn start(direction: Direction, peer: &str, route: Route) -> Transfer {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let record = Record { started, direction, peer: peer.to_string(), files: Vec::new(), route, duration_ms: 0, error: None };
+        Transfer { record, clock: Instant::now(), done: false }
+    }
+
+    // For transfers that only learn who they are with once they're under way
+    pub fn set_peer(&mut self, peer: &str) {
+        self.record.peer = peer.to_string();
+    }
+
+    pub fn add_file(&mut self, name: &str, size: u64, sha256: Option<&str>, path: Option<&Path>) {
+        let file = FileRecord { name: name.to_string(), size, sha256: sha256.map(str::to_string), path: path.map(Path::to_path_buf) };
+        self.record.files.push(file);
+    }
+
+    pub fn finish(mut self, error: Option<String>) {
+        self.write(error);
+    }
+
+    pub fn finish_with<T, E: Display>(self, result: &Result<T, E>) {
+        self.finish(result.as_ref().err().map(|e| e.to_string()));
+    }
+
+    fn write(&mut self, error: Option<String>) {
+        self.done = true;
+        self.record.duration_ms = self.clock.elapsed().as_millis() as u64;
+        self.record.error = error;
+        if let Err(e) = append(&self.record) {
+            eprintln!("❌ Failed to write the transfer history: {}", e);
+        }
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        if !self.done {
+            self.write(Some("Interrupted".to_string()));
+        }
+    }
+}
+
+fn append(record: &Record) -> io::Result<()> {
+    let dir = data_dir();
+    fs::create_dir_all(&dir)?;
+    let mut file = OpenOptions::new().create(true).append(true).open(dir.join(HISTORY_FILE))?;
+    writeln!(file, "{}", serde_json::to_string(record)?)
+}
+
+// Every record, oldest first. Lines that don't parse are skipped.
+pub fn load() -> Vec<Record> {
+    let Ok(text) = fs::read_to_string(data_dir().join(HISTORY_FILE)) else { return Vec::new() };
+    text.lines().filter_map(|line| serde_json::from_str(line).ok()).collect()
+}
+
+// "2026-10-19 14:05" in UTC
+pub fn format_time(secs: u64) -> String {
+    // Days to civil date, after Howard Hinnant's days_from_civil inverse
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    let minutes = secs / 60 % (24 * 60);
+    format!("{}-{:02}-{:02} {:02}:{:02}", year, month, day, minutes / 60, minutes % 60)
+}
@@ -0,0 +1,453 @@
+// Long-term Ed25519 identity of this client and trust-on-first-use pins for peers.
+// The public key is published at register, but the server is never trusted with it:
+// at the start of every relay session both sides prove they hold their key over
+// fresh nonces, and the key is checked against the one pinned on first contact.
+// Two users can compare the safety code out of band to rule out an impostor on
+// that first contact.
+use crate::offline::Opener;
+use crate::store::{data_dir, load_json, save_json};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+const KEY_FILE: &str = "identity_key";
+const KNOWN_PEERS_FILE: &str = "known_peers.json";
+
+#[derive(Clone)]
+pub struct Identity {
+    key: SigningKey,
+}
+
+impl Identity {
+    // Loads the key from the data directory, generating it on first start
+    pub fn load_or_create() -> io::Result<Identity> {
+        let path = data_dir().join(KEY_FILE);
+        if let Ok(seed) = fs::read(&path)
+            && let Ok(seed) = <[u8; 32]>::try_from(seed.as_slice())
+        {
+            return Ok(Identity { key: SigningKey::from_bytes(&seed) });
+        }
+
+        let key = SigningKey::generate(&mut OsRng);
+        fs::create_dir_all(data_dir())?;
+        fs::write(&path, key.to_bytes())?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        }
+        eprintln!("🔑 Generated a new identity key");
+        Ok(Identity { key })
+    }
+
+    pub fn public_key(&self) -> String {
+        STANDARD.encode(self.key.verifying_key().to_bytes())
+    }
+
+    fn sign(&self, message: &str) -> String {
+        STANDARD.encode(self.key.sign(message.as_bytes()).to_bytes())
+    }
+
+    // Header of a file we leave on the server for `to` (see offline.rs). The
+    // signature ties the ephemeral key to us, so `to` knows who sealed the file.
+    pub fn offline_header(&self, from: &str, to: &str, ephemeral: &str) -> Value {
+        json!({
+            "ephemeral": ephemeral,
+            "identity_key": self.public_key(),
+            "signature": self.sign(&offline_transcript(from, to, ephemeral))
+        })
+    }
+
+    // Checks who sealed a file `from` left for `me` and returns what opens it. The
+    // sender's key is pinned and checked like in a relay session.
+    pub fn offline_opener(&self, from: &str, me: &str, header: &Value) -> Result<Opener, String> {
+        let field = |name: &str| header.get(name).and_then(|v| v.as_str()).unwrap_or_default();
+        let (ephemeral, key) = (field("ephemeral"), field("identity_key"));
+        if !verify(key, &offline_transcript(from, me, ephemeral), field("signature")) {
+            return Err(format!("The file isn't signed by {}", from));
+        }
+        if matches!(check_and_pin(from, key), Trust::Changed) {
+            return Err(format!(
+                "⚠️ The identity key of {} changed. Compare safety codes before trusting the new key.",
+                from
+            ));
+        }
+        Opener::new(&self.key, ephemeral)
+    }
+}
+
+fn offline_transcript(from: &str, to: &str, ephemeral: &str) -> String {
+    format!("p2p_rust offline v1|{}|{}|{}", from, to, ephemeral)
+}
+
+fn verify(public_key: &str, message: &str, signature: &str) -> bool {
+    let Some(key) = decode_key(public_key) else { return false };
+    let Some(signature) = STANDARD.decode(signature).ok().and_then(|s| Signature::from_slice(&s).ok()) else {
+        return false;
+    };
+    key.verify(message.as_bytes(), &signature).is_ok()
+}
+
+fn decode_key(public_key: &str) -> Option<VerifyingKey> {
+    let bytes = <[u8; 32]>::try_from(STANDARD.decode(public_key).ok()?).ok()?;
+    VerifyingKey::from_bytes(&bytes).ok()
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct KnownPeer {
+    identity_key: String,
+    // The user compared safety codes with this peer
+    verified: bool,
+    // A different key the peer presented, waiting for the user to accept it
+    #[serde(default)]
+    pending_key: Option<String>,
+}
+
+pub enum Trust {
+    // Never seen this peer before, the key gets pinned when the session succeeds
+    FirstUse,
+    Known { verified: bool },
+    // The peer presented a different key than the pinned one
+    Changed,
+}
+
+// Read-only check of `key` against the pin for `username`
+pub fn peer_trust(username: &str, key: &str) -> Trust {
+    match load_json::<HashMap<String, KnownPeer>>(KNOWN_PEERS_FILE).get(username) {
+        None => Trust::FirstUse,
+        Some(peer) if peer.identity_key == key => Trust::Known { verified: peer.verified },
+        Some(_) => Trust::Changed,
+    }
+}
+
+pub fn pinned_key(username: &str) -> Option<String> {
+    load_json::<HashMap<String, KnownPeer>>(KNOWN_PEERS_FILE).remove(username).map(|p| p.identity_key)
+}
+
+// Pins on first use, remembers a changed key as pending, leaves known keys alone
+fn check_and_pin(username: &str, key: &str) -> Trust {
+    let trust = peer_trust(username, key);
+    let mut peers: HashMap<String, KnownPeer> = load_json(KNOWN_PEERS_FILE);
+    match trust {
+        Trust::FirstUse => {
+            eprintln!("📌 Pinned identity key of {}", username);
+            peers.insert(username.to_string(), KnownPeer { identity_key: key.to_string(), ..Default::default() });
+        }
+        Trust::Changed => {
+            eprintln!("⚠️ Identity key of {} changed!", username);
+            if let Some(peer) = peers.get_mut(username) {
+                peer.pending_key = Some(key.to_string());
+            }
+        }
+        Trust::Known { .. } => return trust,
+    }
+    if let Err(e) = save_json(KNOWN_PEERS_FILE, &peers) {
+        eprintln!("❌ Failed to save known peers: {}", e);
+    }
+    trust
+}
+
+// The user decided to trust the key `username` presented last. Returns false if
+// there was no changed key waiting.
+pub fn accept_pending_key(username: &str) -> bool {
+    let mut peers: HashMap<String, KnownPeer> = load_json(KNOWN_PEERS_FILE);
+    let Some(peer) = peers.get_mut(username) else { return false };
+    let Some(key) = peer.pending_key.take() else { return false };
+    *peer = KnownPeer { identity_key: key, ..Default::default() };
+    save_json(KNOWN_PEERS_FILE, &peers).is_ok()
+}
+
+pub fn pending_key(username: &str) -> Option<String> {
+    load_json::<HashMap<String, KnownPeer>>(KNOWN_PEERS_FILE).remove(username).and_then(|p| p.pending_key)
+}
+
+pub fn mark_verified(username: &str) {
+    let mut peers: HashMap<String, KnownPeer> = load_json(KNOWN_PEERS_FILE);
+    if let Some(peer) = peers.get_mut(username) {
+        peer.verified = true;
+        if let Err(e) = save_json(KNOWN_PEERS_FILE, &peers) {
+            eprintln!("❌ Failed to save known peers: {}", e);
+        }
+    }
+}
+
+const WORDS: [&str; 64] = [
+    "apple", "arrow", "badge", "beach", "bison", "blade", "bloom", "cabin",
+    "camel", "candy", "cedar", "chalk", "cloud", "coral", "crane", "dance",
+    "delta", "eagle", "ember", "fable", "ferry", "flame", "frost", "giant",
+    "grape", "honey", "igloo", "ivory", "jelly", "jewel", "koala", "lemon",
+    "lilac", "llama", "lotus", "mango", "maple", "medal", "moose", "noble",
+    "ocean", "olive", "opera", "otter", "pearl", "piano", "pilot", "pixel",
+    "quilt", "radar", "raven", "robin", "salad", "shark", "solar", "spice",
+    "tiger", "toast", "tulip", "umbra", "vivid", "whale", "yacht", "zebra",
+];
+
+// Six words derived from both public keys. Both users see the same code, a
+// different code means one of them is not talking to who they think.
+pub fn safety_code(key_a: &str, key_b: &str) -> String {
+    let (first, second) = if key_a <= key_b { (key_a, key_b) } else { (key_b, key_a) };
+    let digest = Sha256::new()
+        .chain_update(b"p2p_rust safety code")
+        .chain_update(STANDARD.decode(first).unwrap_or_default())
+        .chain_update(STANDARD.decode(second).unwrap_or_default())
+        .finalize();
+    let bits = u64::from_be_bytes(digest[..8].try_into().unwrap());
+    (0..6)
+        .map(|i| WORDS[((bits >> (58 - 6 * i)) & 63) as usize])
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn new_nonce() -> String {
+    let mut nonce = [0u8; 16];
+    OsRng.fill_bytes(&mut nonce);
+    STANDARD.encode(nonce)
+}
+
+// What each side signs: its role, both names and both nonces, so a signature can't
+// be replayed into another session or reflected back at its author.
+fn transcript(role: &str, signer: &str, peer: &str, initiator_nonce: &str, responder_nonce: &str) -> String {
+    format!("p2p_rust identity v1|{}|{}|{}|{}|{}", role, signer, peer, initiator_nonce, responder_nonce)
+}
+
+// Mutual proof of identity keys over a relay session:
+//   initiator -> identity {username, identity_key, nonce}
+//   responder -> identity {username, identity_key, nonce, signature}
+//   initiator -> identity_proof {signature}
+pub struct Handshake {
+    identity: Identity,
+    username: String,
+    // Who the initiator asked the server for, the responder learns the name from the hello
+    peer: Option<String>,
+    peer_key: String,
+    my_nonce: String,
+    peer_nonce: String,
+}
+
+impl Handshake {
+    pub fn initiator(identity: Identity, username: &str, target: &str) -> Handshake {
+        Handshake::new(identity, username, Some(target.to_string()))
+    }
+
+    pub fn responder(identity: Identity, username: &str) -> Handshake {
+        Handshake::new(identity, username, None)
+    }
+
+    fn new(identity: Identity, username: &str, peer: Option<String>) -> Handshake {
+        Handshake {
+            identity,
+            username: username.to_string(),
+            peer,
+            peer_key: String::new(),
+            my_nonce: String::new(),
+            peer_nonce: String::new(),
+        }
+    }
+
+    pub fn hello(&mut self) -> Value {
+        self.my_nonce = new_nonce();
+        self.identity_message()
+    }
+
+    // Handles an identity or identity_proof message from the peer. Returns the message
+    // to answer with; an error means the transfer must not go ahead.
+    pub fn on_message(&mut self, msg: &Value) -> Result<Option<Value>, String> {
+        let field = |name: &str| msg.get(name).and_then(|v| v.as_str()).map(|s| s.to_string());
+
+        match msg.get("type").and_then(|v| v.as_str()) {
+            // Hello from the initiator, we are the responder
+            Some("identity") if msg.get("signature").is_none() => {
+                let (Some(peer), Some(key), Some(nonce)) = (field("username"), field("identity_key"), field("nonce")) else {
+                    return Err("Malformed identity message".to_string());
+                };
+                decode_key(&key).ok_or("Peer sent an invalid identity key")?;
+                self.peer = Some(peer);
+                self.peer_key = key;
+                self.peer_nonce = nonce;
+                self.my_nonce = new_nonce();
+
+                let peer = self.peer.as_deref().unwrap_or_default();
+                let signature =
+                    self.identity.sign(&transcript("responder", &self.username, peer, &self.peer_nonce, &self.my_nonce));
+                let mut reply = self.identity_message();
+                reply["signature"] = Value::from(signature);
+                Ok(Some(reply))
+            }
+
+            // Answer to our hello, we are the initiator
+            Some("identity") => {
+                let (Some(peer), Some(key), Some(nonce), Some(signature)) =
+                    (field("username"), field("identity_key"), field("nonce"), field("signature"))
+                else {
+                    return Err("Malformed identity message".to_string());
+                };
+                if self.peer.as_deref() != Some(peer.as_str()) {
+                    return Err(format!("Expected {} but {} answered", self.peer.as_deref().unwrap_or("?"), peer));
+                }
+                if !verify(&key, &transcript("responder", &peer, &self.username, &self.my_nonce, &nonce), &signature) {
+                    return Err(format!("{} failed to prove its identity key", peer));
+                }
+                self.peer_key = key;
+                self.peer_nonce = nonce;
+                self.check_trust()?;
+
+                let signature =
+                    self.identity.sign(&transcript("initiator", &self.username, &peer, &self.my_nonce, &self.peer_nonce));
+                Ok(Some(json!({ "type": "identity_proof", "signature": signature })))
+            }
+
+            Some("identity_proof") => {
+                let peer = self.peer.clone().ok_or("Identity proof before identity")?;
+                let signature = field("signature").unwrap_or_default();
+                let signed = transcript("initiator", &peer, &self.username, &self.peer_nonce, &self.my_nonce);
+                if !verify(&self.peer_key, &signed, &signature) {
+                    return Err(format!("{} failed to prove its identity key", peer));
+                }
+                self.check_trust()?;
+                Ok(None)
+            }
+
+            _ => Ok(None),
+        }
+    }
+
+    fn identity_message(&self) -> Value {
+        json!({
+            "type": "identity",
+            "username": self.username,
+            "identity_key": self.identity.public_key(),
+            "nonce": self.my_nonce
+        })
+    }
+
+    fn check_trust(&self) -> Result<(), String> {
+        let peer = self.peer.as_deref().unwrap_or_default();
+        match check_and_pin(peer, &self.peer_key) {
+            Trust::Changed => Err(format!(
+                "⚠️ The identity key of {} changed. Compare safety codes before trusting the new key.",
+                peer
+            )),
+            Trust::FirstUse | Trust::Known { .. } => {
+                eprintln!("🔐 Safety code with {}: {}", peer, safety_code(&self.identity.public_key(), &self.peer_key));
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::set_data_dir;
+    use std::sync::Once;
+
+    // Pins go to known_peers.json, kept out of the user's data directory. Every test
+    // uses its own usernames, they share the file.
+    fn identity() -> Identity {
+        static DATA_DIR: Once = Once::new();
+        DATA_DIR.call_once(|| {
+            set_data_dir(tempfile::tempdir().unwrap().into_path());
+        });
+        Identity { key: SigningKey::generate(&mut OsRng) }
+    }
+
+    // Runs the handshake, the initiator's result first
+    fn handshake(alice: &Identity, alice_name: &str, bob: &Identity, bob_name: &str) -> Result<(), String> {
+        let mut initiator = Handshake::initiator(alice.clone(), alice_name, bob_name);
+        let mut responder = Handshake::responder(bob.clone(), bob_name);
+        let reply = responder.on_message(&initiator.hello())?.expect("the responder answers the hello");
+        let proof = initiator.on_message(&reply)?.expect("the initiator sends its proof");
+        assert_eq!(responder.on_message(&proof)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn handshake_pins_on_first_use() {
+        let (alice, bob) = (identity(), identity());
+        assert!(matches!(peer_trust("pin-bob", &bob.public_key()), Trust::FirstUse));
+
+        handshake(&alice, "pin-alice", &bob, "pin-bob").unwrap();
+        assert_eq!(pinned_key("pin-bob"), Some(bob.public_key()));
+        assert_eq!(pinned_key("pin-alice"), Some(alice.public_key()));
+        assert!(matches!(peer_trust("pin-bob", &bob.public_key()), Trust::Known { verified: false }));
+
+        mark_verified("pin-bob");
+        assert!(matches!(peer_trust("pin-bob", &bob.public_key()), Trust::Known { verified: true }));
+        handshake(&alice, "pin-alice", &bob, "pin-bob").unwrap();
+    }
+
+    #[test]
+    fn changed_key_is_refused_until_accepted() {
+        let (alice, bob, impostor) = (identity(), identity(), identity());
+        handshake(&alice, "change-alice", &bob, "change-bob").unwrap();
+
+        let error = handshake(&alice, "change-alice", &impostor, "change-bob").unwrap_err();
+        assert!(error.contains("identity key of change-bob changed"), "{}", error);
+        assert_eq!(pinned_key("change-bob"), Some(bob.public_key()));
+        assert_eq!(pending_key("change-bob"), Some(impostor.public_key()));
+
+        // Until the user takes the new key, which starts over unverified
+        assert!(accept_pending_key("change-bob"));
+        assert!(!accept_pending_key("change-bob"));
+        handshake(&alice, "change-alice", &impostor, "change-bob").unwrap();
+        assert!(matches!(peer_trust("change-bob", &impostor.public_key()), Trust::Known { verified: false }));
+    }
+
+    #[test]
+    fn replayed_answer_is_refused() {
+        let (alice, bob) = (identity(), identity());
+        let mut first = Handshake::initiator(alice.clone(), "replay-alice", "replay-bob");
+        let mut responder = Handshake::responder(bob.clone(), "replay-bob");
+        let recorded = responder.on_message(&first.hello()).unwrap().unwrap();
+        first.on_message(&recorded).unwrap();
+
+        // Signed over the first session's nonce, worthless for the next one
+        let mut second = Handshake::initiator(alice, "replay-alice", "replay-bob");
+        second.hello();
+        let error = second.on_message(&recorded).unwrap_err();
+        assert_eq!(error, "replay-bob failed to prove its identity key");
+    }
+
+    #[test]
+    fn reflected_signature_is_refused() {
+        let (alice, bob) = (identity(), identity());
+        let mut initiator = Handshake::initiator(alice, "reflect-alice", "reflect-bob");
+        let mut responder = Handshake::responder(bob, "reflect-bob");
+        let reply = responder.on_message(&initiator.hello()).unwrap().unwrap();
+
+        // The responder's own signature sent back as the initiator's proof
+        let reflected = json!({"type": "identity_proof", "signature": reply["signature"]});
+        let error = responder.on_message(&reflected).unwrap_err();
+        assert_eq!(error, "reflect-alice failed to prove its identity key");
+    }
+
+    #[test]
+    fn someone_else_answering_is_refused() {
+        let (alice, mallory) = (identity(), identity());
+        let mut initiator = Handshake::initiator(alice, "other-alice", "other-bob");
+        let mut responder = Handshake::responder(mallory, "other-mallory");
+        let reply = responder.on_message(&initiator.hello()).unwrap().unwrap();
+        assert_eq!(initiator.on_message(&reply).unwrap_err(), "Expected other-bob but other-mallory answered");
+
+        let mut missing = reply.clone();
+        missing.as_object_mut().unwrap().remove("nonce");
+        assert_eq!(initiator.on_message(&missing).unwrap_err(), "Malformed identity message");
+    }
+
+    #[test]
+    fn safety_code_is_the_same_on_both_sides() {
+        let (alice, bob, carol) = (identity().public_key(), identity().public_key(), identity().public_key());
+        let code = safety_code(&alice, &bob);
+        assert_eq!(code, safety_code(&bob, &alice));
+        assert_eq!(code.split(' ').filter(|word| WORDS.contains(word)).count(), 6);
+        assert_ne!(code, safety_code(&alice, &carol));
+    }
+}
@@ -0,0 +1,273 @@
+// A file being received. Data goes to `<name>.part` next to the final path and only
+// replaces the destination once the whole-file hash the sender announced matches,
+// so an interrupted or corrupted transfer never clobbers a good file.
+//
+// Streams from pipe mode have no size up front. They are hashed as they come and end
+// with the file_end that carries the hash, see IncomingStream below.
+use crate::compression::{ChunkDecoder, Codec};
+use crate::delta::{self, Signature};
+use crate::mirror::safe_relative_path;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub struct IncomingFile {
+    pub name: String,
+    pub size: u64,
+    pub written: u64,
+    path: PathBuf,
+    part_path: PathBuf,
+    file: File,
+    hasher: Sha256,
+    decoder: ChunkDecoder,
+    // The existing copy delta_copy instructions read from, and its block size
+    basis: Option<(File, usize)>,
+    // Set once ranges are written in place (multistream.rs), the hash is then taken
+    // from the finished file instead of along the way
+    positional: bool,
+}
+
+impl IncomingFile {
+    // `name` comes from the sender, only its last component is used
+    pub fn create(dir: &Path, name: &str, size: u64, codec: Codec) -> Result<IncomingFile, String> {
+        let file_name = file_name(name)?;
+        IncomingFile::open(dir.join(file_name), file_name.to_string(), size, codec)
+    }
+
+    // Like create, but keeps the sender's subdirectories (mirroring) as long as they
+    // stay inside `dir`
+    pub fn create_at(dir: &Path, relative: &str, size: u64, codec: Codec) -> Result<IncomingFile, String> {
+        let relative_path = safe_relative_path(relative).ok_or_else(|| format!("Refusing path {:?}", relative))?;
+        let path = dir.join(relative_path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("Can't create {}: {}", parent.display(), e))?;
+        }
+        IncomingFile::open(path, relative.to_string(), size, codec)
+    }
+
+    fn open(path: PathBuf, name: String, size: u64, codec: Codec) -> Result<IncomingFile, String> {
+        let (part_path, file) = create_part(&path)?;
+        Ok(IncomingFile {
+            name,
+            size,
+            written: 0,
+            path,
+            part_path,
+            file,
+            hasher: Sha256::new(),
+            decoder: ChunkDecoder::new(codec, size),
+            basis: None,
+            positional: false,
+        })
+    }
+
+    // The version we already have, if there is one to start a delta from. Hashing it
+    // for the signature can take a while, so that is left to the caller.
+    pub fn delta_basis(&self) -> Option<PathBuf> {
+        fs::metadata(&self.path).ok().filter(|m| m.is_file() && m.len() > 0).map(|_| self.path.clone())
+    }
+
+    // Switches to delta mode, `signature` is what the sender was given
+    pub fn use_delta(&mut self, signature: &Signature) -> io::Result<()> {
+        self.basis = Some((File::open(&self.path)?, signature.block_size));
+        Ok(())
+    }
+
+    // A (possibly compressed) chunk of literal data
+    pub fn write_chunk(&mut self, chunk: &[u8]) -> Result<(), String> {
+        let data = self.decoder.decode(chunk)?;
+        self.append(&data)
+    }
+
+    // Copies blocks `block..block + count` from the existing version
+    pub fn copy_blocks(&mut self, block: usize, count: usize) -> Result<(), String> {
+        let Some((basis, block_size)) = self.basis.as_mut() else {
+            return Err("delta_copy without a delta offer".to_string());
+        };
+        let mut data = vec![0u8; *block_size];
+        let offset = block.checked_mul(*block_size).ok_or("delta_copy out of range")? as u64;
+        basis.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+
+        // One block at a time, append() stops a sender that copies past the declared size
+        for _ in 0..count {
+            let (basis, _) = self.basis.as_mut().expect("checked above");
+            let n = delta::read_full(basis, &mut data).map_err(|e| e.to_string())?;
+            if n == 0 {
+                return Err("delta_copy past the end of the existing file".to_string());
+            }
+            let block = data[..n].to_vec();
+            self.append(&block)?;
+        }
+        Ok(())
+    }
+
+    // Sizes the file up front for write_at
+    pub fn preallocate(&mut self) -> Result<(), String> {
+        self.file.set_len(self.size).map_err(|e| format!("Can't make room for {}: {}", self.name, e))?;
+        self.positional = true;
+        Ok(())
+    }
+
+    // Raw data for `offset`, ranges may arrive in any order
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), String> {
+        if !self.positional {
+            return Err("Range data without a parallel offer".to_string());
+        }
+        if offset.checked_add(data.len() as u64).is_none_or(|end| end > self.size) {
+            return Err("Sender sent data past the declared size".to_string());
+        }
+        self.file.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+        self.file.write_all(data).map_err(|e| format!("Write failed: {}", e))?;
+        self.written += data.len() as u64;
+        Ok(())
+    }
+
+    fn append(&mut self, data: &[u8]) -> Result<(), String> {
+        if self.written + data.len() as u64 > self.size {
+            return Err("Sender sent more data than it declared".to_string());
+        }
+        self.file.write_all(data).map_err(|e| format!("Write failed: {}", e))?;
+        self.hasher.update(data);
+        self.written += data.len() as u64;
+        Ok(())
+    }
+
+    // Verifies size and hash and moves the file into place. A file made from blocks of
+    // the older copy or from ranges written in place needs the sender's hash.
+    pub fn finish(self, sha256: Option<&str>) -> Result<PathBuf, String> {
+        let IncomingFile { name, size, written, path, part_path, file, hasher, basis, positional, .. } = self;
+        let verified = file.sync_all().map_err(|e| e.to_string()).and_then(|_| {
+            if written != size {
+                return Err(format!("{}: got {} of {} bytes", name, written, size));
+            }
+            if sha256.is_none() && basis.is_some() {
+                return Err(format!("{}: the delta ended without a hash", name));
+            }
+            if sha256.is_none() && positional {
+                return Err(format!("{}: the parallel streams ended without a hash", name));
+            }
+            let actual = if positional {
+                delta::sha256_file(&part_path).map_err(|e| e.to_string())?
+            } else {
+                delta::to_hex(&hasher.finalize())
+            };
+            match sha256 {
+                Some(expected) if !expected.eq_ignore_ascii_case(&actual) => {
+                    Err(format!("{}: SHA-256 mismatch, the file was corrupted in transit", name))
+                }
+                _ => Ok(()),
+            }
+        });
+        drop(file);
+        if let Err(e) = verified {
+            let _ = fs::remove_file(&part_path);
+            return Err(e);
+        }
+        fs::rename(&part_path, &path).map_err(|e| format!("Can't move {} into place: {}", name, e))?;
+        Ok(path)
+    }
+
+    pub fn abort(self) {
+        let _ = fs::remove_file(&self.part_path);
+    }
+
+    pub fn progress(&self) -> f64 {
+        if self.size == 0 { 100.0 } else { self.written as f64 / self.size as f64 * 100.0 }
+    }
+}
+
+// The last component of a name the sender gave us
+fn file_name(name: &str) -> Result<&str, String> {
+    Path::new(name)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .filter(|n| !n.is_empty())
+        .ok_or_else(|| format!("Refusing file name {:?}", name))
+}
+
+fn create_part(path: &Path) -> Result<(PathBuf, File), String> {
+    let mut part_name = path.file_name().unwrap_or_default().to_os_string();
+    part_name.push(".part");
+    let part_path = path.with_file_name(part_name);
+    let file = File::create(&part_path).map_err(|e| format!("Can't create {}: {}", part_path.display(), e))?;
+    Ok((part_path, file))
+}
+
+// A stream written as it comes, into a part file in a directory or straight to
+// standard output. Standard output can't be taken back, so there a bad hash only
+// means the command fails.
+pub struct IncomingStream {
+    pub name: String,
+    pub written: u64,
+    // Known when a sized file is sent to standard output
+    size: Option<u64>,
+    out: Box<dyn Write + Send>,
+    // The part file and where it goes, None for standard output
+    paths: Option<(PathBuf, PathBuf)>,
+    hasher: Sha256,
+    decoder: ChunkDecoder,
+}
+
+impl IncomingStream {
+    pub fn create(dir: &Path, name: &str, codec: Codec) -> Result<IncomingStream, String> {
+        let name = file_name(name)?;
+        let path = dir.join(name);
+        let (part_path, file) = create_part(&path)?;
+        Ok(IncomingStream::new(name, None, Box::new(file), Some((part_path, path)), codec))
+    }
+
+    pub fn stdout(name: &str, size: Option<u64>, codec: Codec) -> Result<IncomingStream, String> {
+        Ok(IncomingStream::new(file_name(name)?, size, Box::new(io::stdout()), None, codec))
+    }
+
+    fn new(name: &str, size: Option<u64>, out: Box<dyn Write + Send>, paths: Option<(PathBuf, PathBuf)>, codec: Codec) -> IncomingStream {
+        IncomingStream {
+            name: name.to_string(),
+            written: 0,
+            size,
+            out,
+            paths,
+            hasher: Sha256::new(),
+            decoder: ChunkDecoder::new(codec, size.unwrap_or(u64::MAX)),
+        }
+    }
+
+    pub fn write_chunk(&mut self, chunk: &[u8]) -> Result<(), String> {
+        let data = self.decoder.decode(chunk)?;
+        self.out.write_all(&data).map_err(|e| format!("Write failed: {}", e))?;
+        self.hasher.update(&data);
+        self.written += data.len() as u64;
+        Ok(())
+    }
+
+    // Checks the hash, which is all that says the whole stream arrived, and moves a
+    // part file into place. Returns where it went, None for standard output.
+    pub fn finish(self, sha256: Option<&str>) -> Result<Option<PathBuf>, String> {
+        let IncomingStream { name, written, size, mut out, paths, hasher, .. } = self;
+        let verified = out.flush().map_err(|e| e.to_string()).and_then(|_| {
+            if let Some(size) = size.filter(|&size| size != written) {
+                return Err(format!("{}: got {} of {} bytes", name, written, size));
+            }
+            let expected = sha256.ok_or_else(|| format!("{}: the stream ended without a hash", name))?;
+            if !expected.eq_ignore_ascii_case(&delta::to_hex(&hasher.finalize())) {
+                return Err(format!("{}: SHA-256 mismatch, the stream was corrupted in transit", name));
+            }
+            Ok(())
+        });
+        drop(out);
+        let Some((part_path, path)) = paths else { return verified.map(|_| None) };
+        if let Err(e) = verified {
+            let _ = fs::remove_file(&part_path);
+            return Err(e);
+        }
+        fs::rename(&part_path, &path).map_err(|e| format!("Can't move {} into place: {}", name, e))?;
+        Ok(Some(path))
+    }
+
+    pub fn abort(self) {
+        if let Some((part_path, _)) = &self.paths {
+            let _ = fs::remove_file(part_path);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+// Background NAT keep-alive for the sockets we registered with the signaling server.
+// Every 25 s each socket re-queries its STUN server, which both refreshes the NAT
+// mapping and tells us whether the public address changed (roaming, DHCP renew,
+// NAT rebinding). On a change the new addresses are sent to the server so peers
+// always see a reachable address. That goes over a connection of our own, the
+// registered one belongs to relay sessions and its reader.
+use crate::signaling::server_request;
+use crate::tls::{connect_signaling, SignalingConfig};
+use serde_json::{json, Value};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use stunclient::StunClient;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::net::TcpStream;
+use tokio::time::{interval, timeout, Duration, MissedTickBehavior};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(25);
+const UPDATE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Starts the keep-alive task. `register` is the payload that was sent at register
+// time with the session token in it, its ipv4/ipv6 fields are updated in place
+// whenever a mapping changes. Abort the returned handle when the client unregisters.
+pub fn spawn_keepalive(register: Value, sockets: Vec<(UdpSocket, SocketAddr)>, config: SignalingConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut register = register;
+        let mut sockets: Vec<(tokio::net::UdpSocket, SocketAddr)> = sockets
+            .into_iter()
+            .filter_map(|(socket, stun)| {
+                socket.set_nonblocking(true).ok()?;
+                Some((tokio::net::UdpSocket::from_std(socket).ok()?, stun))
+            })
+            .collect();
+        if sockets.is_empty() {
+            return;
+        }
+
+        let mut ticker = interval(KEEPALIVE_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticker.tick().await; // the first tick fires immediately, we just registered
+        let mut update_pending = false;
+        let mut connection = None;
+
+        loop {
+            ticker.tick().await;
+
+            for (socket, stun_server) in sockets.iter_mut() {
+                let (ip_key, port_key) = if stun_server.is_ipv4() {
+                    ("ipv4_ip", "ipv4_port")
+                } else {
+                    ("ipv6_ip", "ipv6_port")
+                };
+
+                match StunClient::new(*stun_server).query_external_address_async(socket).await {
+                    Ok(addr) => {
+                        let ip = addr.ip().to_string();
+                        let changed = register.get(ip_key).and_then(|v| v.as_str()) != Some(ip.as_str())
+                            || register.get(port_key).and_then(|v| v.as_u64()) != Some(addr.port() as u64);
+                        if changed {
+                            println!("🔀 Public address changed to {}, re-registering", addr);
+                            register[ip_key] = Value::from(ip);
+                            register[port_key] = Value::from(addr.port());
+                            update_pending = true;
+                        }
+                    }
+                    Err(err) => eprintln!("❌ Keep-alive STUN query to {} failed: {}", stun_server, err),
+                }
+            }
+
+            // Tried again on the next tick until the server took it
+            if update_pending {
+                match push_update(&register, &config, &mut connection).await {
+                    Ok(()) => update_pending = false,
+                    Err(err) => {
+                        eprintln!("❌ Failed to push the new address: {}", err);
+                        connection = None;
+                    }
+                }
+            }
+        }
+    })
+}
+
+// Sends the addresses in `register` and waits for the answer, connecting first if need be
+async fn push_update(register: &Value, config: &SignalingConfig, connection: &mut Option<Arc<Mutex<WsStream>>>) -> Result<(), String> {
+    let ws_stream = match connection {
+        Some(ws_stream) => ws_stream.clone(),
+        None => connection.insert(Arc::new(Mutex::new(connect_signaling(config).await?))).clone(),
+    };
+    let update = json!({
+        "type": "update_address",
+        "token": register["token"],
+        "ipv4_ip": register["ipv4_ip"],
+        "ipv4_port": register["ipv4_port"],
+        "ipv6_ip": register["ipv6_ip"],
+        "ipv6_port": register["ipv6_port"],
+    });
+    match timeout(UPDATE_TIMEOUT, server_request(update, "address_updated", ws_stream)).await {
+        Ok(reply) => reply.map(|_| ()),
+        Err(_) => Err("No answer from the server".to_string()),
+    }
+}
@@ -0,0 +1,42 @@
+// The client side of p2p_rust: talking to the signaling server, sending and receiving
+// files over the relay and everything built on that. The ui, the p2p command and
+// other programs use it through `Client` (see client.rs), or the modules directly for
+// what `Client` doesn't cover. The signaling server is here as well (signaling_server.rs),
+// so it can run inside another program or a test.
+pub mod accounts;
+pub mod chat;
+pub mod client;
+pub mod clipboard;
+pub mod compression;
+pub mod contacts;
+pub mod delta;
+pub mod events;
+pub mod fanout;
+pub mod history;
+pub mod identity;
+pub mod incoming;
+pub mod keepalive;
+pub mod mailbox;
+pub mod mirror;
+pub mod multistream;
+pub mod netsim;
+pub mod offline;
+pub mod presence;
+pub mod protocol;
+pub mod ratelimit;
+pub mod receive;
+pub mod send;
+pub mod session;
+pub mod shares;
+pub mod signaling;
+pub mod signaling_server;
+pub mod store;
+pub mod swarm;
+pub mod tcp;
+pub mod tls;
+pub mod turn;
+pub mod udp;
+pub mod wormhole;
+
+pub use client::{Client, Source};
+pub use events::TransferEvent;
@@ -0,0 +1,241 @@
+// Store-and-forward for users that are offline. An upload is kept on disk until its
+// recipient fetches and deletes it, or until it expires. The server can't read it,
+// the client seals every frame for the recipient (see offline.rs); each
+// frame is stored with its length in front so it can be handed back frame by frame.
+// Every recipient has a quota, and the sender gets a receipt once the upload is
+// delivered, deleted unread or expired.
+use crate::protocol::{MailboxEntry, MailboxReceipt};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const INDEX_FILE: &str = "mailbox.json";
+// No frame the client seals is anywhere near this
+pub const MAX_FRAME: usize = 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Clone)]
+struct StoredItem {
+    from: String,
+    to: String,
+    size: u64,
+    header: Value,
+    expires: u64,
+    // Handed out in full at least once, deleting it then counts as delivered
+    #[serde(default)]
+    fetched: bool,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Index {
+    items: BTreeMap<String, StoredItem>,
+    // Sender -> receipts it hasn't picked up yet
+    receipts: BTreeMap<String, Vec<MailboxReceipt>>,
+    // Last identity key each user registered with, senders seal for it while the
+    // user is offline
+    #[serde(default)]
+    keys: BTreeMap<String, String>,
+}
+
+pub struct Mailbox {
+    dir: PathBuf,
+    index: Index,
+    // Bytes per recipient, across all its uploads
+    quota: u64,
+    ttl_secs: u64,
+}
+
+// An upload on its way in, written to a .part file until it is complete
+pub struct Upload {
+    id: String,
+    from: String,
+    to: String,
+    header: Value,
+    size: u64,
+    left: u64,
+    file: File,
+}
+
+impl Upload {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn to(&self) -> &str {
+        &self.to
+    }
+}
+
+impl Mailbox {
+    // Loads the index from `dir` and drops whatever expired while the server was down
+    pub fn load(dir: &Path, quota: u64, ttl_secs: u64) -> io::Result<Mailbox> {
+        fs::create_dir_all(dir)?;
+        let index = match fs::read_to_string(dir.join(INDEX_FILE)) {
+            Ok(text) => serde_json::from_str(&text)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Index::default(),
+            Err(e) => return Err(e),
+        };
+        // Uploads cut off by a restart
+        for entry in fs::read_dir(dir)?.flatten() {
+            if entry.path().extension().is_some_and(|ext| ext == "part") {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+        let mut mailbox = Mailbox { dir: dir.to_path_buf(), index, quota, ttl_secs };
+        mailbox.expire();
+        Ok(mailbox)
+    }
+
+    pub fn start_upload(&mut self, from: &str, to: &str, size: u64, header: Value) -> Result<Upload, String> {
+        self.expire();
+        if size > self.quota.saturating_sub(self.used_by(to)) {
+            return Err(format!("{}'s mailbox is full", to));
+        }
+        let mut id = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut id);
+        let id: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+        let file = File::create(self.part_path(&id)).map_err(|e| e.to_string())?;
+        Ok(Upload { id, from: from.to_string(), to: to.to_string(), header, size, left: size, file })
+    }
+
+    pub fn write_frame(&self, upload: &mut Upload, frame: &[u8]) -> Result<(), String> {
+        if frame.len() > MAX_FRAME || frame.len() as u64 > upload.left {
+            return Err("Upload is bigger than announced".to_string());
+        }
+        upload.file.write_all(&(frame.len() as u32).to_le_bytes()).map_err(|e| e.to_string())?;
+        upload.file.write_all(frame).map_err(|e| e.to_string())?;
+        upload.left -= frame.len() as u64;
+        Ok(())
+    }
+
+    // Returns the id and when it expires
+    pub fn finish_upload(&mut self, upload: Upload) -> Result<(String, u64), String> {
+        if upload.left > 0 {
+            self.abort_upload(upload);
+            return Err("Upload ended early".to_string());
+        }
+        // Another upload may have filled the mailbox in the meantime
+        if upload.size > self.quota.saturating_sub(self.used_by(&upload.to)) {
+            let error = format!("{}'s mailbox is full", upload.to);
+            self.abort_upload(upload);
+            return Err(error);
+        }
+        let Upload { id, from, to, header, size, file, .. } = upload;
+        file.sync_all().map_err(|e| e.to_string())?;
+        drop(file);
+        fs::rename(self.part_path(&id), self.item_path(&id)).map_err(|e| e.to_string())?;
+        let expires = now_secs() + self.ttl_secs;
+        self.index.items.insert(id.clone(), StoredItem { from, to, size, header, expires, fetched: false });
+        self.save();
+        Ok((id, expires))
+    }
+
+    pub fn abort_upload(&self, upload: Upload) {
+        drop(upload.file);
+        let _ = fs::remove_file(self.part_path(&upload.id));
+    }
+
+    pub fn remember_key(&mut self, username: &str, key: &str) {
+        if self.index.keys.get(username).map(String::as_str) != Some(key) {
+            self.index.keys.insert(username.to_string(), key.to_string());
+            self.save();
+        }
+    }
+
+    pub fn key(&self, username: &str) -> Option<String> {
+        self.index.keys.get(username).cloned()
+    }
+
+    pub fn pending(&self, to: &str) -> Vec<MailboxEntry> {
+        self.index.items.iter().filter(|(_, item)| item.to == to).map(|(id, item)| entry(id, item)).collect()
+    }
+
+    pub fn receipts_waiting(&self, from: &str) -> usize {
+        self.index.receipts.get(from).map_or(0, Vec::len)
+    }
+
+    pub fn take_receipts(&mut self, from: &str) -> Vec<MailboxReceipt> {
+        let receipts = self.index.receipts.remove(from).unwrap_or_default();
+        if !receipts.is_empty() {
+            self.save();
+        }
+        receipts
+    }
+
+    // Where the frames of `id` are, if it is for `to`
+    pub fn item(&self, id: &str, to: &str) -> Result<(MailboxEntry, PathBuf), String> {
+        match self.index.items.get(id) {
+            Some(item) if item.to == to => Ok((entry(id, item), self.item_path(id))),
+            _ => Err("No such item".to_string()),
+        }
+    }
+
+    pub fn mark_fetched(&mut self, id: &str) {
+        if let Some(item) = self.index.items.get_mut(id) {
+            item.fetched = true;
+            self.save();
+        }
+    }
+
+    pub fn delete(&mut self, id: &str, to: &str) -> Result<(), String> {
+        let status = match self.index.items.get(id) {
+            Some(item) if item.to == to => if item.fetched { "delivered" } else { "deleted" },
+            _ => return Err("No such item".to_string()),
+        };
+        self.remove(id, status);
+        self.save();
+        Ok(())
+    }
+
+    fn expire(&mut self) {
+        let now = now_secs();
+        let expired: Vec<String> = self.index.items.iter().filter(|(_, item)| item.expires <= now).map(|(id, _)| id.clone()).collect();
+        for id in &expired {
+            self.remove(id, "expired");
+        }
+        if !expired.is_empty() {
+            self.save();
+        }
+    }
+
+    // Removes an item and leaves its sender a receipt
+    fn remove(&mut self, id: &str, status: &str) {
+        let Some(item) = self.index.items.remove(id) else { return };
+        let _ = fs::remove_file(self.item_path(id));
+        let receipt = MailboxReceipt { id: id.to_string(), to: item.to, status: status.to_string() };
+        self.index.receipts.entry(item.from).or_default().push(receipt);
+    }
+
+    fn used_by(&self, to: &str) -> u64 {
+        self.index.items.values().filter(|item| item.to == to).map(|item| item.size).sum()
+    }
+
+    fn save(&self) {
+        let result = serde_json::to_string_pretty(&self.index)
+            .map_err(io::Error::from)
+            .and_then(|text| fs::write(self.dir.join(INDEX_FILE), text));
+        if let Err(e) = result {
+            eprintln!("❌ Failed to save the mailbox index: {}", e);
+        }
+    }
+
+    fn part_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.part", id))
+    }
+
+    fn item_path(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+}
+
+fn entry(id: &str, item: &StoredItem) -> MailboxEntry {
+    MailboxEntry { id: id.to_string(), from: item.from.clone(), size: item.size, header: item.header.clone(), expires: item.expires }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
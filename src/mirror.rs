@@ -0,0 +1,222 @@
+// One-way folder mirroring. The sender keeps a manifest of what the peer has
+// confirmed receiving (persisted in the data directory), scans the folder, and diffs
+// the two to find what has to be sent, renamed or deleted on the peer.
+//
+// Messages on top of the usual file transfer (file_metadata gets a "path"):
+//   mirror_start  {name}          files go to downloads/<name>/ on the receiver
+//   mirror_rename {from, to}
+//   mirror_delete {path}
+//   file_received {path, ok}      receiver -> sender after every file_end
+use crate::delta::sha256_file;
+use crate::store::{load_json, save_json};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+const STATE_FILE: &str = "mirrors.json";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub size: u64,
+    // Modification time in ms, only used to skip re-hashing unchanged files
+    pub modified: u64,
+    pub sha256: String,
+}
+
+// Relative path ('/' separated) -> entry
+pub type Manifest = BTreeMap<String, Entry>;
+
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Upsert(String),
+    Rename { from: String, to: String },
+    Delete(String),
+}
+
+// Key of a mirror in the state file
+pub fn mirror_id(root: &Path, peer: &str) -> String {
+    let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+    format!("{}|{}", peer, root.display())
+}
+
+pub fn load_state(id: &str) -> Manifest {
+    load_json::<HashMap<String, Manifest>>(STATE_FILE).remove(id).unwrap_or_default()
+}
+
+pub fn save_state(id: &str, manifest: &Manifest) -> io::Result<()> {
+    let mut mirrors: HashMap<String, Manifest> = load_json(STATE_FILE);
+    mirrors.insert(id.to_string(), manifest.clone());
+    save_json(STATE_FILE, &mirrors)
+}
+
+// Walks `root`. Files whose size and mtime match `previous` keep their hash, only
+// new and touched files are read. Symlinks are skipped.
+pub fn scan(root: &Path, previous: &Manifest) -> io::Result<Manifest> {
+    let mut manifest = Manifest::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let path = entry.path();
+            if file_type.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            if !file_type.is_file() {
+                continue;
+            }
+            let Some(relative) = relative_name(root, &path) else { continue };
+            // Files can vanish while we look at them, the next scan catches up
+            let Ok(metadata) = entry.metadata() else { continue };
+            let size = metadata.len();
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_millis() as u64);
+
+            let sha256 = match previous.get(&relative) {
+                Some(known) if known.size == size && known.modified == modified => known.sha256.clone(),
+                _ => match sha256_file(&path) {
+                    Ok(hash) => hash,
+                    Err(_) => continue,
+                },
+            };
+            manifest.insert(relative, Entry { size, modified, sha256 });
+        }
+    }
+    Ok(manifest)
+}
+
+// `path` as a '/' separated path below `root`, None if it isn't below it
+pub fn relative_name(root: &Path, path: &Path) -> Option<String> {
+    let parts: Option<Vec<&str>> = path
+        .strip_prefix(root)
+        .ok()?
+        .components()
+        .map(|c| match c {
+            Component::Normal(part) => part.to_str(),
+            _ => None,
+        })
+        .collect();
+    Some(parts?.join("/"))
+}
+
+// What turns the peer's `old` state into `new`. A file that disappeared while one
+// with the same content appeared is sent as a rename instead of a new upload.
+pub fn diff(old: &Manifest, new: &Manifest) -> Vec<Change> {
+    let mut deleted: Vec<&String> = old.keys().filter(|path| !new.contains_key(*path)).collect();
+    let mut changes = Vec::new();
+    let mut upserts = Vec::new();
+
+    for (path, entry) in new {
+        match old.get(path) {
+            Some(known) if known.sha256 == entry.sha256 => {}
+            Some(_) => upserts.push(Change::Upsert(path.clone())),
+            None => {
+                let renamed = deleted.iter().position(|gone| old[*gone].sha256 == entry.sha256);
+                match renamed {
+                    Some(index) => {
+                        let from = deleted.remove(index).clone();
+                        changes.push(Change::Rename { from, to: path.clone() });
+                    }
+                    None => upserts.push(Change::Upsert(path.clone())),
+                }
+            }
+        }
+    }
+
+    changes.extend(upserts);
+    changes.extend(deleted.into_iter().map(|path| Change::Delete(path.clone())));
+    changes
+}
+
+// Turns a relative path from the peer into one that stays inside the mirror folder.
+// Rejects absolute paths, "..", drive prefixes and empty components.
+pub fn safe_relative_path(relative: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for part in relative.split('/') {
+        if part.is_empty() || part == "." || part == ".." || part.contains(['\\', ':']) {
+            return None;
+        }
+        path.push(part);
+    }
+    (path.components().count() > 0 && path.components().all(|c| matches!(c, Component::Normal(_)))).then_some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(files: &[(&str, &str)]) -> Manifest {
+        files.iter().map(|(path, sha256)| (path.to_string(), Entry { size: 1, modified: 0, sha256: sha256.to_string() })).collect()
+    }
+
+    #[test]
+    fn nothing_changed_is_no_changes() {
+        let state = manifest(&[("a.txt", "1"), ("dir/b.txt", "2")]);
+        assert_eq!(diff(&state, &state), Vec::new());
+    }
+
+    #[test]
+    fn new_and_edited_files_are_sent_and_gone_ones_deleted() {
+        let old = manifest(&[("kept.txt", "1"), ("edited.txt", "2"), ("gone.txt", "3")]);
+        let new = manifest(&[("kept.txt", "1"), ("edited.txt", "22"), ("new.txt", "4")]);
+        assert_eq!(
+            diff(&old, &new),
+            vec![Change::Upsert("edited.txt".to_string()), Change::Upsert("new.txt".to_string()), Change::Delete("gone.txt".to_string())]
+        );
+    }
+
+    #[test]
+    fn moved_file_is_a_rename_that_comes_first() {
+        let old = manifest(&[("a.txt", "1"), ("old/name.txt", "2")]);
+        let new = manifest(&[("a.txt", "11"), ("new/name.txt", "2")]);
+        assert_eq!(
+            diff(&old, &new),
+            vec![Change::Rename { from: "old/name.txt".to_string(), to: "new/name.txt".to_string() }, Change::Upsert("a.txt".to_string())]
+        );
+    }
+
+    #[test]
+    fn one_gone_file_renames_into_one_copy_only() {
+        let old = manifest(&[("original.txt", "1")]);
+        let new = manifest(&[("copy1.txt", "1"), ("copy2.txt", "1")]);
+        assert_eq!(
+            diff(&old, &new),
+            vec![Change::Rename { from: "original.txt".to_string(), to: "copy1.txt".to_string() }, Change::Upsert("copy2.txt".to_string())]
+        );
+    }
+
+    #[test]
+    fn copy_of_a_file_that_stays_is_sent() {
+        let old = manifest(&[("a.txt", "1")]);
+        let new = manifest(&[("a.txt", "1"), ("b.txt", "1")]);
+        assert_eq!(diff(&old, &new), vec![Change::Upsert("b.txt".to_string())]);
+    }
+
+    #[test]
+    fn safe_paths_stay_below_the_mirror() {
+        assert_eq!(safe_relative_path("a.txt"), Some(PathBuf::from("a.txt")));
+        assert_eq!(safe_relative_path("dir/sub/a.txt"), Some(["dir", "sub", "a.txt"].iter().collect()));
+        assert_eq!(safe_relative_path("..hidden/a..b"), Some(["..hidden", "a..b"].iter().collect()));
+    }
+
+    #[test]
+    fn unsafe_paths_are_refused() {
+        for relative in ["", "/etc/passwd", "../a.txt", "dir/../../a.txt", "dir/./a.txt", "dir//a.txt", "dir/", "C:/a.txt", "c:a.txt", "dir\\..\\a.txt", ".", ".."] {
+            assert_eq!(safe_relative_path(relative), None, "{:?} was taken", relative);
+        }
+    }
+
+    #[test]
+    fn relative_names_use_slashes_below_the_root() {
+        let root = Path::new("/mirror");
+        assert_eq!(relative_name(root, &root.join("dir").join("a.txt")), Some("dir/a.txt".to_string()));
+        assert_eq!(relative_name(root, Path::new("/elsewhere/a.txt")), None);
+    }
+}
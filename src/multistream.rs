@@ -0,0 +1,49 @@
+// Parallel streams for large files on high-latency links: the file is split into byte
+// ranges that travel over several relay sessions at once, so one TCP window to a
+// distant server no longer caps the transfer. The main session negotiates it:
+//   file_metadata {..., streams: [key, ...]}  the sender has a stream waiting per key
+//   file_accept {..., streams: [key, ...]}    the ones the receiver joined
+// A receiver that doesn't know about streams ignores the keys and gets the file on
+// the main session as usual. Each stream is a relay session of its own, paired by the
+// server from a stream_open (sender) and a stream_join (receiver) with the same key,
+// and carries
+//   range {offset, len}, then `len` bytes of raw binary messages
+// which the receiver writes in place into one preallocated file. file_end on the main
+// session still carries the whole-file hash, checked against the file on disk.
+use crate::delta::to_hex;
+use rand::RngCore;
+
+pub const MAX_STREAMS: usize = 8;
+// Below this, setting up the extra sessions costs more than it saves
+pub const MIN_PARALLEL_SIZE: u64 = 16 * 1024 * 1024;
+
+pub fn stream_key() -> String {
+    let mut key = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut key);
+    to_hex(&key)
+}
+
+// `size` split into `count` contiguous (offset, len) ranges, the first ones a byte
+// longer when it doesn't divide evenly
+pub fn ranges(size: u64, count: usize) -> Vec<(u64, u64)> {
+    let count = count.max(1) as u64;
+    let (base, extra) = (size / count, size % count);
+    let mut offset = 0;
+    (0..count)
+        .map(|i| {
+            let len = base + u64::from(i < extra);
+            let range = (offset, len);
+            offset += len;
+            range
+        })
+        .collect()
+}
+
+// The stream keys in a file_metadata or file_accept, at most MAX_STREAMS
+pub fn keys_in(message: &serde_json::Value) -> Vec<String> {
+    message
+        .get("streams")
+        .and_then(|v| v.as_array())
+        .map(|keys| keys.iter().filter_map(|k| k.as_str()).take(MAX_STREAMS).map(str::to_string).collect())
+        .unwrap_or_default()
+}
@@ -0,0 +1,280 @@
+// A bad network on demand, for trying the UDP transfer under loss, duplication,
+// reordering, delay, jitter and a bandwidth cap. `Impaired` wraps a datagram socket
+// and impairs what arrives on it, so wrapping both ends impairs both directions;
+// `run_proxy` puts the same between two programs that only know addresses (see
+// udp_proxy.rs). With a seed the same packets are hit every run.
+use crate::udp::Datagram;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::{sleep_until, Instant};
+
+// How much later than its successors a reordered packet arrives
+const REORDER_HOLD: Duration = Duration::from_millis(20);
+const MAX_DATAGRAM: usize = 65536;
+
+// Chances are 0.0 to 1.0, the default is a perfect network
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Impairment {
+    pub loss: f64,
+    pub duplicate: f64,
+    pub reorder: f64,
+    pub delay: Duration,
+    // Up to this much more delay, picked per packet
+    pub jitter: Duration,
+    // KB/s, 0 is unlimited. Packets queue up behind each other like on a slow link.
+    pub rate: u64,
+    pub seed: Option<u64>,
+}
+
+impl Impairment {
+    // "loss=5% dup=1% reorder=2% delay=40ms jitter=10ms rate=256 seed=7", any of them
+    // in any order, rate in KB/s like the speed limits
+    pub fn parse(spec: &str) -> Result<Impairment, String> {
+        let mut impairment = Impairment::default();
+        for part in spec.split_whitespace() {
+            let bad = || format!("\"{}\" isn't like loss=5%, delay=40ms or rate=256", part);
+            let (key, value) = part.split_once('=').ok_or_else(bad)?;
+            match key {
+                "loss" => impairment.loss = parse_chance(value).ok_or_else(bad)?,
+                "dup" => impairment.duplicate = parse_chance(value).ok_or_else(bad)?,
+                "reorder" => impairment.reorder = parse_chance(value).ok_or_else(bad)?,
+                "delay" => impairment.delay = parse_millis(value).ok_or_else(bad)?,
+                "jitter" => impairment.jitter = parse_millis(value).ok_or_else(bad)?,
+                "rate" => impairment.rate = value.parse().map_err(|_| bad())?,
+                "seed" => impairment.seed = Some(value.parse().map_err(|_| bad())?),
+                _ => return Err(bad()),
+            }
+        }
+        Ok(impairment)
+    }
+}
+
+// "5%" or "0.05"
+fn parse_chance(text: &str) -> Option<f64> {
+    let chance = match text.strip_suffix('%') {
+        Some(percent) => percent.parse::<f64>().ok()? / 100.0,
+        None => text.parse().ok()?,
+    };
+    (0.0..=1.0).contains(&chance).then_some(chance)
+}
+
+// "40ms" or "40"
+fn parse_millis(text: &str) -> Option<Duration> {
+    text.strip_suffix("ms").unwrap_or(text).parse().ok().map(Duration::from_millis)
+}
+
+// What happened to the packets so far
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub received: u64,
+    pub lost: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    pub delivered: u64,
+}
+
+struct Pending {
+    at: Instant,
+    // Ties go in arrival order
+    order: u64,
+    from: SocketAddr,
+    data: Vec<u8>,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Pending) -> bool {
+        (self.at, self.order) == (other.at, other.order)
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Pending) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Pending) -> std::cmp::Ordering {
+        (self.at, self.order).cmp(&(other.at, other.order))
+    }
+}
+
+struct Link {
+    rng: StdRng,
+    // Packets on their way, the earliest first
+    pending: BinaryHeap<Reverse<Pending>>,
+    // When the bandwidth cap lets the next packet through
+    free_at: Instant,
+    order: u64,
+    stats: Stats,
+    // What recv_from reads into, lent out while it waits (see Lent)
+    incoming: Vec<u8>,
+}
+
+// The link's receive buffer, handed back when recv_from returns or is cancelled
+struct Lent<'a> {
+    link: &'a Mutex<Link>,
+    buf: Vec<u8>,
+}
+
+impl Drop for Lent<'_> {
+    fn drop(&mut self) {
+        self.link.lock().unwrap().incoming = std::mem::take(&mut self.buf);
+    }
+}
+
+pub struct Impaired<D> {
+    inner: D,
+    impairment: Impairment,
+    link: Mutex<Link>,
+}
+
+impl<D: Datagram> Impaired<D> {
+    pub fn new(inner: D, impairment: Impairment) -> Impaired<D> {
+        let rng = match impairment.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let link = Link {
+            rng,
+            pending: BinaryHeap::new(),
+            free_at: Instant::now(),
+            order: 0,
+            stats: Stats::default(),
+            incoming: vec![0u8; MAX_DATAGRAM],
+        };
+        Impaired { inner, impairment, link: Mutex::new(link) }
+    }
+
+    pub fn get_ref(&self) -> &D {
+        &self.inner
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.link.lock().unwrap().stats
+    }
+
+    fn lend(&self) -> Lent<'_> {
+        let mut buf = std::mem::take(&mut self.link.lock().unwrap().incoming);
+        // Only empty if another recv_from has it, which then gets its own
+        if buf.is_empty() {
+            buf = vec![0u8; MAX_DATAGRAM];
+        }
+        Lent { link: &self.link, buf }
+    }
+
+    // Decides when (and whether) a packet that just came in gets handed on
+    fn arrive(&self, data: &[u8], from: SocketAddr) {
+        let impairment = &self.impairment;
+        let mut link = self.link.lock().unwrap();
+        let link = &mut *link;
+        link.stats.received += 1;
+        if link.rng.gen_bool(impairment.loss) {
+            link.stats.lost += 1;
+            return;
+        }
+        let copies = if link.rng.gen_bool(impairment.duplicate) {
+            link.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let now = Instant::now();
+            let mut at = now;
+            if impairment.rate > 0 {
+                let transmit = Duration::from_secs_f64(data.len() as f64 / (impairment.rate * 1024) as f64);
+                link.free_at = link.free_at.max(now) + transmit;
+                at = link.free_at;
+            }
+            at += impairment.delay + impairment.jitter.mul_f64(link.rng.gen_range(0.0..=1.0));
+            if link.rng.gen_bool(impairment.reorder) {
+                link.stats.reordered += 1;
+                at += REORDER_HOLD;
+            }
+            link.order += 1;
+            link.pending.push(Reverse(Pending { at, order: link.order, from, data: data.to_vec() }));
+        }
+    }
+
+    // The next packet that is due, or when it will be
+    fn due(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), Option<Instant>> {
+        let mut link = self.link.lock().unwrap();
+        match link.pending.peek() {
+            Some(Reverse(next)) if next.at <= Instant::now() => {}
+            Some(Reverse(next)) => return Err(Some(next.at)),
+            None => return Err(None),
+        }
+        let Reverse(packet) = link.pending.pop().unwrap();
+        link.stats.delivered += 1;
+        // Cut short like a real socket when `buf` is too small
+        let len = packet.data.len().min(buf.len());
+        buf[..len].copy_from_slice(&packet.data[..len]);
+        Ok((len, packet.from))
+    }
+}
+
+impl<D: Datagram> Datagram for Impaired<D> {
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        self.inner.send_to(buf, target).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut incoming = self.lend();
+        loop {
+            let next = match self.due(buf) {
+                Ok(packet) => return Ok(packet),
+                Err(next) => next,
+            };
+            // Both are cancel safe, and so is this: packets wait in `link` until taken
+            tokio::select! {
+                received = self.inner.recv_from(&mut incoming.buf) => {
+                    let (len, from) = received?;
+                    self.arrive(&incoming.buf[..len], from);
+                }
+                _ = sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {}
+            }
+        }
+    }
+}
+
+// Forwards datagrams between whoever sends to `listen` and `upstream`, impairing them
+// with `forward` on the way there and `back` on the way back. Replies go to the
+// address that sent last, so it serves one client at a time.
+pub async fn run_proxy(listen: UdpSocket, upstream: SocketAddr, forward: Impairment, back: Impairment) -> io::Result<()> {
+    let outside = match upstream {
+        SocketAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?,
+        SocketAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?,
+    };
+    let listen = Impaired::new(listen, forward);
+    let outside = Impaired::new(outside, back);
+    let mut client: Option<SocketAddr> = None;
+    let mut to_upstream = vec![0u8; MAX_DATAGRAM];
+    let mut to_client = vec![0u8; MAX_DATAGRAM];
+    loop {
+        tokio::select! {
+            received = listen.recv_from(&mut to_upstream) => {
+                let (len, from) = received?;
+                client = Some(from);
+                outside.send_to(&to_upstream[..len], upstream).await?;
+            }
+            received = outside.recv_from(&mut to_client) => {
+                let (len, from) = received?;
+                if from == upstream
+                    && let Some(client) = client
+                {
+                    listen.send_to(&to_client[..len], client).await?;
+                }
+            }
+        }
+    }
+}
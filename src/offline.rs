@@ -0,0 +1,124 @@
+// End-to-end encryption for files left on the server for a peer that is offline.
+// The sender combines a fresh X25519 key with the recipient's identity key (the
+// Ed25519 key in its Montgomery form), so only the recipient can open the file and
+// the server only ever stores ciphertext. The upload is a list of frames sealed with
+// ChaCha20-Poly1305 under a counter nonce: first the file's name and size as JSON,
+// then the data. The last frame is marked in its nonce, so a file cut short on the
+// server doesn't open.
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde_json::Value;
+use sha2::Sha256;
+
+const INFO: &[u8] = b"p2p_rust offline v1";
+// Plain bytes per data frame
+pub const FRAME_SIZE: usize = 64 * 1024;
+const TAG_SIZE: u64 = 16;
+
+// Bytes the data frames of a `size` byte file take once sealed. An empty file
+// still has its one (last) data frame.
+pub fn sealed_size(size: u64) -> u64 {
+    let frames = size.div_ceil(FRAME_SIZE as u64).max(1);
+    size + frames * TAG_SIZE
+}
+
+pub struct Sealer {
+    cipher: ChaCha20Poly1305,
+    sealed: u64,
+}
+
+impl Sealer {
+    // Returns the sealer and the base64 ephemeral key the recipient needs to open it
+    pub fn new(recipient_key: &str) -> Result<(Sealer, String), String> {
+        let recipient = decode_key(recipient_key)?.to_montgomery();
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        let ephemeral = MontgomeryPoint::mul_base_clamped(secret);
+        let shared = recipient.mul_clamped(secret);
+        let cipher = cipher(&shared, &ephemeral, &recipient);
+        Ok((Sealer { cipher, sealed: 0 }, STANDARD.encode(ephemeral.to_bytes())))
+    }
+
+    pub fn seal(&mut self, plain: &[u8], last: bool) -> Vec<u8> {
+        let nonce = frame_nonce(self.sealed, last);
+        self.sealed += 1;
+        self.cipher.encrypt(&nonce, plain).expect("encrypting into a Vec can't fail")
+    }
+
+    pub fn seal_json(&mut self, value: &Value) -> Vec<u8> {
+        self.seal(value.to_string().as_bytes(), false)
+    }
+}
+
+pub struct Opener {
+    cipher: ChaCha20Poly1305,
+    opened: u64,
+    done: bool,
+}
+
+impl Opener {
+    pub fn new(identity: &SigningKey, ephemeral: &str) -> Result<Opener, String> {
+        let ephemeral = STANDARD
+            .decode(ephemeral)
+            .ok()
+            .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+            .map(MontgomeryPoint)
+            .ok_or("Invalid ephemeral key")?;
+        let shared: MontgomeryPoint = identity.to_scalar() * ephemeral;
+        let cipher = cipher(&shared, &ephemeral, &identity.verifying_key().to_montgomery());
+        Ok(Opener { cipher, opened: 0, done: false })
+    }
+
+    // The frame's plain bytes and whether it was the last one
+    pub fn open(&mut self, sealed: &[u8]) -> Result<(Vec<u8>, bool), String> {
+        if self.done {
+            return Err("Data after the last frame".to_string());
+        }
+        for last in [false, true] {
+            if let Ok(plain) = self.cipher.decrypt(&frame_nonce(self.opened, last), sealed) {
+                self.opened += 1;
+                self.done = last;
+                return Ok((plain, last));
+            }
+        }
+        Err("Frame doesn't decrypt, it wasn't sealed for this identity key".to_string())
+    }
+
+    pub fn open_json(&mut self, sealed: &[u8]) -> Result<Value, String> {
+        let (plain, _) = self.open(sealed)?;
+        serde_json::from_slice(&plain).map_err(|e| e.to_string())
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
+fn decode_key(key: &str) -> Result<VerifyingKey, String> {
+    let bytes = STANDARD.decode(key).ok().and_then(|bytes| <[u8; 32]>::try_from(bytes).ok());
+    bytes.and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok()).ok_or_else(|| "Invalid identity key".to_string())
+}
+
+// Both public keys go into the derivation, so the key is tied to this pair
+fn cipher(shared: &MontgomeryPoint, ephemeral: &MontgomeryPoint, recipient: &MontgomeryPoint) -> ChaCha20Poly1305 {
+    let mut salt = ephemeral.to_bytes().to_vec();
+    salt.extend_from_slice(recipient.as_bytes());
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes());
+    let mut key = [0u8; 32];
+    hkdf.expand(INFO, &mut key).expect("32 bytes is a valid HKDF length");
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+fn frame_nonce(counter: u64, last: bool) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[0] = last as u8;
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    *Nonce::from_slice(&nonce)
+}
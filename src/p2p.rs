@@ -0,0 +1,103 @@
+// Command line client for scripts, over the relay unless --udp says otherwise:
+//   p2p send <user> <file>            sends one file
+//   p2p send <user> - [--name NAME]   sends standard input until it ends
+//   p2p send --udp <user> <file>      sends one file straight over UDP, or through the
+//                                     TURN server in P2P_TURN_SERVER when that fails
+//   p2p receive                       takes files into downloads/ until stopped
+//   p2p receive --stdout              writes the first file to standard output and exits
+//   p2p receive --udp                 takes files sent with --udp into downloads/
+// so `tar c dir | p2p send alice -` and `p2p receive --stdout | tar x` work.
+//
+// Logs in as P2P_USERNAME with the session token the ui saved, or P2P_PASSWORD when
+// there is none. The server is configured as for the ui (see tls.rs).
+use p2p_rust::identity::Identity;
+use p2p_rust::receive::Destination;
+use p2p_rust::signaling::get_pip_port_json_and_sockets;
+use p2p_rust::tls::SignalingConfig;
+use p2p_rust::udp::receive_file_udp;
+use p2p_rust::{Client, Source};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use tokio::net::UdpSocket;
+
+const USAGE: &str =
+    "usage: p2p send <user> <file | -> [--name NAME]\n       p2p send --udp <user> <file>\n       p2p receive [--stdout | --udp]";
+
+enum Command {
+    Send { target: String, source: Source },
+    SendUdp { target: String, path: PathBuf },
+    Receive { destination: Destination },
+    ReceiveUdp,
+}
+
+fn stdin(name: &str) -> Source {
+    Source::Stream { name: name.to_string(), reader: Box::new(tokio::io::stdin()) }
+}
+
+fn parse_args(args: &[String]) -> Option<Command> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["send", target, "-"] => Some(Command::Send { target: target.to_string(), source: stdin("stdin") }),
+        ["send", target, "-", "--name", name] => Some(Command::Send { target: target.to_string(), source: stdin(name) }),
+        ["send", "--udp", target, path] => Some(Command::SendUdp { target: target.to_string(), path: PathBuf::from(path) }),
+        ["send", target, path] => Some(Command::Send { target: target.to_string(), source: Source::File(PathBuf::from(path)) }),
+        ["receive"] => Some(Command::Receive { destination: Destination::Folder(PathBuf::from("downloads")) }),
+        ["receive", "--stdout"] => Some(Command::Receive { destination: Destination::Stdout }),
+        ["receive", "--udp"] => Some(Command::ReceiveUdp),
+        _ => None,
+    }
+}
+
+async fn run(command: Command) -> Result<(), String> {
+    let username = std::env::var("P2P_USERNAME").map_err(|_| "Set P2P_USERNAME to log in".to_string())?;
+    let identity = Identity::load_or_create().map_err(|e| format!("Can't load identity key: {}", e))?;
+    let client = Client::connect(SignalingConfig::from_env()?, identity).await?;
+    let password = std::env::var("P2P_PASSWORD").ok();
+
+    // Senders over UDP look up the address STUN saw for the socket the files come to
+    let (address, socket) = match command {
+        Command::ReceiveUdp => {
+            let (address, socket) = stun_socket(&username)?;
+            (address, Some(socket))
+        }
+        _ => (Value::Null, None),
+    };
+    client.register_at(&username, password.as_deref(), &address).await?;
+
+    match (command, socket) {
+        (Command::Send { target, source }, _) => client.send(&target, source).await,
+        (Command::SendUdp { target, path }, _) => client.send_udp(&target, &path).await,
+        (Command::Receive { destination }, _) => client.receive(destination).await,
+        (Command::ReceiveUdp, socket) => {
+            let socket = socket.ok_or("No socket to receive on")?;
+            loop {
+                receive_file_udp(&socket, Path::new("downloads")).await.map_err(|e| e.to_string())?;
+            }
+        }
+    }
+}
+
+// A UDP socket and the address STUN saw it at, for registering
+fn stun_socket(username: &str) -> Result<(Value, UdpSocket), String> {
+    let (address, mut sockets) = get_pip_port_json_and_sockets(username, "");
+    let (socket, _) = sockets.pop().ok_or("STUN didn't find an address for UDP")?;
+    socket.set_nonblocking(true).map_err(|e| e.to_string())?;
+    Ok((address, UdpSocket::from_std(socket).map_err(|e| e.to_string())?))
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(command) = parse_args(&args) else {
+        eprintln!("{}", USAGE);
+        return ExitCode::from(2);
+    };
+    match run(command).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
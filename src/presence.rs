@@ -0,0 +1,108 @@
+// Who is online, pushed by the server instead of asked for. A connection of its own
+// sends presence_subscribe and is answered with
+//   presence {peers}          everyone registered right now
+// and after that, as registrations come and go,
+//   peer_online {username, ...}   someone registered
+//   peer_updated {username, ...}  someone registered again with other addresses or key
+//   peer_offline {username}       someone's connection went away
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+pub enum PresenceEvent {
+    // The whole list, sent first
+    Snapshot,
+    Online(String),
+    Updated(String),
+    Offline(String),
+}
+
+// Usernames online, alphabetical
+#[derive(Default)]
+pub struct Presence {
+    online: BTreeSet<String>,
+    // False until the snapshot is in, and again once the subscription is lost
+    live: bool,
+}
+
+impl Presence {
+    pub fn online(&self) -> Vec<String> {
+        self.online.iter().cloned().collect()
+    }
+
+    pub fn is_online(&self, username: &str) -> bool {
+        self.online.contains(username)
+    }
+
+    pub fn is_live(&self) -> bool {
+        self.live
+    }
+
+    pub fn lost(&mut self) {
+        self.live = false;
+    }
+
+    // Applies one message from the subscription, None if it wasn't about presence
+    fn apply(&mut self, data: &Value) -> Option<PresenceEvent> {
+        let username = data["username"].as_str().unwrap_or_default().to_string();
+        match data["type"].as_str()? {
+            "presence" => {
+                let peers = data["peers"].as_array()?;
+                self.online = peers.iter().filter_map(|peer| peer["username"].as_str()).map(str::to_string).collect();
+                self.live = true;
+                Some(PresenceEvent::Snapshot)
+            }
+            "peer_online" => {
+                self.online.insert(username.clone());
+                Some(PresenceEvent::Online(username))
+            }
+            "peer_updated" => {
+                self.online.insert(username.clone());
+                Some(PresenceEvent::Updated(username))
+            }
+            "peer_offline" => {
+                self.online.remove(&username);
+                Some(PresenceEvent::Offline(username))
+            }
+            _ => None,
+        }
+    }
+}
+
+// Subscribes on `ws_stream` and keeps `presence` up to date, calling `on_event` after
+// each change. Runs until the connection goes, returns why.
+pub async fn watch_presence(
+    mut ws_stream: WsStream,
+    presence: &RefCell<Presence>,
+    mut on_event: impl FnMut(PresenceEvent),
+) -> String {
+    let subscribe = json!({"type": "presence_subscribe"});
+    if let Err(e) = ws_stream.send(Message::Text(subscribe.to_string())).await {
+        return e.to_string();
+    }
+    let error = loop {
+        let text = match ws_stream.next().await {
+            Some(Ok(Message::Text(text))) => text,
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => break e.to_string(),
+            None => break "Connection closed".to_string(),
+        };
+        let Ok(data) = serde_json::from_str::<Value>(&text) else { continue };
+        if data["type"] == "error" {
+            break data["error"].as_str().unwrap_or("unknown error").to_string();
+        }
+        // Not held across the callback, it reads the list too
+        let event = presence.borrow_mut().apply(&data);
+        if let Some(event) = event {
+            on_event(event);
+        }
+    };
+    presence.borrow_mut().lost();
+    error
+}
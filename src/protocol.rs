@@ -0,0 +1,298 @@
+// Typed messages spoken between clients and the signaling server. Everything is JSON
+// with a "type" field; messages the server doesn't know are relayed as-is to the
+// peer of an active relay session (file_metadata, file_end, ...).
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    // Either `password` (first time / login) or `token` (reconnect) is required
+    Register {
+        username: String,
+        password: Option<String>,
+        token: Option<String>,
+        ipv4_ip: Option<String>,
+        ipv4_port: Option<u16>,
+        ipv6_ip: Option<String>,
+        ipv6_port: Option<u16>,
+        // Base64 Ed25519 public key, handed out to other clients as-is
+        identity_key: Option<String>,
+    },
+    // New public addresses for a registered user, from any connection: relay sessions
+    // keep going to the one that registered. Answered with address_updated.
+    UpdateAddress {
+        token: Option<String>,
+        ipv4_ip: Option<String>,
+        ipv4_port: Option<u16>,
+        ipv6_ip: Option<String>,
+        ipv6_port: Option<u16>,
+    },
+    // Answered with a plain JSON array of usernames
+    RequestPeer,
+    #[serde(alias = "getusers")]
+    GetUsers,
+    // Answered with `presence`, everyone online now, then peer_online, peer_updated and
+    // peer_offline follow on this connection as registrations come and go
+    PresenceSubscribe,
+    InitiateRelay {
+        target: String,
+        token: Option<String>,
+    },
+    // Marks this connection as the one that relay sessions for `username` should use
+    RelayReceive {
+        username: String,
+        token: Option<String>,
+    },
+    RelayControl {
+        action: String,
+    },
+    PeerInformation {
+        target: String,
+        token: Option<String>,
+    },
+    // Rooms are answered with `room` (create/join), `room_left` or `rooms` (list)
+    CreateRoom {
+        room: String,
+        token: Option<String>,
+    },
+    JoinRoom {
+        room: String,
+        token: Option<String>,
+    },
+    LeaveRoom {
+        room: String,
+        token: Option<String>,
+    },
+    ListRooms {
+        token: Option<String>,
+    },
+    // Swarm: "I can hand out the file with this content hash", answered with `announced`
+    Announce {
+        hash: String,
+        token: Option<String>,
+    },
+    // Answered with `holders`, the online users that announced `hash`
+    WhoHas {
+        hash: String,
+        token: Option<String>,
+    },
+    // Parallel streams (see multistream.rs): an extra sender connection waits under
+    // `key` until `to` joins it, answered with stream_waiting
+    StreamOpen {
+        key: String,
+        to: String,
+        token: Option<String>,
+    },
+    // Pairs this connection with the one waiting under `key`, both get stream_paired
+    StreamJoin {
+        key: String,
+        token: Option<String>,
+    },
+    // Wormhole codes (see wormhole.rs), no account needed for either
+    WormholeAllocate,
+    WormholeClaim {
+        nameplate: u32,
+    },
+    // Marks this connection as the one chat for the token's user goes to
+    ChatListen {
+        token: Option<String>,
+    },
+    // Sent on the chat connection, `id` is picked by the sender for the ack
+    ChatMessage {
+        to: String,
+        id: String,
+        text: String,
+        timestamp: u64,
+    },
+    ChatAck {
+        to: String,
+        id: String,
+    },
+    // Store-and-forward for offline users (see mailbox.rs). Answered with
+    // mailbox_ready, then the sealed frames follow as binary messages and
+    // mailbox_upload_end is answered with mailbox_stored.
+    MailboxUpload {
+        to: String,
+        // Bytes of all frames together
+        size: u64,
+        // Whatever the recipient needs to open the frames, the server doesn't look
+        header: Value,
+        token: Option<String>,
+    },
+    MailboxUploadEnd,
+    // Answered with mailbox_key, the identity key `to` last registered with
+    MailboxKey {
+        to: String,
+        token: Option<String>,
+    },
+    // Answered with `mailbox`: what waits for us and receipts for what we left
+    MailboxList {
+        token: Option<String>,
+    },
+    // Answered with mailbox_item, the frames as binary messages, then mailbox_end
+    MailboxFetch {
+        id: String,
+        token: Option<String>,
+    },
+    MailboxDelete {
+        id: String,
+        token: Option<String>,
+    },
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Registered {
+        status: String,
+        username: String,
+        token: String,
+    },
+    AddressUpdated,
+    RelayInitiated {
+        status: String,
+        target: String,
+        initiator: String,
+    },
+    RelayControl {
+        action: String,
+    },
+    PeerInfo(PeerInfo),
+    Presence {
+        peers: Vec<PeerInfo>,
+    },
+    PeerOnline(PeerInfo),
+    // Registered again with other addresses or another identity key
+    PeerUpdated(PeerInfo),
+    PeerOffline {
+        username: String,
+    },
+    Room(RoomInfo),
+    RoomLeft {
+        room: String,
+    },
+    Rooms {
+        rooms: Vec<RoomInfo>,
+    },
+    Announced {
+        hash: String,
+    },
+    Holders {
+        hash: String,
+        peers: Vec<String>,
+    },
+    StreamWaiting {
+        key: String,
+    },
+    StreamPaired {
+        key: String,
+    },
+    WormholeAllocated {
+        nameplate: u32,
+    },
+    WormholePaired,
+    ChatListening,
+    ChatMessage {
+        from: String,
+        id: String,
+        text: String,
+        timestamp: u64,
+    },
+    ChatAck {
+        from: String,
+        id: String,
+    },
+    // The message never reached the other side
+    ChatFailed {
+        id: String,
+        error: String,
+    },
+    MailboxReady {
+        id: String,
+    },
+    MailboxKey {
+        to: String,
+        identity_key: Option<String>,
+    },
+    MailboxStored {
+        id: String,
+        to: String,
+        expires: u64,
+    },
+    // Sent after `registered` when something waits in the mailbox
+    MailboxNotice {
+        items: usize,
+        receipts: usize,
+    },
+    Mailbox {
+        items: Vec<MailboxEntry>,
+        receipts: Vec<MailboxReceipt>,
+    },
+    MailboxItem(MailboxEntry),
+    MailboxEnd {
+        id: String,
+    },
+    MailboxDeleted {
+        id: String,
+    },
+    Error {
+        error: String,
+    },
+}
+
+impl ServerMessage {
+    pub fn error(message: &str) -> ServerMessage {
+        ServerMessage::Error { error: message.to_string() }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("server messages always serialize")
+    }
+}
+
+// What other clients may see about a registered peer
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct PeerInfo {
+    pub username: String,
+    pub ipv4_ip: Option<String>,
+    pub ipv4_port: Option<u16>,
+    pub ipv6_ip: Option<String>,
+    pub ipv6_port: Option<u16>,
+    pub identity_key: Option<String>,
+}
+
+pub type UserList = HashMap<String, PeerInfo>;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RoomInfo {
+    pub room: String,
+    pub members: Vec<RoomMember>,
+}
+
+// Members stay in a room while offline, only `online` ones can receive room posts
+#[derive(Serialize, Debug, Clone)]
+pub struct RoomMember {
+    pub username: String,
+    pub online: bool,
+}
+
+// An upload waiting for its recipient, `expires` is in seconds since the epoch
+#[derive(Serialize, Debug, Clone)]
+pub struct MailboxEntry {
+    pub id: String,
+    pub from: String,
+    pub size: u64,
+    pub header: Value,
+    pub expires: u64,
+}
+
+// Tells a sender what became of an upload: "delivered" (fetched, then deleted),
+// "deleted" (deleted unread) or "expired"
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MailboxReceipt {
+    pub id: String,
+    pub to: String,
+    pub status: String,
+}
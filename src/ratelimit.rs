@@ -0,0 +1,290 @@
+// Upload and download speed limits. Every transfer in the process shares one token
+// bucket per direction, and each transfer also has a bucket of its own, so the
+// per-transfer limit keeps a single big file from taking the whole global allowance.
+// A chunk goes out (or is taken in) once both buckets have room for it.
+//
+// The limits are kept in ratelimit.json, read once and then changed through
+// set_limits, which every chunk sees, so transfers already running slow down or
+// speed up too. Edits to the file itself count from the next start. A schedule can
+// swap in other global limits for parts of the day, e.g. a slower upload during
+// office hours.
+use crate::history::Direction;
+use crate::store::{load_json, save_json};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const LIMITS_FILE: &str = "ratelimit.json";
+
+// KB/s, 0 is unlimited
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rates {
+    #[serde(default)]
+    pub upload: u64,
+    #[serde(default)]
+    pub download: u64,
+}
+
+impl Rates {
+    // Bytes per second in `direction`, 0 is unlimited
+    fn bytes_per_sec(&self, direction: Direction) -> u64 {
+        let kb = match direction {
+            Direction::Sent => self.upload,
+            Direction::Received => self.download,
+        };
+        kb * 1024
+    }
+}
+
+// Global limits for `from` until `to`, minutes since midnight UTC. A window that
+// ends before it starts runs past midnight.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Window {
+    pub from: u16,
+    pub to: u16,
+    pub rates: Rates,
+}
+
+impl Window {
+    fn contains(&self, minute: u16) -> bool {
+        if self.from <= self.to {
+            (self.from..self.to).contains(&minute)
+        } else {
+            minute >= self.from || minute < self.to
+        }
+    }
+
+    // "09:00-17:30 200 1000": the times, then upload and download in KB/s
+    pub fn parse(line: &str) -> Result<Window, String> {
+        let bad = || format!("\"{}\" isn't like 09:00-17:30 200 1000", line.trim());
+        let mut parts = line.split_whitespace();
+        let (from, to) = parts.next().and_then(|span| span.split_once('-')).ok_or_else(bad)?;
+        let from = parse_minute(from).ok_or_else(bad)?;
+        let to = parse_minute(to).ok_or_else(bad)?;
+        let upload = parts.next().and_then(|n| n.parse().ok()).ok_or_else(bad)?;
+        let download = parts.next().and_then(|n| n.parse().ok()).ok_or_else(bad)?;
+        if parts.next().is_some() {
+            return Err(bad());
+        }
+        Ok(Window { from, to, rates: Rates { upload, download } })
+    }
+}
+
+impl std::fmt::Display for Window {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}-{} {} {}", format_minute(self.from), format_minute(self.to), self.rates.upload, self.rates.download)
+    }
+}
+
+fn parse_minute(text: &str) -> Option<u16> {
+    let (hours, minutes) = text.split_once(':')?;
+    let (hours, minutes): (u16, u16) = (hours.parse().ok()?, minutes.parse().ok()?);
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+fn format_minute(minute: u16) -> String {
+    format!("{:02}:{:02}", minute / 60, minute % 60)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Limits {
+    // Shared by all transfers, unless a schedule window says otherwise
+    #[serde(default)]
+    pub global: Rates,
+    #[serde(default)]
+    pub per_transfer: Rates,
+    // The first window that contains the current time wins
+    #[serde(default)]
+    pub schedule: Vec<Window>,
+}
+
+impl Limits {
+    // The global limits at `minute` since midnight UTC, and the window they come from
+    pub fn global_at(&self, minute: u16) -> (Rates, Option<&Window>) {
+        match self.schedule.iter().find(|window| window.contains(minute)) {
+            Some(window) => (window.rates, Some(window)),
+            None => (self.global, None),
+        }
+    }
+}
+
+pub fn minute_now() -> u16 {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    (secs / 60 % (24 * 60)) as u16
+}
+
+// Tokens are bytes. They may go negative: a chunk bigger than what is saved up still
+// goes out, and whoever comes next waits off the debt. At most a second's worth is
+// saved up while idle.
+#[derive(Default)]
+struct Bucket {
+    tokens: f64,
+    updated: Option<Instant>,
+}
+
+impl Bucket {
+    // Takes `bytes` at `rate` bytes/s, returns how long to wait before using them
+    fn take(&mut self, rate: u64, bytes: u64, now: Instant) -> Duration {
+        let elapsed = self.updated.map_or(Duration::ZERO, |updated| now - updated);
+        self.updated = Some(now);
+        if rate == 0 {
+            self.tokens = 0.0;
+            return Duration::ZERO;
+        }
+        let rate = rate as f64;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * rate).min(rate) - bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+struct Shared {
+    // None until first used, then whatever was loaded or set last
+    limits: Option<Limits>,
+    upload: Bucket,
+    download: Bucket,
+}
+
+static SHARED: LazyLock<Mutex<Shared>> =
+    LazyLock::new(|| Mutex::new(Shared { limits: None, upload: Bucket::default(), download: Bucket::default() }));
+
+pub fn limits() -> Limits {
+    let mut shared = SHARED.lock().unwrap();
+    shared.limits.get_or_insert_with(|| load_json(LIMITS_FILE)).clone()
+}
+
+// Saves `limits`, running transfers pick them up with their next chunk
+pub fn set_limits(limits: Limits) -> io::Result<()> {
+    save_json(LIMITS_FILE, &limits)?;
+    SHARED.lock().unwrap().limits = Some(limits);
+    Ok(())
+}
+
+// One transfer's share of the limits in one direction. Transfers that pull from
+// several peers at once share theirs between the peers.
+pub struct Throttle {
+    direction: Direction,
+    own: Mutex<Bucket>,
+}
+
+impl Throttle {
+    pub fn new(direction: Direction) -> Throttle {
+        Throttle { direction, own: Mutex::new(Bucket::default()) }
+    }
+
+    // Call with every chunk. Waits as long as the limits ask for, or just yields to
+    // the other tasks when there is room.
+    pub async fn wait(&self, bytes: usize) {
+        let delay = {
+            let mut shared = SHARED.lock().unwrap();
+            let limits = shared.limits.get_or_insert_with(|| load_json(LIMITS_FILE));
+            let global = limits.global_at(minute_now()).0.bytes_per_sec(self.direction);
+            let own = limits.per_transfer.bytes_per_sec(self.direction);
+            let now = Instant::now();
+            let bucket = match self.direction {
+                Direction::Sent => &mut shared.upload,
+                Direction::Received => &mut shared.download,
+            };
+            bucket.take(global, bytes as u64, now).max(self.own.lock().unwrap().take(own, bytes as u64, now))
+        };
+        if delay.is_zero() {
+            tokio::task::yield_now().await;
+        } else {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(from: u16, to: u16) -> Window {
+        Window { from, to, rates: Rates::default() }
+    }
+
+    #[test]
+    fn window_parses_and_prints_back() {
+        let window = Window::parse(" 09:00-17:30 200 1000 ").unwrap();
+        assert_eq!(window, Window { from: 9 * 60, to: 17 * 60 + 30, rates: Rates { upload: 200, download: 1000 } });
+        assert_eq!(window.to_string(), "09:00-17:30 200 1000");
+        assert_eq!(Window::parse("22:00-06:00 0 0").unwrap().to_string(), "22:00-06:00 0 0");
+    }
+
+    #[test]
+    fn window_refuses_what_isnt_one() {
+        for line in ["", "09:00 200 1000", "09:00-17:30 200", "09:00-17:30 200 1000 5", "24:00-01:00 1 1", "09:60-10:00 1 1", "9-17 1 1", "09:00-17:30 -1 1", "09:00-17:30 fast 1"] {
+            assert!(Window::parse(line).is_err(), "{:?} was taken", line);
+        }
+    }
+
+    #[test]
+    fn window_contains_its_start_but_not_its_end() {
+        let office = window(9 * 60, 17 * 60);
+        assert!(!office.contains(9 * 60 - 1));
+        assert!(office.contains(9 * 60));
+        assert!(office.contains(17 * 60 - 1));
+        assert!(!office.contains(17 * 60));
+    }
+
+    #[test]
+    fn window_wraps_past_midnight() {
+        let night = window(22 * 60, 6 * 60);
+        assert!(night.contains(22 * 60));
+        assert!(night.contains(23 * 60 + 59));
+        assert!(night.contains(0));
+        assert!(night.contains(6 * 60 - 1));
+        assert!(!night.contains(6 * 60));
+        assert!(!night.contains(12 * 60));
+        assert!(!night.contains(22 * 60 - 1));
+    }
+
+    #[test]
+    fn first_window_wins_over_the_global_limits() {
+        let limits = Limits {
+            global: Rates { upload: 1, download: 1 },
+            per_transfer: Rates::default(),
+            schedule: vec![
+                Window { from: 60, to: 120, rates: Rates { upload: 2, download: 2 } },
+                Window { from: 0, to: 24 * 60 - 1, rates: Rates { upload: 3, download: 3 } },
+            ],
+        };
+        assert_eq!(limits.global_at(90).0.upload, 2);
+        assert_eq!(limits.global_at(30).0.upload, 3);
+        assert_eq!(limits.global_at(24 * 60 - 1), (Rates { upload: 1, download: 1 }, None));
+    }
+
+    #[test]
+    fn bucket_lets_a_second_through_then_makes_the_rest_wait() {
+        let start = Instant::now();
+        let mut bucket = Bucket::default();
+        // Nothing saved up the first time, a full second's worth waits a second
+        assert_eq!(bucket.take(1000, 1000, start), Duration::from_secs(1));
+        // That debt is paid off after a second, the next 500 bytes wait half a second
+        assert_eq!(bucket.take(1000, 500, start + Duration::from_secs(1)), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn bucket_saves_up_at_most_a_second() {
+        let start = Instant::now();
+        let mut bucket = Bucket::default();
+        bucket.take(1000, 0, start);
+        // Idle for a minute still only allows a second's worth without waiting
+        assert_eq!(bucket.take(1000, 1000, start + Duration::from_secs(60)), Duration::ZERO);
+        assert_eq!(bucket.take(1000, 1000, start + Duration::from_secs(60)), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn bucket_without_a_rate_never_waits() {
+        let start = Instant::now();
+        let mut bucket = Bucket::default();
+        assert_eq!(bucket.take(0, 10_000_000, start), Duration::ZERO);
+        // No debt is left over for when a limit is set
+        assert_eq!(bucket.take(1000, 0, start + Duration::from_secs(1)), Duration::ZERO);
+        assert_eq!(bucket.take(1000, 1000, start + Duration::from_secs(1)), Duration::ZERO);
+    }
+}
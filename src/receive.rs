@@ -0,0 +1,863 @@
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::fs;
+use std::io;
+use std::path::Path;
+use tokio_tungstenite::tungstenite::Message;
+use tokio::net::TcpStream;
+use std::error::Error;
+use tokio::sync::Mutex;
+use std::sync::Arc;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::MaybeTlsStream;
+use crate::clipboard::{Clip, IncomingClip};
+use crate::compression::choose_codec;
+use crate::incoming::{IncomingFile, IncomingStream};
+use crate::mirror::safe_relative_path;
+use crate::offline::Opener;
+use crate::history::{Direction, Route, Transfer};
+use crate::ratelimit::Throttle;
+use crate::multistream::keys_in;
+use crate::shares::{list, open_share, readable_by, resolve};
+use crate::delta::{self, read_full, sha256_file};
+use crate::events::FileProgress;
+use futures_util::Sink;
+use std::cell::RefCell;
+use crate::swarm::{find_seed, read_chunk, Seed};
+use crate::compression::Codec;
+use crate::wormhole::{exchange_pake, next_frame, parse_code, Frame};
+use std::path::PathBuf;
+use std::future::Future;
+use tokio::time::{timeout, Duration};
+
+// How long a room post waits for the user to accept it, the sender waits a bit longer
+pub const OFFER_TIMEOUT: Duration = Duration::from_secs(120);
+// A parallel stream that brings nothing for this long fails the file
+const STREAM_TIMEOUT: Duration = Duration::from_secs(60);
+const SHARE_CHUNK_SIZE: usize = 64 * 1024;
+
+// Where relay_receive puts files
+#[derive(Clone, PartialEq)]
+pub enum Destination {
+    // Usually ./downloads
+    Folder(PathBuf),
+    // The first file only, as a stream, for `p2p receive --stdout`
+    Stdout,
+}
+
+// Every identity message from the sender goes through `on_identity`, which returns the
+// answer to send or an error to refuse the sender. Files are only accepted once the
+// sender's identity_proof was accepted. Every file, room post and clipboard share is
+// offered to `on_offer` (sender, file_metadata) first and declined unless it resolves
+// to true. Clipboard shares are kept in memory and handed to `on_clipboard`.
+// `open_stream` connects to the signaling server again, for joining the parallel
+// streams a sender offers with a large file (see multistream.rs).
+// Contacts that may read one of our shared folders can browse and pull it
+// (see shares.rs), that is answered here without asking.
+// Streams of unknown length (file_metadata with "stream": true) are written as they
+// come and end with file_end. Status lines go to stderr, with Destination::Stdout
+// standard output carries nothing but the file.
+#[allow(clippy::too_many_arguments)]
+pub async fn relay_receive<F: Future<Output = bool>, S: Future<Output = Result<WebSocketStream<MaybeTlsStream<TcpStream>>, String>>>(
+    username: String,
+    token: String,
+    ws_stream: Arc<Mutex<WebSocketStream<MaybeTlsStream<TcpStream>>>>,
+    mut on_identity: impl FnMut(&Value) -> Result<Option<Value>, String>,
+    mut on_offer: impl FnMut(&str, &Value) -> F,
+    mut on_clipboard: impl FnMut(&str, Clip),
+    mut open_stream: impl FnMut() -> S,
+    destination: Destination,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut guard = ws_stream.lock().await;
+    let (mut write, mut read) = StreamExt::split(&mut *guard);
+
+        eprintln!("🔌 Connected to signaling server");
+
+    // Relay sessions for us should come to this socket
+    write.send(Message::Text(json!({
+        "type": "relay_receive",
+        "username": username.trim(),
+        "token": token
+    }).to_string())).await?;
+
+    // Prepare downloads directory, mirrored folders still go to ./downloads with Destination::Stdout
+    let downloads = match &destination {
+        Destination::Folder(dir) => dir.clone(),
+        Destination::Stdout => PathBuf::from("downloads"),
+    };
+    if !downloads.exists() && destination != Destination::Stdout {
+        fs::create_dir_all(&downloads)?;
+        eprintln!("📁 Created {}", downloads.display());
+    }
+
+    eprintln!("📡 Waiting for files...");
+    let mut current_file: Option<IncomingFile> = None;
+    let mut current_stream: Option<IncomingStream> = None;
+    // History entry of the current file, mirrored files have none: the mirror isn't a transfer
+    let mut current_transfer: Option<Transfer> = None;
+    // Events of the current file or stream, mirrored files included
+    let mut current_progress: Option<FileProgress> = None;
+    // Sessions come one after another on this socket, so one of each covers them all
+    let download_throttle = Throttle::new(Direction::Received);
+    let seed_throttle = Throttle::new(Direction::Sent);
+    let mut current_clip: Option<IncomingClip> = None;
+    let mut initiator: Option<String> = None;
+    let mut sender_verified = false;
+    // Set by mirror_start, mirrored files keep their paths below it
+    let mut mirror_root: Option<std::path::PathBuf> = None;
+    // The seed a swarm downloader is pulling from, saves reading seeds.json per chunk
+    let mut serving: Option<(String, Seed)> = None;
+
+    while let Some(msg) = read.next().await {
+        match msg? {
+            Message::Text(text) => {
+                if let Ok(data) = serde_json::from_str::<Value>(&text) {
+                    match data.get("type").and_then(|v| v.as_str()) {
+                        Some("file_metadata" | "delta_copy" | "mirror_start" | "mirror_rename" | "mirror_delete" | "swarm_manifest_request" | "swarm_get" | "share_browse" | "share_get") if !sender_verified => {
+                            write.send(end_relay()).await?;
+                            let sender = initiator.as_deref().unwrap_or("unknown");
+                            return Err(format!("{} didn't prove its identity, transfer refused", sender).into());
+                        },
+                        Some("file_metadata") => {
+                            // Files sent to us, mirrored and posted to rooms alike; clipboard
+                            // shares are asked about below
+                            let sender = initiator.as_deref().unwrap_or("unknown");
+                            if data.get("clipboard").is_none() && !on_offer(sender, &data).await {
+                                let reply = match data.get("room").and_then(|v| v.as_str()) {
+                                    Some(room) => {
+                                        eprintln!("🚫 Declined a file posted to {}", room);
+                                        json!({"type": "file_decline"})
+                                    }
+                                    None => {
+                                        eprintln!("🚫 Declined a file from {}", sender);
+                                        json!({"type": "file_decline", "reason": format!("{} doesn't take files from {}", username, sender)})
+                                    }
+                                };
+                                write.send(Message::Text(reply.to_string())).await?;
+                                continue;
+                            }
+                            if data.get("clipboard").is_some() {
+                                let reply = if !on_offer(sender, &data).await {
+                                    eprintln!("🚫 Declined a clipboard share from {}", sender);
+                                    json!({"type": "file_decline", "reason": format!("{} doesn't accept clipboard shares", username)})
+                                } else {
+                                    let codec = choose_codec(&data);
+                                    match IncomingClip::new(&data, codec) {
+                                        Ok(clip) => {
+                                            current_clip = Some(clip);
+                                            json!({"type": "file_accept", "codec": codec.name()})
+                                        }
+                                        Err(e) => json!({"type": "file_decline", "reason": e}),
+                                    }
+                                };
+                                write.send(Message::Text(reply.to_string())).await?;
+                                continue;
+                            }
+                            if data.get("stream").and_then(|v| v.as_bool()) == Some(true) || destination == Destination::Stdout {
+                                let name = data.get("name").and_then(|v| v.as_str()).unwrap_or_default();
+                                let size = data.get("size").and_then(|v| v.as_u64());
+                                let codec = choose_codec(&data);
+                                let created = match destination {
+                                    Destination::Stdout => IncomingStream::stdout(name, size, codec),
+                                    Destination::Folder(_) => IncomingStream::create(&downloads, name, codec),
+                                };
+                                let reply = match created {
+                                    Ok(incoming) => {
+                                        eprintln!("📥 Receiving {} as a stream (compression: {})", incoming.name, codec.name());
+                                        current_transfer = Some(Transfer::start(Direction::Received, initiator.as_deref().unwrap_or("unknown"), Route::Relay));
+                                        current_progress = Some(FileProgress::start(Direction::Received, initiator.as_deref().unwrap_or("unknown"), &incoming.name, size));
+                                        current_stream = Some(incoming);
+                                        json!({"type": "file_accept", "codec": codec.name()})
+                                    }
+                                    Err(e) => {
+                                        eprintln!("❌ {}", e);
+                                        json!({"type": "file_decline", "reason": e})
+                                    }
+                                };
+                                write.send(Message::Text(reply.to_string())).await?;
+                                continue;
+                            }
+                            if let (Some(name), Some(size)) = (
+                                data.get("name").and_then(|v| v.as_str()),
+                                data.get("size").and_then(|v| v.as_u64())
+                            ) {
+                                let codec = choose_codec(&data);
+                                let relative = data.get("path").and_then(|v| v.as_str());
+                                let mut created = match (relative, mirror_root.as_deref()) {
+                                    (Some(relative), Some(root)) => IncomingFile::create_at(root, relative, size, codec),
+                                    _ => IncomingFile::create(&downloads, name, size, codec),
+                                };
+                                // Offered over parallel streams: join what we can and take the
+                                // ranges before going back to the main session for file_end
+                                let offered = keys_in(&data);
+                                let mut joined = Vec::new();
+                                if created.is_ok() && !offered.is_empty() && relative.is_none() {
+                                    joined = join_streams(&mut open_stream, &offered, &token).await;
+                                }
+                                if !joined.is_empty()
+                                    && let Ok(incoming) = created.as_mut()
+                                    && let Err(e) = incoming.preallocate()
+                                {
+                                    // Accepting without streams keeps the file on the main session
+                                    eprintln!("❌ {}", e);
+                                    joined.clear();
+                                }
+                                if !joined.is_empty() && let Ok(incoming) = created {
+                                    let keys: Vec<&str> = joined.iter().map(|(key, _)| key.as_str()).collect();
+                                    let accept = json!({"type": "file_accept", "codec": Codec::None.name(), "streams": keys});
+                                    write.send(Message::Text(accept.to_string())).await?;
+                                    eprintln!("📥 Receiving {} ({} bytes) over {} streams", incoming.name, size, joined.len());
+                                    let mut transfer = Transfer::start(Direction::Received, initiator.as_deref().unwrap_or("unknown"), Route::Relay);
+                                    let progress = RefCell::new(FileProgress::start(Direction::Received, initiator.as_deref().unwrap_or("unknown"), &incoming.name, Some(size)));
+                                    let incoming = RefCell::new(incoming);
+                                    let streams = joined.into_iter().map(|(_, ws_stream)| ws_stream).collect();
+                                    let result = receive_ranges(streams, &incoming, &download_throttle, &progress).await;
+                                    let (incoming, progress) = (incoming.into_inner(), progress.into_inner());
+                                    if let Err(e) = result {
+                                        let name = incoming.name.clone();
+                                        transfer.add_file(&name, size, None, None);
+                                        transfer.finish(Some(e.clone()));
+                                        progress.fail(&e);
+                                        incoming.abort();
+                                        write.send(end_relay()).await?;
+                                        return Err(format!("{}: {}", name, e).into());
+                                    }
+                                    current_transfer = Some(transfer);
+                                    current_progress = Some(progress);
+                                    current_file = Some(incoming);
+                                    continue;
+                                }
+                                match created {
+                                    Ok(mut incoming) => {
+                                        // An older copy lets the sender skip the blocks we already have.
+                                        // Its signature follows the accept, the sender waits for it.
+                                        let wants_delta = data.get("delta").and_then(|v| v.as_bool()) == Some(true);
+                                        let basis = wants_delta.then(|| incoming.delta_basis()).flatten();
+                                        let mut accept = json!({"type": "file_accept", "codec": codec.name()});
+                                        if basis.is_some() {
+                                            accept["delta_follows"] = Value::from(true);
+                                        }
+                                        write.send(Message::Text(accept.to_string())).await?;
+                                        if let Some(basis) = basis {
+                                            eprintln!("🧩 Have an older {}, hashing it for a delta", incoming.name);
+                                            let signature = tokio::task::spawn_blocking(move || delta::signature(&basis)).await?;
+                                            let signature = match signature.and_then(|signature| incoming.use_delta(&signature).map(|_| signature)) {
+                                                Ok(signature) => serde_json::to_value(signature)?,
+                                                Err(e) => {
+                                                    eprintln!("❌ Can't use the older {}: {}", incoming.name, e);
+                                                    Value::Null
+                                                }
+                                            };
+                                            write.send(Message::Text(json!({"type": "delta_signature", "signature": signature}).to_string())).await?;
+                                        }
+                                        eprintln!("📥 Receiving {} ({} bytes, compression: {})", incoming.name, size, codec.name());
+                                        current_transfer = relative.is_none().then(|| {
+                                            Transfer::start(Direction::Received, initiator.as_deref().unwrap_or("unknown"), Route::Relay)
+                                        });
+                                        current_progress = Some(FileProgress::start(Direction::Received, initiator.as_deref().unwrap_or("unknown"), relative.unwrap_or(&incoming.name), Some(size)));
+                                        current_file = Some(incoming);
+                                    },
+                                    Err(e) => eprintln!("❌ File creation failed: {}", e),
+                                }
+                            }
+                        },
+                        Some("delta_copy") => {
+                            if let Some(incoming) = current_file.as_mut() {
+                                let block = data.get("block").and_then(|v| v.as_u64()).unwrap_or(0) as usize;
+                                let count = data.get("count").and_then(|v| v.as_u64()).unwrap_or(0) as usize;
+                                if let Err(e) = incoming.copy_blocks(block, count) {
+                                    let name = incoming.name.clone();
+                                    if let Some(mut transfer) = current_transfer.take() {
+                                        transfer.add_file(&name, incoming.size, None, None);
+                                        transfer.finish(Some(e.clone()));
+                                    }
+                                    if let Some(progress) = current_progress.take() {
+                                        progress.fail(&e);
+                                    }
+                                    if let Some(incoming) = current_file.take() {
+                                        incoming.abort();
+                                    }
+                                    write.send(end_relay()).await?;
+                                    return Err(format!("{}: {}", name, e).into());
+                                }
+                                if let Some(progress) = current_progress.as_mut() {
+                                    progress.update(incoming.written);
+                                }
+                            }
+                        },
+                        Some("file_end") => {
+                            if let Some(clip) = current_clip.take() {
+                                let result = clip.finish(data.get("sha256").and_then(|v| v.as_str()));
+                                let error = match result {
+                                    Ok(clip) => {
+                                        eprintln!("📋 Got {} from {}", clip.describe(), initiator.as_deref().unwrap_or("unknown"));
+                                        on_clipboard(initiator.as_deref().unwrap_or("unknown"), clip);
+                                        None
+                                    }
+                                    Err(e) => {
+                                        eprintln!("❌ {}", e);
+                                        Some(e)
+                                    }
+                                };
+                                write.send(received("clipboard", error)).await?;
+                            }
+                            if let Some(incoming) = current_stream.take() {
+                                let (name, written) = (incoming.name.clone(), incoming.written);
+                                let sha256 = data.get("sha256").and_then(|v| v.as_str());
+                                let result = incoming.finish(sha256);
+                                if let Some(mut transfer) = current_transfer.take() {
+                                    transfer.add_file(&name, written, sha256, result.as_ref().ok().and_then(|path| path.as_deref()));
+                                    transfer.finish_with(&result);
+                                }
+                                if let Some(progress) = current_progress.take() {
+                                    progress.finish_with(&result, result.as_ref().ok().and_then(|path| path.as_deref()));
+                                }
+                                match &result {
+                                    Ok(_) => eprintln!("\n✅ {} received successfully ({} bytes)", name, written),
+                                    Err(e) => eprintln!("\n❌ {}", e),
+                                }
+                                write.send(received(&name, result.as_ref().err().cloned())).await?;
+                                if destination == Destination::Stdout {
+                                    return result.map(|_| ()).map_err(Into::into);
+                                }
+                            }
+                            if let Some(incoming) = current_file.take() {
+                                let name = incoming.name.clone();
+                                let size = incoming.size;
+                                let sha256 = data.get("sha256").and_then(|v| v.as_str());
+                                let result = incoming.finish(sha256);
+                                if let Some(mut transfer) = current_transfer.take() {
+                                    transfer.add_file(&name, size, sha256, result.as_deref().ok());
+                                    transfer.finish_with(&result);
+                                }
+                                if let Some(progress) = current_progress.take() {
+                                    progress.finish_with(&result, result.as_deref().ok());
+                                }
+                                match &result {
+                                    Ok(_) => eprintln!("\n✅ {} received successfully!", name),
+                                    Err(e) => eprintln!("\n❌ {}", e),
+                                }
+                                write.send(received(&name, result.err())).await?;
+                            }
+                        },
+                        Some("mirror_start") => {
+                            let name = data.get("name").and_then(|v| v.as_str()).unwrap_or_default();
+                            match safe_relative_path(name).filter(|p| p.components().count() == 1) {
+                                Some(name) => {
+                                    let root = downloads.join(name);
+                                    fs::create_dir_all(&root)?;
+                                    eprintln!("🪞 Mirroring into {}", root.display());
+                                    mirror_root = Some(root);
+                                }
+                                None => eprintln!("❌ Refusing mirror folder name {:?}", name),
+                            }
+                        },
+                        Some("mirror_rename") => {
+                            let from = data.get("from").and_then(|v| v.as_str()).unwrap_or_default();
+                            let to = data.get("to").and_then(|v| v.as_str()).unwrap_or_default();
+                            let result = match (mirror_root.as_deref(), safe_relative_path(from), safe_relative_path(to)) {
+                                (Some(root), Some(from_path), Some(to_path)) => {
+                                    let target = root.join(to_path);
+                                    target.parent().map(fs::create_dir_all).transpose()
+                                        .and_then(|_| fs::rename(root.join(from_path), &target))
+                                        .map_err(|e| e.to_string())
+                                }
+                                _ => Err("Refusing rename outside the mirror".to_string()),
+                            };
+                            if result.is_ok() {
+                                eprintln!("🔀 {} -> {}", from, to);
+                            }
+                            write.send(received(to, result.err())).await?;
+                        },
+                        Some("mirror_delete") => {
+                            let path = data.get("path").and_then(|v| v.as_str()).unwrap_or_default();
+                            if let (Some(root), Some(relative)) = (mirror_root.as_deref(), safe_relative_path(path)) {
+                                let mut target = root.join(relative);
+                                if fs::remove_file(&target).is_ok() {
+                                    eprintln!("🗑️ {}", path);
+                                }
+                                // Tidy up directories the delete left empty
+                                while target.pop() && target.as_path() != root && fs::remove_dir(&target).is_ok() {}
+                            }
+                        },
+                        Some(kind @ ("swarm_manifest_request" | "swarm_get")) => {
+                            let hash = data.get("hash").and_then(|v| v.as_str()).unwrap_or_default();
+                            if serving.as_ref().is_none_or(|(serving_hash, _)| serving_hash != hash) {
+                                serving = find_seed(hash).map(|seed| (hash.to_string(), seed));
+                            }
+                            let seed = serving.as_ref().map(|(_, seed)| seed);
+                            let index = (kind == "swarm_get").then(|| data.get("index").and_then(|v| v.as_u64()).unwrap_or(u64::MAX));
+                            let reply = match (seed, index) {
+                                (Some(seed), None) => json!({"type": "swarm_manifest", "hash": hash, "manifest": seed.manifest}),
+                                (Some(seed), Some(index)) => match read_chunk(seed, index as usize) {
+                                    Some(chunk) => {
+                                        let len = chunk.len();
+                                        write.send(Message::Binary(chunk)).await?;
+                                        seed_throttle.wait(len).await;
+                                        continue;
+                                    }
+                                    None => json!({"type": "swarm_missing", "hash": hash, "index": index}),
+                                },
+                                (None, _) => json!({"type": "swarm_missing", "hash": hash}),
+                            };
+                            write.send(Message::Text(reply.to_string())).await?;
+                        },
+                        Some(kind @ ("share_browse" | "share_get")) => {
+                            let reader = initiator.as_deref().unwrap_or("unknown");
+                            let share = data.get("share").and_then(|v| v.as_str()).unwrap_or_default();
+                            let path = data.get("path").and_then(|v| v.as_str()).unwrap_or_default();
+                            let denied = |reason: String| json!({"type": "share_denied", "share": share, "path": path, "reason": reason});
+                            let reply = if kind == "share_browse" {
+                                let recursive = data.get("recursive").and_then(|v| v.as_bool()) == Some(true);
+                                let listing = match share {
+                                    "" => Ok(readable_by(reader)),
+                                    _ => open_share(share, reader).and_then(|opened| list(&opened, path, recursive)),
+                                };
+                                match listing {
+                                    Ok(entries) => json!({"type": "share_listing", "share": share, "path": path, "entries": entries}),
+                                    Err(reason) => denied(reason),
+                                }
+                            } else {
+                                let file = open_share(share, reader)
+                                    .and_then(|opened| resolve(&opened, path))
+                                    .and_then(|file| if file.is_file() { Ok(file) } else { Err(format!("{} isn't a file", path)) });
+                                match file {
+                                    Ok(file) => {
+                                        eprintln!("📂 {} is pulling {}/{}", reader, share, path);
+                                        serve_shared_file(&mut write, reader, share, path, &file, &seed_throttle).await?;
+                                        continue;
+                                    }
+                                    Err(reason) => denied(reason),
+                                }
+                            };
+                            write.send(Message::Text(reply.to_string())).await?;
+                        },
+                        Some("relay_initiated") => {
+                            initiator = data.get("initiator").and_then(|v| v.as_str()).map(|s| s.to_string());
+                            sender_verified = false;
+                            mirror_root = None;
+                            current_clip = None;
+                            eprintln!("🤝 Connected to {}", initiator.as_deref().unwrap_or("unknown"));
+                        },
+                        Some(kind @ ("identity" | "identity_proof")) => {
+                            // The server told us who initiated, the key has to belong to that name
+                            let claimed = data.get("username").and_then(|v| v.as_str());
+                            let result = if kind == "identity" && claimed != initiator.as_deref() {
+                                Err(format!("{} claimed to be {}", initiator.as_deref().unwrap_or("unknown"), claimed.unwrap_or("nobody")))
+                            } else {
+                                on_identity(&data)
+                            };
+                            match result {
+                                Ok(Some(answer)) => write.send(Message::Text(answer.to_string())).await?,
+                                Ok(None) => sender_verified = kind == "identity_proof",
+                                Err(e) => {
+                                    write.send(end_relay()).await?;
+                                    return Err(e.into());
+                                }
+                            }
+                        },
+                        Some("error") => {
+                            let error = data.get("error").and_then(|v| v.as_str()).unwrap_or("unknown error");
+                            return Err(error.to_string().into());
+                        },
+                        _ => {}
+                    }
+                }
+            },
+            Message::Binary(data) => {
+                if let Some(incoming) = current_stream.as_mut() {
+                    if let Err(e) = incoming.write_chunk(&data) {
+                        let name = incoming.name.clone();
+                        if let Some(mut transfer) = current_transfer.take() {
+                            transfer.add_file(&name, incoming.written, None, None);
+                            transfer.finish(Some(e.clone()));
+                        }
+                        if let Some(progress) = current_progress.take() {
+                            progress.fail(&e);
+                        }
+                        if let Some(incoming) = current_stream.take() {
+                            incoming.abort();
+                        }
+                        write.send(end_relay()).await?;
+                        return Err(format!("{}: {}", name, e).into());
+                    }
+                    if let Some(progress) = current_progress.as_mut() {
+                        progress.update(incoming.written);
+                    }
+                    eprint!("\r📥 {}: {} bytes", incoming.name, incoming.written);
+                }
+                if let Some(clip) = current_clip.as_mut()
+                    && let Err(e) = clip.write_chunk(&data)
+                {
+                    write.send(end_relay()).await?;
+                    return Err(format!("Clipboard share: {}", e).into());
+                }
+                if let Some(incoming) = current_file.as_mut() {
+                    if let Err(e) = incoming.write_chunk(&data) {
+                        // Corrupt or oversized data, don't keep any of it
+                        let name = incoming.name.clone();
+                        if let Some(mut transfer) = current_transfer.take() {
+                            transfer.add_file(&name, incoming.size, None, None);
+                            transfer.finish(Some(e.clone()));
+                        }
+                        if let Some(progress) = current_progress.take() {
+                            progress.fail(&e);
+                        }
+                        if let Some(incoming) = current_file.take() {
+                            incoming.abort();
+                        }
+                        write.send(end_relay()).await?;
+                        return Err(format!("{}: {}", name, e).into());
+                    }
+                    // Progress reporting
+                    if let Some(progress) = current_progress.as_mut() {
+                        progress.update(incoming.written);
+                    }
+                    eprint!("\r📥 {}: {:.1}%", incoming.name, incoming.progress());
+                }
+                download_throttle.wait(data.len()).await;
+            },
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+
+    eprintln!("👋 Session ended");
+    Ok(())
+}
+
+
+// Receives one file through a wormhole code (see wormhole.rs) into `downloads`. Needs
+// no account, `ws_stream` is a fresh connection to the signaling server.
+pub async fn wormhole_receive(
+    mut ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    code: String,
+    downloads: PathBuf,
+    mut on_progress: impl FnMut(f64),
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let mut transfer = Transfer::start(Direction::Received, "wormhole", Route::Relay);
+    let result = async {
+        let (code, nameplate) = parse_code(&code).ok_or("That isn't a wormhole code, they look like 7-crossword-banana")?;
+        let (mut write, mut read) = StreamExt::split(&mut ws_stream);
+        write.send(Message::Text(json!({"type": "wormhole_claim", "nameplate": nameplate}).to_string())).await?;
+        loop {
+            let Some(msg) = read.next().await else { return Err("Connection closed".into()) };
+            let Message::Text(text) = msg? else { continue };
+            let Ok(data) = serde_json::from_str::<Value>(&text) else { continue };
+            match data.get("type").and_then(|v| v.as_str()) {
+                Some("wormhole_paired") => break,
+                Some("error") => {
+                    let error = data.get("error").and_then(|v| v.as_str()).unwrap_or("unknown error");
+                    return Err(format!("{} (codes only work once)", error).into());
+                }
+                _ => {}
+            }
+        }
+        let mut channel = exchange_pake(&mut write, &mut read, &code, false).await?;
+
+        let metadata = match next_frame(&mut write, &mut read, &mut channel).await? {
+            Frame::Json(metadata) if metadata.get("type").and_then(|v| v.as_str()) == Some("file_metadata") => metadata,
+            _ => return Err("Expected file metadata".into()),
+        };
+        let name = metadata.get("name").and_then(|v| v.as_str()).unwrap_or_default();
+        let size = metadata.get("size").and_then(|v| v.as_u64()).ok_or("No file size")?;
+        fs::create_dir_all(&downloads)?;
+        let mut incoming = IncomingFile::create(&downloads, name, size, Codec::None)?;
+        write.send(Message::Binary(channel.seal_json(&json!({"type": "file_accept"})))).await?;
+        println!("📥 Receiving {} ({} bytes) through the wormhole", incoming.name, size);
+        let throttle = Throttle::new(Direction::Received);
+
+        loop {
+            let frame = match next_frame(&mut write, &mut read, &mut channel).await {
+                Ok(frame) => frame,
+                Err(e) => {
+                    incoming.abort();
+                    return Err(e.into());
+                }
+            };
+            match frame {
+                Frame::Data(chunk) => {
+                    if let Err(e) = incoming.write_chunk(&chunk) {
+                        incoming.abort();
+                        return Err(e.into());
+                    }
+                    on_progress(incoming.progress());
+                    throttle.wait(chunk.len()).await;
+                }
+                Frame::Json(data) if data.get("type").and_then(|v| v.as_str()) == Some("file_end") => {
+                    let name = incoming.name.clone();
+                    let sha256 = data.get("sha256").and_then(|v| v.as_str());
+                    let result = incoming.finish(sha256);
+                    transfer.add_file(&name, size, sha256, result.as_deref().ok());
+                    let mut reply = json!({"type": "file_received", "path": name, "ok": result.is_ok()});
+                    if let Err(e) = &result {
+                        reply["error"] = Value::from(e.as_str());
+                    }
+                    write.send(Message::Binary(channel.seal_json(&reply))).await?;
+                    let path = result?;
+                    println!("✅ {} received through the wormhole", name);
+                    return Ok(path);
+                }
+                Frame::Json(_) => {}
+            }
+        }
+    }.await;
+    transfer.finish_with(&result);
+    result
+}
+
+// Downloads item `id` from our server mailbox into `downloads` and deletes it there,
+// which sends its sender the delivery receipt. `opener_for` gets the item (id, from,
+// header) and returns the Opener for it, or an error if the header doesn't check out.
+pub async fn mailbox_fetch(
+    mut ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    token: String,
+    id: String,
+    opener_for: impl FnOnce(&Value) -> Result<Opener, String>,
+    downloads: PathBuf,
+    mut on_progress: impl FnMut(f64),
+) -> Result<PathBuf, Box<dyn Error>> {
+    let mut transfer = Transfer::start(Direction::Received, "unknown", Route::Mailbox);
+    let result = async {
+        ws_stream.send(Message::Text(json!({"type": "mailbox_fetch", "id": id, "token": token}).to_string())).await?;
+        let item = server_reply(&mut ws_stream, "mailbox_item").await?;
+        transfer.set_peer(item.get("from").and_then(|v| v.as_str()).unwrap_or("unknown"));
+        let mut opener = opener_for(&item)?;
+
+        let mut incoming: Option<IncomingFile> = None;
+        let throttle = Throttle::new(Direction::Received);
+        let received: Result<(), String> = loop {
+            let Some(msg) = ws_stream.next().await else { break Err("Connection closed".to_string()) };
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(e) => break Err(e.to_string()),
+            };
+            match msg {
+                Message::Binary(frame) => match incoming.as_mut() {
+                    None => {
+                        let metadata = match opener.open_json(&frame) {
+                            Ok(metadata) => metadata,
+                            Err(e) => break Err(e),
+                        };
+                        let name = metadata.get("name").and_then(|v| v.as_str()).unwrap_or_default();
+                        let Some(size) = metadata.get("size").and_then(|v| v.as_u64()) else { break Err("No file size".to_string()) };
+                        fs::create_dir_all(&downloads)?;
+                        match IncomingFile::create(&downloads, name, size, Codec::None) {
+                            Ok(file) => {
+                                println!("📥 Fetching {} ({} bytes) from the mailbox", file.name, size);
+                                incoming = Some(file);
+                            }
+                            Err(e) => break Err(e),
+                        }
+                    }
+                    Some(file) => {
+                        if let Err(e) = opener.open(&frame).and_then(|(chunk, _)| file.write_chunk(&chunk)) {
+                            break Err(e);
+                        }
+                        on_progress(file.progress());
+                        throttle.wait(frame.len()).await;
+                    }
+                },
+                Message::Text(text) => {
+                    let Ok(data) = serde_json::from_str::<Value>(&text) else { continue };
+                    match data.get("type").and_then(|v| v.as_str()) {
+                        Some("mailbox_end") if opener.is_done() => break Ok(()),
+                        Some("mailbox_end") => break Err("The mailbox item is cut short".to_string()),
+                        Some("error") => break Err(data.get("error").and_then(|v| v.as_str()).unwrap_or("unknown error").to_string()),
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        };
+        let Some(incoming) = incoming else { return Err(received.err().unwrap_or_else(|| "The mailbox item is empty".to_string()).into()) };
+        if let Err(e) = received {
+            incoming.abort();
+            return Err(e.into());
+        }
+        let (name, size) = (incoming.name.clone(), incoming.size);
+        let path = incoming.finish(None)?;
+        transfer.add_file(&name, size, None, Some(&path));
+
+        ws_stream.send(Message::Text(json!({"type": "mailbox_delete", "id": id, "token": token}).to_string())).await?;
+        server_reply(&mut ws_stream, "mailbox_deleted").await?;
+        let _ = ws_stream.close(None).await;
+        println!("✅ {} fetched from the mailbox", name);
+        Ok(path)
+    }.await;
+    transfer.finish_with(&result);
+    result
+}
+
+// Joins the sender's waiting streams, as many as we can get a connection for
+async fn join_streams<S: Future<Output = Result<WebSocketStream<MaybeTlsStream<TcpStream>>, String>>>(
+    open_stream: &mut impl FnMut() -> S,
+    keys: &[String],
+    token: &str,
+) -> Vec<(String, WebSocketStream<MaybeTlsStream<TcpStream>>)> {
+    let mut joined = Vec::new();
+    for key in keys {
+        let result = match open_stream().await {
+            Ok(mut ws_stream) => {
+                let join = json!({"type": "stream_join", "key": key, "token": token});
+                match ws_stream.send(Message::Text(join.to_string())).await {
+                    Ok(()) => server_reply(&mut ws_stream, "stream_paired").await.map(|_| ws_stream).map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                }
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(ws_stream) => joined.push((key.clone(), ws_stream)),
+            Err(e) => eprintln!("❌ Can't join a parallel stream: {}", e),
+        }
+    }
+    joined
+}
+
+// Takes one range per stream, all at once, into `incoming`
+async fn receive_ranges(
+    streams: Vec<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    incoming: &RefCell<IncomingFile>,
+    throttle: &Throttle,
+    progress: &RefCell<FileProgress>,
+) -> Result<(), String> {
+    let receives = streams.into_iter().map(|ws_stream| receive_range(ws_stream, incoming, throttle, progress));
+    futures_util::future::join_all(receives).await.into_iter().collect()
+}
+
+// A range header, then exactly that many bytes
+async fn receive_range(
+    mut ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    incoming: &RefCell<IncomingFile>,
+    throttle: &Throttle,
+    progress: &RefCell<FileProgress>,
+) -> Result<(), String> {
+    let mut range: Option<(u64, u64)> = None;
+    let mut got = 0;
+    loop {
+        if let Some((_, len)) = range
+            && got == len
+        {
+            return Ok(());
+        }
+        let msg = match timeout(STREAM_TIMEOUT, ws_stream.next()).await {
+            Err(_) => return Err("A parallel stream stalled".to_string()),
+            Ok(None) => return Err("A parallel stream closed early".to_string()),
+            Ok(Some(msg)) => msg.map_err(|e| e.to_string())?,
+        };
+        match msg {
+            Message::Text(text) => {
+                let Ok(data) = serde_json::from_str::<Value>(&text) else { continue };
+                match data.get("type").and_then(|v| v.as_str()) {
+                    Some("range") if range.is_none() => {
+                        let offset = data.get("offset").and_then(|v| v.as_u64()).ok_or("No range offset")?;
+                        let len = data.get("len").and_then(|v| v.as_u64()).ok_or("No range length")?;
+                        range = Some((offset, len));
+                    }
+                    Some("relay_control") => return Err("The sender ended a parallel stream early".to_string()),
+                    _ => {}
+                }
+            }
+            Message::Binary(chunk) => {
+                let Some((offset, len)) = range else { return Err("Range data before the range".to_string()) };
+                if got + chunk.len() as u64 > len {
+                    return Err("Sender sent more than the range".to_string());
+                }
+                let percent = {
+                    let mut incoming = incoming.borrow_mut();
+                    incoming.write_at(offset + got, &chunk)?;
+                    progress.borrow_mut().update(incoming.written);
+                    incoming.progress()
+                };
+                got += chunk.len() as u64;
+                eprint!("\r📥 {:.1}%", percent);
+                throttle.wait(chunk.len()).await;
+            }
+            Message::Close(_) => return Err("A parallel stream closed early".to_string()),
+            _ => {}
+        }
+    }
+}
+
+// One file of a share for `reader`: share_file with its size and hash, then the data.
+// Trouble with the file itself goes to the reader as share_denied, only connection
+// errors are returned.
+async fn serve_shared_file(
+    write: &mut (impl Sink<Message, Error = tokio_tungstenite::tungstenite::Error> + Unpin),
+    reader: &str,
+    share: &str,
+    relative: &str,
+    path: &Path,
+    throttle: &Throttle,
+) -> Result<(), Box<dyn Error>> {
+    let denied = |reason: String| Message::Text(json!({"type": "share_denied", "share": share, "path": relative, "reason": reason}).to_string());
+    let mut transfer = Transfer::start(Direction::Sent, reader, Route::Relay);
+    let (mut file, size, sha256) = match open_shared(path) {
+        Ok(opened) => opened,
+        Err(e) => {
+            transfer.finish(Some(e.to_string()));
+            write.send(denied(e.to_string())).await?;
+            return Ok(());
+        }
+    };
+    transfer.add_file(relative, size, Some(&sha256), Some(path));
+    let header = json!({"type": "share_file", "share": share, "path": relative, "size": size, "sha256": sha256});
+    write.send(Message::Text(header.to_string())).await?;
+
+    let mut buffer = vec![0u8; SHARE_CHUNK_SIZE];
+    let mut left = size;
+    while left > 0 {
+        let n = match read_full(&mut file, &mut buffer[..left.min(SHARE_CHUNK_SIZE as u64) as usize]) {
+            Ok(0) => Err(format!("{} got shorter while sending", relative)),
+            Ok(n) => Ok(n),
+            Err(e) => Err(e.to_string()),
+        };
+        let n = match n {
+            Ok(n) => n,
+            Err(reason) => {
+                transfer.finish(Some(reason.clone()));
+                write.send(denied(reason)).await?;
+                return Ok(());
+            }
+        };
+        write.send(Message::Binary(buffer[..n].to_vec())).await?;
+        left -= n as u64;
+        throttle.wait(n).await;
+    }
+    transfer.finish(None);
+    Ok(())
+}
+
+fn open_shared(path: &Path) -> io::Result<(fs::File, u64, String)> {
+    let file = fs::File::open(path)?;
+    let size = file.metadata()?.len();
+    Ok((file, size, sha256_file(path)?))
+}
+
+// The server's answer of type `kind`, or its error
+async fn server_reply(ws_stream: &mut WebSocketStream<MaybeTlsStream<TcpStream>>, kind: &str) -> Result<Value, Box<dyn Error>> {
+    while let Some(msg) = ws_stream.next().await {
+        let Message::Text(text) = msg? else { continue };
+        let Ok(data) = serde_json::from_str::<Value>(&text) else { continue };
+        match data.get("type").and_then(|v| v.as_str()) {
+            Some(t) if t == kind => return Ok(data),
+            Some("error") => return Err(data.get("error").and_then(|v| v.as_str()).unwrap_or("unknown error").into()),
+            _ => {}
+        }
+    }
+    Err("Connection closed".into())
+}
+
+// Tells the sender whether `path` made it, mirroring only records confirmed files
+fn received(path: &str, error: Option<String>) -> Message {
+    let mut msg = json!({"type": "file_received", "path": path, "ok": error.is_none()});
+    if let Some(error) = error {
+        msg["error"] = Value::from(error);
+    }
+    Message::Text(msg.to_string())
+}
+
+fn end_relay() -> Message {
+    Message::Text(json!({"type": "relay_control", "action": "end"}).to_string())
+}
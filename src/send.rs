@@ -0,0 +1,1215 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio_tungstenite::tungstenite::Message;
+use futures_util::{SinkExt, StreamExt};
+use std::fs::File;
+use tokio::sync::Mutex;
+use std::sync::Arc;
+use tokio_tungstenite::WebSocketStream;
+use tokio::net::TcpStream;
+use tokio_tungstenite::MaybeTlsStream;
+use tokio::time::{timeout, Duration};
+use crate::compression::{accepted_codec, offered_codecs, ChunkEncoder, Codec};
+use crate::delta::{diff, read_full, sha256_file, Op, Signature};
+use crate::mirror::{self, load_state, mirror_id, safe_relative_path, save_state, scan, Change};
+use futures_util::stream::{SplitSink, SplitStream};
+use notify::{RecursiveMode, Watcher};
+use std::path::PathBuf;
+use tokio::sync::{mpsc, watch};
+use std::io::{Seek, SeekFrom};
+use crate::fanout::SharedFile;
+use crate::swarm::{Manifest, Next, Scheduler};
+use tokio::sync::Notify;
+use crate::wormhole::{exchange_pake, make_code, next_frame, Frame};
+use std::cell::RefCell;
+use crate::clipboard::Clip;
+use crate::offline::{sealed_size, Sealer, FRAME_SIZE};
+use crate::history::{Direction, Route, Transfer};
+use crate::ratelimit::Throttle;
+use crate::multistream::{keys_in, ranges, stream_key, MIN_PARALLEL_SIZE};
+use std::cell::Cell;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use crate::delta::to_hex;
+use crate::incoming::IncomingFile;
+use crate::shares::Entry;
+use crate::events::FileProgress;
+
+const CHUNK_SIZE: usize = 64 * 1024; // 64 KB chunks
+const IDENTITY_TIMEOUT: Duration = Duration::from_secs(15);
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(30);
+// Hashing the receiver's older copy for a delta reads all of it
+const SIGNATURE_TIMEOUT: Duration = Duration::from_secs(600);
+const ACK_TIMEOUT: Duration = Duration::from_secs(30);
+// Room posts wait for a person to accept, a bit longer than the receiver's prompt
+const CONSENT_TIMEOUT: Duration = Duration::from_secs(130);
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+// A fan-out recipient that doesn't take a chunk for this long is dropped
+const SEND_TIMEOUT: Duration = Duration::from_secs(60);
+// A swarm source that takes longer than this for one chunk loses it to the others
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(60);
+// How long a wormhole code stays usable
+const WORMHOLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RelayStatusResponse {
+    status: String,
+    target: Option<String>,
+    initiator: Option<String>,
+}
+
+
+// `hello` and `on_identity` run the identity handshake (see identity.rs):
+// `hello` is sent once the session is up, every identity message from the receiver is
+// passed to `on_identity`, which returns the proof to send back or an error to abort.
+// `streams` are extra connections to the signaling server for sending a large file
+// in parallel ranges (see multistream.rs), leave it empty for a single stream.
+pub async fn relay_send(
+    ws_stream: Arc<Mutex<WebSocketStream<MaybeTlsStream<TcpStream>>>>,
+    target: String,
+    token: String,
+    hello: Value,
+    on_identity: impl FnMut(&Value) -> Result<Option<Value>, String>,
+    path: PathBuf,
+    streams: Vec<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut transfer = Transfer::start(Direction::Sent, &target, Route::Relay);
+    let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let mut progress = FileProgress::start(Direction::Sent, &target, &name, fs::metadata(&path).ok().map(|m| m.len()));
+    let result = async {
+        sendable_name(&path)?;
+        let streams = if fs::metadata(&path)?.len() >= MIN_PARALLEL_SIZE {
+            open_streams(streams, &target, &token).await
+        } else {
+            Vec::new()
+        };
+        let mut guard = ws_stream.lock().await;
+        let (mut write, mut read) = StreamExt::split(&mut *guard);
+        start_session(&mut write, &mut read, &target, token, hello, on_identity).await?;
+
+        // File transfer
+        let sha256 = send_file(&mut write, &mut read, &path, None, streams, &mut progress).await?;
+        transfer.add_file(&name, fs::metadata(&path)?.len(), Some(&sha256), Some(&path));
+        // Keep the session open until the receiver checked the hash
+        if !confirmed(&mut read, &name).await? {
+            return Err(format!("{} didn't confirm {}", target, name).into());
+        }
+
+        // Close connection
+        if let Err(e) = write.close().await {
+            eprintln!("❌ Error closing connection: {}", e);
+        }
+        Ok(())
+    }.await;
+    transfer.finish_with(&result);
+    progress.finish_with(&result, None);
+    result
+}
+
+// Sends everything `source` gives until it ends, as `name`. For pipes and other
+// sources of unknown length, see send_stream.
+pub async fn relay_send_stream(
+    ws_stream: Arc<Mutex<WebSocketStream<MaybeTlsStream<TcpStream>>>>,
+    target: String,
+    token: String,
+    hello: Value,
+    on_identity: impl FnMut(&Value) -> Result<Option<Value>, String>,
+    name: &str,
+    source: impl AsyncRead + Unpin,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut transfer = Transfer::start(Direction::Sent, &target, Route::Relay);
+    let mut progress = FileProgress::start(Direction::Sent, &target, name, None);
+    let result = async {
+        let mut guard = ws_stream.lock().await;
+        let (mut write, mut read) = StreamExt::split(&mut *guard);
+        start_session(&mut write, &mut read, &target, token, hello, on_identity).await?;
+
+        let (sha256, sent) = send_stream(&mut write, &mut read, name, source, &mut progress).await?;
+        transfer.add_file(name, sent, Some(&sha256), None);
+        if !confirmed(&mut read, name).await? {
+            return Err(format!("{} didn't confirm {}", target, name).into());
+        }
+        if let Err(e) = write.close().await {
+            eprintln!("❌ Error closing connection: {}", e);
+        }
+        Ok(())
+    }.await;
+    transfer.finish_with(&result);
+    progress.finish_with(&result, None);
+    result
+}
+
+// Keeps the peer's copy of `root` in sync with it until `stop` is set: a full scan
+// first, then a rescan whenever the watcher reports a change. Only what differs from
+// the state the peer confirmed last time is sent, so restarts are cheap.
+pub async fn relay_mirror(
+    ws_stream: Arc<Mutex<WebSocketStream<MaybeTlsStream<TcpStream>>>>,
+    target: String,
+    token: String,
+    hello: Value,
+    on_identity: impl FnMut(&Value) -> Result<Option<Value>, String>,
+    root: PathBuf,
+    mut stop: watch::Receiver<bool>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut guard = ws_stream.lock().await;
+    let (mut write, mut read) = StreamExt::split(&mut *guard);
+    start_session(&mut write, &mut read, &target, token, hello, on_identity).await?;
+
+    let name = root.file_name().and_then(|n| n.to_str()).unwrap_or("mirror").to_string();
+    write.send(Message::Text(json!({"type": "mirror_start", "name": name}).to_string())).await?;
+
+    let id = mirror_id(&root, &target);
+    let mut state = load_state(&id);
+
+    // The watcher only tells us that something changed, every round rescans and diffs
+    let (tx, mut changed) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if event.is_ok() {
+            let _ = tx.send(());
+        }
+    })?;
+    watcher.watch(&root, RecursiveMode::Recursive)?;
+
+    loop {
+        let current = scan(&root, &state)?;
+        let changes = mirror::diff(&state, &current);
+        if !changes.is_empty() {
+            println!("🔁 Mirroring {} change(s) of {} to {}", changes.len(), name, target);
+        }
+
+        for change in changes {
+            match change {
+                Change::Rename { from, to } => {
+                    write.send(Message::Text(json!({"type": "mirror_rename", "from": from, "to": to}).to_string())).await?;
+                    state.remove(&from);
+                    // The peer may not have had the old file after all, then upload it
+                    if confirmed(&mut read, &to).await? {
+                        state.insert(to.clone(), current[&to].clone());
+                    } else if let Some(local) = safe_relative_path(&to).map(|p| root.join(p))
+                        && mirror_file(&mut write, &mut read, &target, &local, &to).await?
+                    {
+                        state.insert(to.clone(), current[&to].clone());
+                    }
+                }
+                Change::Upsert(path) => {
+                    let Some(local) = safe_relative_path(&path).map(|p| root.join(p)) else { continue };
+                    // Gone since the scan, the next round sends the delete
+                    if !local.is_file() {
+                        continue;
+                    }
+                    if mirror_file(&mut write, &mut read, &target, &local, &path).await? {
+                        state.insert(path.clone(), current[&path].clone());
+                    }
+                }
+                Change::Delete(path) => {
+                    write.send(Message::Text(json!({"type": "mirror_delete", "path": path}).to_string())).await?;
+                    state.remove(&path);
+                }
+            }
+        }
+        if let Err(e) = save_state(&id, &state) {
+            eprintln!("❌ Failed to save mirror state: {}", e);
+        }
+
+        tokio::select! {
+            _ = changed.recv() => {}
+            _ = stop.changed() => break,
+            msg = read.next() => match msg {
+                Some(Ok(Message::Text(text))) if text.contains("relay_control") => {
+                    return Err(format!("{} ended the mirror session", target).into());
+                }
+                None | Some(Err(_)) => return Err("Connection closed".into()),
+                _ => continue,
+            },
+        }
+        // Let a burst of events (an editor saving, a checkout) settle before rescanning
+        while let Ok(Some(())) = timeout(SETTLE_TIME, changed.recv()).await {}
+        if *stop.borrow() {
+            break;
+        }
+    }
+
+    println!("⏹️ Stopped mirroring {} to {}", name, target);
+    write.send(Message::Text(json!({"type": "relay_control", "action": "end"}).to_string())).await?;
+    Ok(())
+}
+
+// Sends one file of a mirror, true once the peer confirmed it
+async fn mirror_file(
+    write: &mut WsWrite<'_>,
+    read: &mut WsRead<'_>,
+    target: &str,
+    local: &Path,
+    relative: &str,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut progress = FileProgress::start(Direction::Sent, target, relative, fs::metadata(local).ok().map(|m| m.len()));
+    send_file(write, read, local, Some(relative), Vec::new(), &mut progress).await?;
+    let confirmed = confirmed(read, relative).await?;
+    if confirmed {
+        progress.finish(None);
+    } else {
+        progress.fail(format!("{} didn't confirm {}", target, relative));
+    }
+    Ok(confirmed)
+}
+
+// Sends `clip` to `target`, which only takes it if it opted in to clipboard shares
+pub async fn relay_clipboard(
+    ws_stream: Arc<Mutex<WebSocketStream<MaybeTlsStream<TcpStream>>>>,
+    target: String,
+    token: String,
+    hello: Value,
+    on_identity: impl FnMut(&Value) -> Result<Option<Value>, String>,
+    clip: Clip,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut guard = ws_stream.lock().await;
+    let (mut write, mut read) = StreamExt::split(&mut *guard);
+    start_session(&mut write, &mut read, &target, token, hello, on_identity).await?;
+
+    write.send(Message::Text(clip.metadata().to_string())).await?;
+    let accept = next_of_type(&mut read, "file_accept", ACCEPT_TIMEOUT).await?.ok_or_else(|| format!("{} didn't answer", target))?;
+    let mut encoder = ChunkEncoder::new(accepted_codec(&accept));
+    println!("📋 Sending {} to {}", clip.describe(), target);
+    let throttle = Throttle::new(Direction::Sent);
+    for chunk in clip.bytes().chunks(CHUNK_SIZE) {
+        write.send(Message::Binary(encoder.encode(chunk))).await?;
+        throttle.wait(chunk.len()).await;
+    }
+    write.send(Message::Text(json!({ "type": "file_end", "sha256": clip.sha256() }).to_string())).await?;
+
+    let ok = confirmed(&mut read, "clipboard").await?;
+    write.send(Message::Text(json!({"type": "relay_control", "action": "end"}).to_string())).await?;
+    if !ok {
+        return Err(format!("{} didn't confirm the clipboard", target).into());
+    }
+    if let Err(e) = write.close().await {
+        eprintln!("❌ Error closing connection: {}", e);
+    }
+    Ok(())
+}
+
+pub type IdentityCheck = Box<dyn FnMut(&Value) -> Result<Option<Value>, String>>;
+
+// A peer for fan-out sends and swarm downloads, each on its own signaling connection
+// so a slow peer only backs up its own socket
+pub struct RelayPeer {
+    pub target: String,
+    pub ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    pub hello: Value,
+    pub on_identity: IdentityCheck,
+}
+
+#[derive(Debug, Clone)]
+pub enum RecipientStatus {
+    Connecting,
+    Sending(f64),
+    Done,
+    Failed(String),
+}
+
+// Sends `path` to every recipient at the same time, see fanout.rs. `on_status` gets
+// the recipient's index. With a `room` every recipient is asked before the file
+// comes in. Fails only if every recipient failed.
+pub async fn relay_fanout(
+    recipients: Vec<RelayPeer>,
+    token: String,
+    path: PathBuf,
+    room: Option<String>,
+    on_status: impl FnMut(usize, RecipientStatus),
+) -> Result<(), Box<dyn std::error::Error>> {
+    sendable_name(&path)?;
+    let shared = SharedFile::open(&path, CHUNK_SIZE)?;
+    let on_status = RefCell::new(on_status);
+    let count = recipients.len();
+
+    let sends = recipients.into_iter().enumerate().map(|(index, recipient)| {
+        let shared = shared.clone();
+        let (path, room, token, on_status) = (&path, room.as_deref(), token.clone(), &on_status);
+        async move {
+            let target = recipient.target.clone();
+            let report = |status| (on_status.borrow_mut())(index, status);
+            let mut transfer = Transfer::start(Direction::Sent, &target, Route::Relay);
+            let result = fanout_one(recipient, token, path, room, shared.clone(), &report).await;
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            transfer.add_file(&name, shared.size, shared.sha256().as_deref(), Some(path));
+            transfer.finish_with(&result);
+            match result {
+                Ok(()) => {
+                    report(RecipientStatus::Done);
+                    true
+                }
+                Err(e) => {
+                    eprintln!("❌ Sending to {} failed: {}", target, e);
+                    report(RecipientStatus::Failed(e.to_string()));
+                    false
+                }
+            }
+        }
+    });
+    let delivered = futures_util::future::join_all(sends).await.into_iter().filter(|ok| *ok).count();
+
+    println!("✅ {} sent to {} of {} recipients", path.display(), delivered, count);
+    if delivered == 0 && count > 0 {
+        return Err("The file couldn't be sent to anyone".into());
+    }
+    Ok(())
+}
+
+async fn fanout_one(
+    mut recipient: RelayPeer,
+    token: String,
+    path: &Path,
+    room: Option<&str>,
+    shared: Arc<SharedFile>,
+    report: &impl Fn(RecipientStatus),
+) -> Result<(), Box<dyn std::error::Error>> {
+    report(RecipientStatus::Connecting);
+    let (mut write, mut read) = StreamExt::split(&mut recipient.ws_stream);
+    start_session(&mut write, &mut read, &recipient.target, token, recipient.hello, recipient.on_identity).await?;
+
+    // Everyone gets the same bytes, so no delta: the recipients' older copies differ
+    let file_name = sendable_name(path)?;
+    let mut metadata = json!({
+        "type": "file_metadata",
+        "name": file_name,
+        "size": shared.size,
+        "codecs": offered_codecs(path)
+    });
+    if let Some(room) = room {
+        metadata["room"] = Value::from(room);
+    }
+    write.send(Message::Text(metadata.to_string())).await?;
+    let wait = if room.is_some() { CONSENT_TIMEOUT } else { ACCEPT_TIMEOUT };
+    // Codecs were offered, so without an answer nothing can go out
+    let Some(accept) = next_of_type(&mut read, "file_accept", wait).await? else {
+        write.send(Message::Text(json!({"type": "relay_control", "action": "end"}).to_string())).await?;
+        return Err(format!("{} didn't answer", recipient.target).into());
+    };
+    let mut encoder = ChunkEncoder::new(accepted_codec(&accept));
+
+    let mut chunks = shared.reader();
+    let mut last_percent = -1.0;
+    let throttle = Throttle::new(Direction::Sent);
+    while let Some(chunk) = chunks.next_chunk()? {
+        match timeout(SEND_TIMEOUT, write.send(Message::Binary(encoder.encode(&chunk)))).await {
+            Ok(sent) => sent?,
+            Err(_) => return Err(format!("{} stopped taking data", recipient.target).into()),
+        }
+        let percent = (chunks.position() as f64 / shared.size.max(1) as f64 * 100.0).floor();
+        if percent > last_percent {
+            report(RecipientStatus::Sending(percent));
+            last_percent = percent;
+        }
+        throttle.wait(chunk.len()).await;
+    }
+
+    let sha256 = shared.sha256().ok_or_else(|| format!("{} changed while sending", file_name))?;
+    write.send(Message::Text(json!({ "type": "file_end", "sha256": sha256 }).to_string())).await?;
+    let ok = confirmed(&mut read, &file_name).await?;
+    write.send(Message::Text(json!({"type": "relay_control", "action": "end"}).to_string())).await?;
+    if !ok {
+        return Err(format!("{} didn't confirm {}", recipient.target, file_name).into());
+    }
+    if let Err(e) = write.close().await {
+        eprintln!("❌ Error closing connection: {}", e);
+    }
+    Ok(())
+}
+
+// Sends `path` to whoever types the code `on_code` is given (see wormhole.rs). Needs
+// no account, `ws_stream` is a fresh connection to the signaling server.
+pub async fn wormhole_send(
+    mut ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    path: PathBuf,
+    on_code: impl FnOnce(String),
+    mut on_progress: impl FnMut(f64),
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut transfer = Transfer::start(Direction::Sent, "wormhole", Route::Relay);
+    let result = async {
+        let file_name = sendable_name(&path)?;
+        let (mut write, mut read) = StreamExt::split(&mut ws_stream);
+        write.send(Message::Text(json!({"type": "wormhole_allocate"}).to_string())).await?;
+        let allocated = next_of_type(&mut read, "wormhole_allocated", ACCEPT_TIMEOUT).await?.ok_or("The server didn't hand out a code")?;
+        let nameplate = allocated.get("nameplate").and_then(|v| v.as_u64()).ok_or("No nameplate in the reply")?;
+        let code = make_code(nameplate as u32);
+        println!("🕳️ Wormhole code: {}", code);
+        on_code(code.clone());
+
+        next_of_type(&mut read, "wormhole_paired", WORMHOLE_TIMEOUT).await?.ok_or("Nobody used the code in time")?;
+        let mut channel = exchange_pake(&mut write, &mut read, &code, true).await?;
+
+        let file_size = fs::metadata(&path)?.len();
+        let metadata = json!({"type": "file_metadata", "name": file_name, "size": file_size});
+        write.send(Message::Binary(channel.seal_json(&metadata))).await?;
+        match next_frame(&mut write, &mut read, &mut channel).await? {
+            Frame::Json(reply) if reply.get("type").and_then(|v| v.as_str()) == Some("file_accept") => {}
+            _ => return Err("The receiver didn't accept the file".into()),
+        }
+
+        println!("📤 Sending {} ({} bytes) through the wormhole...", file_name, file_size);
+        let sha256 = sha256_file(&path)?;
+        transfer.add_file(&file_name, file_size, Some(&sha256), Some(&path));
+        let mut file = File::open(&path)?;
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        let mut total_sent: u64 = 0;
+        let throttle = Throttle::new(Direction::Sent);
+        loop {
+            let n = read_full(&mut file, &mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            write.send(Message::Binary(channel.seal_data(&buffer[..n]))).await?;
+            total_sent += n as u64;
+            on_progress(total_sent as f64 / file_size.max(1) as f64 * 100.0);
+            throttle.wait(n).await;
+        }
+        write.send(Message::Binary(channel.seal_json(&json!({"type": "file_end", "sha256": sha256})))).await?;
+
+        let received = match next_frame(&mut write, &mut read, &mut channel).await? {
+            Frame::Json(reply) => reply,
+            Frame::Data(_) => return Err("Unexpected data from the receiver".into()),
+        };
+        let _ = write.send(Message::Text(json!({"type": "relay_control", "action": "end"}).to_string())).await;
+        let _ = write.close().await;
+        if received.get("ok").and_then(|v| v.as_bool()) != Some(true) {
+            let error = received.get("error").and_then(|v| v.as_str()).unwrap_or("unknown error");
+            return Err(format!("The receiver didn't keep {}: {}", file_name, error).into());
+        }
+        println!("✅ {} went through the wormhole", file_name);
+        Ok(())
+    }.await;
+    transfer.finish_with(&result);
+    result
+}
+
+// Leaves `path` on the server for `target`, who is offline (see offline.rs). Every
+// frame is sealed for the recipient, `header` tells it who sealed them and how.
+// Returns the id the server stored it under and when it expires.
+pub async fn mailbox_send(
+    mut ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    target: String,
+    token: String,
+    mut sealer: Sealer,
+    header: Value,
+    path: PathBuf,
+    mut on_progress: impl FnMut(f64),
+) -> Result<(String, u64), Box<dyn std::error::Error>> {
+    let mut transfer = Transfer::start(Direction::Sent, &target, Route::Mailbox);
+    let result = async {
+        let file_name = sendable_name(&path)?;
+        let file_size = fs::metadata(&path)?.len();
+        let metadata = sealer.seal_json(&json!({"name": file_name, "size": file_size}));
+        let total = metadata.len() as u64 + sealed_size(file_size);
+        transfer.add_file(&file_name, file_size, None, Some(&path));
+
+        let (mut write, mut read) = StreamExt::split(&mut ws_stream);
+        let upload = json!({"type": "mailbox_upload", "to": target, "size": total, "header": header, "token": token});
+        write.send(Message::Text(upload.to_string())).await?;
+        server_reply(&mut read, "mailbox_ready").await?;
+        write.send(Message::Binary(metadata)).await?;
+
+        println!("📮 Leaving {} ({} bytes) for {}...", file_name, file_size, target);
+        let mut file = File::open(&path)?;
+        let mut buffer = vec![0u8; FRAME_SIZE];
+        let mut sent: u64 = 0;
+        let throttle = Throttle::new(Direction::Sent);
+        loop {
+            let want = (file_size - sent).min(FRAME_SIZE as u64) as usize;
+            let n = read_full(&mut file, &mut buffer[..want])?;
+            if n < want {
+                return Err(format!("{} changed while sending", file_name).into());
+            }
+            sent += n as u64;
+            let last = sent >= file_size;
+            write.send(Message::Binary(sealer.seal(&buffer[..n], last))).await?;
+            on_progress(sent as f64 / file_size.max(1) as f64 * 100.0);
+            if last {
+                break;
+            }
+            throttle.wait(n).await;
+        }
+        write.send(Message::Text(json!({"type": "mailbox_upload_end"}).to_string())).await?;
+        let stored = server_reply(&mut read, "mailbox_stored").await?;
+        let _ = write.close().await;
+        let id = stored.get("id").and_then(|v| v.as_str()).ok_or("No id in the reply")?.to_string();
+        let expires = stored.get("expires").and_then(|v| v.as_u64()).unwrap_or(0);
+        println!("✅ {} is waiting for {}", file_name, target);
+        Ok((id, expires))
+    }.await;
+    transfer.finish_with(&result);
+    result
+}
+
+// The server's answer of type `kind`, or its error
+async fn server_reply(read: &mut WsRead<'_>, kind: &str) -> Result<Value, Box<dyn std::error::Error>> {
+    loop {
+        let Some(msg) = read.next().await else { return Err("Connection closed".into()) };
+        let Message::Text(text) = msg? else { continue };
+        let Ok(data) = serde_json::from_str::<Value>(&text) else { continue };
+        match data.get("type").and_then(|v| v.as_str()) {
+            Some(t) if t == kind => return Ok(data),
+            Some("error") => return Err(data.get("error").and_then(|v| v.as_str()).unwrap_or("unknown error").into()),
+            _ => {}
+        }
+    }
+}
+
+// The part of a swarm download every source works on
+struct SwarmFile {
+    manifest: Manifest,
+    file: File,
+    part_path: PathBuf,
+    scheduler: Scheduler,
+}
+
+// Downloads the file with content hash `hash` (see swarm.rs) into `downloads`, taking
+// different chunks from every source at once. Sources that don't have the file or
+// send a bad chunk are dropped, their chunks go to the others. `on_progress` gets the
+// percentage and the number of sources still sending.
+pub async fn swarm_download(
+    sources: Vec<RelayPeer>,
+    token: String,
+    hash: String,
+    downloads: PathBuf,
+    on_progress: impl FnMut(f64, usize),
+) -> Result<(PathBuf, Manifest), Box<dyn std::error::Error>> {
+    let peers: Vec<&str> = sources.iter().map(|source| source.target.as_str()).collect();
+    let mut transfer = Transfer::start(Direction::Received, &peers.join(", "), Route::Relay);
+    let swarm: RefCell<Option<SwarmFile>> = RefCell::new(None);
+    let wake = Notify::new();
+    let active = RefCell::new(sources.len());
+    let on_progress = RefCell::new(on_progress);
+    let throttle = Throttle::new(Direction::Received);
+
+    let pulls = sources.into_iter().map(|source| {
+        let (swarm, wake, active, on_progress, hash, downloads, throttle) = (&swarm, &wake, &active, &on_progress, &hash, &downloads, &throttle);
+        let token = token.clone();
+        async move {
+            let target = source.target.clone();
+            let result = swarm_pull(source, token, hash, downloads, swarm, wake, throttle, &|| {
+                let progress = swarm.borrow().as_ref().map_or(0.0, |s| s.scheduler.progress());
+                (on_progress.borrow_mut())(progress, *active.borrow());
+            }).await;
+            *active.borrow_mut() -= 1;
+            wake.notify_waiters();
+            if let Err(e) = result {
+                eprintln!("❌ Swarm source {}: {}", target, e);
+            }
+        }
+    });
+    futures_util::future::join_all(pulls).await;
+
+    let Some(SwarmFile { manifest, file, part_path, scheduler }) = swarm.into_inner() else {
+        let error = "None of the peers could hand out the file";
+        transfer.finish(Some(error.to_string()));
+        return Err(error.into());
+    };
+    if !scheduler.is_complete() {
+        drop(file);
+        let _ = fs::remove_file(&part_path);
+        let error = format!("{}: every source dropped out at {:.0}%", manifest.name, scheduler.progress());
+        transfer.finish(Some(error.clone()));
+        return Err(error.into());
+    }
+    file.sync_all()?;
+    drop(file);
+    let path = part_path.with_file_name(&manifest.name);
+    fs::rename(&part_path, &path)?;
+    transfer.add_file(&manifest.name, manifest.size, None, Some(&path));
+    transfer.finish(None);
+    println!("✅ {} downloaded from the swarm", manifest.name);
+    Ok((path, manifest))
+}
+
+// One source: fetch and check the manifest, then take chunks until none are left
+#[allow(clippy::too_many_arguments)]
+async fn swarm_pull(
+    mut source: RelayPeer,
+    token: String,
+    hash: &str,
+    downloads: &Path,
+    swarm: &RefCell<Option<SwarmFile>>,
+    wake: &Notify,
+    throttle: &Throttle,
+    report: &impl Fn(),
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut write, mut read) = StreamExt::split(&mut source.ws_stream);
+    start_session(&mut write, &mut read, &source.target, token, source.hello, source.on_identity).await?;
+
+    let request = json!({"type": "swarm_manifest_request", "hash": hash});
+    write.send(Message::Text(request.to_string())).await?;
+    let reply = next_of_type(&mut read, "swarm_manifest", ACCEPT_TIMEOUT).await?.ok_or("No manifest")?;
+    let manifest: Manifest = serde_json::from_value(reply.get("manifest").cloned().unwrap_or_default())?;
+    if manifest.content_hash() != hash || !manifest.is_consistent() {
+        return Err("Sent a manifest that doesn't match the hash".into());
+    }
+
+    // The first source with a good manifest sets up the download
+    if swarm.borrow().is_none() {
+        let name = Path::new(&manifest.name)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .filter(|n| !n.is_empty())
+            .ok_or("Refusing the file name in the manifest")?;
+        let part_path = downloads.join(format!("{}.part", name));
+        let file = File::create(&part_path)?;
+        file.set_len(manifest.size)?;
+        let scheduler = Scheduler::new(manifest.chunks.len());
+        let manifest = Manifest { name: name.to_string(), ..manifest };
+        println!("🐝 Downloading {} ({} bytes) from the swarm", manifest.name, manifest.size);
+        *swarm.borrow_mut() = Some(SwarmFile { manifest, file, part_path, scheduler });
+    }
+
+    loop {
+        let next = swarm.borrow_mut().as_mut().map_or(Next::Finished, |s| s.scheduler.take());
+        let index = match next {
+            Next::Chunk(index) => index,
+            Next::Wait => {
+                // Someone else's chunk may still fail and need a new home
+                let _ = timeout(CHUNK_TIMEOUT, wake.notified()).await;
+                continue;
+            }
+            Next::Finished => break,
+        };
+
+        write.send(Message::Text(json!({"type": "swarm_get", "hash": hash, "index": index}).to_string())).await?;
+        let chunk = match timeout(CHUNK_TIMEOUT, next_swarm_chunk(&mut read)).await {
+            Ok(Ok(Some(chunk))) => chunk,
+            outcome => {
+                if let Some(s) = swarm.borrow_mut().as_mut() {
+                    s.scheduler.failed(index);
+                }
+                wake.notify_waiters();
+                return Err(match outcome {
+                    Ok(Err(e)) => e,
+                    Ok(Ok(None)) => format!("doesn't have chunk {}", index).into(),
+                    _ => format!("timed out on chunk {}", index).into(),
+                });
+            }
+        };
+
+        {
+            let mut guard = swarm.borrow_mut();
+            let Some(s) = guard.as_mut() else { break };
+            if !s.manifest.verify(index, &chunk) {
+                s.scheduler.failed(index);
+                drop(guard);
+                wake.notify_waiters();
+                return Err(format!("sent a corrupted chunk {}", index).into());
+            }
+            s.file.seek(SeekFrom::Start(index as u64 * s.manifest.chunk_size as u64))?;
+            s.file.write_all(&chunk)?;
+            s.scheduler.complete(index);
+        }
+        wake.notify_waiters();
+        report();
+        throttle.wait(chunk.len()).await;
+    }
+
+    write.send(Message::Text(json!({"type": "relay_control", "action": "end"}).to_string())).await?;
+    let _ = write.close().await;
+    Ok(())
+}
+
+// The binary answer to a swarm_get, None if the source said it doesn't have the chunk
+async fn next_swarm_chunk(read: &mut WsRead<'_>) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    while let Some(msg) = read.next().await {
+        match msg? {
+            Message::Binary(chunk) => return Ok(Some(chunk)),
+            Message::Text(text) => {
+                let Ok(data) = serde_json::from_str::<Value>(&text) else { continue };
+                match data.get("type").and_then(|v| v.as_str()) {
+                    Some("swarm_missing") => return Ok(None),
+                    Some("relay_control") => return Err("The source ended the session".into()),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    Err("Connection closed".into())
+}
+
+// Lists `path` in `share` on the source's side, or the shares it lets us read when
+// `share` is empty (see shares.rs)
+pub async fn share_browse(
+    mut source: RelayPeer,
+    token: String,
+    share: &str,
+    path: &str,
+) -> Result<Vec<Entry>, Box<dyn std::error::Error>> {
+    let (mut write, mut read) = StreamExt::split(&mut source.ws_stream);
+    start_session(&mut write, &mut read, &source.target, token, source.hello, source.on_identity).await?;
+    let entries = browse_share(&mut write, &mut read, &source.target, share, path, false).await?;
+    write.send(Message::Text(json!({"type": "relay_control", "action": "end"}).to_string())).await?;
+    let _ = write.close().await;
+    Ok(entries)
+}
+
+// Pulls `path` from `share`, a file or everything below a directory, into
+// `downloads`/<share>/ keeping the share's layout. `on_progress` gets the percentage
+// of all the bytes. Files that are done stay if a later one fails.
+pub async fn share_pull(
+    mut source: RelayPeer,
+    token: String,
+    share: &str,
+    path: &str,
+    downloads: &Path,
+    mut on_progress: impl FnMut(f64),
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut transfer = Transfer::start(Direction::Received, &source.target, Route::Relay);
+    let result = async {
+        let (mut write, mut read) = StreamExt::split(&mut source.ws_stream);
+        start_session(&mut write, &mut read, &source.target, token, source.hello, source.on_identity).await?;
+        let files: Vec<Entry> = browse_share(&mut write, &mut read, &source.target, share, path, true).await?
+            .into_iter()
+            .filter(|entry| !entry.dir)
+            .collect();
+        let total: u64 = files.iter().map(|entry| entry.size).sum();
+        println!("📂 Pulling {} files ({} bytes) from {}/{}", files.len(), total, share, path);
+
+        let throttle = Throttle::new(Direction::Received);
+        let mut done = 0;
+        let mut saved = Vec::new();
+        for entry in files {
+            write.send(Message::Text(json!({"type": "share_get", "share": share, "path": entry.path}).to_string())).await?;
+            let header = next_of_type(&mut read, "share_file", CHUNK_TIMEOUT).await?.ok_or("The owner didn't answer")?;
+            let size = header.get("size").and_then(|v| v.as_u64()).ok_or("No size in share_file")?;
+            let sha256 = header.get("sha256").and_then(|v| v.as_str()).map(str::to_string);
+            let mut incoming = IncomingFile::create_at(downloads, &format!("{}/{}", share, entry.path), size, Codec::None)?;
+            while incoming.written < size {
+                let chunk = match timeout(CHUNK_TIMEOUT, next_share_chunk(&mut read)).await {
+                    Ok(Ok(chunk)) => chunk,
+                    outcome => {
+                        incoming.abort();
+                        return Err(match outcome {
+                            Ok(Err(e)) => e,
+                            _ => format!("timed out on {}", entry.path).into(),
+                        });
+                    }
+                };
+                if let Err(e) = incoming.write_chunk(&chunk) {
+                    incoming.abort();
+                    return Err(e.into());
+                }
+                on_progress((done + incoming.written) as f64 / total.max(1) as f64 * 100.0);
+                throttle.wait(chunk.len()).await;
+            }
+            let path = incoming.finish(sha256.as_deref())?;
+            transfer.add_file(&entry.path, size, sha256.as_deref(), Some(&path));
+            done += size;
+            saved.push(path);
+        }
+
+        write.send(Message::Text(json!({"type": "relay_control", "action": "end"}).to_string())).await?;
+        let _ = write.close().await;
+        Ok(saved)
+    }.await;
+    transfer.finish_with(&result);
+    result
+}
+
+async fn browse_share(
+    write: &mut WsWrite<'_>,
+    read: &mut WsRead<'_>,
+    owner: &str,
+    share: &str,
+    path: &str,
+    recursive: bool,
+) -> Result<Vec<Entry>, Box<dyn std::error::Error>> {
+    let request = json!({"type": "share_browse", "share": share, "path": path, "recursive": recursive});
+    write.send(Message::Text(request.to_string())).await?;
+    let listing = next_of_type(read, "share_listing", ACCEPT_TIMEOUT).await?
+        .ok_or_else(|| format!("{} doesn't share folders", owner))?;
+    Ok(serde_json::from_value(listing.get("entries").cloned().unwrap_or_default())?)
+}
+
+// Data for the file being pulled, or why the owner stopped sending it
+async fn next_share_chunk(read: &mut WsRead<'_>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    while let Some(msg) = read.next().await {
+        match msg? {
+            Message::Binary(chunk) => return Ok(chunk),
+            Message::Text(text) => {
+                let Ok(data) = serde_json::from_str::<Value>(&text) else { continue };
+                match data.get("type").and_then(|v| v.as_str()) {
+                    Some("share_denied") => {
+                        return Err(data.get("reason").and_then(|v| v.as_str()).unwrap_or("The owner stopped sending").into());
+                    }
+                    Some("relay_control") => return Err("The owner ended the session".into()),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    Err("Connection closed".into())
+}
+
+type WsWrite<'a> = SplitSink<&'a mut WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
+type WsRead<'a> = SplitStream<&'a mut WebSocketStream<MaybeTlsStream<TcpStream>>>;
+
+// Asks the server for a relay session with `target` and runs the identity handshake
+async fn start_session(
+    write: &mut WsWrite<'_>,
+    read: &mut WsRead<'_>,
+    target: &str,
+    token: String,
+    hello: Value,
+    mut on_identity: impl FnMut(&Value) -> Result<Option<Value>, String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Initiate relay
+    write.send(Message::Text(json!({
+        "type": "initiate_relay",
+        "target": target,
+        "token": token
+    }).to_string())).await?;
+
+    // Wait for relay confirmation
+    while let Some(msg) = read.next().await {
+        if let Ok(Message::Text(text)) = msg {
+            if let Ok(resp) = serde_json::from_str::<RelayStatusResponse>(&text) {
+                if resp.status == "relay_initiated" {
+                    println!("🔁 Relay session started with {}", target);
+                    break;
+                }
+            } else if let Ok(err) = serde_json::from_str::<ErrorResponse>(&text) {
+                return Err(err.error.into());
+            }
+        }
+    }
+
+    // Nothing is sent until the receiver proved it holds the key we pinned for it
+    write.send(Message::Text(hello.to_string())).await?;
+    loop {
+        let msg = match timeout(IDENTITY_TIMEOUT, read.next()).await {
+            Ok(Some(msg)) => msg?,
+            Ok(None) => return Err("Connection closed during the identity check".into()),
+            Err(_) => {
+                write.send(Message::Text(json!({"type": "relay_control", "action": "end"}).to_string())).await?;
+                return Err(format!("{} didn't prove its identity", target).into());
+            }
+        };
+        let Message::Text(text) = msg else { continue };
+        let Ok(data) = serde_json::from_str::<Value>(&text) else { continue };
+        match data.get("type").and_then(|v| v.as_str()) {
+            Some("identity") => match on_identity(&data) {
+                Ok(Some(proof)) => {
+                    write.send(Message::Text(proof.to_string())).await?;
+                    break;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    write.send(Message::Text(json!({"type": "relay_control", "action": "end"}).to_string())).await?;
+                    return Err(e.into());
+                }
+            },
+            Some("relay_control") => return Err(format!("{} ended the session", target).into()),
+            Some("error") => {
+                let error = data.get("error").and_then(|v| v.as_str()).unwrap_or("unknown error");
+                return Err(error.to_string().into());
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+// Next message of type `kind`, skipping anything else. None if nothing came in time.
+async fn next_of_type(read: &mut WsRead<'_>, kind: &str, wait: Duration) -> Result<Option<Value>, Box<dyn std::error::Error>> {
+    loop {
+        let msg = match timeout(wait, read.next()).await {
+            Err(_) => return Ok(None),
+            Ok(None) => return Err("Connection closed".into()),
+            Ok(Some(msg)) => msg?,
+        };
+        let Message::Text(text) = msg else { continue };
+        let Ok(data) = serde_json::from_str::<Value>(&text) else { continue };
+        match data.get("type").and_then(|v| v.as_str()) {
+            Some(t) if t == kind => return Ok(Some(data)),
+            Some("relay_control") => return Err("The receiver ended the session".into()),
+            Some("file_decline") => {
+                let reason = data.get("reason").and_then(|v| v.as_str()).unwrap_or("The receiver declined the file");
+                return Err(reason.into());
+            }
+            Some("share_denied") => {
+                let reason = data.get("reason").and_then(|v| v.as_str()).unwrap_or("Not shared with you");
+                return Err(reason.into());
+            }
+            _ => {}
+        }
+    }
+}
+
+// Waits for the receiver's file_received for `path`
+async fn confirmed(read: &mut WsRead<'_>, path: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    while let Some(ack) = next_of_type(read, "file_received", ACK_TIMEOUT).await? {
+        if ack.get("path").and_then(|v| v.as_str()) == Some(path) {
+            if let Some(error) = ack.get("error").and_then(|v| v.as_str()) {
+                eprintln!("❌ {}: {}", path, error);
+            }
+            return Ok(ack.get("ok").and_then(|v| v.as_bool()) == Some(true));
+        }
+    }
+    Ok(false)
+}
+
+// The name `path` goes out under. Directories and paths like ".." have none, so they
+// are refused before a session starts.
+fn sendable_name(path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    let name = path.file_name().filter(|_| fs::metadata(path).is_ok_and(|m| m.is_file()));
+    match name {
+        Some(name) => Ok(name.to_string_lossy().to_string()),
+        None if !path.exists() => Err(format!("{} doesn't exist", path.display()).into()),
+        None => Err(format!("{} is not a file", path.display()).into()),
+    }
+}
+
+// Streams one file: metadata, then literal chunks and delta_copy instructions, then
+// file_end with the hash, which it returns. `relative` is the path inside a mirrored folder.
+// If the receiver joins any of the waiting `streams`, the data goes over those instead.
+async fn send_file(
+    write: &mut WsWrite<'_>,
+    read: &mut WsRead<'_>,
+    path: &Path,
+    relative: Option<&str>,
+    streams: Vec<(String, WebSocketStream<MaybeTlsStream<TcpStream>>)>,
+    progress: &mut FileProgress,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let file_name = sendable_name(path)?;
+    let file_size = fs::metadata(path)?.len();
+
+    // Send metadata
+    let mut metadata = json!({
+        "type": "file_metadata",
+        "name": file_name,
+        "size": file_size,
+        "codecs": offered_codecs(path),
+        "delta": true
+    });
+    if let Some(relative) = relative {
+        metadata["path"] = Value::from(relative);
+    }
+    if !streams.is_empty() {
+        metadata["streams"] = streams.iter().map(|(key, _)| Value::from(key.as_str())).collect();
+    }
+    write.send(Message::Text(metadata.to_string())).await?;
+
+    // The receiver picks the codec, and sends block signatures next if it has an older
+    // copy. Without an answer nothing goes out: the receiver may have taken the codec
+    // or the delta we offered, frames without them would corrupt the file.
+    let accept = next_of_type(read, "file_accept", ACCEPT_TIMEOUT).await?.ok_or_else(|| format!("{} wasn't accepted in time", file_name))?;
+
+    // Streams the receiver didn't join are dropped, which closes them
+    let joined = keys_in(&accept);
+    let streams: Vec<_> = streams.into_iter().filter(|(key, _)| joined.contains(key)).collect();
+    if !streams.is_empty() {
+        println!("📤 Sending {} ({} bytes) over {} streams...", file_name, file_size, streams.len());
+        let sha256 = sha256_file(path)?;
+        send_ranges(path, file_size, streams, progress).await?;
+        println!("\n✅ File sent successfully!");
+        write.send(Message::Text(json!({ "type": "file_end", "sha256": sha256 }).to_string())).await?;
+        return Ok(sha256);
+    }
+    let codec = accepted_codec(&accept);
+    let mut signature: Option<Signature> = None;
+    if accept.get("delta_follows").and_then(|v| v.as_bool()) == Some(true) {
+        let reply = next_of_type(read, "delta_signature", SIGNATURE_TIMEOUT).await?.ok_or("No delta signature from the receiver")?;
+        // Null when the older copy couldn't be read after all
+        signature = match reply.get("signature") {
+            Some(Value::Null) | None => None,
+            Some(value) => Some(serde_json::from_value(value.clone())?),
+        };
+    }
+    let mut encoder = ChunkEncoder::new(codec);
+
+    // Only the parts the receiver doesn't have yet go out as literal data
+    let ops = match &signature {
+        Some(signature) => diff(path, signature)?,
+        None => vec![Op::Literal { offset: 0, len: file_size }],
+    };
+    let literal_bytes: u64 = ops.iter().map(|op| match op { Op::Literal { len, .. } => *len, _ => 0 }).sum();
+    println!("📤 Sending {} ({} bytes, {} as literal data, compression: {})...", file_name, file_size, literal_bytes, codec.name());
+
+    let sha256 = sha256_file(path)?;
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut total_sent: u64 = 0;
+    let throttle = Throttle::new(Direction::Sent);
+    let block_size = signature.as_ref().map_or(0, |s| s.block_size as u64);
+
+    for op in ops {
+        match op {
+            Op::Copy { block, count } => {
+                write.send(Message::Text(json!({"type": "delta_copy", "block": block, "count": count}).to_string())).await?;
+                total_sent = (total_sent + block_size * count as u64).min(file_size);
+            }
+            Op::Literal { offset, len } => {
+                file.seek(SeekFrom::Start(offset))?;
+                let mut left = len;
+                while left > 0 {
+                    let n = read_full(&mut file, &mut buffer[..left.min(CHUNK_SIZE as u64) as usize])?;
+                    if n == 0 { return Err(format!("{} changed while sending", file_name).into()) }
+                    write.send(Message::Binary(encoder.encode(&buffer[..n]))).await?;
+                    left -= n as u64;
+                    total_sent += n as u64;
+                    progress.update(total_sent);
+                    throttle.wait(n).await;
+                }
+            }
+        }
+
+        // Progress reporting
+        progress.update(total_sent);
+        print!("\r🚀 Progress: {:.1}%", (total_sent as f64 / file_size.max(1) as f64) * 100.0);
+        io::stdout().flush()?;
+    }
+
+    // Finalize transfer, the receiver checks the hash before keeping the file
+    println!("\n✅ File sent successfully!");
+    write.send(Message::Text(json!({ "type": "file_end", "sha256": sha256 }).to_string())).await?;
+    Ok(sha256)
+}
+
+// Streams `source` until it ends. file_metadata has "stream": true and no size, the
+// receiver takes whatever comes until file_end, whose hash is the only thing that says
+// it got all of it. Returns the hash and how many bytes went out.
+async fn send_stream(
+    write: &mut WsWrite<'_>,
+    read: &mut WsRead<'_>,
+    name: &str,
+    mut source: impl AsyncRead + Unpin,
+    progress: &mut FileProgress,
+) -> Result<(String, u64), Box<dyn std::error::Error>> {
+    // Nothing to sniff before it starts, so both codecs are offered
+    let metadata = json!({
+        "type": "file_metadata",
+        "name": name,
+        "stream": true,
+        "codecs": [Codec::Zstd.name(), Codec::Lz4.name()]
+    });
+    write.send(Message::Text(metadata.to_string())).await?;
+    let accept = next_of_type(read, "file_accept", ACCEPT_TIMEOUT).await?
+        .ok_or("The receiver doesn't take streams")?;
+    let codec = accepted_codec(&accept);
+    println!("📤 Sending {} as a stream (compression: {})...", name, codec.name());
+
+    let mut encoder = ChunkEncoder::new(codec);
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut total_sent: u64 = 0;
+    let throttle = Throttle::new(Direction::Sent);
+    loop {
+        let n = source.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        write.send(Message::Binary(encoder.encode(&buffer[..n]))).await?;
+        total_sent += n as u64;
+        progress.update(total_sent);
+        print!("\r🚀 Sent {} bytes", total_sent);
+        io::stdout().flush()?;
+        throttle.wait(n).await;
+    }
+
+    let sha256 = to_hex(&hasher.finalize());
+    println!("\n✅ Stream sent successfully!");
+    write.send(Message::Text(json!({ "type": "file_end", "sha256": sha256 }).to_string())).await?;
+    Ok((sha256, total_sent))
+}
+
+// Puts every extra connection on the server as a stream waiting for `target`. The ones
+// the server turns down are dropped, the file then goes over fewer streams.
+async fn open_streams(
+    conns: Vec<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    target: &str,
+    token: &str,
+) -> Vec<(String, WebSocketStream<MaybeTlsStream<TcpStream>>)> {
+    let mut open = Vec::new();
+    for mut ws_stream in conns {
+        let key = stream_key();
+        let request = json!({"type": "stream_open", "key": key, "to": target, "token": token});
+        let waiting = {
+            let (mut write, mut read) = StreamExt::split(&mut ws_stream);
+            match write.send(Message::Text(request.to_string())).await {
+                Ok(()) => server_reply(&mut read, "stream_waiting").await.map(|_| ()),
+                Err(e) => Err(e.into()),
+            }
+        };
+        match waiting {
+            Ok(()) => open.push((key, ws_stream)),
+            Err(e) => eprintln!("❌ Can't open a parallel stream: {}", e),
+        }
+    }
+    open
+}
+
+// Sends `path` split into one range per stream, all at once
+async fn send_ranges(
+    path: &Path,
+    file_size: u64,
+    streams: Vec<(String, WebSocketStream<MaybeTlsStream<TcpStream>>)>,
+    progress: &mut FileProgress,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let throttle = Throttle::new(Direction::Sent);
+    let sent = Cell::new(0u64);
+    let progress = RefCell::new(progress);
+    let ranges = ranges(file_size, streams.len());
+    let sends = streams.into_iter().zip(ranges).map(|((_, ws_stream), range)| {
+        let (throttle, sent, progress) = (&throttle, &sent, &progress);
+        async move {
+            send_range(ws_stream, path, range, throttle, &|n| {
+                sent.set(sent.get() + n);
+                progress.borrow_mut().update(sent.get());
+                print!("\r🚀 Progress: {:.1}%", sent.get() as f64 / file_size.max(1) as f64 * 100.0);
+                let _ = io::stdout().flush();
+            }).await
+        }
+    });
+    futures_util::future::join_all(sends).await.into_iter().collect::<Result<Vec<()>, _>>()?;
+    Ok(())
+}
+
+// One range over one stream, once the receiver has joined it. `on_sent` gets the
+// number of bytes of every chunk.
+async fn send_range(
+    mut ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    path: &Path,
+    (offset, len): (u64, u64),
+    throttle: &Throttle,
+    on_sent: &impl Fn(u64),
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut write, mut read) = StreamExt::split(&mut ws_stream);
+    next_of_type(&mut read, "stream_paired", ACCEPT_TIMEOUT).await?.ok_or("The receiver didn't join a stream")?;
+    write.send(Message::Text(json!({"type": "range", "offset": offset, "len": len}).to_string())).await?;
+
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut left = len;
+    while left > 0 {
+        let n = read_full(&mut file, &mut buffer[..left.min(CHUNK_SIZE as u64) as usize])?;
+        if n == 0 {
+            return Err(format!("{} changed while sending", path.display()).into());
+        }
+        match timeout(SEND_TIMEOUT, write.send(Message::Binary(buffer[..n].to_vec()))).await {
+            Ok(sent) => sent?,
+            Err(_) => return Err("The receiver stopped taking data on a stream".into()),
+        }
+        left -= n as u64;
+        on_sent(n as u64);
+        throttle.wait(n).await;
+    }
+    write.send(Message::Text(json!({"type": "relay_control", "action": "end"}).to_string())).await?;
+    let _ = write.close().await;
+    Ok(())
+}
@@ -0,0 +1,25 @@
+// Session tokens issued by the signaling server at register, stored per username
+// so the client can reconnect without sending the password again.
+use crate::store::{load_json, save_json};
+use std::collections::HashMap;
+
+const SESSION_FILE: &str = "session.json";
+
+pub fn load_token(username: &str) -> Option<String> {
+    load_json::<HashMap<String, String>>(SESSION_FILE).remove(username)
+}
+
+pub fn save_token(username: &str, token: &str) {
+    let mut tokens: HashMap<String, String> = load_json(SESSION_FILE);
+    tokens.insert(username.to_string(), token.to_string());
+    if let Err(e) = save_json(SESSION_FILE, &tokens) {
+        eprintln!("❌ Failed to save session token: {}", e);
+    }
+}
+
+pub fn forget_token(username: &str) {
+    let mut tokens: HashMap<String, String> = load_json(SESSION_FILE);
+    if tokens.remove(username).is_some() {
+        let _ = save_json(SESSION_FILE, &tokens);
+    }
+}
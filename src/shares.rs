@@ -0,0 +1,130 @@
+// Folders this user shares with contacts, read-only. A contact opens a relay session
+// to us and, once the identity check passed, browses and pulls; our receiving side
+// answers on its own:
+//   share_browse {share, path, recursive}   ->  share_listing {share, path, entries}
+//   share_get {share, path}                 ->  share_file {share, path, size, sha256}
+//                                               followed by the data as binary chunks
+// or share_denied {share, path, reason} when the share isn't theirs to read or the
+// path is no good. Browsing the empty share name lists the shares they may read.
+// Nothing a contact sends can change anything here.
+use crate::mirror::{relative_name, safe_relative_path};
+use crate::store::{load_json, save_json};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+const SHARES_FILE: &str = "shares.json";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Share {
+    pub name: String,
+    pub root: PathBuf,
+    // Usernames that may browse and pull
+    #[serde(default)]
+    pub readers: Vec<String>,
+}
+
+impl Share {
+    pub fn allows(&self, username: &str) -> bool {
+        self.readers.iter().any(|reader| reader == username)
+    }
+}
+
+// A file or directory in a listing
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Entry {
+    // Relative to the share, '/' separated
+    pub path: String,
+    pub size: u64,
+    // Seconds since the epoch
+    pub modified: u64,
+    pub dir: bool,
+}
+
+impl Entry {
+    // The last part of the path
+    pub fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or_default()
+    }
+}
+
+pub fn shares() -> Vec<Share> {
+    load_json(SHARES_FILE)
+}
+
+pub fn set_shares(shares: &[Share]) -> io::Result<()> {
+    save_json(SHARES_FILE, &shares)
+}
+
+// The shares `username` may read, as directory entries
+pub fn readable_by(username: &str) -> Vec<Entry> {
+    shares()
+        .into_iter()
+        .filter(|share| share.allows(username))
+        .map(|share| Entry { path: share.name, size: 0, modified: 0, dir: true })
+        .collect()
+}
+
+// The share `name` if `username` may read it. A share that doesn't exist and one that
+// isn't theirs get the same answer, so nobody learns what else is shared.
+pub fn open_share(name: &str, username: &str) -> Result<Share, String> {
+    shares()
+        .into_iter()
+        .find(|share| share.name == name && share.allows(username))
+        .ok_or_else(|| format!("No share called {} for {}", name, username))
+}
+
+// `relative` inside `share`, the share itself when empty. Refuses anything that ends
+// up outside the shared folder, through ".." or a symlink.
+pub fn resolve(share: &Share, relative: &str) -> Result<PathBuf, String> {
+    let root = share.root.canonicalize().map_err(|e| format!("{} is gone: {}", share.name, e))?;
+    if relative.is_empty() {
+        return Ok(root);
+    }
+    let bad = || format!("No {} in {}", relative, share.name);
+    let path = root.join(safe_relative_path(relative).ok_or_else(bad)?).canonicalize().map_err(|_| bad())?;
+    if !path.starts_with(&root) {
+        return Err(bad());
+    }
+    Ok(path)
+}
+
+// What is at `relative`: a directory's contents, or with `recursive` every file below
+// it. A file lists just itself. Symlinks are left out, like in mirrors.
+pub fn list(share: &Share, relative: &str, recursive: bool) -> Result<Vec<Entry>, String> {
+    let path = resolve(share, relative)?;
+    let root = share.root.canonicalize().map_err(|e| e.to_string())?;
+    let metadata = fs::metadata(&path).map_err(|e| e.to_string())?;
+    if !metadata.is_dir() {
+        return Ok(vec![entry(relative.to_string(), &metadata)]);
+    }
+
+    let mut entries = Vec::new();
+    let mut dirs = vec![path];
+    while let Some(dir) = dirs.pop() {
+        for item in fs::read_dir(&dir).map_err(|e| e.to_string())? {
+            let Ok(item) = item else { continue };
+            let Ok(file_type) = item.file_type() else { continue };
+            let (Some(path), Ok(metadata)) = (relative_name(&root, &item.path()), item.metadata()) else { continue };
+            if file_type.is_dir() {
+                if recursive {
+                    dirs.push(item.path());
+                } else {
+                    entries.push(entry(path, &metadata));
+                }
+            } else if file_type.is_file() {
+                entries.push(entry(path, &metadata));
+            }
+        }
+    }
+    // Directories first, then by name
+    entries.sort_by(|a, b| b.dir.cmp(&a.dir).then_with(|| a.path.cmp(&b.path)));
+    Ok(entries)
+}
+
+fn entry(path: String, metadata: &fs::Metadata) -> Entry {
+    let modified = metadata.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs());
+    Entry { path, size: if metadata.is_dir() { 0 } else { metadata.len() }, modified, dir: metadata.is_dir() }
+}
@@ -0,0 +1,164 @@
+// Talking to the signaling server from the client side: registering, listing peers and
+// requests that wait for one answer. Connections come from tls.rs, so they go wherever
+// SignalingConfig says. Also finds our public addresses with STUN for register.
+use serde_json::{json, Value};
+use std::net::{ToSocketAddrs, UdpSocket, SocketAddr};
+use tokio_tungstenite::tungstenite::Message;
+use futures_util::{SinkExt, StreamExt};
+use stunclient::StunClient;
+use tokio::net::TcpStream;
+use std::error::Error;
+use tokio::sync::Mutex;
+use std::sync::Arc;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::MaybeTlsStream;
+
+
+pub fn get_pip_port_json(username:&str, password:&str) -> serde_json::Value {
+    get_pip_port_json_and_sockets(username, password).0
+}
+
+// STUN server asked for the public address, P2P_STUN_SERVER overrides it
+const DEFAULT_STUN_SERVER: &str = "stun.l.google.com:19302";
+// Local ports the STUN queries go out from, and so the ports registered for peers
+const STUN_PORT_V4: u16 = 42069;
+const STUN_PORT_V6: u16 = 42070;
+
+// Same as get_pip_port_json, but also hands back the sockets that were used for the
+// STUN queries (with the STUN server each one asked), so the NAT mappings that were
+// registered can be kept alive.
+pub fn get_pip_port_json_and_sockets(username:&str, password:&str) -> (serde_json::Value, Vec<(UdpSocket, SocketAddr)>) {
+    let stun_server = std::env::var("P2P_STUN_SERVER").unwrap_or_else(|_| DEFAULT_STUN_SERVER.to_string());
+    get_pip_port_json_and_sockets_with(&stun_server, STUN_PORT_V4, STUN_PORT_V6, username, password)
+}
+
+// get_pip_port_json_and_sockets with the STUN server and the local ports given, port 0
+// takes any free one
+pub fn get_pip_port_json_and_sockets_with(stun_server: &str, port_v4: u16, port_v6: u16, username:&str, password:&str) -> (serde_json::Value, Vec<(UdpSocket, SocketAddr)>) {
+    let mut ipv4_ip = None;let mut ipv4_port = None;let mut ipv6_ip = None;let mut ipv6_port = None;
+    let mut sockets = Vec::new();
+    // Nothing is found when the name doesn't resolve, e.g. without a network
+    let stun_addrs: Vec<SocketAddr> = stun_server.to_socket_addrs().map(Iterator::collect).unwrap_or_default();
+
+    if let Some(&stun_ipv4) = stun_addrs.iter().find(|a| a.is_ipv4())
+        && let Ok(socket_v4) = UdpSocket::bind(("0.0.0.0", port_v4))
+    {
+        let client_v4 = StunClient::new(stun_ipv4);
+        if let Ok(addr) = client_v4.query_external_address(&socket_v4) {
+            ipv4_ip = Some(addr.ip().to_string());
+            ipv4_port = Some(addr.port());
+            sockets.push((socket_v4, stun_ipv4));
+        }
+    }
+
+    if let Some(&stun_ipv6) = stun_addrs.iter().find(|a| a.is_ipv6())
+        && let Ok(socket_v6) = UdpSocket::bind(("::", port_v6))
+    {
+        let client_v6 = StunClient::new(stun_ipv6);
+        if let Ok(addr) = client_v6.query_external_address(&socket_v6) {
+            ipv6_ip = Some(addr.ip().to_string());
+            ipv6_port = Some(addr.port());
+            sockets.push((socket_v6, stun_ipv6));
+        }
+    }
+    let json = json!({"type" : "register","username":username,"password": password,"ipv4_ip": ipv4_ip,"ipv4_port": ipv4_port, "ipv6_ip": ipv6_ip,"ipv6_port": ipv6_port});
+    (json, sockets)
+}
+
+
+pub async fn send_json_value(json_value: &Value, ws_stream:Arc<Mutex<WebSocketStream<MaybeTlsStream<TcpStream>>>>) -> tokio::io::Result<()>{
+    let json_str = serde_json::to_string(json_value)?;
+    let mut guard = ws_stream.lock().await;
+    let (mut write, _) = StreamExt::split(&mut *guard);
+    write.send(Message::Text(json_str)).await.unwrap();
+    Ok(())
+}
+
+pub async fn get_clients(ws_stream:Arc<Mutex<WebSocketStream<MaybeTlsStream<TcpStream>>>>) -> Result<String, Box<dyn Error>> {
+    let mut ws_stream = ws_stream.lock().await;
+
+    let json_val = json!({"type":"request_peer"});
+    let json_str = serde_json::to_string(&json_val).unwrap();
+    ws_stream.send(Message::Text(json_str)).await?;
+
+    // Skip anything the server pushed in the meantime (e.g. the reply to a keep-alive re-register)
+    while let Some(msg) = ws_stream.next().await {
+        let text = match msg? {
+            Message::Text(text) => text,
+            Message::Binary(bin) => String::from_utf8(bin)?,
+            _ => return Err("Unexpected message type".into()),
+        };
+        if serde_json::from_str::<Value>(&text).is_ok_and(|v| v.is_array()) {
+            return Ok(text);
+        }
+    }
+    Err("No response from server".into())
+}
+
+// Full user list with addresses and identity keys, keyed by username
+pub async fn get_users(ws_stream:Arc<Mutex<WebSocketStream<MaybeTlsStream<TcpStream>>>>) -> Result<Value, String> {
+    let mut ws_stream = ws_stream.lock().await;
+    ws_stream.send(Message::Text(json!({"type":"get_users"}).to_string())).await.map_err(|e| e.to_string())?;
+
+    while let Some(msg) = ws_stream.next().await {
+        let Message::Text(text) = msg.map_err(|e| e.to_string())? else { continue };
+        let Ok(reply) = serde_json::from_str::<Value>(&text) else { continue };
+        if let Some(error) = reply.get("error").and_then(|v| v.as_str()) {
+            return Err(error.to_string());
+        }
+        // Server messages all have a type string, the user list is a plain object
+        if reply.is_object() && !reply.get("type").is_some_and(|t| t.is_string()) {
+            return Ok(reply);
+        }
+    }
+    Err("Connection closed".to_string())
+}
+
+// Sends a request (rooms, announce, who_has, ...) and waits for the server's
+// `reply_type` answer, or its error message
+pub async fn server_request(payload: Value, reply_type: &str, ws_stream:Arc<Mutex<WebSocketStream<MaybeTlsStream<TcpStream>>>>) -> Result<Value, String> {
+    let mut ws_stream = ws_stream.lock().await;
+    ws_stream.send(Message::Text(payload.to_string())).await.map_err(|e| e.to_string())?;
+
+    while let Some(msg) = ws_stream.next().await {
+        let Message::Text(text) = msg.map_err(|e| e.to_string())? else { continue };
+        let Ok(reply) = serde_json::from_str::<Value>(&text) else { continue };
+        if let Some(error) = reply.get("error").and_then(|v| v.as_str()) {
+            return Err(error.to_string());
+        }
+        if reply.get("type").and_then(|v| v.as_str()) == Some(reply_type) {
+            return Ok(reply);
+        }
+    }
+    Err("Connection closed".to_string())
+}
+
+// Sends a register payload and waits for the server's answer. On success returns the
+// session token, on failure the server's error message.
+pub async fn register(payload: &Value, ws_stream:Arc<Mutex<WebSocketStream<MaybeTlsStream<TcpStream>>>>) -> Result<String, String> {
+    let mut ws_stream = ws_stream.lock().await;
+    ws_stream.send(Message::Text(payload.to_string())).await.map_err(|e| e.to_string())?;
+
+    while let Some(msg) = ws_stream.next().await {
+        let Message::Text(text) = msg.map_err(|e| e.to_string())? else { continue };
+        let Ok(reply) = serde_json::from_str::<Value>(&text) else { continue };
+        if let Some(error) = reply.get("error").and_then(|v| v.as_str()) {
+            return Err(error.to_string());
+        }
+        if reply.get("type").and_then(|v| v.as_str()) == Some("registered") {
+            return reply.get("token").and_then(|v| v.as_str()).map(|t| t.to_string()).ok_or("No session token in reply".to_string());
+        }
+    }
+    Err("Connection closed".to_string())
+}
+
+
+pub fn keys_from_json_str(json_str: String) -> Vec<String>{
+    let _json: Value = serde_json::from_str(json_str.as_str()).unwrap();
+    let vec: Vec<String> = _json.as_array()
+        .expect("Expected a JSON array")
+        .iter()
+        .map(|v| v.as_str().unwrap().to_string())
+        .collect();
+    vec
+}
\ No newline at end of file
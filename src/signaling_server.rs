@@ -0,0 +1,896 @@
+// Rust port of the Python signaling server (a.py). Keeps track of registered peers,
+// pairs two sockets into a relay session on initiate_relay and forwards everything
+// else (file chunks, file_metadata, ...) between the paired sockets.
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures_util::{SinkExt, StreamExt};
+use crate::swarm::is_content_hash;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tokio::sync::{mpsc, Mutex};
+use tokio_tungstenite::accept_async_with_config;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Message;
+use crate::accounts::{hash_password, verify_password, Accounts};
+use crate::mailbox::{Mailbox, Upload};
+use crate::protocol::{ClientMessage, PeerInfo, RoomInfo, RoomMember, ServerMessage, UserList};
+
+type ConnId = u64;
+type Tx = mpsc::Sender<Message>;
+
+// Outgoing messages are queued per connection, a full queue slows down whoever
+// is relaying into it instead of buffering whole files in memory.
+const OUTGOING_QUEUE: usize = 64;
+const MAX_ROOM_NAME: usize = 64;
+const MAX_STREAM_KEY: usize = 64;
+const MAX_CHAT_LENGTH: usize = 4096;
+// Store-and-forward defaults, P2P_MAILBOX_QUOTA_MB=0 turns the mailbox off
+pub const MAILBOX_QUOTA_MB: u64 = 512;
+pub const MAILBOX_DAYS: u64 = 7;
+
+struct Peer {
+    info: PeerInfo,
+    conn: ConnId,
+}
+
+struct Connection {
+    tx: Tx,
+    username: Option<String>,
+    // Set on a user's chat connection
+    chat_user: Option<String>,
+}
+
+struct ServerState {
+    accounts: Accounts,
+    peers: HashMap<String, Peer>,
+    conns: HashMap<ConnId, Connection>,
+    relay_sessions: HashMap<ConnId, ConnId>,
+    // Room name -> usernames, a room goes away when its last member leaves
+    rooms: BTreeMap<String, BTreeSet<String>>,
+    // Content hash -> users that announced it, forgotten when they disconnect
+    seeds: HashMap<String, BTreeSet<String>>,
+    // Wormhole nameplate -> the sender waiting on it
+    nameplates: HashMap<u32, ConnId>,
+    // Parallel stream key -> the sender's connection waiting on it and who may join
+    streams: HashMap<String, (ConnId, String)>,
+    // Username -> chat connection
+    chat_conns: HashMap<String, ConnId>,
+    // Connections that get told when peers come, go or change
+    presence_conns: BTreeSet<ConnId>,
+    // None when store-and-forward is turned off
+    mailbox: Option<Mailbox>,
+    // Mailbox uploads in progress, by the connection sending them
+    uploads: HashMap<ConnId, Upload>,
+    next_conn: ConnId,
+}
+
+type SharedState = Arc<Mutex<ServerState>>;
+
+impl ServerState {
+    fn new(accounts: Accounts, mailbox: Option<Mailbox>) -> ServerState {
+        ServerState {
+            accounts,
+            peers: HashMap::new(),
+            conns: HashMap::new(),
+            relay_sessions: HashMap::new(),
+            rooms: BTreeMap::new(),
+            seeds: HashMap::new(),
+            nameplates: HashMap::new(),
+            streams: HashMap::new(),
+            chat_conns: HashMap::new(),
+            presence_conns: BTreeSet::new(),
+            mailbox,
+            uploads: HashMap::new(),
+            next_conn: 1,
+        }
+    }
+
+    fn add_connection(&mut self, tx: Tx) -> ConnId {
+        let id = self.next_conn;
+        self.next_conn += 1;
+        self.conns.insert(id, Connection { tx, username: None, chat_user: None });
+        id
+    }
+
+    fn tx(&self, conn: ConnId) -> Option<Tx> {
+        self.conns.get(&conn).map(|c| c.tx.clone())
+    }
+
+    // Ends the relay session `conn` is part of, returns the other side
+    fn end_relay(&mut self, conn: ConnId) -> Option<ConnId> {
+        let peer = self.relay_sessions.remove(&conn)?;
+        if self.relay_sessions.get(&peer) == Some(&conn) {
+            self.relay_sessions.remove(&peer);
+        }
+        Some(peer)
+    }
+
+    // Who is chatting on `conn` and where chat for `to` goes
+    fn chat_route(&self, conn: ConnId, to: &str) -> (Option<String>, Option<Tx>) {
+        let from = self.conns.get(&conn).and_then(|c| c.chat_user.clone());
+        let to = self.chat_conns.get(to).and_then(|chat| self.tx(*chat));
+        (from, to)
+    }
+
+    // Smallest free number, so codes stay short
+    fn allocate_nameplate(&mut self, conn: ConnId) -> u32 {
+        self.nameplates.retain(|_, owner| *owner != conn);
+        let nameplate = (1..).find(|n| !self.nameplates.contains_key(n)).expect("fewer than u32::MAX nameplates");
+        self.nameplates.insert(nameplate, conn);
+        nameplate
+    }
+
+    // Queued while the state is locked, so subscribers see the events in the order the
+    // peers changed. A subscriber too far behind to take one more message misses it.
+    fn tell_presence(&self, msg: ServerMessage) {
+        let text = msg.to_json();
+        for tx in self.presence_conns.iter().filter_map(|conn| self.tx(*conn)) {
+            let _ = tx.try_send(Message::Text(text.clone()));
+        }
+    }
+
+    // Forgets a closed connection, returns the relay peer that has to be told
+    fn remove_connection(&mut self, conn: ConnId) -> Option<Tx> {
+        self.nameplates.retain(|_, owner| *owner != conn);
+        self.streams.retain(|_, (owner, _)| *owner != conn);
+        self.chat_conns.retain(|_, chat| *chat != conn);
+        self.presence_conns.remove(&conn);
+        if let (Some(upload), Some(mailbox)) = (self.uploads.remove(&conn), self.mailbox.as_ref()) {
+            mailbox.abort_upload(upload);
+        }
+        if let Some(connection) = self.conns.remove(&conn)
+            && let Some(username) = connection.username
+            && self.peers.get(&username).is_some_and(|p| p.conn == conn)
+        {
+            self.peers.remove(&username);
+            self.seeds.retain(|_, holders| {
+                holders.remove(&username);
+                !holders.is_empty()
+            });
+            println!("👋 {} disconnected", username);
+            self.tell_presence(ServerMessage::PeerOffline { username });
+        }
+        let peer = self.end_relay(conn)?;
+        self.tx(peer)
+    }
+
+    fn authorize(&self, token: Option<&str>) -> Option<String> {
+        self.accounts.verify_token(token?)
+    }
+
+    // Writes a binary message to conn's mailbox upload. None if it isn't uploading.
+    fn upload_frame(&mut self, conn: ConnId, frame: &[u8]) -> Option<Result<(), String>> {
+        let mut upload = self.uploads.remove(&conn)?;
+        let mailbox = self.mailbox.as_ref()?;
+        match mailbox.write_frame(&mut upload, frame) {
+            Ok(()) => {
+                self.uploads.insert(conn, upload);
+                Some(Ok(()))
+            }
+            Err(e) => {
+                mailbox.abort_upload(upload);
+                Some(Err(e))
+            }
+        }
+    }
+
+    // Upload, list and delete. Who is asking comes from the session token.
+    fn mailbox_request(&mut self, conn: ConnId, token: Option<&str>, msg: ClientMessage) -> ServerMessage {
+        let username = match msg {
+            ClientMessage::MailboxUploadEnd => None,
+            _ => match self.authorize(token) {
+                Some(username) => Some(username),
+                None => return ServerMessage::error("Authentication required"),
+            },
+        };
+        let Some(mailbox) = self.mailbox.as_mut() else {
+            return ServerMessage::error("This server doesn't keep files for offline users");
+        };
+
+        match (msg, username) {
+            (ClientMessage::MailboxUpload { to, size, header, .. }, Some(from)) => {
+                if self.accounts.password_hash(&to).is_none() {
+                    return ServerMessage::error("No such user");
+                }
+                if let Some(previous) = self.uploads.remove(&conn) {
+                    mailbox.abort_upload(previous);
+                }
+                match mailbox.start_upload(&from, &to, size, header) {
+                    Ok(upload) => {
+                        let id = upload.id().to_string();
+                        self.uploads.insert(conn, upload);
+                        ServerMessage::MailboxReady { id }
+                    }
+                    Err(e) => ServerMessage::error(&e),
+                }
+            }
+            (ClientMessage::MailboxUploadEnd, _) => {
+                let Some(upload) = self.uploads.remove(&conn) else {
+                    return ServerMessage::error("No upload in progress");
+                };
+                let to = upload.to().to_string();
+                match mailbox.finish_upload(upload) {
+                    Ok((id, expires)) => {
+                        println!("📬 Stored an upload for {}", to);
+                        ServerMessage::MailboxStored { id, to, expires }
+                    }
+                    Err(e) => ServerMessage::error(&e),
+                }
+            }
+            (ClientMessage::MailboxKey { to, .. }, Some(_)) => {
+                let identity_key = mailbox.key(&to);
+                ServerMessage::MailboxKey { to, identity_key }
+            }
+            (ClientMessage::MailboxDelete { id, .. }, Some(username)) => match mailbox.delete(&id, &username) {
+                Ok(()) => ServerMessage::MailboxDeleted { id },
+                Err(e) => ServerMessage::error(&e),
+            },
+            (_, Some(username)) => {
+                let items = mailbox.pending(&username);
+                let receipts = mailbox.take_receipts(&username);
+                ServerMessage::Mailbox { items, receipts }
+            }
+            (_, None) => ServerMessage::error("Authentication required"),
+        }
+    }
+
+    fn room_info(&self, room: &str) -> Option<RoomInfo> {
+        let members = self.rooms.get(room)?;
+        Some(RoomInfo {
+            room: room.to_string(),
+            members: members
+                .iter()
+                .map(|username| RoomMember { username: username.clone(), online: self.peers.contains_key(username) })
+                .collect(),
+        })
+    }
+
+    // Create, join, leave and list. Who is asking comes from the session token.
+    fn room_request(&mut self, token: Option<&str>, msg: ClientMessage) -> ServerMessage {
+        let Some(username) = self.authorize(token) else {
+            return ServerMessage::error("Authentication required");
+        };
+
+        let room = match msg {
+            ClientMessage::CreateRoom { room, .. } => {
+                if !is_room_name(&room) {
+                    return ServerMessage::error("Invalid room name");
+                }
+                if self.rooms.contains_key(&room) {
+                    return ServerMessage::error("Room already exists");
+                }
+                println!("🏠 {} created {}", username, room);
+                self.rooms.insert(room.clone(), BTreeSet::from([username]));
+                room
+            }
+            ClientMessage::JoinRoom { room, .. } => {
+                let Some(members) = self.rooms.get_mut(&room) else {
+                    return ServerMessage::error("Room not found");
+                };
+                members.insert(username);
+                room
+            }
+            ClientMessage::LeaveRoom { room, .. } => {
+                let Some(members) = self.rooms.get_mut(&room) else {
+                    return ServerMessage::error("Room not found");
+                };
+                members.remove(&username);
+                if members.is_empty() {
+                    self.rooms.remove(&room);
+                }
+                return ServerMessage::RoomLeft { room };
+            }
+            _ => {
+                let rooms = self.rooms.keys().filter_map(|room| self.room_info(room)).collect();
+                return ServerMessage::Rooms { rooms };
+            }
+        };
+        match self.room_info(&room) {
+            Some(info) => ServerMessage::Room(info),
+            None => ServerMessage::error("Room not found"),
+        }
+    }
+}
+
+// wss:// is served when P2P_TLS_CERT (PEM chain) and P2P_TLS_KEY (PEM key) are set
+pub fn tls_acceptor() -> Result<Option<TlsAcceptor>, String> {
+    let (Ok(cert_path), Ok(key_path)) = (std::env::var("P2P_TLS_CERT"), std::env::var("P2P_TLS_KEY")) else {
+        return Ok(None);
+    };
+
+    let cert_file = File::open(&cert_path).map_err(|e| format!("{}: {}", cert_path, e))?;
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+        .map_err(|e| format!("{}: {}", cert_path, e))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    let key_file = File::open(&key_path).map_err(|e| format!("{}: {}", key_path, e))?;
+    let key = rustls_pemfile::read_all(&mut BufReader::new(key_file))
+        .map_err(|e| format!("{}: {}", key_path, e))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key) => {
+                Some(PrivateKey(key))
+            }
+            _ => None,
+        })
+        .ok_or_else(|| format!("{}: no private key found", key_path))?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| e.to_string())?;
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+pub async fn run_server(listener: TcpListener, accounts: Accounts, mailbox: Option<Mailbox>, tls: Option<TlsAcceptor>) {
+    let state = Arc::new(Mutex::new(ServerState::new(accounts, mailbox)));
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                eprintln!("❌ Accept failed: {}", e);
+                continue;
+            }
+        };
+        let state = state.clone();
+        match tls.clone() {
+            Some(acceptor) => {
+                tokio::spawn(async move {
+                    match acceptor.accept(stream).await {
+                        Ok(tls_stream) => handle_connection(tls_stream, state).await,
+                        Err(e) => eprintln!("❌ TLS handshake failed: {}", e),
+                    }
+                });
+            }
+            None => {
+                tokio::spawn(handle_connection(stream, state));
+            }
+        }
+    }
+}
+
+async fn handle_connection<S>(stream: S, state: SharedState)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // Whole files go through here, so no message size limit (like max_size=None in a.py)
+    let config = WebSocketConfig { max_message_size: None, max_frame_size: None, ..Default::default() };
+    let ws_stream = match accept_async_with_config(stream, Some(config)).await {
+        Ok(ws) => ws,
+        Err(e) => {
+            eprintln!("❌ WebSocket handshake failed: {}", e);
+            return;
+        }
+    };
+    let (mut write, mut read) = ws_stream.split();
+
+    let (tx, mut rx) = mpsc::channel::<Message>(OUTGOING_QUEUE);
+    let conn = state.lock().await.add_connection(tx.clone());
+    let writer = tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            if write.send(msg).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    while let Some(msg) = read.next().await {
+        match msg {
+            Ok(Message::Text(text)) => handle_text(conn, text, &tx, &state).await,
+            Ok(Message::Binary(data)) => relay_binary(conn, data, &tx, &state).await,
+            Ok(Message::Close(_)) | Err(_) => break,
+            _ => {}
+        }
+    }
+
+    let peer_tx = state.lock().await.remove_connection(conn);
+    if let Some(peer_tx) = peer_tx {
+        let _ = peer_tx.send(end_message()).await;
+    }
+    writer.abort();
+}
+
+// Forwards a message to the other side of conn's relay session
+async fn relay(conn: ConnId, msg: Message, state: &SharedState) -> bool {
+    let peer_tx = {
+        let state = state.lock().await;
+        state.relay_sessions.get(&conn).and_then(|peer| state.tx(*peer))
+    };
+    match peer_tx {
+        Some(peer_tx) => peer_tx.send(msg).await.is_ok(),
+        None => false,
+    }
+}
+
+// Relay binary message if in a relay session, or store it if it's part of a mailbox upload
+async fn relay_binary(conn: ConnId, data: Vec<u8>, tx: &Tx, state: &SharedState) {
+    let uploaded = state.lock().await.upload_frame(conn, &data);
+    match uploaded {
+        Some(Ok(())) => return,
+        Some(Err(e)) => {
+            reply(tx, ServerMessage::error(&e)).await;
+            return;
+        }
+        None => {}
+    }
+    if !relay(conn, Message::Binary(data), state).await {
+        println!("⚠️ Received binary message but not in relay");
+    }
+}
+
+async fn reply(tx: &Tx, msg: ServerMessage) {
+    let _ = tx.send(Message::Text(msg.to_json())).await;
+}
+
+fn end_message() -> Message {
+    Message::Text(ServerMessage::RelayControl { action: "end".to_string() }.to_json())
+}
+
+async fn handle_text(conn: ConnId, text: String, tx: &Tx, state: &SharedState) {
+    let value: Value = match serde_json::from_str(&text) {
+        Ok(value) => value,
+        Err(_) => {
+            reply(tx, ServerMessage::error("Invalid JSON")).await;
+            return;
+        }
+    };
+
+    match serde_json::from_value::<ClientMessage>(value) {
+        Ok(msg) => handle_message(conn, msg, tx, state).await,
+        Err(_) => {
+            // Relay all other JSON messages if in a session
+            if !relay(conn, Message::Text(text), state).await {
+                reply(tx, ServerMessage::error("Unknown or invalid request type")).await;
+            }
+        }
+    }
+}
+
+async fn handle_message(conn: ConnId, msg: ClientMessage, tx: &Tx, state: &SharedState) {
+    match msg {
+        ClientMessage::Register { username, password, token, ipv4_ip, ipv4_port, ipv6_ip, ipv6_port, identity_key } => {
+            if identity_key.as_deref().is_some_and(|key| !is_identity_key(key)) {
+                reply(tx, ServerMessage::error("Invalid identity key")).await;
+                return;
+            }
+            if let Err(error) = authenticate(&username, password, token, state).await {
+                reply(tx, ServerMessage::error(error)).await;
+                return;
+            }
+
+            let token = {
+                let mut state = state.lock().await;
+                if let (Some(mailbox), Some(key)) = (state.mailbox.as_mut(), identity_key.as_deref()) {
+                    mailbox.remember_key(&username, key);
+                }
+                let info = PeerInfo { username: username.clone(), ipv4_ip, ipv4_port, ipv6_ip, ipv6_port, identity_key };
+                // Keep-alives re-register every few seconds, only changes are news
+                match state.peers.get(&username) {
+                    None => state.tell_presence(ServerMessage::PeerOnline(info.clone())),
+                    Some(peer) if peer.info != info => state.tell_presence(ServerMessage::PeerUpdated(info.clone())),
+                    Some(_) => {}
+                }
+                state.peers.insert(username.clone(), Peer { info, conn });
+                if let Some(connection) = state.conns.get_mut(&conn) {
+                    connection.username = Some(username.clone());
+                }
+                state.accounts.issue_token(&username)
+            };
+            println!("✅ Registered: {}", username);
+            let notice = state.lock().await.mailbox.as_ref().map(|mailbox| (mailbox.pending(&username).len(), mailbox.receipts_waiting(&username)));
+            reply(tx, ServerMessage::Registered { status: "registered".to_string(), username, token }).await;
+            if let Some((items, receipts)) = notice.filter(|(items, receipts)| items + receipts > 0) {
+                reply(tx, ServerMessage::MailboxNotice { items, receipts }).await;
+            }
+        }
+
+        ClientMessage::UpdateAddress { token, ipv4_ip, ipv4_port, ipv6_ip, ipv6_port } => {
+            let response = {
+                let mut state = state.lock().await;
+                let username = state.authorize(token.as_deref());
+                match username.and_then(|username| state.peers.get(&username).map(|peer| peer.info.clone())) {
+                    None => ServerMessage::error("Not registered"),
+                    Some(info) => {
+                        let updated = PeerInfo { ipv4_ip, ipv4_port, ipv6_ip, ipv6_port, ..info.clone() };
+                        if updated != info {
+                            state.tell_presence(ServerMessage::PeerUpdated(updated.clone()));
+                        }
+                        if let Some(peer) = state.peers.get_mut(&updated.username) {
+                            peer.info = updated;
+                        }
+                        ServerMessage::AddressUpdated
+                    }
+                }
+            };
+            reply(tx, response).await;
+        }
+
+        ClientMessage::RequestPeer => {
+            let mut usernames: Vec<String> = state.lock().await.peers.keys().cloned().collect();
+            usernames.sort();
+            let _ = tx.send(Message::Text(serde_json::to_string(&usernames).unwrap())).await;
+        }
+
+        ClientMessage::GetUsers => {
+            let users: UserList = state
+                .lock()
+                .await
+                .peers
+                .iter()
+                .map(|(name, peer)| (name.clone(), peer.info.clone()))
+                .collect();
+            let _ = tx.send(Message::Text(serde_json::to_string(&users).unwrap())).await;
+        }
+
+        ClientMessage::PresenceSubscribe => {
+            // The snapshot goes out before the lock is let go, so no event can overtake it
+            let mut state = state.lock().await;
+            state.presence_conns.insert(conn);
+            let mut peers: Vec<PeerInfo> = state.peers.values().map(|peer| peer.info.clone()).collect();
+            peers.sort_by(|a, b| a.username.cmp(&b.username));
+            let _ = tx.try_send(Message::Text(ServerMessage::Presence { peers }.to_json()));
+        }
+
+        ClientMessage::InitiateRelay { target, token } => {
+            let mut state = state.lock().await;
+            let Some(initiator) = state.authorize(token.as_deref()) else {
+                drop(state);
+                reply(tx, ServerMessage::error("Authentication required")).await;
+                return;
+            };
+            let Some(target_conn) = state.peers.get(&target).map(|p| p.conn) else {
+                drop(state);
+                reply(tx, ServerMessage::error("Target user not found")).await;
+                return;
+            };
+            let Some(target_tx) = state.tx(target_conn) else { return };
+
+            // Register the session (both directions)
+            state.relay_sessions.insert(conn, target_conn);
+            state.relay_sessions.insert(target_conn, conn);
+            drop(state);
+
+            let response = ServerMessage::RelayInitiated {
+                status: "relay_initiated".to_string(),
+                target: target.clone(),
+                initiator: initiator.clone(),
+            };
+            reply(tx, response.clone()).await; // Tell sender
+            reply(&target_tx, response).await; // Tell receiver
+            println!("🔄 Relay started between {} and {}", initiator, target);
+        }
+
+        ClientMessage::RelayReceive { username, token } => {
+            let mut state = state.lock().await;
+            if state.authorize(token.as_deref()).as_deref() != Some(username.as_str()) {
+                drop(state);
+                reply(tx, ServerMessage::error("Authentication required")).await;
+                return;
+            }
+            match state.peers.get_mut(&username) {
+                Some(peer) => peer.conn = conn,
+                None => {
+                    drop(state);
+                    reply(tx, ServerMessage::error("username not found")).await;
+                    return;
+                }
+            }
+            if let Some(connection) = state.conns.get_mut(&conn) {
+                connection.username = Some(username);
+            }
+        }
+
+        ClientMessage::RelayControl { action } => {
+            if action != "end" {
+                reply(tx, ServerMessage::error("Unknown relay action")).await;
+                return;
+            }
+            let peer_tx = {
+                let mut state = state.lock().await;
+                state.end_relay(conn).and_then(|peer| state.tx(peer))
+            };
+            if let Some(peer_tx) = peer_tx {
+                let _ = peer_tx.send(end_message()).await;
+                println!("❌ Relay session ended.");
+            }
+        }
+
+        ClientMessage::CreateRoom { ref token, .. }
+        | ClientMessage::JoinRoom { ref token, .. }
+        | ClientMessage::LeaveRoom { ref token, .. }
+        | ClientMessage::ListRooms { ref token } => {
+            let token = token.clone();
+            let response = state.lock().await.room_request(token.as_deref(), msg);
+            reply(tx, response).await;
+        }
+
+        ClientMessage::Announce { hash, token } => {
+            let response = {
+                let mut state = state.lock().await;
+                match state.authorize(token.as_deref()) {
+                    None => ServerMessage::error("Authentication required"),
+                    Some(_) if !is_content_hash(&hash) => ServerMessage::error("Invalid content hash"),
+                    Some(username) => {
+                        state.seeds.entry(hash.clone()).or_default().insert(username);
+                        ServerMessage::Announced { hash }
+                    }
+                }
+            };
+            reply(tx, response).await;
+        }
+
+        ClientMessage::WhoHas { hash, token } => {
+            let response = {
+                let state = state.lock().await;
+                match state.authorize(token.as_deref()) {
+                    None => ServerMessage::error("Authentication required"),
+                    Some(username) => {
+                        let peers = state
+                            .seeds
+                            .get(&hash)
+                            .into_iter()
+                            .flatten()
+                            .filter(|holder| **holder != username && state.peers.contains_key(*holder))
+                            .cloned()
+                            .collect();
+                        ServerMessage::Holders { hash, peers }
+                    }
+                }
+            };
+            reply(tx, response).await;
+        }
+
+        ClientMessage::WormholeAllocate => {
+            let nameplate = state.lock().await.allocate_nameplate(conn);
+            reply(tx, ServerMessage::WormholeAllocated { nameplate }).await;
+        }
+
+        ClientMessage::WormholeClaim { nameplate } => {
+            // One claim per nameplate: whoever guesses wrong burns the code
+            let sender_tx = {
+                let mut state = state.lock().await;
+                match state.nameplates.remove(&nameplate) {
+                    Some(sender) if sender != conn => {
+                        state.relay_sessions.insert(conn, sender);
+                        state.relay_sessions.insert(sender, conn);
+                        state.tx(sender)
+                    }
+                    _ => None,
+                }
+            };
+            match sender_tx {
+                Some(sender_tx) => {
+                    reply(&sender_tx, ServerMessage::WormholePaired).await;
+                    reply(tx, ServerMessage::WormholePaired).await;
+                    println!("🕳️ Wormhole {} paired", nameplate);
+                }
+                None => reply(tx, ServerMessage::error("No such code")).await,
+            }
+        }
+
+        ClientMessage::StreamOpen { key, to, token } => {
+            let response = {
+                let mut state = state.lock().await;
+                match state.authorize(token.as_deref()) {
+                    None => ServerMessage::error("Authentication required"),
+                    Some(_) if key.is_empty() || key.len() > MAX_STREAM_KEY => ServerMessage::error("Invalid stream key"),
+                    Some(_) if state.streams.contains_key(&key) => ServerMessage::error("Stream key in use"),
+                    Some(_) => {
+                        state.streams.insert(key.clone(), (conn, to));
+                        ServerMessage::StreamWaiting { key }
+                    }
+                }
+            };
+            reply(tx, response).await;
+        }
+
+        ClientMessage::StreamJoin { key, token } => {
+            // Only the user the sender named can join, and only once
+            let sender_tx = {
+                let mut state = state.lock().await;
+                let user = state.authorize(token.as_deref());
+                match state.streams.get(&key) {
+                    Some((sender, to)) if user.as_ref() == Some(to) && *sender != conn => {
+                        let sender = *sender;
+                        state.streams.remove(&key);
+                        state.relay_sessions.insert(conn, sender);
+                        state.relay_sessions.insert(sender, conn);
+                        state.tx(sender)
+                    }
+                    _ => None,
+                }
+            };
+            match sender_tx {
+                Some(sender_tx) => {
+                    reply(&sender_tx, ServerMessage::StreamPaired { key: key.clone() }).await;
+                    reply(tx, ServerMessage::StreamPaired { key }).await;
+                }
+                None => reply(tx, ServerMessage::error("No such stream")).await,
+            }
+        }
+
+        ClientMessage::ChatListen { token } => {
+            let response = {
+                let mut state = state.lock().await;
+                match state.authorize(token.as_deref()) {
+                    Some(username) => {
+                        if let Some(connection) = state.conns.get_mut(&conn) {
+                            connection.chat_user = Some(username.clone());
+                        }
+                        state.chat_conns.insert(username, conn);
+                        ServerMessage::ChatListening
+                    }
+                    None => ServerMessage::error("Authentication required"),
+                }
+            };
+            reply(tx, response).await;
+        }
+
+        ClientMessage::ChatMessage { to, id, text, timestamp } => {
+            let (from, to_tx) = state.lock().await.chat_route(conn, &to);
+            let error = match (from, to_tx) {
+                (None, _) => "Send chat_listen first",
+                (_, None) => "Not online",
+                (Some(_), Some(_)) if text.chars().count() > MAX_CHAT_LENGTH => "Message too long",
+                (Some(from), Some(to_tx)) => {
+                    reply(&to_tx, ServerMessage::ChatMessage { from, id, text, timestamp }).await;
+                    return;
+                }
+            };
+            reply(tx, ServerMessage::ChatFailed { id, error: error.to_string() }).await;
+        }
+
+        ClientMessage::ChatAck { to, id } => {
+            if let (Some(from), Some(to_tx)) = state.lock().await.chat_route(conn, &to) {
+                reply(&to_tx, ServerMessage::ChatAck { from, id }).await;
+            }
+        }
+
+        ClientMessage::MailboxUpload { ref token, .. }
+        | ClientMessage::MailboxList { ref token }
+        | ClientMessage::MailboxKey { ref token, .. }
+        | ClientMessage::MailboxDelete { ref token, .. } => {
+            let token = token.clone();
+            let response = state.lock().await.mailbox_request(conn, token.as_deref(), msg);
+            reply(tx, response).await;
+        }
+
+        ClientMessage::MailboxUploadEnd => {
+            let response = state.lock().await.mailbox_request(conn, None, msg);
+            reply(tx, response).await;
+        }
+
+        ClientMessage::MailboxFetch { id, token } => {
+            let item = {
+                let state = state.lock().await;
+                match (state.authorize(token.as_deref()), state.mailbox.as_ref()) {
+                    (None, _) => Err("Authentication required".to_string()),
+                    (_, None) => Err("This server doesn't keep files for offline users".to_string()),
+                    (Some(username), Some(mailbox)) => mailbox.item(&id, &username),
+                }
+            };
+            let (entry, path) = match item {
+                Ok(item) => item,
+                Err(e) => {
+                    reply(tx, ServerMessage::error(&e)).await;
+                    return;
+                }
+            };
+            reply(tx, ServerMessage::MailboxItem(entry)).await;
+            // The outgoing queue is bounded, so this reads no faster than the client takes it
+            if let Err(e) = send_frames(&path, tx).await {
+                reply(tx, ServerMessage::error(&format!("Failed to read the upload: {}", e))).await;
+                return;
+            }
+            reply(tx, ServerMessage::MailboxEnd { id: id.clone() }).await;
+            if let Some(mailbox) = state.lock().await.mailbox.as_mut() {
+                mailbox.mark_fetched(&id);
+            }
+        }
+
+        ClientMessage::PeerInformation { target, token } => {
+            let response = {
+                let state = state.lock().await;
+                if state.authorize(token.as_deref()).is_none() {
+                    ServerMessage::error("Authentication required")
+                } else {
+                    match state.peers.get(&target) {
+                        Some(peer) => ServerMessage::PeerInfo(peer.info.clone()),
+                        None => ServerMessage::error("User not found"),
+                    }
+                }
+            };
+            reply(tx, response).await;
+        }
+    }
+}
+
+// Sends the frames of a stored upload, each as one binary message
+async fn send_frames(path: &std::path::Path, tx: &Tx) -> std::io::Result<()> {
+    let mut file = tokio::io::BufReader::new(tokio::fs::File::open(path).await?);
+    let mut len = [0u8; 4];
+    loop {
+        match file.read_exact(&mut len).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let len = u32::from_le_bytes(len) as usize;
+        if len > crate::mailbox::MAX_FRAME {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "corrupt frame length"));
+        }
+        let mut frame = vec![0u8; len];
+        file.read_exact(&mut frame).await?;
+        if tx.send(Message::Binary(frame)).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+// Something like "#build-artifacts", no whitespace so it reads well in logs and lists
+fn is_room_name(room: &str) -> bool {
+    !room.is_empty() && room.len() <= MAX_ROOM_NAME && !room.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+// Clients verify keys themselves, this only keeps obvious garbage out of get_users
+fn is_identity_key(key: &str) -> bool {
+    STANDARD.decode(key).is_ok_and(|bytes| bytes.len() == 32)
+}
+
+// Checks the register credentials. A token must have been issued for this username;
+// a password either matches the stored hash or claims a username nobody owns yet.
+async fn authenticate(
+    username: &str,
+    password: Option<String>,
+    token: Option<String>,
+    state: &SharedState,
+) -> Result<(), &'static str> {
+    if username.trim().is_empty() {
+        return Err("Username required");
+    }
+
+    let stored_hash = {
+        let state = state.lock().await;
+        if let Some(token) = token.as_deref()
+            && state.accounts.verify_token(token).as_deref() == Some(username)
+        {
+            return Ok(());
+        }
+        state.accounts.password_hash(username)
+    };
+
+    let Some(password) = password.filter(|p| !p.is_empty()) else {
+        return Err("Password or valid token required");
+    };
+
+    match stored_hash {
+        Some(hash) => {
+            let valid = tokio::task::spawn_blocking(move || verify_password(&password, &hash))
+                .await
+                .unwrap_or(false);
+            if valid { Ok(()) } else { Err("Invalid username or password") }
+        }
+        None => {
+            let hash = tokio::task::spawn_blocking(move || hash_password(&password))
+                .await
+                .ok()
+                .flatten()
+                .ok_or("Failed to hash password")?;
+            match state.lock().await.accounts.create(username, hash) {
+                Ok(true) => Ok(()),
+                Ok(false) => Err("Username already taken"),
+                Err(_) => Err("Failed to save account"),
+            }
+        }
+    }
+}
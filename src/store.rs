@@ -0,0 +1,40 @@
+// Small JSON files kept in the per-user data directory (~/.local/share/p2p_rust on
+// Linux, %APPDATA%\p2p_rust on Windows).
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+static DATA_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+pub fn data_dir() -> PathBuf {
+    match DATA_DIR.get() {
+        Some(dir) => dir.clone(),
+        None => dirs::data_dir().unwrap_or_else(|| PathBuf::from(".")).join("p2p_rust"),
+    }
+}
+
+// Keeps everything in `dir` instead, for programs and tests that shouldn't touch the
+// user's files. Only the first call counts, false if it was too late.
+pub fn set_data_dir(dir: PathBuf) -> bool {
+    DATA_DIR.set(dir).is_ok()
+}
+
+// Missing or unreadable files give the default value
+pub fn load_json<T: DeserializeOwned + Default>(name: &str) -> T {
+    fs::read_to_string(data_dir().join(name))
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+pub fn save_json<T: Serialize>(name: &str, value: &T) -> io::Result<()> {
+    let dir = data_dir();
+    fs::create_dir_all(&dir)?;
+    // Write then rename so a crash never leaves a half written file behind
+    let tmp = dir.join(format!("{}.tmp", name));
+    fs::write(&tmp, serde_json::to_string_pretty(value)?)?;
+    fs::rename(tmp, dir.join(name))
+}
@@ -0,0 +1,169 @@
+// Content-addressed swarm downloads. A file is described by a manifest: its size and
+// the SHA-256 of every CHUNK_SIZE chunk. The content hash is the SHA-256 of the
+// manifest, so the manifest a peer hands out can be checked against the hash that was
+// asked for, and every chunk against the manifest before it is written.
+//
+// Peers that have a file announce its hash to the server (announce {hash}), a
+// downloader asks who_has {hash} and opens a relay session to each holder:
+//   swarm_manifest_request {hash}      -> swarm_manifest {hash, manifest}
+//   swarm_get {hash, index}            -> binary chunk, or swarm_missing {hash, index}
+use crate::delta::{read_full, to_hex};
+use crate::store::{load_json, save_json};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+const SEEDS_FILE: &str = "seeds.json";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Manifest {
+    pub name: String,
+    pub size: u64,
+    pub chunk_size: usize,
+    // Hex SHA-256 of every chunk, the last one may be short
+    pub chunks: Vec<String>,
+}
+
+impl Manifest {
+    pub fn build(path: &Path) -> io::Result<Manifest> {
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut chunks = Vec::new();
+        loop {
+            let n = read_full(&mut reader, &mut buf)?;
+            if n == 0 {
+                break;
+            }
+            chunks.push(to_hex(&Sha256::digest(&buf[..n])));
+        }
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("download").to_string();
+        Ok(Manifest { name, size, chunk_size: CHUNK_SIZE, chunks })
+    }
+
+    // The name isn't part of it, the same bytes shared under two names are one swarm
+    pub fn content_hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.size.to_le_bytes());
+        hasher.update((self.chunk_size as u64).to_le_bytes());
+        for chunk in &self.chunks {
+            hasher.update(chunk.as_bytes());
+        }
+        to_hex(&hasher.finalize())
+    }
+
+    // Size and chunk count have to agree, or chunk offsets would point anywhere
+    pub fn is_consistent(&self) -> bool {
+        self.chunk_size > 0
+            && self.chunk_size <= 16 * CHUNK_SIZE
+            && self.chunks.len() as u64 == self.size.div_ceil(self.chunk_size as u64)
+    }
+
+    pub fn chunk_len(&self, index: usize) -> usize {
+        let start = (index as u64).saturating_mul(self.chunk_size as u64);
+        self.size.saturating_sub(start).min(self.chunk_size as u64) as usize
+    }
+
+    pub fn verify(&self, index: usize, data: &[u8]) -> bool {
+        data.len() == self.chunk_len(index) && self.chunks.get(index).is_some_and(|hash| *hash == to_hex(&Sha256::digest(data)))
+    }
+}
+
+pub fn is_content_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+// Files we hand out, by content hash
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Seed {
+    pub path: PathBuf,
+    pub manifest: Manifest,
+}
+
+pub fn seeds() -> HashMap<String, Seed> {
+    load_json(SEEDS_FILE)
+}
+
+pub fn find_seed(hash: &str) -> Option<Seed> {
+    seeds().remove(hash)
+}
+
+// Starts seeding `path`, returns its content hash
+pub fn add_seed(path: &Path, manifest: Manifest) -> io::Result<String> {
+    let hash = manifest.content_hash();
+    let mut seeds = seeds();
+    seeds.insert(hash.clone(), Seed { path: path.to_path_buf(), manifest });
+    save_json(SEEDS_FILE, &seeds)?;
+    Ok(hash)
+}
+
+// Reads chunk `index` of a seed, None if the file changed or is gone since it was shared
+pub fn read_chunk(seed: &Seed, index: usize) -> Option<Vec<u8>> {
+    if index >= seed.manifest.chunks.len() {
+        return None;
+    }
+    let len = seed.manifest.chunk_len(index);
+    let mut file = File::open(&seed.path).ok()?;
+    file.seek(SeekFrom::Start(index as u64 * seed.manifest.chunk_size as u64)).ok()?;
+    let mut buf = vec![0u8; len];
+    let n = read_full(&mut file, &mut buf).ok()?;
+    (n == len && seed.manifest.verify(index, &buf)).then_some(buf)
+}
+
+// Hands out chunk indexes to peers. A chunk a peer failed to deliver goes back in the
+// queue for someone else.
+pub struct Scheduler {
+    pending: VecDeque<usize>,
+    in_flight: usize,
+    done: Vec<bool>,
+}
+
+pub enum Next {
+    Chunk(usize),
+    // Nothing left to hand out, but chunks in flight may still come back
+    Wait,
+    Finished,
+}
+
+impl Scheduler {
+    pub fn new(chunks: usize) -> Scheduler {
+        Scheduler { pending: (0..chunks).collect(), in_flight: 0, done: vec![false; chunks] }
+    }
+
+    pub fn take(&mut self) -> Next {
+        match self.pending.pop_front() {
+            Some(index) => {
+                self.in_flight += 1;
+                Next::Chunk(index)
+            }
+            None if self.in_flight > 0 => Next::Wait,
+            None => Next::Finished,
+        }
+    }
+
+    pub fn complete(&mut self, index: usize) {
+        self.in_flight -= 1;
+        self.done[index] = true;
+    }
+
+    pub fn failed(&mut self, index: usize) {
+        self.in_flight -= 1;
+        self.pending.push_back(index);
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.done.iter().all(|done| *done)
+    }
+
+    pub fn progress(&self) -> f64 {
+        if self.done.is_empty() {
+            return 100.0;
+        }
+        self.done.iter().filter(|done| **done).count() as f64 / self.done.len() as f64 * 100.0
+    }
+}
@@ -0,0 +1,146 @@
+// File transfer straight over TCP, for peers that can open a port to each other (see
+// tcp_sender_v6.rs and tcp_receiver_v6.rs). The sender writes one line of JSON with the
+// name, size and SHA-256 of the file, then its bytes; the receiver answers with a line
+// saying whether they all arrived and matched. TCP does the ordering and resending
+// that the UDP path does itself (see udp.rs).
+use crate::compression::Codec;
+use crate::delta::sha256_file;
+use crate::events::FileProgress;
+use crate::history::Direction;
+use crate::incoming::IncomingFile;
+use crate::ratelimit::Throttle;
+use serde_json::{json, Value};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+// A header line longer than this isn't one
+const MAX_HEADER: u64 = 64 * 1024;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+pub async fn send_file_tcp(file_path: &Path, peer: &str) -> io::Result<()> {
+    let stream = TcpStream::connect(peer).await?;
+    let name = file_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, format!("{} has no file name", file_path.display())))?;
+    let sha256 = {
+        let path = file_path.to_path_buf();
+        tokio::task::spawn_blocking(move || sha256_file(&path)).await.map_err(io::Error::other)??
+    };
+    let mut file = File::open(file_path).await?;
+    let size = file.metadata().await?.len();
+
+    let (read, mut write) = stream.into_split();
+    let header = json!({"name": name, "size": size, "sha256": sha256});
+    write.write_all(format!("{}\n", header).as_bytes()).await?;
+
+    let mut progress = FileProgress::start(Direction::Sent, peer, name, Some(size));
+    let throttle = Throttle::new(Direction::Sent);
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut sent = 0;
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        write.write_all(&buf[..n]).await?;
+        sent += n as u64;
+        progress.update(sent);
+        throttle.wait(n).await;
+    }
+    if sent != size {
+        let error = format!("{} changed while it was sent", name);
+        progress.fail(&error);
+        return Err(invalid(error));
+    }
+    write.flush().await?;
+
+    let mut reply = String::new();
+    BufReader::new(read).read_line(&mut reply).await?;
+    let result = match serde_json::from_str::<Value>(&reply) {
+        Ok(reply) if reply["ok"] == true => Ok(()),
+        Ok(reply) => Err(invalid(reply["error"].as_str().unwrap_or("The receiver refused the file").to_string())),
+        Err(_) => Err(invalid(format!("{} didn't confirm {}", peer, name))),
+    };
+    progress.finish_with(&result, None);
+    if result.is_ok() {
+        println!("✅ {} sent over TCP to {}", name, peer);
+    }
+    result
+}
+
+// Takes one file from the next sender that connects to `listener` into `out_dir`,
+// gives where it went. A file that arrives short or doesn't match its hash leaves
+// whatever was there under that name alone.
+pub async fn receive_file_tcp(listener: &TcpListener, out_dir: &Path) -> io::Result<PathBuf> {
+    tokio::fs::create_dir_all(out_dir).await?;
+    let (stream, from) = listener.accept().await?;
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read);
+
+    let mut header = String::new();
+    (&mut read).take(MAX_HEADER).read_line(&mut header).await?;
+    let header: Value = serde_json::from_str(&header).map_err(|_| invalid(format!("{} didn't send a file header", from)))?;
+    let (Some(name), Some(size), Some(sha256)) = (header["name"].as_str(), header["size"].as_u64(), header["sha256"].as_str()) else {
+        return Err(invalid(format!("{} sent a header without name, size or hash", from)));
+    };
+    // Into a .part file that only replaces `name` once the hash matches, and only the
+    // last part of the name, so nothing lands outside `out_dir`
+    let mut incoming = IncomingFile::create(out_dir, name, size, Codec::None).map_err(invalid)?;
+    let name = incoming.name.clone();
+    println!("📥 Receiving {} ({} bytes) over TCP from {}", name, size, from);
+
+    let mut progress = FileProgress::start(Direction::Received, &from.to_string(), &name, Some(size));
+    let throttle = Throttle::new(Direction::Received);
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut received = 0;
+    while received < size {
+        let wanted = buf.len().min((size - received) as usize);
+        let n = match read.read(&mut buf[..wanted]).await {
+            Ok(n) => n,
+            Err(e) => {
+                incoming.abort();
+                progress.fail(&e);
+                return Err(e);
+            }
+        };
+        if n == 0 {
+            break;
+        }
+        if let Err(e) = incoming.write_chunk(&buf[..n]) {
+            incoming.abort();
+            progress.fail(&e);
+            return Err(io::Error::other(e));
+        }
+        received += n as u64;
+        progress.update(received);
+        throttle.wait(n).await;
+    }
+
+    let result = if received < size {
+        incoming.abort();
+        Err(format!("{} ended after {} of {} bytes", name, received, size))
+    } else {
+        incoming.finish(Some(sha256))
+    };
+    let reply = match &result {
+        Ok(_) => json!({"ok": true}),
+        Err(e) => json!({"ok": false, "error": e}),
+    };
+    write.write_all(format!("{}\n", reply).as_bytes()).await?;
+    progress.finish_with(&result, result.as_ref().ok().map(PathBuf::as_path));
+    match result {
+        Ok(path) => {
+            println!("✅ {} received over TCP", path.display());
+            Ok(path)
+        }
+        Err(e) => Err(invalid(e)),
+    }
+}
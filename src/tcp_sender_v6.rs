@@ -1,16 +1,18 @@
-use std::io::Write;
-use std::net::TcpStream;
+// Sends one file straight over TCP (see tcp.rs):
+//   tcp_sender_v6 <[ipv6]:port> <file>
+use p2p_rust::tcp::send_file_tcp;
+use std::path::PathBuf;
 
-fn main() {
-    let target = "[2401:4900:8838:54da:5d7e:74b8:4a01:e4c2]:42070";
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [target, path] = args.as_slice() else {
+        eprintln!("usage: tcp_sender_v6 <[ipv6]:port> <file>");
+        return;
+    };
 
-    match TcpStream::connect(target) {
-        Ok(mut stream) => {
-            stream.write_all(b"hello").expect("Failed to send data");
-            println!("✅ Sent 'hello' to {}", target);
-        }
-        Err(e) => {
-            eprintln!("❌ Could not connect: {}", e);
-        }
+    match send_file_tcp(&PathBuf::from(path), target).await {
+        Ok(()) => println!("✅ Sent {} to {}", path, target),
+        Err(e) => eprintln!("❌ Could not send to {}: {}", target, e),
     }
 }
@@ -1,4 +1,4 @@
-use helper::send_json_value;
+use p2p_rust::signaling::send_json_value;
 use slint::{Timer, TimerMode, ModelRc, VecModel, SharedString, spawn_local};
 use rfd::FileDialog;
 use std::io::{self, Write};
@@ -6,11 +6,13 @@ slint::include_modules!();
 use serde_json::{Value};    
 use tokio::net::TcpStream;
 use tokio::task;
-mod helper;
-use helper::print_json;
-use helper::get_pip_port_json;
-use helper::keys_from_json_str;
-use helper::get_clients;
+use p2p_rust::signaling::get_pip_port_json;
+use p2p_rust::signaling::keys_from_json_str;
+use p2p_rust::signaling::get_clients;
+use p2p_rust::tls::{connect_signaling, SignalingConfig};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
 use std::error::Error;
 use tokio::io::AsyncWriteExt;
 use tokio::io::AsyncReadExt;
@@ -36,13 +38,22 @@ fn process_input_json(input: SharedString) {
 async fn main(){
     let app = TestWindow::new().unwrap(); let app_weak = app.as_weak();
     
+    // Whoever registered last sends
+    let registered = Rc::new(RefCell::new(String::new()));
+
     let weak_app_register = app.as_weak();
+    let registered_user = registered.clone();
     app.on_register(move |username: SharedString, password: SharedString| {
         let app_weak = weak_app_register.clone();
+        *registered_user.borrow_mut() = username.to_string();
         task::block_in_place(move || {
             let pip_port_json = get_pip_port_json(username.as_str(), password.as_str());
             let pip_port_string = serde_json::to_string(&pip_port_json).unwrap();
-            let _ = tokio::runtime::Runtime::new().unwrap().block_on(async {send_json_value(&pip_port_json).await});
+            let _ = tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let config = SignalingConfig::from_env()?;
+                let ws_stream = connect_signaling(&config).await?;
+                send_json_value(&pip_port_json, Arc::new(tokio::sync::Mutex::new(ws_stream))).await.map_err(|e| e.to_string())
+            });
             if let Some(app_strong) = app_weak.upgrade() {
                 app_strong.set_output(SharedString::from(pip_port_string));
             }
@@ -53,7 +64,9 @@ async fn main(){
     app.on_get_clients(move || {
         let app_weak = weak_app_clients.clone(); 
         slint::spawn_local(async move {
-            let response = get_clients().await;
+            let config = SignalingConfig::from_env().unwrap();
+            let ws_stream = connect_signaling(&config).await.unwrap();
+            let response = get_clients(Arc::new(tokio::sync::Mutex::new(ws_stream))).await;
             let clients = keys_from_json_str(response.unwrap());
             println!("{:?}", clients);
             let model = ModelRc::new(VecModel::from(clients.into_iter().map(Into::into).collect::<Vec<_>>(),));
@@ -63,11 +76,13 @@ async fn main(){
 
     let weak_app_target = app.as_weak();
     app.on_send(
-        move |username: SharedString, target_username: SharedString| {
+        move |target_username: SharedString| {
             let app_weak = weak_app_target.clone();
-            let username = username.to_string();
+            let username = registered.borrow().clone();
             slint::spawn_local(async move {
-                relay_send(target_username, username).await;
+                if let Err(e) = relay_send(target_username.to_string(), username).await {
+                    eprintln!("❌ {}", e);
+                }
             }).unwrap();
     });
     
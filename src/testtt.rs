@@ -8,6 +8,12 @@ use std::path::Path;
 use tokio_tungstenite::{connect_async, tungstenite::Message};
 use url::Url;
 use std::io::{self, Write};
+use serde_json::Value;
+use tokio::time::{timeout, Duration};
+use p2p_rust::compression::{accepted_codec, offered_codecs, ChunkEncoder, Codec};
+
+// Receivers from before compression never answer file_metadata
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Serialize, Deserialize, Debug)]
 struct RegisterResponse {
@@ -181,7 +187,10 @@ pub async fn relay_send(target_username: String, username: String) -> Result<(),
 }
 
 async fn send_file_via_websocket(
-    ws_stream: &mut (impl SinkExt<Message, Error = tokio_tungstenite::tungstenite::Error> + Unpin + Send),
+    ws_stream: &mut (impl SinkExt<Message, Error = tokio_tungstenite::tungstenite::Error>
+              + StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>>
+              + Unpin
+              + Send),
     file_path: &Path,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let mut file = File::open(file_path)?;
@@ -192,12 +201,27 @@ async fn send_file_via_websocket(
     let metadata_payload = json!({
         "type": "file_metadata",
         "name": file_name,
-        "size": file_size
+        "size": file_size,
+        "codecs": offered_codecs(file_path)
     }).to_string();
     
     ws_stream.send(Message::Text(metadata_payload)).await?;
     println!("📝 Sent file metadata: {} ({} bytes)", file_name, file_size);
 
+    // Wait for the receiver to pick a codec
+    let mut codec = Codec::None;
+    while let Ok(Some(msg)) = timeout(ACCEPT_TIMEOUT, ws_stream.next()).await {
+        if let Message::Text(text) = msg?
+            && let Ok(data) = serde_json::from_str::<Value>(&text)
+            && data.get("type").and_then(|v| v.as_str()) == Some("file_accept")
+        {
+            codec = accepted_codec(&data);
+            break;
+        }
+    }
+    let mut encoder = ChunkEncoder::new(codec);
+    println!("🗜️ Compression: {}", codec.name());
+
     // Send file data in chunks
     let mut buffer = vec![0u8; 16384]; // 16KB chunks
     let mut total_sent = 0;
@@ -207,7 +231,7 @@ async fn send_file_via_websocket(
             break;
         }
         
-        ws_stream.send(Message::Binary(buffer[..bytes_read].to_vec())).await?;
+        ws_stream.send(Message::Binary(encoder.encode(&buffer[..bytes_read]))).await?;
         
         total_sent += bytes_read;
         let progress = (total_sent as f64 / file_size as f64) * 100.0;
@@ -0,0 +1,159 @@
+// Connection to the signaling server, ws:// or wss://.
+//
+// Configured through environment variables:
+//   P2P_SERVER_URL          ws://host:port or wss://host:port (default: the public server)
+//   P2P_CA_FILE             PEM file with the CA roots to trust instead of the system roots
+//   P2P_PIN_SPKI_SHA256     hex SHA-256 of the server's SubjectPublicKeyInfo
+//   P2P_PIN_CERT_SHA256     hex SHA-256 of the server's whole (DER) certificate
+// With a pin set the pin replaces CA validation, which is what self-hosted servers
+// with a self-signed certificate need. Several pins can be given comma separated.
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::{connect_async_tls_with_config, Connector, MaybeTlsStream, WebSocketStream};
+
+pub const DEFAULT_SERVER_URL: &str = "ws://54.66.23.75:8765";
+
+#[derive(Clone)]
+pub struct SignalingConfig {
+    pub url: String,
+    pub ca_file: Option<String>,
+    pub spki_pins: Vec<[u8; 32]>,
+    pub cert_pins: Vec<[u8; 32]>,
+}
+
+impl SignalingConfig {
+    pub fn from_env() -> Result<SignalingConfig, String> {
+        Ok(SignalingConfig {
+            url: std::env::var("P2P_SERVER_URL").unwrap_or_else(|_| DEFAULT_SERVER_URL.to_string()),
+            ca_file: std::env::var("P2P_CA_FILE").ok(),
+            spki_pins: parse_pins("P2P_PIN_SPKI_SHA256")?,
+            cert_pins: parse_pins("P2P_PIN_CERT_SHA256")?,
+        })
+    }
+
+    fn is_pinned(&self) -> bool {
+        !self.spki_pins.is_empty() || !self.cert_pins.is_empty()
+    }
+}
+
+fn parse_pins(var: &str) -> Result<Vec<[u8; 32]>, String> {
+    let Ok(value) = std::env::var(var) else { return Ok(Vec::new()) };
+    value
+        .split(',')
+        .map(|pin| pin.trim().replace(':', ""))
+        .filter(|pin| !pin.is_empty())
+        .map(|pin| {
+            let bytes = (0..pin.len())
+                .step_by(2)
+                .map(|i| pin.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+                .collect::<Option<Vec<u8>>>();
+            bytes
+                .and_then(|b| <[u8; 32]>::try_from(b).ok())
+                .ok_or_else(|| format!("{} must be a hex SHA-256 digest", var))
+        })
+        .collect()
+}
+
+// Connects to the configured signaling server. Errors are worded for the UI.
+pub async fn connect_signaling(config: &SignalingConfig) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, String> {
+    let connector = if config.url.starts_with("wss://") {
+        Some(Connector::Rustls(Arc::new(client_config(config)?)))
+    } else {
+        None
+    };
+
+    match connect_async_tls_with_config(config.url.as_str(), None, false, connector).await {
+        Ok((ws_stream, _)) => Ok(ws_stream),
+        Err(e) => Err(describe_connect_error(&config.url, e)),
+    }
+}
+
+fn client_config(config: &SignalingConfig) -> Result<ClientConfig, String> {
+    let builder = ClientConfig::builder().with_safe_defaults();
+
+    if config.is_pinned() {
+        let verifier = PinnedVerifier { spki_pins: config.spki_pins.clone(), cert_pins: config.cert_pins.clone() };
+        return Ok(builder.with_custom_certificate_verifier(Arc::new(verifier)).with_no_client_auth());
+    }
+
+    let mut roots = RootCertStore::empty();
+    match &config.ca_file {
+        Some(path) => {
+            let file = File::open(path).map_err(|e| format!("Can't open CA file {}: {}", path, e))?;
+            let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+                .map_err(|e| format!("Can't read CA file {}: {}", path, e))?;
+            let (added, _) = roots.add_parsable_certificates(&certs);
+            if added == 0 {
+                return Err(format!("No usable certificates in CA file {}", path));
+            }
+        }
+        None => {
+            let certs = rustls_native_certs::load_native_certs()
+                .map_err(|e| format!("Can't load system CA roots: {}", e))?;
+            for cert in certs {
+                let _ = roots.add(&Certificate(cert.0));
+            }
+        }
+    }
+    Ok(builder.with_root_certificates(roots).with_no_client_auth())
+}
+
+// Accepts exactly the pinned server certificate / key, regardless of who issued it
+struct PinnedVerifier {
+    spki_pins: Vec<[u8; 32]>,
+    cert_pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let cert_hash: [u8; 32] = Sha256::digest(&end_entity.0).into();
+        if self.cert_pins.contains(&cert_hash) {
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        let (_, cert) = x509_parser::parse_x509_certificate(&end_entity.0)
+            .map_err(|_| rustls::Error::General("server certificate can't be parsed".to_string()))?;
+        let spki_hash: [u8; 32] = Sha256::digest(cert.tbs_certificate.subject_pki.raw).into();
+        if self.spki_pins.contains(&spki_hash) {
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        Err(rustls::Error::General(format!(
+            "certificate pin mismatch (server key sha256 {}, certificate sha256 {})",
+            to_hex(&spki_hash),
+            to_hex(&cert_hash)
+        )))
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn describe_connect_error(url: &str, error: WsError) -> String {
+    let tls_failure = match &error {
+        WsError::Tls(_) => true,
+        WsError::Io(e) => e.get_ref().is_some_and(|inner| inner.is::<rustls::Error>()),
+        _ => false,
+    };
+    if tls_failure {
+        format!("TLS verification of {} failed: {}", url, error)
+    } else {
+        format!("Can't connect to {}: {}", url, error)
+    }
+}
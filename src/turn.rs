@@ -0,0 +1,614 @@
+// TURN client (RFC 5766 / RFC 8656) used as a relay for the UDP transfer path
+// when the peer can't be reached directly. Only UDP transport to the TURN
+// server and long-term credentials are supported.
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use rand::RngCore;
+use sha1::Sha1;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use tokio::net::UdpSocket;
+use tokio::time::{timeout, Duration, Instant};
+
+const MAGIC_COOKIE: u32 = 0x2112A442;
+
+// Methods
+const ALLOCATE: u16 = 0x003;
+const REFRESH: u16 = 0x004;
+const SEND: u16 = 0x006;
+const DATA: u16 = 0x007;
+const CREATE_PERMISSION: u16 = 0x008;
+const CHANNEL_BIND: u16 = 0x009;
+
+// Classes
+const REQUEST: u16 = 0x0000;
+const INDICATION: u16 = 0x0010;
+const SUCCESS: u16 = 0x0100;
+const ERROR: u16 = 0x0110;
+
+// Attributes
+const ATTR_USERNAME: u16 = 0x0006;
+const ATTR_MESSAGE_INTEGRITY: u16 = 0x0008;
+const ATTR_ERROR_CODE: u16 = 0x0009;
+const ATTR_CHANNEL_NUMBER: u16 = 0x000C;
+const ATTR_LIFETIME: u16 = 0x000D;
+const ATTR_XOR_PEER_ADDRESS: u16 = 0x0012;
+const ATTR_DATA: u16 = 0x0013;
+const ATTR_REALM: u16 = 0x0014;
+const ATTR_NONCE: u16 = 0x0015;
+const ATTR_XOR_RELAYED_ADDRESS: u16 = 0x0016;
+const ATTR_REQUESTED_TRANSPORT: u16 = 0x0019;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+const TRANSPORT_UDP: u8 = 17;
+const FIRST_CHANNEL: u16 = 0x4000;
+const LAST_CHANNEL: u16 = 0x7FFE;
+
+// Permissions last 5 minutes and channel bindings 10 minutes on the server,
+// so both are renewed well before that.
+const PERMISSION_REFRESH: Duration = Duration::from_secs(240);
+const CHANNEL_REFRESH: Duration = Duration::from_secs(480);
+const DEFAULT_LIFETIME: u32 = 600;
+const MAX_DATAGRAM: usize = 65536;
+
+// Request retransmission (RFC 5389 7.2.1): 500 ms doubling, 7 tries
+const RTO: Duration = Duration::from_millis(500);
+const MAX_TRANSMISSIONS: u32 = 7;
+
+#[derive(Debug, Clone)]
+pub struct TurnConfig {
+    pub server: SocketAddr,
+    pub username: String,
+    pub password: String,
+}
+
+impl TurnConfig {
+    // Reads P2P_TURN_SERVER (host:port), P2P_TURN_USERNAME and P2P_TURN_PASSWORD
+    pub fn from_env() -> Option<TurnConfig> {
+        let server = std::env::var("P2P_TURN_SERVER").ok()?;
+        let server = server.to_socket_addrs().ok()?.find(|a| a.is_ipv4())?;
+        Some(TurnConfig {
+            server,
+            username: std::env::var("P2P_TURN_USERNAME").ok()?,
+            password: std::env::var("P2P_TURN_PASSWORD").ok()?,
+        })
+    }
+}
+
+// A parsed STUN/TURN message, attributes are kept raw (type, value)
+#[derive(Debug)]
+struct StunMessage {
+    msg_type: u16,
+    transaction_id: [u8; 12],
+    attributes: Vec<(u16, Vec<u8>)>,
+}
+
+impl StunMessage {
+    fn new(method: u16, class: u16) -> StunMessage {
+        let mut transaction_id = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut transaction_id);
+        StunMessage { msg_type: method | class, transaction_id, attributes: Vec::new() }
+    }
+
+    fn method(&self) -> u16 {
+        self.msg_type & !0x0110
+    }
+
+    fn class(&self) -> u16 {
+        self.msg_type & 0x0110
+    }
+
+    fn add(&mut self, attr_type: u16, value: Vec<u8>) {
+        self.attributes.push((attr_type, value));
+    }
+
+    fn get(&self, attr_type: u16) -> Option<&[u8]> {
+        self.attributes.iter().find(|(t, _)| *t == attr_type).map(|(_, v)| v.as_slice())
+    }
+
+    fn get_string(&self, attr_type: u16) -> Option<String> {
+        self.get(attr_type).map(|v| String::from_utf8_lossy(v).into_owned())
+    }
+
+    fn get_address(&self, attr_type: u16) -> Option<SocketAddr> {
+        decode_xor_address(self.get(attr_type)?, &self.transaction_id)
+    }
+
+    fn error_code(&self) -> Option<(u16, String)> {
+        let v = self.get(ATTR_ERROR_CODE)?;
+        if v.len() < 4 {
+            return None;
+        }
+        let code = (v[2] & 0x07) as u16 * 100 + v[3] as u16;
+        Some((code, String::from_utf8_lossy(&v[4..]).into_owned()))
+    }
+
+    // Serializes the message, appending MESSAGE-INTEGRITY when a key is given
+    fn encode(&self, key: Option<&[u8]>) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(128);
+        buf.extend_from_slice(&self.msg_type.to_be_bytes());
+        buf.extend_from_slice(&[0, 0]); // length, filled in below
+        buf.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf.extend_from_slice(&self.transaction_id);
+
+        for (attr_type, value) in &self.attributes {
+            push_attribute(&mut buf, *attr_type, value);
+        }
+
+        if let Some(key) = key {
+            // The length must already cover the MESSAGE-INTEGRITY attribute (4 + 20)
+            let len = (buf.len() - 20 + 24) as u16;
+            buf[2..4].copy_from_slice(&len.to_be_bytes());
+            let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts any key length");
+            mac.update(&buf);
+            let digest = mac.finalize().into_bytes();
+            push_attribute(&mut buf, ATTR_MESSAGE_INTEGRITY, &digest);
+        }
+
+        let len = (buf.len() - 20) as u16;
+        buf[2..4].copy_from_slice(&len.to_be_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<StunMessage> {
+        if buf.len() < 20 || buf[0] & 0xC0 != 0 {
+            return None;
+        }
+        if u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]) != MAGIC_COOKIE {
+            return None;
+        }
+        let msg_type = u16::from_be_bytes([buf[0], buf[1]]);
+        let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        if buf.len() < 20 + len {
+            return None;
+        }
+        let mut transaction_id = [0u8; 12];
+        transaction_id.copy_from_slice(&buf[8..20]);
+
+        let mut attributes = Vec::new();
+        let mut i = 20;
+        while i + 4 <= 20 + len {
+            let attr_type = u16::from_be_bytes([buf[i], buf[i + 1]]);
+            let attr_len = u16::from_be_bytes([buf[i + 2], buf[i + 3]]) as usize;
+            if i + 4 + attr_len > 20 + len {
+                return None;
+            }
+            attributes.push((attr_type, buf[i + 4..i + 4 + attr_len].to_vec()));
+            i += 4 + attr_len + (4 - attr_len % 4) % 4;
+        }
+        Some(StunMessage { msg_type, transaction_id, attributes })
+    }
+
+    // Checks MESSAGE-INTEGRITY of a received message against the raw bytes
+    fn verify_integrity(raw: &[u8], key: &[u8]) -> bool {
+        let mut i = 20;
+        while i + 4 <= raw.len() {
+            let attr_type = u16::from_be_bytes([raw[i], raw[i + 1]]);
+            let attr_len = u16::from_be_bytes([raw[i + 2], raw[i + 3]]) as usize;
+            if attr_type == ATTR_MESSAGE_INTEGRITY {
+                if attr_len != 20 || i + 24 > raw.len() {
+                    return false;
+                }
+                let mut covered = raw[..i].to_vec();
+                let len = (i - 20 + 24) as u16;
+                covered[2..4].copy_from_slice(&len.to_be_bytes());
+                let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts any key length");
+                mac.update(&covered);
+                return mac.verify_slice(&raw[i + 4..i + 24]).is_ok();
+            }
+            i += 4 + attr_len + (4 - attr_len % 4) % 4;
+        }
+        false
+    }
+}
+
+fn push_attribute(buf: &mut Vec<u8>, attr_type: u16, value: &[u8]) {
+    buf.extend_from_slice(&attr_type.to_be_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+    let padding = (4 - value.len() % 4) % 4;
+    buf.extend(std::iter::repeat_n(0u8, padding));
+}
+
+fn encode_xor_address(addr: SocketAddr, transaction_id: &[u8; 12]) -> Vec<u8> {
+    let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    let mut value = Vec::with_capacity(20);
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            value.extend_from_slice(&[0, 0x01]);
+            value.extend_from_slice(&port.to_be_bytes());
+            let xored = u32::from(ip) ^ MAGIC_COOKIE;
+            value.extend_from_slice(&xored.to_be_bytes());
+        }
+        IpAddr::V6(ip) => {
+            value.extend_from_slice(&[0, 0x02]);
+            value.extend_from_slice(&port.to_be_bytes());
+            let mut mask = [0u8; 16];
+            mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+            mask[4..].copy_from_slice(transaction_id);
+            for (b, m) in ip.octets().iter().zip(mask.iter()) {
+                value.push(b ^ m);
+            }
+        }
+    }
+    value
+}
+
+fn decode_xor_address(value: &[u8], transaction_id: &[u8; 12]) -> Option<SocketAddr> {
+    if value.len() < 8 {
+        return None;
+    }
+    let port = u16::from_be_bytes([value[2], value[3]]) ^ (MAGIC_COOKIE >> 16) as u16;
+    match value[1] {
+        0x01 => {
+            let ip = u32::from_be_bytes([value[4], value[5], value[6], value[7]]) ^ MAGIC_COOKIE;
+            Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), port))
+        }
+        0x02 if value.len() >= 20 => {
+            let mut mask = [0u8; 16];
+            mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+            mask[4..].copy_from_slice(transaction_id);
+            let mut octets = [0u8; 16];
+            for (o, (b, m)) in octets.iter_mut().zip(value[4..20].iter().zip(mask.iter())) {
+                *o = b ^ m;
+            }
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+        }
+        _ => None,
+    }
+}
+
+fn long_term_key(username: &str, realm: &str, password: &str) -> Vec<u8> {
+    let mut hasher = Md5::new();
+    hasher.update(format!("{}:{}:{}", username, realm, password).as_bytes());
+    hasher.finalize().to_vec()
+}
+
+fn turn_error(message: String) -> Error {
+    Error::other(message)
+}
+
+pub struct TurnClient {
+    socket: UdpSocket,
+    config: TurnConfig,
+    realm: String,
+    nonce: Vec<u8>,
+    key: Vec<u8>,
+    relayed_addr: SocketAddr,
+    mapped_addr: Option<SocketAddr>,
+    lifetime: Duration,
+    allocated_at: Instant,
+    permissions: HashMap<IpAddr, Instant>,
+    channels: HashMap<SocketAddr, (u16, Instant)>,
+    next_channel: u16,
+    // Peer data that arrived while waiting for a transaction response
+    pending: VecDeque<(SocketAddr, Vec<u8>)>,
+    // Everything from the server is read into this
+    buf: Vec<u8>,
+}
+
+impl TurnClient {
+    // Creates an allocation on the TURN server, answering the 401 challenge
+    // with the long-term credentials.
+    pub async fn allocate(config: TurnConfig) -> tokio::io::Result<TurnClient> {
+        let bind_addr = if config.server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind_addr).await?;
+
+        let mut client = TurnClient {
+            socket,
+            config,
+            realm: String::new(),
+            nonce: Vec::new(),
+            key: Vec::new(),
+            relayed_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            mapped_addr: None,
+            lifetime: Duration::from_secs(DEFAULT_LIFETIME as u64),
+            allocated_at: Instant::now(),
+            permissions: HashMap::new(),
+            channels: HashMap::new(),
+            next_channel: FIRST_CHANNEL,
+            pending: VecDeque::new(),
+            buf: vec![0u8; MAX_DATAGRAM],
+        };
+
+        let response = client
+            .authenticated_request(ALLOCATE, |msg| {
+                msg.add(ATTR_REQUESTED_TRANSPORT, vec![TRANSPORT_UDP, 0, 0, 0]);
+                msg.add(ATTR_LIFETIME, DEFAULT_LIFETIME.to_be_bytes().to_vec());
+            })
+            .await?;
+
+        client.relayed_addr = response
+            .get_address(ATTR_XOR_RELAYED_ADDRESS)
+            .ok_or_else(|| turn_error("Allocate response has no XOR-RELAYED-ADDRESS".into()))?;
+        client.mapped_addr = response.get_address(ATTR_XOR_MAPPED_ADDRESS);
+        client.lifetime = lifetime_of(&response);
+        client.allocated_at = Instant::now();
+        println!("🔁 TURN allocation {} via {}", client.relayed_addr, client.config.server);
+        Ok(client)
+    }
+
+    // Address peers should send to in order to reach us through the relay
+    pub fn relayed_addr(&self) -> SocketAddr {
+        self.relayed_addr
+    }
+
+    // Our public address as seen by the TURN server
+    pub fn mapped_addr(&self) -> Option<SocketAddr> {
+        self.mapped_addr
+    }
+
+    pub async fn create_permission(&mut self, peer: SocketAddr) -> tokio::io::Result<()> {
+        self.authenticated_request(CREATE_PERMISSION, |msg| {
+            let value = encode_xor_address(peer, &msg.transaction_id);
+            msg.add(ATTR_XOR_PEER_ADDRESS, value);
+        })
+        .await?;
+        self.permissions.insert(peer.ip(), Instant::now());
+        Ok(())
+    }
+
+    // Binds (or rebinds) a channel to the peer, which also installs a permission
+    pub async fn channel_bind(&mut self, peer: SocketAddr) -> tokio::io::Result<u16> {
+        let channel = match self.channels.get(&peer) {
+            Some((channel, _)) => *channel,
+            None => {
+                if self.next_channel > LAST_CHANNEL {
+                    return Err(turn_error("No TURN channel numbers left".into()));
+                }
+                let channel = self.next_channel;
+                self.next_channel += 1;
+                channel
+            }
+        };
+
+        self.authenticated_request(CHANNEL_BIND, |msg| {
+            msg.add(ATTR_CHANNEL_NUMBER, vec![(channel >> 8) as u8, channel as u8, 0, 0]);
+            let value = encode_xor_address(peer, &msg.transaction_id);
+            msg.add(ATTR_XOR_PEER_ADDRESS, value);
+        })
+        .await?;
+
+        let now = Instant::now();
+        self.channels.insert(peer, (channel, now));
+        self.permissions.insert(peer.ip(), now);
+        Ok(channel)
+    }
+
+    // Extends the allocation, a lifetime of 0 releases it
+    pub async fn refresh(&mut self, lifetime: u32) -> tokio::io::Result<()> {
+        let response = self
+            .authenticated_request(REFRESH, |msg| {
+                msg.add(ATTR_LIFETIME, lifetime.to_be_bytes().to_vec());
+            })
+            .await?;
+        self.lifetime = lifetime_of(&response);
+        self.allocated_at = Instant::now();
+        Ok(())
+    }
+
+    pub async fn deallocate(mut self) -> tokio::io::Result<()> {
+        self.refresh(0).await
+    }
+
+    // Renews the allocation, permissions and channel bindings that are close to expiring
+    pub async fn maintain(&mut self) -> tokio::io::Result<()> {
+        if self.allocated_at.elapsed() > self.lifetime / 2 {
+            self.refresh(DEFAULT_LIFETIME).await?;
+        }
+
+        let stale_channels: Vec<SocketAddr> = self
+            .channels
+            .iter()
+            .filter(|(_, (_, bound_at))| bound_at.elapsed() > CHANNEL_REFRESH)
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in stale_channels {
+            self.channel_bind(peer).await?;
+        }
+
+        let stale_permissions: Vec<IpAddr> = self
+            .permissions
+            .iter()
+            .filter(|(_, created_at)| created_at.elapsed() > PERMISSION_REFRESH)
+            .map(|(ip, _)| *ip)
+            .collect();
+        for ip in stale_permissions {
+            self.create_permission(SocketAddr::new(ip, 0)).await?;
+        }
+        Ok(())
+    }
+
+    // Sends data to the peer through the relay, using ChannelData when a channel
+    // is bound and a Send indication otherwise.
+    pub async fn send_to(&mut self, data: &[u8], peer: SocketAddr) -> tokio::io::Result<()> {
+        self.maintain().await?;
+
+        let packet = match self.channels.get(&peer) {
+            Some((channel, _)) => {
+                let mut packet = Vec::with_capacity(4 + data.len() + 3);
+                packet.extend_from_slice(&channel.to_be_bytes());
+                packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+                packet.extend_from_slice(data);
+                let padding = (4 - data.len() % 4) % 4;
+                packet.extend(std::iter::repeat_n(0u8, padding));
+                packet
+            }
+            None => {
+                let mut msg = StunMessage::new(SEND, INDICATION);
+                let value = encode_xor_address(peer, &msg.transaction_id);
+                msg.add(ATTR_XOR_PEER_ADDRESS, value);
+                msg.add(ATTR_DATA, data.to_vec());
+                msg.encode(None)
+            }
+        };
+        self.socket.send_to(&packet, self.config.server).await?;
+        Ok(())
+    }
+
+    // Receives the next datagram relayed from a peer (Data indication or ChannelData).
+    // Keeps the allocation, permissions and channels alive while it waits, so a client
+    // that only receives doesn't lose them.
+    pub async fn recv_from(&mut self, buf: &mut [u8]) -> tokio::io::Result<(usize, SocketAddr)> {
+        loop {
+            self.maintain().await?;
+            if let Some((peer, data)) = self.pending.pop_front() {
+                return Ok((copy_into(buf, &data), peer));
+            }
+            let (len, from) = match timeout(self.until_maintenance(), self.socket.recv_from(&mut self.buf)).await {
+                Ok(result) => result?,
+                Err(_) => continue,
+            };
+            if from != self.config.server {
+                continue;
+            }
+            if let Some((peer, data)) = self.parse_peer_data(&self.buf[..len]) {
+                return Ok((copy_into(buf, &data), peer));
+            }
+        }
+    }
+
+    // How long until maintain has something to renew
+    fn until_maintenance(&self) -> Duration {
+        let due = self
+            .channels
+            .values()
+            .map(|(_, bound_at)| *bound_at + CHANNEL_REFRESH)
+            .chain(self.permissions.values().map(|created_at| *created_at + PERMISSION_REFRESH))
+            .fold(self.allocated_at + self.lifetime / 2, Instant::min);
+        due.saturating_duration_since(Instant::now()).max(Duration::from_millis(1))
+    }
+
+    fn parse_peer_data(&self, raw: &[u8]) -> Option<(SocketAddr, Vec<u8>)> {
+        if raw.len() >= 4 && (0x40..=0x7F).contains(&raw[0]) {
+            // ChannelData
+            let channel = u16::from_be_bytes([raw[0], raw[1]]);
+            let len = u16::from_be_bytes([raw[2], raw[3]]) as usize;
+            if raw.len() < 4 + len {
+                return None;
+            }
+            let peer = self
+                .channels
+                .iter()
+                .find(|(_, (c, _))| *c == channel)
+                .map(|(peer, _)| *peer)?;
+            return Some((peer, raw[4..4 + len].to_vec()));
+        }
+
+        let msg = StunMessage::decode(raw)?;
+        if msg.method() == DATA && msg.class() == INDICATION {
+            let peer = msg.get_address(ATTR_XOR_PEER_ADDRESS)?;
+            let data = msg.get(ATTR_DATA)?.to_vec();
+            return Some((peer, data));
+        }
+        None
+    }
+
+    // Sends an authenticated request, retrying once on 401 (first challenge)
+    // and 438 (stale nonce), and returns the success response.
+    async fn authenticated_request<F>(&mut self, method: u16, build: F) -> tokio::io::Result<StunMessage>
+    where
+        F: Fn(&mut StunMessage),
+    {
+        for _ in 0..3 {
+            let mut msg = StunMessage::new(method, REQUEST);
+            build(&mut msg);
+            let key = if self.key.is_empty() {
+                None
+            } else {
+                msg.add(ATTR_USERNAME, self.config.username.as_bytes().to_vec());
+                msg.add(ATTR_REALM, self.realm.as_bytes().to_vec());
+                msg.add(ATTR_NONCE, self.nonce.clone());
+                Some(self.key.clone())
+            };
+
+            let (response, raw) = self.transaction(&msg, key.as_deref()).await?;
+            match response.class() {
+                SUCCESS => {
+                    // Once we hold a key, anything unsigned could have come from anyone
+                    if let Some(key) = &key
+                        && (response.get(ATTR_MESSAGE_INTEGRITY).is_none() || !StunMessage::verify_integrity(&raw, key))
+                    {
+                        return Err(turn_error("TURN response failed integrity check".into()));
+                    }
+                    return Ok(response);
+                }
+                ERROR => {
+                    let (code, reason) = response.error_code().unwrap_or((0, String::new()));
+                    match code {
+                        401 | 438 => {
+                            if code == 401 && !self.key.is_empty() && self.realm_matches(&response) {
+                                return Err(turn_error("TURN server rejected the credentials".into()));
+                            }
+                            if let Some(realm) = response.get_string(ATTR_REALM) {
+                                self.realm = realm;
+                            }
+                            if let Some(nonce) = response.get(ATTR_NONCE) {
+                                self.nonce = nonce.to_vec();
+                            }
+                            self.key = long_term_key(&self.config.username, &self.realm, &self.config.password);
+                        }
+                        _ => return Err(turn_error(format!("TURN error {}: {}", code, reason))),
+                    }
+                }
+                _ => return Err(turn_error("Unexpected TURN response class".into())),
+            }
+        }
+        Err(turn_error("TURN authentication failed".into()))
+    }
+
+    fn realm_matches(&self, response: &StunMessage) -> bool {
+        response.get_string(ATTR_REALM).is_none_or(|realm| realm == self.realm)
+    }
+
+    // Sends a request and waits for the response with the same transaction id,
+    // retransmitting on timeout. Peer data arriving meanwhile is queued.
+    async fn transaction(&mut self, msg: &StunMessage, key: Option<&[u8]>) -> tokio::io::Result<(StunMessage, Vec<u8>)> {
+        let bytes = msg.encode(key);
+        let mut rto = RTO;
+
+        for _ in 0..MAX_TRANSMISSIONS {
+            self.socket.send_to(&bytes, self.config.server).await?;
+            let deadline = Instant::now() + rto;
+
+            loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                let (len, from) = match timeout(remaining, self.socket.recv_from(&mut self.buf)).await {
+                    Ok(result) => result?,
+                    Err(_) => break,
+                };
+                if from != self.config.server {
+                    continue;
+                }
+                let raw = &self.buf[..len];
+                if let Some(response) = StunMessage::decode(raw)
+                    && response.transaction_id == msg.transaction_id
+                    && (response.class() == SUCCESS || response.class() == ERROR)
+                {
+                    return Ok((response, raw.to_vec()));
+                }
+                if let Some(data) = self.parse_peer_data(raw) {
+                    self.pending.push_back(data);
+                }
+            }
+            rto *= 2;
+        }
+        Err(Error::new(ErrorKind::TimedOut, "TURN server did not respond"))
+    }
+}
+
+fn lifetime_of(response: &StunMessage) -> Duration {
+    let seconds = response
+        .get(ATTR_LIFETIME)
+        .filter(|v| v.len() == 4)
+        .map(|v| u32::from_be_bytes([v[0], v[1], v[2], v[3]]))
+        .unwrap_or(DEFAULT_LIFETIME);
+    Duration::from_secs(seconds as u64)
+}
+
+fn copy_into(buf: &mut [u8], data: &[u8]) -> usize {
+    let n = data.len().min(buf.len());
+    buf[..n].copy_from_slice(&data[..n]);
+    n
+}
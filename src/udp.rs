@@ -0,0 +1,285 @@
+// File transfer straight over UDP, one packet at a time: every packet carries a
+// sequence number and a CRC and is sent again until the receiver acks it. Packet 0
+// holds the file name and an empty packet ends the file. send_file_turn does the same
+// through a TURN relay, send_file_direct falls back to it when the peer can't be
+// reached directly (see turn.rs).
+// Sockets are used through `Datagram`, so a simulated network can stand in for the
+// real one (see netsim.rs).
+use crate::history::Direction;
+use crate::ratelimit::Throttle;
+use crate::turn::{TurnClient, TurnConfig};
+use crc16::*;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::future::Future;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::time::{timeout, Duration};
+
+const HEADER: u64 = 0x12345678ABCDEF00;
+const MAX_RETRIES: u32 = 20;
+
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Packet {
+    pub header: u64,
+    pub sno: u32,
+    pub payload_length: u16,
+    pub checksum: u16,
+    pub payload: Vec<u8>,
+}
+
+fn calculate_checksum(data: &[u8]) -> u16 {
+    State::<ARC>::calculate(data)
+}
+
+fn end_packet(sno: u32) -> Packet {
+    Packet { header: HEADER, sno, payload_length: 0, checksum: calculate_checksum(&[]), payload: Vec::new() }
+}
+
+// What the transfer needs from a socket
+pub trait Datagram {
+    fn send_to(&self, buf: &[u8], target: SocketAddr) -> impl Future<Output = io::Result<usize>>;
+    // Has to be cancel safe, the ack wait gives up on it after a timeout
+    fn recv_from(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<(usize, SocketAddr)>>;
+}
+
+impl Datagram for UdpSocket {
+    fn send_to(&self, buf: &[u8], target: SocketAddr) -> impl Future<Output = io::Result<usize>> {
+        UdpSocket::send_to(self, buf, target)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<(usize, SocketAddr)>> {
+        UdpSocket::recv_from(self, buf)
+    }
+}
+
+pub async fn send_file_udp(file_path: &Path, server_addr: &str) -> tokio::io::Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let peer = lookup_host(server_addr)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, format!("Can't resolve {}", server_addr)))?;
+    send_file_to(&socket, peer, file_path).await
+}
+
+// The name the first packet carries
+fn name_of(file_path: &Path) -> io::Result<&str> {
+    file_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, format!("{} has no usable file name", file_path.display())))
+}
+
+// send_file_udp on a socket that is already there
+pub async fn send_file_to(socket: &impl Datagram, peer: SocketAddr, file_path: &Path) -> tokio::io::Result<()> {
+    let file_name = name_of(file_path)?;
+    let mut file = File::open(file_path)?;
+
+    let mut packet_count = 0;
+
+    // Send filename as first packet
+    let name_packet = Packet {
+        header: HEADER,
+        sno: 0,
+        payload_length: file_name.len() as u16,
+        checksum: calculate_checksum(file_name.as_bytes()),
+        payload: file_name.as_bytes().to_vec(),
+    };
+    send_with_ack(socket, peer, &name_packet).await?;
+    packet_count += 1;
+
+    let mut buffer = [0u8; 1024];
+    let mut seq_num = 1;
+    let throttle = Throttle::new(Direction::Sent);
+
+    loop {
+        let bytes_read = file.read(&mut buffer)?;
+        if bytes_read == 0 { break; }
+
+        let data = &buffer[..bytes_read];
+        let packet = Packet {
+            header: HEADER,
+            sno: seq_num,
+            payload_length: bytes_read as u16,
+            checksum: calculate_checksum(data),
+            payload: data.to_vec(),
+        };
+
+        send_with_ack(socket, peer, &packet).await?;
+        packet_count += 1;
+        seq_num += 1;
+        throttle.wait(bytes_read).await;
+    }
+
+    // An empty packet marks the end of the file
+    send_with_ack(socket, peer, &end_packet(seq_num)).await?;
+
+    println!("✅ File sent via UDP. Total packets sent: {}", packet_count);
+    Ok(())
+}
+
+async fn send_with_ack(socket: &impl Datagram, peer: SocketAddr, packet: &Packet) -> tokio::io::Result<()> {
+    let packet_bytes = bincode::serialize(&packet).unwrap();
+    let expected_ack = format!("ACK:{}", packet.sno);
+    let mut buf = [0u8; 128];
+
+    for _ in 0..MAX_RETRIES {
+        socket.send_to(&packet_bytes, peer).await?;
+        println!("📤 Sent packet {}", packet.sno);
+
+        match timeout(Duration::from_millis(500), socket.recv_from(&mut buf)).await {
+            Ok(Ok((received, from))) if from == peer => {
+                let ack_msg = String::from_utf8_lossy(&buf[..received]);
+                if ack_msg == expected_ack {
+                    println!("✅ Received ACK for {}", packet.sno);
+                    return Ok(());
+                }
+            }
+            _ => {
+                println!("⏳ Timeout for packet {}, retrying...", packet.sno);
+            }
+        }
+    }
+    Err(std::io::Error::new(std::io::ErrorKind::TimedOut, format!("No ACK for packet {}", packet.sno)))
+}
+
+// send_file_udp to `peer`, and when nothing gets through again through an allocation on
+// the TURN server in `turn`: a peer behind a NAT that only lets in what it sent to
+// first may still take packets from the relay
+pub async fn send_file_direct(file_path: &Path, peer: SocketAddr, turn: Option<&TurnConfig>) -> tokio::io::Result<()> {
+    let socket = match peer {
+        SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0").await?,
+        SocketAddr::V6(_) => UdpSocket::bind("[::]:0").await?,
+    };
+    let direct = send_file_to(&socket, peer, file_path).await;
+    let (Err(e), Some(turn)) = (&direct, turn) else { return direct };
+    eprintln!("❌ Direct UDP transfer failed: {}, trying the TURN relay {}", e, turn.server);
+    let mut client = TurnClient::allocate(turn.clone()).await?;
+    let result = send_file_turn(file_path, &mut client, peer).await;
+    if let Err(e) = client.deallocate().await {
+        eprintln!("❌ Can't release the TURN allocation: {}", e);
+    }
+    result
+}
+
+// Same as send_file_udp, but every packet goes through a TURN allocation
+pub async fn send_file_turn(file_path: &Path, turn: &mut TurnClient, peer: SocketAddr) -> tokio::io::Result<()> {
+    turn.channel_bind(peer).await?;
+
+    let file_name = name_of(file_path)?;
+    let mut file = File::open(file_path)?;
+    let mut packet_count = 0;
+
+    let name_packet = Packet {
+        header: HEADER,
+        sno: 0,
+        payload_length: file_name.len() as u16,
+        checksum: calculate_checksum(file_name.as_bytes()),
+        payload: file_name.as_bytes().to_vec(),
+    };
+    send_with_ack_turn(turn, peer, &name_packet).await?;
+    packet_count += 1;
+
+    let mut buffer = [0u8; 1024];
+    let mut seq_num = 1;
+    let throttle = Throttle::new(Direction::Sent);
+
+    loop {
+        let bytes_read = file.read(&mut buffer)?;
+        if bytes_read == 0 { break; }
+
+        let data = &buffer[..bytes_read];
+        let packet = Packet {
+            header: HEADER,
+            sno: seq_num,
+            payload_length: bytes_read as u16,
+            checksum: calculate_checksum(data),
+            payload: data.to_vec(),
+        };
+
+        send_with_ack_turn(turn, peer, &packet).await?;
+        packet_count += 1;
+        seq_num += 1;
+        throttle.wait(bytes_read).await;
+    }
+
+    send_with_ack_turn(turn, peer, &end_packet(seq_num)).await?;
+
+    println!("✅ File sent via TURN relay {}. Total packets sent: {}", turn.relayed_addr(), packet_count);
+    Ok(())
+}
+
+async fn send_with_ack_turn(turn: &mut TurnClient, peer: SocketAddr, packet: &Packet) -> tokio::io::Result<()> {
+    let packet_bytes = bincode::serialize(&packet).unwrap();
+    let expected_ack = format!("ACK:{}", packet.sno);
+    let mut buf = [0u8; 128];
+
+    for _ in 0..MAX_RETRIES {
+        turn.send_to(&packet_bytes, peer).await?;
+
+        match timeout(Duration::from_millis(500), turn.recv_from(&mut buf)).await {
+            Ok(Ok((received, from))) if from == peer => {
+                let ack_msg = String::from_utf8_lossy(&buf[..received]);
+                if ack_msg == expected_ack {
+                    return Ok(());
+                }
+            }
+            Ok(Err(e)) => return Err(e),
+            _ => {
+                println!("⏳ Timeout for packet {} over TURN, retrying...", packet.sno);
+            }
+        }
+    }
+    Err(std::io::Error::new(std::io::ErrorKind::TimedOut, format!("No ACK for packet {} over TURN", packet.sno)))
+}
+
+// Receives one file sent with send_file_udp / send_file_turn on an already bound
+// socket, acking every packet back to whichever address it came from (the peer
+// itself or its TURN relayed address).
+pub async fn receive_file_udp(socket: &impl Datagram, out_dir: &Path) -> tokio::io::Result<()> {
+    fs::create_dir_all(out_dir)?;
+    let mut buf = vec![0u8; 65536];
+    let mut file: Option<File> = None;
+    let mut expected_sno = 0;
+    let throttle = Throttle::new(Direction::Received);
+
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        let packet: Packet = match bincode::deserialize(&buf[..len]) {
+            Ok(packet) => packet,
+            Err(_) => continue,
+        };
+        if packet.header != HEADER || calculate_checksum(&packet.payload) != packet.checksum {
+            continue;
+        }
+
+        // Duplicates (lost ACKs) are acked again but written only once
+        if packet.sno == expected_sno {
+            if packet.sno == 0 {
+                let name = String::from_utf8_lossy(&packet.payload).to_string();
+                let name = Path::new(&name).file_name().unwrap_or_default().to_owned();
+                println!("📥 Receiving {:?} from {}", name, from);
+                file = Some(File::create(out_dir.join(name))?);
+            } else if let Some(file) = file.as_mut() {
+                file.write_all(&packet.payload)?;
+                throttle.wait(packet.payload.len()).await;
+            }
+            expected_sno += 1;
+        }
+        socket.send_to(format!("ACK:{}", packet.sno).as_bytes(), from).await?;
+
+        if packet.sno > 0 && packet.payload_length == 0 && packet.sno + 1 == expected_sno {
+            println!("✅ File received via UDP ({} packets)", expected_sno);
+            // Keep acking retransmissions for a moment in case the last ACK was lost
+            while let Ok(Ok((len, from))) = timeout(Duration::from_secs(2), socket.recv_from(&mut buf)).await {
+                if let Ok(packet) = bincode::deserialize::<Packet>(&buf[..len]) {
+                    socket.send_to(format!("ACK:{}", packet.sno).as_bytes(), from).await?;
+                }
+            }
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,46 @@
+// A bad network between two programs, for trying the UDP transfer outside of tests:
+//   udp_proxy 127.0.0.1:9000 127.0.0.1:8080 "loss=5% delay=40ms jitter=10ms rate=256"
+// forwards what comes to 127.0.0.1:9000 on to 127.0.0.1:8080 and the replies back,
+// impaired as described (see netsim.rs). A second description is used for the way
+// back, otherwise both ways get the first one.
+use p2p_rust::netsim::{run_proxy, Impairment};
+use std::net::SocketAddr;
+use std::process::ExitCode;
+use tokio::net::UdpSocket;
+
+const USAGE: &str = "usage: udp_proxy <listen addr> <upstream addr> [impairment] [impairment back]";
+
+async fn run(listen: &str, upstream: &str, forward: &str, back: Option<&str>) -> Result<(), String> {
+    let upstream: SocketAddr = upstream.parse().map_err(|_| format!("\"{}\" isn't an address", upstream))?;
+    let forward = Impairment::parse(forward)?;
+    let back = match back {
+        Some(back) => Impairment::parse(back)?,
+        None => forward.clone(),
+    };
+    let socket = UdpSocket::bind(listen).await.map_err(|e| format!("Can't listen on {}: {}", listen, e))?;
+    println!("🐢 Forwarding {} to {}", socket.local_addr().map_err(|e| e.to_string())?, upstream);
+    println!("   there: {:?}\n   back:  {:?}", forward, back);
+    run_proxy(socket, upstream, forward, back).await.map_err(|e| e.to_string())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let (listen, upstream, forward, back) = match args.as_slice() {
+        [listen, upstream] => (*listen, *upstream, "", None),
+        [listen, upstream, forward] => (*listen, *upstream, *forward, None),
+        [listen, upstream, forward, back] => (*listen, *upstream, *forward, Some(*back)),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    match run(listen, upstream, forward, back).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
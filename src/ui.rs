@@ -1,27 +1,597 @@
-use helper::send_json_value;
-use slint::{Timer, TimerMode, ModelRc, VecModel, SharedString, spawn_local};
+use slint::{Timer, TimerMode, Model, ModelRc, VecModel, SharedString, spawn_local};
 use rfd::FileDialog;
 use std::io::{self, Write};
 slint::include_modules!();
-use serde_json::{Value};    
+use serde_json::{json, Value};    
 use tokio::net::TcpStream;
 use tokio::task;
-mod helper;
-use helper::print_json;
-use helper::get_pip_port_json;
-use helper::keys_from_json_str;
-use helper::get_clients;
+use p2p_rust::signaling::get_pip_port_json_and_sockets;
+use p2p_rust::signaling::keys_from_json_str;
+use p2p_rust::signaling::get_clients;
+use p2p_rust::signaling::get_users;
+use p2p_rust::signaling::server_request;
 use std::error::Error;
 use tokio::io::AsyncWriteExt;
 use tokio::io::AsyncReadExt;
-mod true_test;
-use true_test::relay_send;
-use tokio_tungstenite::connect_async;
+use p2p_rust::send::{mailbox_send, relay_clipboard, relay_fanout, relay_mirror, share_browse, share_pull, swarm_download, wormhole_send, RecipientStatus, RelayPeer};
+use p2p_rust::swarm::{add_seed, seeds, Manifest};
+use p2p_rust::clipboard::Clip;
+use p2p_rust::history::{self, Direction, Record, Route};
+use p2p_rust::offline::Sealer;
+use p2p_rust::ratelimit::{limits, minute_now, set_limits, Limits, Rates, Window};
+use p2p_rust::shares::{self, set_shares, Entry, Share};
+use p2p_rust::store::{load_json, save_json};
+use std::collections::BTreeMap;
+use arboard::Clipboard;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 use tokio_tungstenite::tungstenite::protocol::Message;
-use tokio::sync::Mutex;
+use tokio::sync::{oneshot, watch, Mutex};
+use tokio::time::timeout;
 use std::sync::Arc;
-mod test_receiver;
-use test_receiver::relay_receive;
+use p2p_rust::receive::{mailbox_fetch, wormhole_receive, Destination, OFFER_TIMEOUT};
+use p2p_rust::keepalive::spawn_keepalive;
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::rc::Rc;
+use tokio::task::JoinHandle;
+use p2p_rust::tls::{connect_signaling, SignalingConfig};
+use p2p_rust::session::load_token;
+use p2p_rust::chat::{clock, open_chat, ChatEntry, ChatEvent, ChatSender, Conversations, Delivery};
+use p2p_rust::presence::{watch_presence, Presence, PresenceEvent};
+use p2p_rust::events::subscribe;
+use p2p_rust::{Client, Source, TransferEvent};
+use futures_util::StreamExt;
+use p2p_rust::contacts::{auto_accept, contacts, favorites_first, remove_contact, save_contact, update_contact, AutoAccept, Contact};
+use p2p_rust::identity::{accept_pending_key, mark_verified, peer_trust, pending_key, pinned_key, safety_code, Handshake, Identity, Trust};
+
+// Fills the sender page's identity box for `peer`. `published` is the key the server
+// reports, it is only shown until the first session pins the real one.
+fn show_peer_identity(app: &TestWindow, my_key: &str, peer: &str, published: Option<String>) {
+    let pending = pending_key(peer);
+    app.set_key_changed(pending.is_some());
+    let (code, status) = match (pending, pinned_key(peer), published) {
+        (Some(pending), _, _) => (
+            safety_code(my_key, &pending),
+            format!("⚠️ The identity key of {} changed. Only trust the new key if {} sees the same code.", peer, peer),
+        ),
+        (None, Some(pinned), published) => {
+            let status = match peer_trust(peer, published.as_deref().unwrap_or(&pinned)) {
+                Trust::Changed => format!("⚠️ The server reports a different key for {} than the one you pinned.", peer),
+                Trust::Known { verified: true } => format!("✅ You verified {}.", peer),
+                Trust::Known { verified: false } | Trust::FirstUse => {
+                    format!("🔐 Known key. Compare the code with {} to verify it.", peer)
+                }
+            };
+            (safety_code(my_key, &pinned), status)
+        }
+        (None, None, Some(published)) => (
+            safety_code(my_key, &published),
+            format!("🆕 First contact with {}. Compare the code with them before sending anything private.", peer),
+        ),
+        (None, None, None) => (String::new(), format!("{} hasn't published an identity key.", peer)),
+    };
+    app.set_peer_code(SharedString::from(code));
+    app.set_peer_status(SharedString::from(status));
+}
+
+// Runs `f` on the system clipboard, opened on first use and then kept: on X11 what we
+// put in it is only there while we hold it
+fn with_clipboard<T>(clipboard: &RefCell<Option<Clipboard>>, f: impl FnOnce(&mut Clipboard) -> Result<T, String>) -> Result<T, String> {
+    let mut clipboard = clipboard.borrow_mut();
+    let clipboard = match clipboard.as_mut() {
+        Some(clipboard) => clipboard,
+        None => clipboard.insert(Clipboard::new().map_err(|e| format!("No clipboard: {}", e))?),
+    };
+    f(clipboard)
+}
+
+// Shows a received clipboard share on the receiver page
+fn show_clip(app: &TestWindow, sender: &str, clip: &Clip) {
+    app.set_clip_from(SharedString::from(sender));
+    match clip {
+        Clip::Text(text) => {
+            app.set_clip_text(SharedString::from(text.as_str()));
+            app.set_clip_is_image(false);
+        }
+        Clip::Image { width, height, rgba } => {
+            let pixels = slint::SharedPixelBuffer::<slint::Rgba8Pixel>::clone_from_slice(rgba, *width as u32, *height as u32);
+            app.set_clip_image(slint::Image::from_rgba8(pixels));
+            app.set_clip_text(SharedString::new());
+            app.set_clip_is_image(true);
+        }
+    }
+}
+
+// Fills the chat page with the conversation with `peer`, which counts as read now
+fn show_conversation(app: &TestWindow, conversations: &mut Conversations, peer: &str) {
+    conversations.mark_read(peer);
+    let lines: Vec<ChatLine> = conversations
+        .get(peer)
+        .iter()
+        .map(|entry| {
+            let mark = match &entry.delivery {
+                _ if !entry.from_me => String::new(),
+                Delivery::Sending => " …".to_string(),
+                Delivery::Delivered => " ✓".to_string(),
+                Delivery::Failed(e) => format!(" ❌ {}", e),
+            };
+            ChatLine { from_me: entry.from_me, text: entry.text.as_str().into(), meta: format!("{}{}", clock(entry.timestamp), mark).into() }
+        })
+        .collect();
+    app.set_chat_lines(ModelRc::new(VecModel::from(lines)));
+    app.set_unread_chats(conversations.unread() as i32);
+}
+
+// Keeps the chat page and the unread count in step with the chat connection
+fn on_chat_event(app_weak: &slint::Weak<TestWindow>, conversations: &RefCell<Conversations>, event: ChatEvent) {
+    let Some(app) = app_weak.upgrade() else { return };
+    let mut conversations = conversations.borrow_mut();
+    let peer = match event {
+        ChatEvent::Received { from, id, text, timestamp } => {
+            let entry = ChatEntry { id, from_me: false, text, timestamp, delivery: Delivery::Delivered };
+            conversations.push(&from, entry);
+            Some(from)
+        }
+        ChatEvent::Delivered { id } => conversations.mark(&id, Delivery::Delivered),
+        ChatEvent::Failed { id, error } => conversations.mark(&id, Delivery::Failed(error)),
+        ChatEvent::Closed(error) => {
+            app.set_chat_status(SharedString::from(format!("❌ Chat disconnected: {}", error)));
+            None
+        }
+    };
+    let showing = app.get_show_chat_page() && peer.as_deref() == Some(app.get_chat_peer().as_str());
+    match peer {
+        Some(peer) if showing => show_conversation(&app, &mut conversations, &peer),
+        _ => app.set_unread_chats(conversations.unread() as i32),
+    }
+}
+
+// Sends `path` to `targets` (row in the recipient list, username), one signaling
+// connection each, and keeps every row's progress and status up to date
+#[allow(clippy::too_many_arguments)]
+async fn fan_out(
+    app_weak: slint::Weak<TestWindow>,
+    config: Rc<SignalingConfig>,
+    identity: Identity,
+    me: String,
+    token: String,
+    targets: Vec<(usize, String)>,
+    path: PathBuf,
+    room: Option<String>,
+) {
+    let set_row = |row: usize, progress: f32, status: String| {
+        let Some(app) = app_weak.upgrade() else { return };
+        let rows = app.get_recipients();
+        if let Some(mut data) = rows.row_data(row) {
+            data.progress = progress;
+            data.status = status.into();
+            rows.set_row_data(row, data);
+        }
+    };
+
+    let mut recipients = Vec::new();
+    let mut rows_of = Vec::new();
+    for (row, target) in targets {
+        match connect_signaling(&config).await {
+            Ok(ws_stream) => {
+                let mut handshake = Handshake::initiator(identity.clone(), &me, &target);
+                let hello = handshake.hello();
+                recipients.push(RelayPeer { target, ws_stream, hello, on_identity: Box::new(move |msg| handshake.on_message(msg)) });
+                rows_of.push(row);
+            }
+            Err(e) => set_row(row, 0.0, format!("❌ {}", e)),
+        }
+    }
+
+    let result = relay_fanout(recipients, token, path, room, |index, status| {
+        let (progress, text) = match status {
+            RecipientStatus::Connecting => (0.0, "Connecting…".to_string()),
+            RecipientStatus::Sending(percent) => (percent as f32 / 100.0, format!("{:.0}%", percent)),
+            RecipientStatus::Done => (1.0, "✅ Done".to_string()),
+            RecipientStatus::Failed(e) => (0.0, format!("❌ {}", e)),
+        };
+        set_row(rows_of[index], progress, text);
+    }).await;
+
+    let Some(app) = app_weak.upgrade() else { return };
+    app.set_sending_many(false);
+    if let Err(e) = result {
+        eprintln!("Error sending: {}", e);
+        app.set_output(SharedString::from(format!("❌ {}", e)));
+    }
+}
+
+// "alice, bob (offline)" for a room from the server's room/rooms replies
+fn room_members(info: &Value) -> String {
+    info["members"]
+        .as_array()
+        .map(|members| {
+            members
+                .iter()
+                .map(|m| {
+                    let name = m["username"].as_str().unwrap_or_default();
+                    if m["online"].as_bool() == Some(true) { name.to_string() } else { format!("{} (offline)", name) }
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .unwrap_or_default()
+}
+
+// Hashes `path`, adds it to the seeds and announces it, returns its name and content hash
+async fn seed_file(
+    path: PathBuf,
+    token: String,
+    ws_stream: Arc<Mutex<WebSocketStream<MaybeTlsStream<TcpStream>>>>,
+) -> Result<(String, String), String> {
+    let manifest_path = path.clone();
+    let manifest = task::spawn_blocking(move || Manifest::build(&manifest_path))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    let name = manifest.name.clone();
+    let hash = add_seed(&path, manifest).map_err(|e| e.to_string())?;
+    server_request(json!({"type": "announce", "hash": hash, "token": token}), "announced", ws_stream).await?;
+    Ok((name, hash))
+}
+
+// Names of the files we left on the server, by mailbox id, so receipts can say which
+const MAILBOX_SENT_FILE: &str = "mailbox_sent.json";
+
+// "2 days", "5 hours" until `expires` (seconds since the epoch)
+fn time_left(expires: u64) -> String {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let hours = expires.saturating_sub(now) / 3600;
+    if hours >= 48 { format!("{} days", hours / 24) } else { format!("{} hours", hours) }
+}
+
+fn set_mail_row_status(app: &TestWindow, id: &str, status: String) {
+    let rows = app.get_mail_rows();
+    if let Some((row, mut data)) = rows.iter().enumerate().find(|(_, data)| data.id == id) {
+        data.status = status.into();
+        rows.set_row_data(row, data);
+    }
+}
+
+// Fills the mailbox page: what waits for us on the server, and the receipts for what
+// we left for others. The server hands each receipt out once, they are kept in `receipts`.
+async fn refresh_mailbox(app_weak: slint::Weak<TestWindow>, config: Rc<SignalingConfig>, token: String, receipts: Rc<RefCell<Vec<String>>>) {
+    let request = json!({"type": "mailbox_list", "token": token});
+    let reply = match connect_signaling(&config).await {
+        Ok(ws_stream) => server_request(request, "mailbox", Arc::new(Mutex::new(ws_stream))).await,
+        Err(e) => Err(e),
+    };
+    let Some(app) = app_weak.upgrade() else { return };
+    let reply = match reply {
+        Ok(reply) => reply,
+        Err(e) => return app.set_mail_status(SharedString::from(format!("❌ Mailbox unavailable: {}", e))),
+    };
+
+    let items = reply["items"].as_array().cloned().unwrap_or_default();
+    let rows: Vec<MailRow> = items
+        .iter()
+        .map(|item| MailRow {
+            id: item["id"].as_str().unwrap_or_default().into(),
+            from: item["from"].as_str().unwrap_or_default().into(),
+            detail: format!("{} bytes, kept for {}", item["size"].as_u64().unwrap_or(0), time_left(item["expires"].as_u64().unwrap_or(0))).into(),
+            status: SharedString::new(),
+        })
+        .collect();
+    app.set_mail_waiting(rows.len() as i32);
+    app.set_mail_rows(ModelRc::new(VecModel::from(rows)));
+
+    let new_receipts = reply["receipts"].as_array().cloned().unwrap_or_default();
+    if !new_receipts.is_empty() {
+        let mut sent: BTreeMap<String, String> = load_json(MAILBOX_SENT_FILE);
+        let mut receipts = receipts.borrow_mut();
+        for receipt in new_receipts {
+            let name = sent.remove(receipt["id"].as_str().unwrap_or_default()).unwrap_or_else(|| "A file".to_string());
+            let to = receipt["to"].as_str().unwrap_or_default();
+            let line = match receipt["status"].as_str() {
+                Some("delivered") => format!("✅ {} was delivered to {}", name, to),
+                Some("expired") => format!("⌛ {} expired before {} fetched it", name, to),
+                _ => format!("🗑️ {} deleted {} without fetching it", to, name),
+            };
+            receipts.insert(0, line);
+        }
+        if let Err(e) = save_json(MAILBOX_SENT_FILE, &sent) {
+            eprintln!("❌ Failed to save {}: {}", MAILBOX_SENT_FILE, e);
+        }
+        app.set_mail_receipts(SharedString::from(receipts.join("\n")));
+    }
+}
+
+// Seals `path` for `target` and leaves it on the server until they fetch it. The key we
+// pinned in an earlier session beats the one the server remembers.
+async fn leave_for_later(
+    config: &SignalingConfig,
+    identity: &Identity,
+    me: &str,
+    token: &str,
+    target: &str,
+    path: PathBuf,
+    on_progress: impl FnMut(f64),
+) -> Result<(String, u64), String> {
+    if pending_key(target).is_some() {
+        return Err(format!("The identity key of {} changed, compare safety codes first", target));
+    }
+    let key = match pinned_key(target) {
+        Some(key) => key,
+        None => {
+            let ws_stream = connect_signaling(config).await?;
+            let request = json!({"type": "mailbox_key", "to": target, "token": token});
+            let reply = server_request(request, "mailbox_key", Arc::new(Mutex::new(ws_stream))).await?;
+            let key = reply["identity_key"].as_str().ok_or_else(|| format!("{} hasn't published an identity key", target))?;
+            key.to_string()
+        }
+    };
+    let (sealer, ephemeral) = Sealer::new(&key)?;
+    let header = identity.offline_header(me, target, &ephemeral);
+    let ws_stream = connect_signaling(config).await?;
+    mailbox_send(ws_stream, target.to_string(), token.to_string(), sealer, header, path, on_progress).await.map_err(|e| e.to_string())
+}
+
+// Fills the history page with the records matching its filters, newest first. The
+// records behind the rows go to `shown`, the row buttons refer to them by index.
+fn show_history(app: &TestWindow, shown: &RefCell<Vec<Record>>) {
+    let query = app.get_history_query();
+    let direction = match app.get_history_direction().as_str() {
+        "Sent" => Some(Direction::Sent),
+        "Received" => Some(Direction::Received),
+        _ => None,
+    };
+    let failed_only = app.get_history_failed_only();
+    let records: Vec<Record> = history::load()
+        .into_iter()
+        .rev()
+        .filter(|r| r.matches(&query) && direction.is_none_or(|d| r.direction == d) && (!failed_only || r.error.is_some()))
+        .collect();
+    let rows: Vec<HistoryRow> = records
+        .iter()
+        .enumerate()
+        .map(|(index, r)| {
+            let files: Vec<&str> = r.files.iter().map(|f| f.name.as_str()).collect();
+            let route = match r.route {
+                Route::Relay => "relay",
+                Route::Direct => "direct",
+                Route::Lan => "LAN",
+                Route::Mailbox => "mailbox",
+            };
+            let outcome = match &r.error {
+                None => "✅".to_string(),
+                Some(e) => format!("❌ {}", e),
+            };
+            HistoryRow {
+                index: index as i32,
+                when: history::format_time(r.started).into(),
+                direction: if r.direction == Direction::Sent { "⬆️ to" } else { "⬇️ from" }.into(),
+                peer: r.peer.as_str().into(),
+                files: files.join(", ").into(),
+                detail: format!("{} bytes · {} · {:.1}s · {}", r.size(), route, r.duration_ms as f64 / 1000.0, outcome).into(),
+                ok: r.error.is_none(),
+                can_resend: r.direction == Direction::Sent && resend_path(r).is_some(),
+            }
+        })
+        .collect();
+    app.set_history_rows(ModelRc::new(VecModel::from(rows)));
+    *shown.borrow_mut() = records;
+}
+
+// The file to send again for a record, if it went to a user and is still there
+fn resend_path(record: &Record) -> Option<PathBuf> {
+    if record.route == Route::Relay && record.peer == "wormhole" {
+        return None;
+    }
+    match record.files.as_slice() {
+        [file] => file.path.clone().filter(|path| path.is_file()),
+        _ => None,
+    }
+}
+
+// Shows `dir` in the system's file manager
+fn open_folder(dir: &std::path::Path) -> io::Result<()> {
+    let program = if cfg!(target_os = "windows") {
+        "explorer"
+    } else if cfg!(target_os = "macos") {
+        "open"
+    } else {
+        "xdg-open"
+    };
+    std::process::Command::new(program).arg(dir).spawn().map(|_| ())
+}
+
+// Favourites in the recipient picker are labelled with this in front of the username
+const FAVORITE_MARK: &str = "★ ";
+// How long to wait before subscribing to presence again after losing the connection
+const PRESENCE_RETRY: std::time::Duration = std::time::Duration::from_secs(10);
+
+// Fills the contacts page, favourites first. `online` is everyone registered right now.
+fn show_contacts(app: &TestWindow, my_key: &str, online: &[String]) {
+    let mut contacts: Vec<(String, Contact)> = contacts().into_iter().collect();
+    contacts.sort_by_key(|(_, contact)| !contact.favorite);
+    let rows: Vec<ContactRow> = contacts
+        .into_iter()
+        .map(|(username, contact)| ContactRow {
+            online: online.contains(&username),
+            key: match pinned_key(&username) {
+                Some(key) => safety_code(my_key, &key).into(),
+                None => "No key pinned yet, the first transfer pins it".into(),
+            },
+            username: username.into(),
+            nickname: contact.nickname.into(),
+            favorite: contact.favorite,
+            policy: contact.auto_accept.name().into(),
+        })
+        .collect();
+    app.set_contact_rows(ModelRc::new(VecModel::from(rows)));
+}
+
+// Fills the recipient picker and list with `clients`, favourites on top whether online
+// or not. Ticks survive for whoever is still listed. The list is left alone while a
+// fan-out is using its rows.
+fn show_clients(app: &TestWindow, clients: Vec<String>) {
+    let clients = favorites_first(clients);
+    let contacts = contacts();
+    let labels: Vec<SharedString> = clients
+        .iter()
+        .map(|name| if contacts.get(name).is_some_and(|c| c.favorite) { format!("{}{}", FAVORITE_MARK, name) } else { name.clone() })
+        .map(Into::into)
+        .collect();
+    app.set_available_clients(ModelRc::new(VecModel::from(labels)));
+    if app.get_sending_many() {
+        return;
+    }
+    let ticked: Vec<SharedString> = app.get_recipients().iter().filter(|row| row.selected).map(|row| row.name).collect();
+    let rows: Vec<RecipientRow> = clients
+        .into_iter()
+        .map(|name| RecipientRow { selected: ticked.iter().any(|t| t.as_str() == name), name: name.into(), ..Default::default() })
+        .collect();
+    app.set_recipients(ModelRc::new(VecModel::from(rows)));
+}
+
+// Keeps a presence subscription open on a connection of its own for as long as the
+// app runs, and redraws whatever shows who is online after every change
+async fn watch_online(app_weak: slint::Weak<TestWindow>, config: Rc<SignalingConfig>, presence: Rc<RefCell<Presence>>, my_key: String) {
+    loop {
+        let error = match connect_signaling(&config).await {
+            Ok(ws_stream) => {
+                watch_presence(ws_stream, &presence, |event| {
+                    match &event {
+                        PresenceEvent::Snapshot => println!("👥 {} online", presence.borrow().online().len()),
+                        PresenceEvent::Online(username) => println!("🟢 {} came online", username),
+                        PresenceEvent::Updated(username) => println!("🔄 {} registered again", username),
+                        PresenceEvent::Offline(username) => println!("⚪ {} went offline", username),
+                    }
+                    let Some(app) = app_weak.upgrade() else { return };
+                    let online = presence.borrow().online();
+                    if matches!(event, PresenceEvent::Snapshot) {
+                        app.set_contact_status(SharedString::new());
+                    }
+                    if app.get_show_contacts_page() {
+                        show_contacts(&app, &my_key, &online);
+                    }
+                    show_clients(&app, online);
+                })
+                .await
+            }
+            Err(e) => e,
+        };
+        eprintln!("❌ Presence updates stopped: {}", error);
+        let Some(app) = app_weak.upgrade() else { return };
+        app.set_contact_status(SharedString::from(format!("❌ Can't tell who is online: {}", error)));
+        drop(app);
+        tokio::time::sleep(PRESENCE_RETRY).await;
+    }
+}
+
+// Moves the progress bars on the send and receive pages along with whichever file
+// reported last, for as long as the app runs. Plain sends and receives report to the
+// client, the other transfers to the whole process.
+async fn show_transfers(app_weak: slint::Weak<TestWindow>, client: Rc<Client>) {
+    let mut events = futures::stream::select(subscribe(), client.events());
+    while let Some(event) = events.next().await {
+        let Some(app) = app_weak.upgrade() else { return };
+        let (progress, status) = transfer_text(&event);
+        app.set_transfer_progress(progress);
+        app.set_transfer_status(SharedString::from(status));
+    }
+}
+
+// The progress bar fill and the line on it
+fn transfer_text(event: &TransferEvent) -> (f32, String) {
+    let label = |direction: &Direction, peer: &str, name: &str| match direction {
+        Direction::Sent => format!("📤 {} to {}", name, peer),
+        Direction::Received => format!("📥 {} from {}", name, peer),
+    };
+    match event {
+        TransferEvent::Started { direction, peer, name, .. } => (0.0, label(direction, peer, name)),
+        TransferEvent::Progress { direction, peer, name, bytes, size: Some(size) } => {
+            let done = *bytes as f32 / (*size).max(1) as f32;
+            (done, format!("{}: {:.0}%", label(direction, peer, name), done * 100.0))
+        }
+        // A stream, no telling how far along it is
+        TransferEvent::Progress { direction, peer, name, bytes, size: None } => {
+            (0.0, format!("{}: {} bytes", label(direction, peer, name), bytes))
+        }
+        TransferEvent::Finished { direction, peer, name, .. } => (1.0, format!("✅ {}", label(direction, peer, name))),
+        TransferEvent::Failed { direction, peer, name, error } => (0.0, format!("❌ {}: {}", label(direction, peer, name), error)),
+    }
+}
+
+// "200 KB/s", or "no limit" for 0
+fn rate_text(kb: u64) -> String {
+    if kb == 0 { "no limit".to_string() } else { format!("{} KB/s", kb) }
+}
+
+// What the limits mean right now, the schedule may have swapped in other global ones
+fn describe_limits(limits: &Limits) -> String {
+    let minute = minute_now();
+    let (global, window) = limits.global_at(minute);
+    let source = window.map_or(String::new(), |w| format!(" (schedule {})", w));
+    format!("Now {:02}:{:02} UTC: up {}, down {}{}", minute / 60, minute % 60, rate_text(global.upload), rate_text(global.download), source)
+}
+
+// A KB/s field, empty means no limit
+fn parse_rate(field: &str, label: &str) -> Result<u64, String> {
+    let field = field.trim();
+    if field.is_empty() {
+        return Ok(0);
+    }
+    field.parse().map_err(|_| format!("{} should be a number of KB/s", label))
+}
+
+fn limits_from_page(app: &TestWindow) -> Result<Limits, String> {
+    let global = Rates {
+        upload: parse_rate(&app.get_limit_upload(), "The upload limit")?,
+        download: parse_rate(&app.get_limit_download(), "The download limit")?,
+    };
+    let per_transfer = Rates {
+        upload: parse_rate(&app.get_per_transfer_upload(), "The upload limit per transfer")?,
+        download: parse_rate(&app.get_per_transfer_download(), "The download limit per transfer")?,
+    };
+    let schedule = app.get_limit_schedule().lines().filter(|line| !line.trim().is_empty()).map(Window::parse).collect::<Result<_, _>>()?;
+    Ok(Limits { global, per_transfer, schedule })
+}
+
+fn show_shares(app: &TestWindow) {
+    let rows: Vec<ShareRow> = shares::shares()
+        .into_iter()
+        .map(|share| ShareRow {
+            name: share.name.into(),
+            folder: share.root.display().to_string().into(),
+            readers: share.readers.join(", ").into(),
+        })
+        .collect();
+    app.set_share_rows(ModelRc::new(VecModel::from(rows)));
+}
+
+// Shows a listing of `peer`'s `share` at `path`, the shares themselves when `share` is empty
+fn show_remote(app: &TestWindow, peer: &str, share: &str, path: &str, entries: Vec<Entry>) {
+    let rows: Vec<RemoteRow> = entries
+        .iter()
+        .map(|entry| {
+            let detail = match (entry.dir, share.is_empty()) {
+                (true, true) => "Shared folder".to_string(),
+                (true, false) => "Folder".to_string(),
+                (false, _) => format!("{} bytes · {}", entry.size, history::format_time(entry.modified)),
+            };
+            let (share, path) = if share.is_empty() { (entry.path.as_str(), "") } else { (share, entry.path.as_str()) };
+            RemoteRow { share: share.into(), path: path.into(), name: entry.name().into(), detail: detail.into(), dir: entry.dir }
+        })
+        .collect();
+    app.set_remote_peer(peer.into());
+    app.set_remote_share(share.into());
+    app.set_remote_path(path.into());
+    app.set_remote_rows(ModelRc::new(VecModel::from(rows)));
+}
+
+// A relay connection to `target` with the identity handshake ready to go
+async fn connect_peer(config: &SignalingConfig, identity: &Identity, me: &str, target: &str) -> Result<RelayPeer, String> {
+    let ws_stream = connect_signaling(config).await?;
+    let mut handshake = Handshake::initiator(identity.clone(), me, target);
+    let hello = handshake.hello();
+    Ok(RelayPeer { target: target.to_string(), ws_stream, hello, on_identity: Box::new(move |msg| handshake.on_message(msg)) })
+}
 
 fn process_input_json(input: SharedString) {
     let input_str = input.as_str();
@@ -40,75 +610,1150 @@ fn process_input_json(input: SharedString) {
 
 #[tokio::main]
 async fn main(){
-    let (ws_stream, _) = connect_async("ws://54.66.23.75:8765").await.expect("Failed to connect");
-    let ws_stream = Arc::new(Mutex::new(ws_stream));
     let app = TestWindow::new().unwrap(); 
+
+    let identity = match Identity::load_or_create() {
+        Ok(identity) => identity,
+        Err(error) => {
+            eprintln!("❌ Can't load identity key: {}", error);
+            app.set_connected(false);
+            app.set_output(SharedString::from(format!("❌ Can't load identity key: {}", error)));
+            app.run().unwrap();
+            return;
+        }
+    };
+    // Without a server connection there is nothing to do but show why
+    let connection = match SignalingConfig::from_env() {
+        Ok(config) => Client::connect(config, identity.clone()).await,
+        Err(e) => Err(e),
+    };
+    let client = match connection {
+        Ok(client) => Rc::new(client),
+        Err(error) => {
+            eprintln!("❌ {}", error);
+            app.set_connected(false);
+            app.set_output(SharedString::from(format!("❌ {}", error)));
+            app.run().unwrap();
+            return;
+        }
+    };
+    // Requests the client has no method for go over its connection, with its token
+    let ws_stream = client.connection();
+    // Fan-out sends open a connection per recipient
+    let config = Rc::new(client.config().clone());
     let app_weak = app.as_weak();
     
+    // NAT keep-alive task, runs while we're registered
+    let keepalive: Rc<RefCell<Option<JoinHandle<()>>>> = Rc::new(RefCell::new(None));
+
+    // Chat runs on its own connection, opened at register
+    let chat: Rc<RefCell<Option<ChatSender>>> = Rc::new(RefCell::new(None));
+    let conversations: Rc<RefCell<Conversations>> = Rc::new(RefCell::new(Conversations::default()));
+
+    // Opened on first use, see with_clipboard. The last share we received is kept for
+    // the Copy button.
+    let clipboard: Rc<RefCell<Option<Clipboard>>> = Rc::new(RefCell::new(None));
+    let last_clip: Rc<RefCell<Option<Clip>>> = Rc::new(RefCell::new(None));
+
+    // Receipts for files we left on the server, newest first
+    let mail_receipts: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+
+    app.on_has_session(|username: SharedString| load_token(username.as_str()).is_some());
+
     // Register event handler
     let weak_app_register = app.as_weak();
-    let ws_stream_clone_register = ws_stream.clone();
+    let client_register = client.clone();
+    let keepalive_register = keepalive.clone();
+    let config_register = config.clone();
+    let chat_register = chat.clone();
+    let conversations_register = conversations.clone();
+    let mail_receipts_register = mail_receipts.clone();
     app.on_register(move |username: SharedString, password: SharedString| {
         let app_weak = weak_app_register.clone();
-        let ws_stream = ws_stream_clone_register.clone();
+        let client = client_register.clone();
+        let keepalive = keepalive_register.clone();
+        let config = config_register.clone();
+        let chat = chat_register.clone();
+        let conversations = conversations_register.clone();
+        let mail_receipts = mail_receipts_register.clone();
         
         // Use async block directly
         let _ = slint::spawn_local(async move {
-            let pip_port_json = get_pip_port_json(username.as_str(), password.as_str());
-            let pip_port_string = serde_json::to_string(&pip_port_json).unwrap();
-            
-            // Send JSON asynchronously
-            if let Err(e) = send_json_value(&pip_port_json, ws_stream).await {
-                eprintln!("Error sending JSON: {}", e);
-            }
+            let (mut pip_port_json, sockets) = get_pip_port_json_and_sockets(username.as_str(), password.as_str());
+
+            // Reconnects with the stored token when there is one, the password is the fallback
+            let password = Some(password.as_str()).filter(|password| !password.is_empty());
+            let result = client.register_at(username.as_str(), password, &pip_port_json).await;
+
+            let Some(app_strong) = app_weak.upgrade() else { return };
+            match result.map(|()| client.token().unwrap_or_default()) {
+                Ok(token) => {
+                    let ws_stream = client.connection();
+
+                    // Tell the server again which files we can hand out to a swarm
+                    for hash in seeds().into_keys() {
+                        let announce = json!({"type": "announce", "hash": hash, "token": token});
+                        if let Err(e) = server_request(announce, "announced", ws_stream.clone()).await {
+                            eprintln!("❌ Failed to announce {}: {}", hash, e);
+                        }
+                    }
+
+                    // Replacing the sender closes the previous chat connection
+                    let opened = match connect_signaling(&config).await {
+                        Ok(chat_stream) => open_chat(chat_stream, &token).await,
+                        Err(e) => Err(e.to_string()),
+                    };
+                    match opened {
+                        Ok((sender, listener)) => {
+                            *chat.borrow_mut() = Some(sender);
+                            app_strong.set_chat_status(SharedString::new());
+                            let app_weak = app_weak.clone();
+                            slint::spawn_local(listener.run(move |event| on_chat_event(&app_weak, &conversations, event))).unwrap();
+                        }
+                        Err(e) => {
+                            *chat.borrow_mut() = None;
+                            app_strong.set_chat_status(SharedString::from(format!("❌ Chat unavailable: {}", e)));
+                        }
+                    }
+
+                    // Anything left for us while we were offline
+                    slint::spawn_local(refresh_mailbox(app_weak.clone(), config.clone(), token.clone(), mail_receipts)).unwrap();
 
-            // Update UI output
-            if let Some(app_strong) = app_weak.upgrade() {
-                app_strong.set_output(SharedString::from(pip_port_string));
+                    // Keep-alive re-registers with the token, never the password
+                    pip_port_json["password"] = Value::Null;
+                    pip_port_json["token"] = Value::from(token);
+                    let handle = spawn_keepalive(pip_port_json, sockets, (*config).clone());
+                    if let Some(previous) = keepalive.borrow_mut().replace(handle) {
+                        previous.abort();
+                    }
+
+                    app_strong.set_output(SharedString::new());
+                    app_strong.set_show_register_page(false);
+                    app_strong.set_show_picker_page(true);
+                }
+                Err(error) => {
+                    app_strong.set_output(SharedString::from(format!("❌ Registration failed: {}", error)));
+                }
             }
         });
     });
 
+    let keepalive_unregister = keepalive.clone();
+    let chat_unregister = chat.clone();
+    app.on_unregister(move || {
+        if let Some(handle) = keepalive_unregister.borrow_mut().take() {
+            handle.abort();
+        }
+        chat_unregister.borrow_mut().take();
+    });
+
+    // Chat page: everyone online plus anyone we already talked to
+    let weak_app_chat_page = app.as_weak();
+    let config_chat_page = config.clone();
+    let conversations_chat_page = conversations.clone();
+    let client_chat_page = client.clone();
+    app.on_open_chat_page(move || {
+        let app_weak = weak_app_chat_page.clone();
+        let config = config_chat_page.clone();
+        let conversations = conversations_chat_page.clone();
+        let me = client_chat_page.username().unwrap_or_default();
+        slint::spawn_local(async move {
+            // A connection of its own, the main one may be busy with a transfer
+            let online = match connect_signaling(&config).await {
+                Ok(ws_stream) => get_clients(Arc::new(Mutex::new(ws_stream))).await.map(keys_from_json_str).unwrap_or_default(),
+                Err(e) => {
+                    eprintln!("❌ Failed to get clients: {}", e);
+                    Vec::new()
+                }
+            };
+            let Some(app) = app_weak.upgrade() else { return };
+            let mut peers: Vec<String> = online.into_iter().chain(conversations.borrow().peers().cloned()).filter(|peer| *peer != me).collect();
+            peers.sort();
+            peers.dedup();
+            app.set_chat_peers(ModelRc::new(VecModel::from(peers.into_iter().map(SharedString::from).collect::<Vec<_>>())));
+            let peer = app.get_chat_peer();
+            if !peer.is_empty() {
+                show_conversation(&app, &mut conversations.borrow_mut(), &peer);
+            }
+        }).unwrap();
+    });
+
+    let weak_app_chat_peer = app.as_weak();
+    let conversations_chat_peer = conversations.clone();
+    app.on_select_chat_peer(move |peer: SharedString| {
+        let Some(app) = weak_app_chat_peer.upgrade() else { return };
+        app.set_chat_peer(peer.clone());
+        show_conversation(&app, &mut conversations_chat_peer.borrow_mut(), &peer);
+    });
+
+    let weak_app_chat_send = app.as_weak();
+    let chat_send = chat.clone();
+    let conversations_chat_send = conversations.clone();
+    app.on_send_chat(move |peer: SharedString, text: SharedString| {
+        let Some(app) = weak_app_chat_send.upgrade() else { return };
+        let sent = match chat_send.borrow().as_ref() {
+            Some(chat) => chat.send(&peer, &text),
+            None => Err("Chat is not connected".to_string()),
+        };
+        match sent {
+            Ok(entry) => {
+                let mut conversations = conversations_chat_send.borrow_mut();
+                conversations.push(&peer, entry);
+                show_conversation(&app, &mut conversations, &peer);
+            }
+            Err(e) => app.set_chat_status(SharedString::from(format!("❌ {}", e))),
+        }
+    });
+
+    // Mailbox: files left on the server while we were offline, over fresh connections
+    let weak_app_mailbox_page = app.as_weak();
+    let config_mailbox_page = config.clone();
+    let client_mailbox_page = client.clone();
+    let mail_receipts_page = mail_receipts.clone();
+    app.on_open_mailbox_page(move || {
+        let token = client_mailbox_page.token().unwrap_or_default();
+        let refresh = refresh_mailbox(weak_app_mailbox_page.clone(), config_mailbox_page.clone(), token, mail_receipts_page.clone());
+        slint::spawn_local(refresh).unwrap();
+    });
+
+    let weak_app_fetch_mail = app.as_weak();
+    let config_fetch_mail = config.clone();
+    let client_fetch_mail = client.clone();
+    let identity_fetch_mail = identity.clone();
+    let mail_receipts_fetch = mail_receipts.clone();
+    app.on_fetch_mail(move |id: SharedString| {
+        let app_weak = weak_app_fetch_mail.clone();
+        let config = config_fetch_mail.clone();
+        let token = client_fetch_mail.token().unwrap_or_default();
+        let me = client_fetch_mail.username().unwrap_or_default();
+        let identity = identity_fetch_mail.clone();
+        let mail_receipts = mail_receipts_fetch.clone();
+        slint::spawn_local(async move {
+            let set_status = |status: String| {
+                if let Some(app) = app_weak.upgrade() {
+                    set_mail_row_status(&app, &id, status);
+                }
+            };
+            set_status("Connecting…".to_string());
+            let result = match connect_signaling(&config).await {
+                Ok(ws_stream) => {
+                    // The header says who sealed it, their key is checked against the pinned one
+                    let opener_for = |item: &Value| identity.offline_opener(item["from"].as_str().unwrap_or_default(), &me, &item["header"]);
+                    let downloads = PathBuf::from("downloads");
+                    mailbox_fetch(ws_stream, token.clone(), id.to_string(), opener_for, downloads, |progress| set_status(format!("📥 {:.0}%", progress))).await
+                }
+                Err(e) => Err(e.into()),
+            };
+            let Some(app) = app_weak.upgrade() else { return };
+            match result {
+                Ok(path) => {
+                    app.set_mail_status(SharedString::from(format!("✅ Saved {}", path.display())));
+                    refresh_mailbox(app_weak.clone(), config, token, mail_receipts).await;
+                }
+                Err(e) => {
+                    set_status(String::new());
+                    app.set_mail_status(SharedString::from(format!("❌ {}", e)));
+                }
+            }
+        }).unwrap();
+    });
+
+    let weak_app_delete_mail = app.as_weak();
+    let config_delete_mail = config.clone();
+    let client_delete_mail = client.clone();
+    let mail_receipts_delete = mail_receipts.clone();
+    app.on_delete_mail(move |id: SharedString| {
+        let app_weak = weak_app_delete_mail.clone();
+        let config = config_delete_mail.clone();
+        let token = client_delete_mail.token().unwrap_or_default();
+        let mail_receipts = mail_receipts_delete.clone();
+        slint::spawn_local(async move {
+            let request = json!({"type": "mailbox_delete", "id": id.as_str(), "token": token});
+            let result = match connect_signaling(&config).await {
+                Ok(ws_stream) => server_request(request, "mailbox_deleted", Arc::new(Mutex::new(ws_stream))).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result
+                && let Some(app) = app_weak.upgrade()
+            {
+                app.set_mail_status(SharedString::from(format!("❌ {}", e)));
+            }
+            refresh_mailbox(app_weak, config, token, mail_receipts).await;
+        }).unwrap();
+    });
+
+    let weak_app_leave = app.as_weak();
+    let config_leave = config.clone();
+    let client_leave = client.clone();
+    let identity_leave = identity.clone();
+    app.on_leave_for_later(move |target: SharedString| {
+        let Some(path) = FileDialog::new().pick_file() else { return };
+        let app_weak = weak_app_leave.clone();
+        let config = config_leave.clone();
+        let token = client_leave.token().unwrap_or_default();
+        let me = client_leave.username().unwrap_or_default();
+        let identity = identity_leave.clone();
+        let target = target.trim().to_string();
+        slint::spawn_local(async move {
+            let set_output = |output: String| {
+                if let Some(app) = app_weak.upgrade() {
+                    app.set_output(SharedString::from(output));
+                }
+            };
+            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            let result = leave_for_later(&config, &identity, &me, &token, &target, path, |progress| set_output(format!("📮 {:.0}%", progress))).await;
+            match result {
+                Ok((id, expires)) => {
+                    let mut sent: BTreeMap<String, String> = load_json(MAILBOX_SENT_FILE);
+                    sent.insert(id, name.clone());
+                    if let Err(e) = save_json(MAILBOX_SENT_FILE, &sent) {
+                        eprintln!("❌ Failed to save {}: {}", MAILBOX_SENT_FILE, e);
+                    }
+                    set_output(format!("📮 {} waits for {} on the server for {}", name, target, time_left(expires)));
+                }
+                Err(e) => set_output(format!("❌ {}", e)),
+            }
+        }).unwrap();
+    });
+
+    // History: every transfer from history.jsonl, filtered on the page
+    let history_shown: Rc<RefCell<Vec<Record>>> = Rc::new(RefCell::new(Vec::new()));
+    let weak_app_history = app.as_weak();
+    let history_shown_show = history_shown.clone();
+    app.on_show_history(move || {
+        if let Some(app) = weak_app_history.upgrade() {
+            show_history(&app, &history_shown_show);
+        }
+    });
+
+    let weak_app_history_folder = app.as_weak();
+    let history_shown_folder = history_shown.clone();
+    app.on_open_history_folder(move |index| {
+        let Some(app) = weak_app_history_folder.upgrade() else { return };
+        let shown = history_shown_folder.borrow();
+        let path = shown.get(index as usize).and_then(|r| r.files.first()).and_then(|f| f.path.clone());
+        let result = match path.as_deref().and_then(|path| path.parent()) {
+            Some(dir) => open_folder(dir).map_err(|e| format!("Can't open {}: {}", dir.display(), e)),
+            None => Err("No folder was recorded for this transfer".to_string()),
+        };
+        app.set_history_status(SharedString::from(result.err().map(|e| format!("❌ {}", e)).unwrap_or_default()));
+    });
+
+    let weak_app_resend = app.as_weak();
+    let history_shown_resend = history_shown.clone();
+    let client_resend = client.clone();
+    let config_resend = config.clone();
+    let identity_resend = identity.clone();
+    app.on_resend_history(move |index| {
+        let Some(record) = history_shown_resend.borrow().get(index as usize).cloned() else { return };
+        let Some(path) = resend_path(&record) else { return };
+        let app_weak = weak_app_resend.clone();
+        let history_shown = history_shown_resend.clone();
+        let client = client_resend.clone();
+        let config = config_resend.clone();
+        let token = client_resend.token().unwrap_or_default();
+        let me = client_resend.username().unwrap_or_default();
+        let identity = identity_resend.clone();
+        // Over as many connections as a send from the send page
+        if let Some(app) = app_weak.upgrade() {
+            client.set_parallel_streams(app.get_parallel_streams().parse().unwrap_or(1));
+        }
+        slint::spawn_local(async move {
+            let set_status = |status: String| {
+                if let Some(app) = app_weak.upgrade() {
+                    app.set_history_status(SharedString::from(status));
+                }
+            };
+            set_status(format!("📤 Sending {} to {} again…", path.display(), record.peer));
+            // Offline peers get it the way they got it the first time
+            let result = if record.route == Route::Mailbox {
+                leave_for_later(&config, &identity, &me, &token, &record.peer, path, |_| {}).await.map(|_| ())
+            } else {
+                client.send(&record.peer, Source::File(path)).await
+            };
+            match result {
+                Ok(()) => set_status(format!("✅ Sent to {} again", record.peer)),
+                Err(e) => set_status(format!("❌ {}", e)),
+            }
+            if let Some(app) = app_weak.upgrade() {
+                show_history(&app, &history_shown);
+            }
+        }).unwrap();
+    });
+
+    // Who is online is pushed by the server, the recipient picker and the contacts
+    // page follow it live
+    let presence = Rc::new(RefCell::new(Presence::default()));
+    slint::spawn_local(watch_online(app.as_weak(), config.clone(), presence.clone(), identity.public_key())).unwrap();
+    slint::spawn_local(show_transfers(app.as_weak(), client.clone())).unwrap();
+
+    // Contacts: kept locally, shown with the live online state
+    let weak_app_contacts = app.as_weak();
+    let presence_contacts = presence.clone();
+    let my_key_contacts = identity.public_key();
+    let refresh = Rc::new(move || {
+        if let Some(app) = weak_app_contacts.upgrade() {
+            show_contacts(&app, &my_key_contacts, &presence_contacts.borrow().online());
+        }
+    });
+    let refresh_open = refresh.clone();
+    app.on_open_contacts_page(move || refresh_open());
+
+    let refresh_add = refresh.clone();
+    let weak_app_add_contact = app.as_weak();
+    app.on_add_contact(move |username: SharedString, nickname: SharedString| {
+        let username = username.trim().to_string();
+        if username.is_empty() {
+            return;
+        }
+        if let Some(app) = weak_app_add_contact.upgrade() {
+            app.set_contact_status(SharedString::new());
+        }
+        let existing = contacts().remove(&username).unwrap_or_default();
+        save_contact(&username, Contact { nickname: nickname.trim().to_string(), ..existing });
+        refresh_add();
+    });
+
+    let refresh_remove = refresh.clone();
+    app.on_remove_contact(move |username: SharedString| {
+        remove_contact(&username);
+        refresh_remove();
+    });
+
+    let refresh_favorite = refresh.clone();
+    app.on_toggle_favorite(move |username: SharedString| {
+        update_contact(&username, |contact| contact.favorite = !contact.favorite);
+        refresh_favorite();
+    });
+
+    app.on_set_auto_accept(move |username: SharedString, policy: SharedString| {
+        update_contact(&username, |contact| contact.auto_accept = AutoAccept::from_name(&policy));
+    });
+
+    let weak_app_send_to_contact = app.as_weak();
+    app.on_send_to_contact(move |username: SharedString| {
+        let Some(app) = weak_app_send_to_contact.upgrade() else { return };
+        app.invoke_get_clients();
+        app.invoke_get_rooms(app.get_selected_room());
+        app.invoke_select_target(username);
+    });
+
+    // Speed limits: saved on Apply, running transfers slow down or speed up right away
+    let weak_app_limits = app.as_weak();
+    app.on_open_limits_page(move || {
+        let Some(app) = weak_app_limits.upgrade() else { return };
+        let limits = limits();
+        let field = |kb: u64| SharedString::from(if kb == 0 { String::new() } else { kb.to_string() });
+        app.set_limit_upload(field(limits.global.upload));
+        app.set_limit_download(field(limits.global.download));
+        app.set_per_transfer_upload(field(limits.per_transfer.upload));
+        app.set_per_transfer_download(field(limits.per_transfer.download));
+        let schedule: Vec<String> = limits.schedule.iter().map(Window::to_string).collect();
+        app.set_limit_schedule(SharedString::from(schedule.join("\n")));
+        app.set_limit_status(SharedString::from(describe_limits(&limits)));
+    });
+
+    let weak_app_apply_limits = app.as_weak();
+    app.on_apply_limits(move || {
+        let Some(app) = weak_app_apply_limits.upgrade() else { return };
+        let status = match limits_from_page(&app) {
+            Ok(limits) => match set_limits(limits.clone()) {
+                Ok(()) => format!("✅ Saved. {}", describe_limits(&limits)),
+                Err(e) => format!("❌ Failed to save the limits: {}", e),
+            },
+            Err(e) => format!("❌ {}", e),
+        };
+        app.set_limit_status(SharedString::from(status));
+    });
+
+    // Shared folders: ours are answered by the receive loop, a contact's are browsed
+    // and pulled over a connection of their own per step
+    let weak_app_shares = app.as_weak();
+    app.on_open_shares_page(move || {
+        if let Some(app) = weak_app_shares.upgrade() {
+            show_shares(&app);
+        }
+    });
+
+    let weak_app_add_share = app.as_weak();
+    app.on_add_share(move |name: SharedString, readers: SharedString| {
+        let Some(app) = weak_app_add_share.upgrade() else { return };
+        let name = name.trim().to_string();
+        let mut all = shares::shares();
+        let status = if name.is_empty() || name.contains(['/', '\\', ':']) || name == "." || name == ".." {
+            format!("❌ {} can't be a share name", name)
+        } else if all.iter().any(|share| share.name == name) {
+            format!("❌ There already is a share called {}", name)
+        } else if let Some(root) = FileDialog::new().pick_folder() {
+            let readers = readers.split([',', ' ']).filter(|reader| !reader.is_empty()).map(str::to_string).collect();
+            all.push(Share { name: name.clone(), root, readers });
+            match set_shares(&all) {
+                Ok(()) => format!("✅ Sharing {}", name),
+                Err(e) => format!("❌ Failed to save the shares: {}", e),
+            }
+        } else {
+            String::new()
+        };
+        app.set_share_status(status.into());
+        show_shares(&app);
+    });
+
+    let weak_app_remove_share = app.as_weak();
+    app.on_remove_share(move |name: SharedString| {
+        let mut all = shares::shares();
+        all.retain(|share| share.name != name.as_str());
+        let Some(app) = weak_app_remove_share.upgrade() else { return };
+        if let Err(e) = set_shares(&all) {
+            app.set_share_status(SharedString::from(format!("❌ Failed to save the shares: {}", e)));
+        }
+        show_shares(&app);
+    });
+
+    let weak_app_browse = app.as_weak();
+    let client_browse = client.clone();
+    let identity_browse = identity.clone();
+    let config_browse = config.clone();
+    app.on_browse_remote(move |peer: SharedString, share: SharedString, path: SharedString| {
+        let app_weak = weak_app_browse.clone();
+        let token = client_browse.token().unwrap_or_default();
+        let me = client_browse.username().unwrap_or_default();
+        let identity = identity_browse.clone();
+        let config = config_browse.clone();
+        let peer = peer.trim().to_string();
+        slint::spawn_local(async move {
+            if let Some(app) = app_weak.upgrade() {
+                app.set_share_status(SharedString::from(format!("📂 Asking {}…", peer)));
+            }
+            let result = match connect_peer(&config, &identity, &me, &peer).await {
+                Ok(source) => share_browse(source, token, &share, &path).await.map_err(|e| e.to_string()),
+                Err(e) => Err(e),
+            };
+            let Some(app) = app_weak.upgrade() else { return };
+            match result {
+                Ok(entries) => {
+                    let status = if entries.is_empty() && share.is_empty() { format!("{} shares nothing with you", peer) } else { String::new() };
+                    show_remote(&app, &peer, &share, &path, entries);
+                    app.set_share_status(status.into());
+                }
+                Err(e) => app.set_share_status(SharedString::from(format!("❌ {}", e))),
+            }
+        }).unwrap();
+    });
+
+    let weak_app_browse_up = app.as_weak();
+    app.on_browse_up(move || {
+        let Some(app) = weak_app_browse_up.upgrade() else { return };
+        let path = app.get_remote_path();
+        let (share, parent) = match path.rsplit_once('/') {
+            Some((parent, _)) => (app.get_remote_share(), SharedString::from(parent)),
+            None if path.is_empty() => (SharedString::new(), SharedString::new()),
+            None => (app.get_remote_share(), SharedString::new()),
+        };
+        app.invoke_browse_remote(app.get_remote_peer(), share, parent);
+    });
+
+    let weak_app_pull = app.as_weak();
+    let client_pull = client.clone();
+    let identity_pull = identity.clone();
+    let config_pull = config.clone();
+    app.on_pull_remote(move |share: SharedString, path: SharedString| {
+        let app_weak = weak_app_pull.clone();
+        let Some(app) = app_weak.upgrade() else { return };
+        let peer = app.get_remote_peer().to_string();
+        let token = client_pull.token().unwrap_or_default();
+        let me = client_pull.username().unwrap_or_default();
+        let identity = identity_pull.clone();
+        let config = config_pull.clone();
+        slint::spawn_local(async move {
+            let set_status = |status: String| {
+                if let Some(app) = app_weak.upgrade() {
+                    app.set_share_status(SharedString::from(status));
+                }
+            };
+            set_status(format!("📥 Asking {}…", peer));
+            let downloads = PathBuf::from("downloads");
+            let result = match connect_peer(&config, &identity, &me, &peer).await {
+                Ok(source) => share_pull(source, token, &share, &path, &downloads, |progress| set_status(format!("📥 {:.0}%", progress)))
+                    .await
+                    .map_err(|e| e.to_string()),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(files) => set_status(format!("✅ {} file(s) saved in {}", files.len(), downloads.join(share.as_str()).display())),
+                Err(e) => set_status(format!("❌ {}", e)),
+            }
+        }).unwrap();
+    });
+
     // Get clients event handler
     let weak_app_clients = app.as_weak();
-    let ws_stream_clone_get_clients = ws_stream.clone();
+    let client_get_clients = client.clone();
+    let presence_get_clients = presence.clone();
     app.on_get_clients(move || {
         let app_weak = weak_app_clients.clone(); 
-        let ws_stream = ws_stream_clone_get_clients.clone();
+        // Nothing to ask while the presence subscription keeps the list current
+        if presence_get_clients.borrow().is_live() {
+            if let Some(app) = app_weak.upgrade() {
+                show_clients(&app, presence_get_clients.borrow().online());
+            }
+            return;
+        }
+        let client = client_get_clients.clone();
         slint::spawn_local(async move {
-            let response = get_clients(ws_stream).await;
-            let clients = keys_from_json_str(response.unwrap());
+            let clients = match client.list_peers().await {
+                Ok(clients) => clients,
+                Err(e) => {
+                    eprintln!("❌ Failed to get clients: {}", e);
+                    return;
+                }
+            };
             println!("{:?}", clients);
-            let model = ModelRc::new(VecModel::from(clients.into_iter().map(Into::into).collect::<Vec<_>>(),));
-            app_weak.upgrade().unwrap().set_available_clients(model);
+            let Some(app) = app_weak.upgrade() else { return };
+            show_clients(&app, clients);
         }).unwrap();
     });
 
-    let ws_stream_clone_send = ws_stream.clone();
     let weak_app_target = app.as_weak();
+    let client_send = client.clone();
     app.on_send(
         move |target_username: SharedString| {
+            let Some(path) = FileDialog::new().pick_file() else { return };
             let app_weak = weak_app_target.clone();
-            let ws_stream = ws_stream_clone_send.clone();
+            let client = client_send.clone();
+            let my_key = client.identity().public_key();
+            // Small files aren't worth the extra connections, the client only opens them for large ones
+            let stream_count = app_weak.upgrade().map_or(1, |app| app.get_parallel_streams().parse().unwrap_or(1));
+            client.set_parallel_streams(stream_count);
             slint::spawn_local(async move {
-                if let Err(e) = relay_send(ws_stream, target_username.to_string()).await {
+                let result = client.send(&target_username, Source::File(path)).await;
+                let Some(app) = app_weak.upgrade() else { return };
+                if let Err(e) = result {
                     eprintln!("Error relaying: {}", e);
+                    if e == "Target user not found" {
+                        app.set_offline_user(target_username.clone());
+                        app.set_output(SharedString::from(format!("❌ {} is offline, Leave for Later keeps the file on the server for them", target_username)));
+                    } else {
+                        app.set_output(SharedString::from(format!("❌ {}", e)));
+                    }
+                } else {
+                    app.set_output(SharedString::new());
                 }
+                // The session may have pinned or flagged the peer's key
+                show_peer_identity(&app, &my_key, &target_username, None);
             }).unwrap();
         }
     );
 
-    let ws_stream_clone_receive = ws_stream.clone();
+    let ws_stream_clone_clipboard = ws_stream.clone();
+    let weak_app_clipboard = app.as_weak();
+    let client_clipboard = client.clone();
+    let identity_clipboard = identity.clone();
+    let clipboard_send = clipboard.clone();
+    app.on_send_clipboard(move |target_username: SharedString| {
+        let Some(app) = weak_app_clipboard.upgrade() else { return };
+        let clip = match with_clipboard(&clipboard_send, Clip::from_system) {
+            Ok(clip) => clip,
+            Err(e) => {
+                app.set_output(SharedString::from(format!("❌ {}", e)));
+                return;
+            }
+        };
+        let app_weak = weak_app_clipboard.clone();
+        let ws_stream = ws_stream_clone_clipboard.clone();
+        let token = client_clipboard.token().unwrap_or_default();
+        let mut handshake = Handshake::initiator(identity_clipboard.clone(), &client_clipboard.username().unwrap_or_default(), &target_username);
+        let description = clip.describe();
+        app.set_output(SharedString::from(format!("📋 Sending {} to {}…", description, target_username)));
+        slint::spawn_local(async move {
+            let hello = handshake.hello();
+            let result = relay_clipboard(ws_stream, target_username.to_string(), token, hello, move |msg| handshake.on_message(msg), clip).await;
+            let Some(app) = app_weak.upgrade() else { return };
+            match result {
+                Ok(()) => app.set_output(SharedString::from(format!("✅ Sent {} to {}", description, target_username))),
+                Err(e) => {
+                    eprintln!("Error sending clipboard: {}", e);
+                    app.set_output(SharedString::from(format!("❌ {}", e)));
+                }
+            }
+        }).unwrap();
+    });
+
+    // Sends one file to every ticked recipient at once
+    let weak_app_many = app.as_weak();
+    let client_many = client.clone();
+    let identity_many = identity.clone();
+    let config_many = config.clone();
+    app.on_send_many(move || {
+        let Some(app) = weak_app_many.upgrade() else { return };
+        let rows = app.get_recipients();
+        let targets: Vec<(usize, String)> = rows.iter().enumerate().filter(|(_, row)| row.selected).map(|(i, row)| (i, row.name.to_string())).collect();
+        if targets.is_empty() {
+            app.set_output(SharedString::from("Tick at least one recipient"));
+            return;
+        }
+        let Some(path) = FileDialog::new().pick_file() else { return };
+
+        let app_weak = weak_app_many.clone();
+        let token = client_many.token().unwrap_or_default();
+        let me = client_many.username().unwrap_or_default();
+        let identity = identity_many.clone();
+        let config = config_many.clone();
+        app.set_sending_many(true);
+        app.set_output(SharedString::new());
+        slint::spawn_local(fan_out(app_weak, config, identity, me, token, targets, path, None)).unwrap();
+    });
+
+    // Rooms: create/join/leave go through the server, the list is refreshed after each
+    let ws_stream_clone_rooms = ws_stream.clone();
+    let weak_app_rooms = app.as_weak();
+    let client_rooms = client.clone();
+    let room_action = Rc::new(move |kind: &'static str, room: String| {
+        let app_weak = weak_app_rooms.clone();
+        let ws_stream = ws_stream_clone_rooms.clone();
+        let token = client_rooms.token().unwrap_or_default();
+        slint::spawn_local(async move {
+            let mut selected = room.clone();
+            if kind != "list_rooms" {
+                let reply_type = if kind == "leave_room" { "room_left" } else { "room" };
+                let request = json!({"type": kind, "room": room, "token": token});
+                if let Err(e) = server_request(request, reply_type, ws_stream.clone()).await {
+                    if let Some(app) = app_weak.upgrade() {
+                        app.set_output(SharedString::from(format!("❌ {}: {}", room, e)));
+                    }
+                    return;
+                }
+                if kind == "leave_room" {
+                    selected.clear();
+                }
+            }
+            let rooms = server_request(json!({"type": "list_rooms", "token": token}), "rooms", ws_stream).await;
+            let Some(app) = app_weak.upgrade() else { return };
+            match rooms {
+                Ok(reply) => {
+                    let rooms = reply["rooms"].as_array().cloned().unwrap_or_default();
+                    let names: Vec<SharedString> = rooms.iter().filter_map(|r| r["room"].as_str()).map(Into::into).collect();
+                    let info = rooms.iter().find(|r| r["room"].as_str() == Some(selected.as_str()));
+                    app.set_room_status(SharedString::from(info.map(room_members).unwrap_or_default()));
+                    app.set_selected_room(SharedString::from(if info.is_some() { selected } else { String::new() }));
+                    app.set_available_rooms(ModelRc::new(VecModel::from(names)));
+                    if kind != "list_rooms" {
+                        app.set_output(SharedString::new());
+                    }
+                }
+                Err(e) => app.set_output(SharedString::from(format!("❌ Failed to get rooms: {}", e))),
+            }
+        }).unwrap();
+    });
+    let action = room_action.clone();
+    app.on_get_rooms(move |selected: SharedString| action("list_rooms", selected.to_string()));
+    let action = room_action.clone();
+    app.on_create_room(move |room: SharedString| action("create_room", room.trim().to_string()));
+    let action = room_action.clone();
+    app.on_join_room(move |room: SharedString| action("join_room", room.to_string()));
+    let action = room_action.clone();
+    app.on_leave_room(move |room: SharedString| action("leave_room", room.to_string()));
+
+    // Posts a file to everyone in the room who is online, each of them is asked first
+    let ws_stream_clone_post = ws_stream.clone();
+    let weak_app_post = app.as_weak();
+    let client_post = client.clone();
+    let identity_post = identity.clone();
+    let config_post = config.clone();
+    app.on_post_to_room(move |room: SharedString| {
+        let Some(path) = FileDialog::new().pick_file() else { return };
+        let app_weak = weak_app_post.clone();
+        let ws_stream = ws_stream_clone_post.clone();
+        let token = client_post.token().unwrap_or_default();
+        let me = client_post.username().unwrap_or_default();
+        let identity = identity_post.clone();
+        let config = config_post.clone();
+        let room = room.to_string();
+        slint::spawn_local(async move {
+            let request = json!({"type": "list_rooms", "token": token});
+            let rooms = server_request(request, "rooms", ws_stream).await;
+            let Some(app) = app_weak.upgrade() else { return };
+            let info = match rooms {
+                Ok(reply) => reply["rooms"].as_array().and_then(|rooms| rooms.iter().find(|r| r["room"] == room.as_str()).cloned()),
+                Err(e) => {
+                    app.set_output(SharedString::from(format!("❌ Failed to get rooms: {}", e)));
+                    return;
+                }
+            };
+            let members = info.as_ref().and_then(|info| info["members"].as_array().cloned()).unwrap_or_default();
+            if !members.iter().any(|m| m["username"] == me.as_str()) {
+                app.set_output(SharedString::from(format!("Join {} first", room)));
+                return;
+            }
+            let targets: Vec<String> = members
+                .iter()
+                .filter(|m| m["online"].as_bool() == Some(true) && m["username"] != me.as_str())
+                .filter_map(|m| m["username"].as_str().map(|name| name.to_string()))
+                .collect();
+            if targets.is_empty() {
+                app.set_output(SharedString::from(format!("Nobody else in {} is online", room)));
+                return;
+            }
+
+            // The recipient list shows the members while the post goes out
+            let rows: Vec<RecipientRow> = targets.iter().map(|name| RecipientRow { name: name.into(), selected: true, ..Default::default() }).collect();
+            app.set_recipients(ModelRc::new(VecModel::from(rows)));
+            app.set_sending_many(true);
+            app.set_output(SharedString::new());
+            let targets = targets.into_iter().enumerate().collect();
+            fan_out(app_weak, config, identity, me, token, targets, path, Some(room)).await;
+        }).unwrap();
+    });
+
+    // Wormhole: one-time codes over a fresh connection, no registration needed
+    let weak_app_wormhole_send = app.as_weak();
+    let config_wormhole_send = config.clone();
+    app.on_wormhole_send(move || {
+        let Some(path) = FileDialog::new().pick_file() else { return };
+        let app_weak = weak_app_wormhole_send.clone();
+        let config = config_wormhole_send.clone();
+        slint::spawn_local(async move {
+            let set_status = |status: String| {
+                if let Some(app) = app_weak.upgrade() {
+                    app.set_wormhole_status(SharedString::from(status));
+                }
+            };
+            if let Some(app) = app_weak.upgrade() {
+                app.set_wormhole_busy(true);
+            }
+            let result = match connect_signaling(&config).await {
+                Ok(ws_stream) => {
+                    let on_code = |code: String| {
+                        if let Some(app) = app_weak.upgrade() {
+                            app.set_wormhole_code(SharedString::from(code.as_str()));
+                        }
+                        set_status(format!("🕳️ Tell the receiver this code: {}", code));
+                    };
+                    wormhole_send(ws_stream, path, on_code, |progress| set_status(format!("🚀 {:.0}%", progress))).await
+                }
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Ok(()) => set_status("✅ Sent".to_string()),
+                Err(e) => set_status(format!("❌ {}", e)),
+            }
+            if let Some(app) = app_weak.upgrade() {
+                app.set_wormhole_busy(false);
+                app.set_wormhole_code(SharedString::new());
+            }
+        }).unwrap();
+    });
+
+    let weak_app_wormhole_receive = app.as_weak();
+    let config_wormhole_receive = config.clone();
+    app.on_wormhole_receive(move |code: SharedString| {
+        let app_weak = weak_app_wormhole_receive.clone();
+        let config = config_wormhole_receive.clone();
+        let code = code.to_string();
+        slint::spawn_local(async move {
+            let set_status = |status: String| {
+                if let Some(app) = app_weak.upgrade() {
+                    app.set_wormhole_status(SharedString::from(status));
+                }
+            };
+            if let Some(app) = app_weak.upgrade() {
+                app.set_wormhole_busy(true);
+            }
+            let result = match connect_signaling(&config).await {
+                Ok(ws_stream) => {
+                    let downloads = PathBuf::from("downloads");
+                    wormhole_receive(ws_stream, code, downloads, |progress| set_status(format!("📥 {:.0}%", progress))).await
+                }
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Ok(path) => set_status(format!("✅ Saved {}", path.display())),
+                Err(e) => set_status(format!("❌ {}", e)),
+            }
+            if let Some(app) = app_weak.upgrade() {
+                app.set_wormhole_busy(false);
+                app.set_wormhole_code(SharedString::new());
+            }
+        }).unwrap();
+    });
+
+    // Swarm: seed a file under its content hash, or download one from everyone who has it
+    let ws_stream_clone_share = ws_stream.clone();
+    let weak_app_share = app.as_weak();
+    let client_share = client.clone();
+    app.on_share_file(move || {
+        let Some(path) = FileDialog::new().pick_file() else { return };
+        let app_weak = weak_app_share.clone();
+        let ws_stream = ws_stream_clone_share.clone();
+        let token = client_share.token().unwrap_or_default();
+        if let Some(app) = app_weak.upgrade() {
+            app.set_swarm_status(SharedString::from(format!("Hashing {}…", path.display())));
+        }
+        slint::spawn_local(async move {
+            let result = seed_file(path, token, ws_stream).await;
+            let Some(app) = app_weak.upgrade() else { return };
+            match result {
+                Ok((name, hash)) => {
+                    app.set_swarm_hash(SharedString::from(hash));
+                    app.set_swarm_status(SharedString::from(format!("🌱 Seeding {}, share the hash above", name)));
+                }
+                Err(e) => app.set_swarm_status(SharedString::from(format!("❌ {}", e))),
+            }
+        }).unwrap();
+    });
+
+    let ws_stream_clone_swarm = ws_stream.clone();
+    let weak_app_swarm = app.as_weak();
+    let client_swarm = client.clone();
+    let identity_swarm = identity.clone();
+    let config_swarm = config.clone();
+    app.on_swarm_download(move |hash: SharedString| {
+        let hash = hash.trim().to_lowercase();
+        let app_weak = weak_app_swarm.clone();
+        let ws_stream = ws_stream_clone_swarm.clone();
+        let token = client_swarm.token().unwrap_or_default();
+        let me = client_swarm.username().unwrap_or_default();
+        let identity = identity_swarm.clone();
+        let config = config_swarm.clone();
+        slint::spawn_local(async move {
+            let set_status = |status: String| {
+                if let Some(app) = app_weak.upgrade() {
+                    app.set_swarm_status(SharedString::from(status));
+                }
+            };
+            let request = json!({"type": "who_has", "hash": hash, "token": token});
+            let holders: Vec<String> = match server_request(request, "holders", ws_stream.clone()).await {
+                Ok(reply) => serde_json::from_value(reply["peers"].clone()).unwrap_or_default(),
+                Err(e) => return set_status(format!("❌ {}", e)),
+            };
+            if holders.is_empty() {
+                return set_status("Nobody online has that file".to_string());
+            }
+
+            let mut sources = Vec::new();
+            for target in holders {
+                match connect_signaling(&config).await {
+                    Ok(ws_stream) => {
+                        let mut handshake = Handshake::initiator(identity.clone(), &me, &target);
+                        let hello = handshake.hello();
+                        sources.push(RelayPeer { target, ws_stream, hello, on_identity: Box::new(move |msg| handshake.on_message(msg)) });
+                    }
+                    Err(e) => eprintln!("❌ {}", e),
+                }
+            }
+            set_status(format!("🐝 Asking {} peer(s)…", sources.len()));
+
+            let downloads = PathBuf::from("downloads");
+            if let Err(e) = std::fs::create_dir_all(&downloads) {
+                return set_status(format!("❌ {}", e));
+            }
+            let result = swarm_download(sources, token.clone(), hash, downloads, |progress, active| {
+                set_status(format!("🐝 {:.0}% from {} peer(s)", progress, active));
+            }).await;
+            match result {
+                // Finished downloads seed to whoever comes next
+                Ok((path, manifest)) => {
+                    let name = manifest.name.clone();
+                    let announced = match add_seed(&path, manifest) {
+                        Ok(hash) => {
+                            let announce = json!({"type": "announce", "hash": hash, "token": token});
+                            server_request(announce, "announced", ws_stream).await.map(|_| ())
+                        }
+                        Err(e) => Err(e.to_string()),
+                    };
+                    match announced {
+                        Ok(()) => set_status(format!("✅ {} downloaded, now seeding it", name)),
+                        Err(e) => set_status(format!("✅ {} downloaded, but seeding failed: {}", name, e)),
+                    }
+                }
+                Err(e) => set_status(format!("❌ {}", e)),
+            }
+        }).unwrap();
+    });
+
+    // Keeps a folder mirrored to the receiver until stop_mirror is pressed
+    let mirror_stop: Rc<RefCell<Option<watch::Sender<bool>>>> = Rc::new(RefCell::new(None));
+    let ws_stream_clone_mirror = ws_stream.clone();
+    let weak_app_mirror = app.as_weak();
+    let client_mirror = client.clone();
+    let identity_mirror = identity.clone();
+    let mirror_stop_start = mirror_stop.clone();
+    app.on_start_mirror(move |target_username: SharedString| {
+        let Some(root) = FileDialog::new().pick_folder() else { return };
+        let app_weak = weak_app_mirror.clone();
+        let ws_stream = ws_stream_clone_mirror.clone();
+        let token = client_mirror.token().unwrap_or_default();
+        let mut handshake = Handshake::initiator(identity_mirror.clone(), &client_mirror.username().unwrap_or_default(), &target_username);
+        let (stop_tx, stop_rx) = watch::channel(false);
+        *mirror_stop_start.borrow_mut() = Some(stop_tx);
+        if let Some(app) = app_weak.upgrade() {
+            app.set_mirroring(true);
+            app.set_output(SharedString::from(format!("🪞 Mirroring {}", root.display())));
+        }
+        let mirror_stop = mirror_stop_start.clone();
+        slint::spawn_local(async move {
+            let hello = handshake.hello();
+            let result = relay_mirror(ws_stream, target_username.to_string(), token, hello, move |msg| handshake.on_message(msg), root, stop_rx).await;
+            mirror_stop.borrow_mut().take();
+            let Some(app) = app_weak.upgrade() else { return };
+            app.set_mirroring(false);
+            match result {
+                Ok(()) => app.set_output(SharedString::new()),
+                Err(e) => {
+                    eprintln!("Error mirroring: {}", e);
+                    app.set_output(SharedString::from(format!("❌ {}", e)));
+                }
+            }
+        }).unwrap();
+    });
+
+    let mirror_stop_stop = mirror_stop.clone();
+    app.on_stop_mirror(move || {
+        if let Some(stop) = mirror_stop_stop.borrow().as_ref() {
+            let _ = stop.send(true);
+        }
+    });
+
+    // Shows the safety code and trust state of the selected receiver
+    let ws_stream_clone_select = ws_stream.clone();
+    let weak_app_select = app.as_weak();
+    let my_key_select = identity.public_key();
+    app.on_select_target(move |target_username: SharedString| {
+        let target_username = SharedString::from(target_username.strip_prefix(FAVORITE_MARK).unwrap_or(&target_username));
+        let app_weak = weak_app_select.clone();
+        if let Some(app) = app_weak.upgrade() {
+            app.set_target_username(target_username.clone());
+        }
+        let ws_stream = ws_stream_clone_select.clone();
+        let my_key = my_key_select.clone();
+        slint::spawn_local(async move {
+            let published = match get_users(ws_stream).await {
+                Ok(users) => users[target_username.as_str()]["identity_key"].as_str().map(|s| s.to_string()),
+                Err(e) => {
+                    eprintln!("❌ Failed to get users: {}", e);
+                    None
+                }
+            };
+            if let Some(app) = app_weak.upgrade() {
+                show_peer_identity(&app, &my_key, &target_username, published);
+            }
+        }).unwrap();
+    });
+
+    let weak_app_verify = app.as_weak();
+    let my_key_verify = identity.public_key();
+    app.on_verify_peer(move |peer: SharedString| {
+        mark_verified(&peer);
+        if let Some(app) = weak_app_verify.upgrade() {
+            show_peer_identity(&app, &my_key_verify, &peer, None);
+        }
+    });
+
+    let weak_app_trust = app.as_weak();
+    let my_key_trust = identity.public_key();
+    app.on_trust_new_key(move |peer: SharedString| {
+        if accept_pending_key(&peer) {
+            println!("📌 Now trusting the new identity key of {}", peer);
+        }
+        if let Some(app) = weak_app_trust.upgrade() {
+            app.set_warning_peer(SharedString::new());
+            app.set_output(SharedString::new());
+            show_peer_identity(&app, &my_key_trust, &peer, None);
+        }
+    });
+
+    let client_receive = client.clone();
     let weak_app_target = app.as_weak();
+    let offer_answer: Rc<RefCell<Option<oneshot::Sender<bool>>>> = Rc::new(RefCell::new(None));
+    let offer_answer_receive = offer_answer.clone();
+    app.on_answer_offer(move |accepted| {
+        if let Some(answer) = offer_answer.borrow_mut().take() {
+            let _ = answer.send(accepted);
+        }
+    });
+
+    let weak_app_copy = app.as_weak();
+    let clipboard_copy = clipboard.clone();
+    let last_clip_copy = last_clip.clone();
+    app.on_copy_clip(move || {
+        let Some(app) = weak_app_copy.upgrade() else { return };
+        let Some(clip) = last_clip_copy.borrow().clone() else { return };
+        match with_clipboard(&clipboard_copy, |clipboard| clip.to_system(clipboard)) {
+            Ok(()) => app.set_output(SharedString::from(format!("📋 Copied {}", clip.describe()))),
+            Err(e) => app.set_output(SharedString::from(format!("❌ {}", e))),
+        }
+    });
+    let clipboard_receive = clipboard.clone();
+    let last_clip_receive = last_clip.clone();
+
     app.on_recieve(
-        move |username: SharedString| {
+        // Relay sessions come for whoever the client registered as
+        move |_username: SharedString| {
             let app_weak = weak_app_target.clone();
-            let ws_stream = ws_stream_clone_receive.clone();
-            let username = username.to_string();
+            let client = client_receive.clone();
+            // Remembers who the handshake was with, to offer trusting a changed key
+            let peer = Rc::new(RefCell::new(String::new()));
+            let peer_hook = peer.clone();
+            // Room posts wait for the Accept/Decline buttons
+            let app_offer = weak_app_target.clone();
+            let offer_answer = offer_answer_receive.clone();
+            let on_offer = move |sender: &str, metadata: &Value| {
+                // Contacts can be set to always or never take offers. By default files sent to
+                // us are taken, clipboard shares go by the opt-in and room posts are asked.
+                let opted_in = match auto_accept(sender) {
+                    AutoAccept::Always => Some(true),
+                    AutoAccept::Never => Some(false),
+                    AutoAccept::Default if metadata.get("clipboard").is_some() => Some(app_offer.upgrade().is_some_and(|app| app.get_accept_clipboard())),
+                    AutoAccept::Default if metadata.get("room").is_some() => None,
+                    AutoAccept::Default => Some(true),
+                };
+                let (tx, rx) = oneshot::channel();
+                if opted_in.is_none() {
+                    *offer_answer.borrow_mut() = Some(tx);
+                    let name = metadata["name"].as_str().unwrap_or_default();
+                    let size = metadata["size"].as_u64().unwrap_or_default();
+                    let room = metadata["room"].as_str().unwrap_or_default();
+                    if let Some(app) = app_offer.upgrade() {
+                        app.set_offer(SharedString::from(format!("{} posted {} ({} bytes) to {}", sender, name, size, room)));
+                    }
+                }
+                let app_offer = app_offer.clone();
+                async move {
+                    if let Some(opted_in) = opted_in {
+                        return opted_in;
+                    }
+                    let accepted = matches!(timeout(OFFER_TIMEOUT, rx).await, Ok(Ok(true)));
+                    if let Some(app) = app_offer.upgrade() {
+                        app.set_offer(SharedString::new());
+                    }
+                    accepted
+                }
+            };
+            let app_clip = weak_app_target.clone();
+            let clipboard = clipboard_receive.clone();
+            let last_clip = last_clip_receive.clone();
+            let on_clipboard = move |sender: &str, clip: Clip| {
+                let Some(app) = app_clip.upgrade() else { return };
+                show_clip(&app, sender, &clip);
+                if app.get_clipboard_direct() {
+                    match with_clipboard(&clipboard, |clipboard| clip.to_system(clipboard)) {
+                        Ok(()) => app.set_output(SharedString::from(format!("📋 {} from {} is in your clipboard", clip.describe(), sender))),
+                        Err(e) => app.set_output(SharedString::from(format!("❌ {}", e))),
+                    }
+                }
+                *last_clip.borrow_mut() = Some(clip);
+            };
             slint::spawn_local(async move {
-                // Call relay_send asynchronously
-                if let Err(e) = relay_receive(username.to_string(), ws_stream).await {
+                let on_peer = move |name: &str| *peer_hook.borrow_mut() = name.to_string();
+                let downloads = Destination::Folder(PathBuf::from("downloads"));
+                if let Err(e) = client.receive_with(downloads, on_peer, on_offer, on_clipboard).await {
                     eprintln!("Error relaying: {}", e);
+                    if let Some(app) = app_weak.upgrade() {
+                        let peer = peer.borrow().clone();
+                        if pending_key(&peer).is_some() {
+                            app.set_warning_peer(SharedString::from(peer));
+                        }
+                        app.set_output(SharedString::from(format!("❌ {}", e)));
+                    }
                 }
             }).unwrap();
         }
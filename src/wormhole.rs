@@ -0,0 +1,313 @@
+// Wormhole-style one-time codes. The sender asks the server for a nameplate (a small
+// number), adds two random words the server never sees and shows the result, e.g.
+// 7-crossword-banana. The receiver claims the nameplate, which pairs the two sockets
+// like a relay session, and both sides run SPAKE2 with the whole code as password.
+// Only someone who knows the words gets the same key, and a nameplate can only be
+// claimed once, so a wrong guess burns the code instead of allowing another try.
+//
+//   wormhole_allocate            -> wormhole_allocated {nameplate}
+//   wormhole_claim {nameplate}   -> wormhole_paired (to both sides)
+//   pake {msg}                   relayed, base64 SPAKE2 message
+//   wormhole_failed              relayed, the first frame didn't decrypt (wrong code)
+//
+// Everything after that is a binary frame sealed with ChaCha20-Poly1305 (see Channel).
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use std::fmt::Display;
+use tokio::time::{timeout, Duration};
+use tokio_tungstenite::tungstenite::{self, Message};
+
+const APP_ID: &[u8] = b"p2p_rust wormhole v1";
+const FRAME_JSON: u8 = 0;
+const FRAME_DATA: u8 = 1;
+const PAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+const WORDS: [&str; 256] = [
+    "acorn", "actor", "agent", "alarm", "album", "alley", "amber", "angle",
+    "ankle", "anvil", "apron", "arena", "armor", "aroma", "atlas", "attic",
+    "award", "bacon", "bagel", "baker", "banana", "banjo", "barn", "basil",
+    "basin", "beard", "beast", "berry", "bingo", "birch", "blimp", "blush",
+    "board", "bonus", "boots", "brain", "brass", "bread", "brick", "broom",
+    "brush", "bucket", "bugle", "cable", "cactus", "camera", "canal", "candle",
+    "canoe", "canyon", "carpet", "carrot", "castle", "cattle", "celery", "cello",
+    "cereal", "chain", "chair", "cheese", "cherry", "chess", "chimney", "cider",
+    "circus", "clock", "clover", "cobra", "cocoa", "comet", "cookie", "copper",
+    "cotton", "cougar", "cowboy", "crayon", "cricket", "crossword", "crown", "cupcake",
+    "curtain", "cymbal", "daisy", "denim", "desert", "diamond", "dinner", "dolphin",
+    "donkey", "dragon", "drum", "dune", "easel", "elbow", "engine", "falcon",
+    "feather", "fiddle", "finch", "fjord", "flute", "forest", "fossil", "fountain",
+    "fox", "galaxy", "garden", "garlic", "gecko", "geyser", "ginger", "glacier",
+    "glove", "goblet", "goose", "gravel", "guitar", "hammer", "harbor", "harp",
+    "hazel", "helmet", "heron", "hockey", "husky", "iceberg", "icicle", "island",
+    "jacket", "jaguar", "jasmine", "jigsaw", "jungle", "kayak", "kettle", "kitten",
+    "kiwi", "ladder", "lagoon", "lantern", "laptop", "lemur", "lentil", "lizard",
+    "lobster", "locket", "magnet", "mammoth", "marble", "meadow", "melon", "meteor",
+    "mitten", "monkey", "mosaic", "muffin", "mushroom", "napkin", "nectar", "needle",
+    "nickel", "noodle", "nutmeg", "oasis", "oatmeal", "octopus", "onion", "orange",
+    "orbit", "orchid", "ostrich", "oyster", "paddle", "panda", "parrot", "pasta",
+    "peach", "peanut", "pebble", "pelican", "pepper", "pickle", "pigeon", "pillow",
+    "pirate", "planet", "plum", "pocket", "pony", "popcorn", "potato", "pretzel",
+    "puffin", "pumpkin", "puzzle", "rabbit", "raccoon", "radish", "rainbow", "rattle",
+    "ribbon", "river", "rocket", "rooster", "saddle", "salmon", "sandal", "scarf",
+    "seal", "shovel", "silver", "skate", "sled", "slipper", "snail", "sparrow",
+    "spider", "sponge", "spoon", "squid", "stamp", "statue", "stove", "sugar",
+    "summit", "sunset", "swan", "sweater", "taco", "teapot", "tennis", "thimble",
+    "thunder", "ticket", "toffee", "tomato", "tractor", "trumpet", "tundra", "turkey",
+    "turnip", "turtle", "unicorn", "valley", "velvet", "violin", "volcano", "waffle",
+    "walnut", "walrus", "wagon", "weasel", "willow", "window", "wizard", "yogurt",
+];
+
+// "<nameplate>-<word>-<word>"
+pub fn make_code(nameplate: u32) -> String {
+    let mut picks = [0u8; 2];
+    OsRng.fill_bytes(&mut picks);
+    format!("{}-{}-{}", nameplate, WORDS[picks[0] as usize], WORDS[picks[1] as usize])
+}
+
+// Tidies up what the user typed and returns it with its nameplate
+pub fn parse_code(code: &str) -> Option<(String, u32)> {
+    let code = code.trim().to_lowercase().split_whitespace().collect::<Vec<_>>().join("-");
+    let (nameplate, words) = code.split_once('-')?;
+    let nameplate = nameplate.parse().ok()?;
+    let words: Vec<&str> = words.split('-').collect();
+    (words.len() == 2 && words.iter().all(|w| WORDS.contains(w))).then_some((code.clone(), nameplate))
+}
+
+pub struct Pake(Spake2<Ed25519Group>);
+
+impl Pake {
+    // Returns the message for the other side, base64 for the pake JSON message
+    pub fn start(code: &str) -> (Pake, String) {
+        let (state, msg) = Spake2::<Ed25519Group>::start_symmetric(&Password::new(code), &Identity::new(APP_ID));
+        (Pake(state), STANDARD.encode(msg))
+    }
+
+    // A wrong code doesn't fail here, it gives a different key the first frame won't open with
+    pub fn finish(self, their_msg: &str, sender: bool) -> Result<Channel, String> {
+        let msg = STANDARD.decode(their_msg).map_err(|_| "Bad pake message".to_string())?;
+        let key = self.0.finish(&msg).map_err(|e| e.to_string())?;
+        Ok(Channel::new(&key, sender))
+    }
+}
+
+pub enum Frame {
+    Json(Value),
+    Data(Vec<u8>),
+}
+
+// One key per direction and a counter nonce, so frames can't be replayed, reordered
+// or reflected back to whoever sealed them
+pub struct Channel {
+    seal_key: ChaCha20Poly1305,
+    open_key: ChaCha20Poly1305,
+    sent: u64,
+    received: u64,
+}
+
+impl Channel {
+    fn new(shared: &[u8], sender: bool) -> Channel {
+        let hkdf = Hkdf::<Sha256>::new(None, shared);
+        let key = |label: &[u8]| {
+            let mut key = [0u8; 32];
+            hkdf.expand(label, &mut key).expect("32 bytes is a valid HKDF length");
+            ChaCha20Poly1305::new(Key::from_slice(&key))
+        };
+        let to_receiver = key(b"p2p_rust wormhole sender to receiver");
+        let to_sender = key(b"p2p_rust wormhole receiver to sender");
+        let (seal_key, open_key) = if sender { (to_receiver, to_sender) } else { (to_sender, to_receiver) };
+        Channel { seal_key, open_key, sent: 0, received: 0 }
+    }
+
+    pub fn seal_json(&mut self, value: &Value) -> Vec<u8> {
+        self.seal(FRAME_JSON, value.to_string().as_bytes())
+    }
+
+    pub fn seal_data(&mut self, data: &[u8]) -> Vec<u8> {
+        self.seal(FRAME_DATA, data)
+    }
+
+    fn seal(&mut self, kind: u8, payload: &[u8]) -> Vec<u8> {
+        let mut plain = Vec::with_capacity(payload.len() + 1);
+        plain.push(kind);
+        plain.extend_from_slice(payload);
+        let nonce = counter_nonce(self.sent);
+        self.sent += 1;
+        self.seal_key.encrypt(&nonce, plain.as_slice()).expect("encrypting into a Vec can't fail")
+    }
+
+    // The first frame failing to open means the other side typed a different code
+    pub fn open(&mut self, sealed: &[u8]) -> Result<Frame, String> {
+        let nonce = counter_nonce(self.received);
+        let plain = self.open_key.decrypt(&nonce, sealed).map_err(|_| "Frame doesn't decrypt".to_string())?;
+        self.received += 1;
+        match plain.split_first() {
+            Some((&FRAME_JSON, json)) => serde_json::from_slice(json).map(Frame::Json).map_err(|e| e.to_string()),
+            Some((&FRAME_DATA, data)) => Ok(Frame::Data(data.to_vec())),
+            _ => Err("Unknown frame".to_string()),
+        }
+    }
+
+    pub fn is_confirmed(&self) -> bool {
+        self.received > 0
+    }
+}
+
+fn counter_nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+// Sends our SPAKE2 message, waits for theirs and derives the channel
+pub async fn exchange_pake<W, R>(write: &mut W, read: &mut R, code: &str, sender: bool) -> Result<Channel, String>
+where
+    W: Sink<Message> + Unpin,
+    W::Error: Display,
+    R: Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    let (pake, msg) = Pake::start(code);
+    let msg = json!({"type": "pake", "msg": msg});
+    write.send(Message::Text(msg.to_string())).await.map_err(|e| e.to_string())?;
+    loop {
+        let msg = match timeout(PAKE_TIMEOUT, read.next()).await {
+            Err(_) => return Err("The other side never answered".to_string()),
+            Ok(None) => return Err("Connection closed".to_string()),
+            Ok(Some(msg)) => msg.map_err(|e| e.to_string())?,
+        };
+        let Message::Text(text) = msg else { continue };
+        let Ok(data) = serde_json::from_str::<Value>(&text) else { continue };
+        match data.get("type").and_then(|v| v.as_str()) {
+            Some("pake") => {
+                let their_msg = data.get("msg").and_then(|v| v.as_str()).unwrap_or_default();
+                return pake.finish(their_msg, sender);
+            }
+            Some("relay_control") => return Err("The other side left".to_string()),
+            _ => {}
+        }
+    }
+}
+
+// Next sealed frame. A frame that doesn't open before any other did means the two
+// codes differ, the other side is told so it doesn't wait forever.
+pub async fn next_frame<W, R>(write: &mut W, read: &mut R, channel: &mut Channel) -> Result<Frame, String>
+where
+    W: Sink<Message> + Unpin,
+    R: Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    while let Some(msg) = read.next().await {
+        match msg.map_err(|e| e.to_string())? {
+            Message::Binary(sealed) => match channel.open(&sealed) {
+                Ok(frame) => return Ok(frame),
+                Err(e) if channel.is_confirmed() => return Err(e),
+                Err(_) => {
+                    let _ = write.send(Message::Text(json!({"type": "wormhole_failed"}).to_string())).await;
+                    return Err("Wrong code: the two sides typed different codes".to_string());
+                }
+            },
+            Message::Text(text) => {
+                let Ok(data) = serde_json::from_str::<Value>(&text) else { continue };
+                match data.get("type").and_then(|v| v.as_str()) {
+                    Some("wormhole_failed") => return Err("Wrong code: the other side couldn't decrypt".to_string()),
+                    Some("relay_control") => return Err("The other side left".to_string()),
+                    Some("error") => {
+                        return Err(data.get("error").and_then(|v| v.as_str()).unwrap_or("unknown error").to_string());
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    Err("Connection closed".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Both sides of a finished exchange, each with the code it typed
+    fn channels(sender_code: &str, receiver_code: &str) -> (Channel, Channel) {
+        let (sender, sender_msg) = Pake::start(sender_code);
+        let (receiver, receiver_msg) = Pake::start(receiver_code);
+        (sender.finish(&receiver_msg, true).unwrap(), receiver.finish(&sender_msg, false).unwrap())
+    }
+
+    fn json_of(frame: Frame) -> Value {
+        match frame {
+            Frame::Json(value) => value,
+            Frame::Data(_) => panic!("expected a JSON frame"),
+        }
+    }
+
+    #[test]
+    fn codes_parse_back() {
+        for nameplate in [0, 7, 4096] {
+            let code = make_code(nameplate);
+            assert_eq!(parse_code(&code), Some((code.clone(), nameplate)));
+        }
+        assert_eq!(parse_code("  7 Crossword BANANA "), Some(("7-crossword-banana".to_string(), 7)));
+        for bad in ["", "7", "7-crossword", "7-crossword-banana-acorn", "x-crossword-banana", "7-crossword-notaword"] {
+            assert_eq!(parse_code(bad), None, "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn same_code_opens_frames_both_ways() {
+        let (mut sender, mut receiver) = channels("7-crossword-banana", "7-crossword-banana");
+        assert!(!receiver.is_confirmed());
+
+        let metadata = json!({"type": "file_metadata", "name": "a.txt"});
+        assert_eq!(json_of(receiver.open(&sender.seal_json(&metadata)).unwrap()), metadata);
+        assert!(receiver.is_confirmed());
+        match receiver.open(&sender.seal_data(b"bytes")).unwrap() {
+            Frame::Data(data) => assert_eq!(data, b"bytes"),
+            Frame::Json(_) => panic!("expected data"),
+        }
+        let reply = json!({"type": "file_accept"});
+        assert_eq!(json_of(sender.open(&receiver.seal_json(&reply)).unwrap()), reply);
+    }
+
+    #[test]
+    fn wrong_code_opens_nothing() {
+        let (mut sender, mut receiver) = channels("7-crossword-banana", "7-crossword-bacon");
+        assert!(receiver.open(&sender.seal_json(&json!({"type": "file_metadata"}))).is_err());
+        assert!(!receiver.is_confirmed());
+    }
+
+    #[test]
+    fn replayed_and_reordered_frames_are_refused() {
+        let (mut sender, mut receiver) = channels("3-acorn-zebra", "3-acorn-zebra");
+        let (first, second) = (sender.seal_data(b"first"), sender.seal_data(b"second"));
+
+        // The second frame out of turn, then the first one twice
+        assert!(receiver.open(&second).is_err());
+        assert!(receiver.open(&first).is_ok());
+        assert!(receiver.open(&first).is_err());
+        assert!(receiver.open(&second).is_ok());
+    }
+
+    #[test]
+    fn reflected_frames_are_refused() {
+        let (mut sender, mut receiver) = channels("3-acorn-zebra", "3-acorn-zebra");
+        // What the sender sealed, bounced back at it, is sealed with the other key
+        let sealed = sender.seal_json(&json!({"type": "file_metadata"}));
+        assert!(sender.open(&sealed).is_err());
+        assert!(receiver.open(&sealed).is_ok());
+
+        let tampered = {
+            let mut sealed = sender.seal_data(b"data");
+            sealed[0] ^= 1;
+            sealed
+        };
+        assert!(receiver.open(&tampered).is_err());
+    }
+}
@@ -0,0 +1,133 @@
+// Everything the integration tests need, on loopback only: the signaling server from
+// the library on an ephemeral port, a STUN responder that answers every binding request
+// with the address it came from, clients registered on that server and a TURN server
+// to relay through (see turn.rs). Nothing talks to 54.66.23.75 or stun.l.google.com.
+#![allow(dead_code)]
+
+pub mod turn;
+
+use p2p_rust::accounts::Accounts;
+use p2p_rust::identity::Identity;
+use p2p_rust::receive::Destination;
+use p2p_rust::signaling_server::run_server;
+use p2p_rust::store::set_data_dir;
+use p2p_rust::tls::SignalingConfig;
+use p2p_rust::Client;
+use std::future::Future;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+use tokio::sync::{Mutex, MutexGuard};
+
+pub const PASSWORD: &str = "correct horse battery staple";
+const MAGIC_COOKIE: u32 = 0x2112A442;
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Identity, contacts and saved tokens live in one data directory for the whole test
+// binary, and transfer events go to every subscriber in the process, so the tests
+// take turns
+static SERIAL: Mutex<()> = Mutex::const_new(());
+static DATA_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
+    let dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR")).unwrap().into_path();
+    set_data_dir(dir.clone());
+    dir
+});
+
+pub struct Harness {
+    pub server_url: String,
+    pub stun_addr: SocketAddr,
+    // Server accounts and whatever the test writes, removed with the harness
+    pub dir: TempDir,
+    _serial: MutexGuard<'static, ()>,
+}
+
+impl Harness {
+    pub async fn start() -> Harness {
+        let serial = SERIAL.lock().await;
+        test_data_dir();
+        let dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR")).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_url = format!("ws://{}", listener.local_addr().unwrap());
+        let accounts = Accounts::load(&dir.path().join("server")).unwrap();
+        tokio::spawn(run_server(listener, accounts, None, None));
+
+        Harness { server_url, stun_addr: start_stun(), dir, _serial: serial }
+    }
+
+    pub fn config(&self) -> SignalingConfig {
+        SignalingConfig { url: self.server_url.clone(), ca_file: None, spki_pins: Vec::new(), cert_pins: Vec::new() }
+    }
+
+    // A client registered as `username`, creating the account
+    pub async fn client(&self, username: &str) -> Client {
+        let identity = Identity::load_or_create().unwrap();
+        let client = Client::connect(self.config(), identity).await.unwrap();
+        client.register(username, Some(PASSWORD)).await.unwrap();
+        client
+    }
+
+    // A fresh folder under the harness directory
+    pub fn folder(&self, name: &str) -> PathBuf {
+        let folder = self.dir.path().join(name);
+        std::fs::create_dir_all(&folder).unwrap();
+        folder
+    }
+}
+
+// Where identities, tokens and speed limits are kept for the tests, set before the
+// library first looks
+pub fn test_data_dir() -> &'static Path {
+    &DATA_DIR
+}
+
+// Runs `send` while `receiver` takes files into `folder`, gives what `send` returned
+pub async fn relay(receiver: &Client, folder: &Path, send: impl Future<Output = Result<(), String>>) -> Result<(), String> {
+    let transfer = async {
+        tokio::select! {
+            result = receiver.receive(Destination::Folder(folder.to_path_buf())) => panic!("receive ended first: {:?}", result),
+            result = send => result,
+        }
+    };
+    tokio::time::timeout(TRANSFER_TIMEOUT, transfer).await.expect("transfer timed out")
+}
+
+// Bytes that don't compress, so codecs and checksums have something to do
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut bytes);
+    bytes
+}
+
+// Answers STUN binding requests until the test binary exits
+fn start_stun() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 1500];
+        while let Ok((len, from)) = socket.recv_from(&mut buf) {
+            if let Some(reply) = binding_response(&buf[..len], from) {
+                let _ = socket.send_to(&reply, from);
+            }
+        }
+    });
+    addr
+}
+
+// Binding success with an XOR-MAPPED-ADDRESS of `from` (RFC 5389), IPv4 only
+fn binding_response(request: &[u8], from: SocketAddr) -> Option<Vec<u8>> {
+    if request.len() < 20 || request[..2] != [0x00, 0x01] || request[4..8] != MAGIC_COOKIE.to_be_bytes() {
+        return None;
+    }
+    let SocketAddr::V4(from) = from else { return None };
+    let mut reply = vec![0x01, 0x01, 0x00, 0x0c];
+    // Magic cookie and transaction id as they came
+    reply.extend_from_slice(&request[4..20]);
+    reply.extend_from_slice(&[0x00, 0x20, 0x00, 0x08, 0x00, 0x01]);
+    reply.extend_from_slice(&(from.port() ^ (MAGIC_COOKIE >> 16) as u16).to_be_bytes());
+    reply.extend_from_slice(&(u32::from(*from.ip()) ^ MAGIC_COOKIE).to_be_bytes());
+    Some(reply)
+}
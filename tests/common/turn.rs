@@ -0,0 +1,457 @@
+// A TURN server on loopback, enough of RFC 5766 for the client in turn.rs: Allocate
+// behind a 401 challenge with long-term credentials, CreatePermission, ChannelBind,
+// Refresh, Send and Data indications and ChannelData. Every request after the first
+// few finds its nonce stale (438), like a server that rotates them. It counts what it
+// did so tests can check the client went the way they expect.
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+
+pub const TURN_USERNAME: &str = "turn-user";
+pub const TURN_PASSWORD: &str = "turn-secret";
+const REALM: &str = "p2p-test";
+const MAGIC_COOKIE: u32 = 0x2112A442;
+
+const ALLOCATE: u16 = 0x003;
+const REFRESH: u16 = 0x004;
+const SEND: u16 = 0x006;
+const DATA: u16 = 0x007;
+const CREATE_PERMISSION: u16 = 0x008;
+const CHANNEL_BIND: u16 = 0x009;
+const REQUEST: u16 = 0x0000;
+const INDICATION: u16 = 0x0010;
+const SUCCESS: u16 = 0x0100;
+const ERROR: u16 = 0x0110;
+
+const ATTR_USERNAME: u16 = 0x0006;
+const ATTR_MESSAGE_INTEGRITY: u16 = 0x0008;
+const ATTR_ERROR_CODE: u16 = 0x0009;
+const ATTR_CHANNEL_NUMBER: u16 = 0x000C;
+const ATTR_LIFETIME: u16 = 0x000D;
+const ATTR_XOR_PEER_ADDRESS: u16 = 0x0012;
+const ATTR_DATA: u16 = 0x0013;
+const ATTR_REALM: u16 = 0x0014;
+const ATTR_NONCE: u16 = 0x0015;
+const ATTR_XOR_RELAYED_ADDRESS: u16 = 0x0016;
+const ATTR_REQUESTED_TRANSPORT: u16 = 0x0019;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+// What the server did so far
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TurnStats {
+    // 401s to requests without credentials, and to wrong ones
+    pub challenges: u32,
+    pub rejected: u32,
+    pub stale_nonces: u32,
+    pub allocations: u32,
+    pub permissions: u32,
+    pub channel_binds: u32,
+    pub refreshes: u32,
+    pub deallocations: u32,
+    // Client to peer
+    pub send_indications: u32,
+    pub channel_data_in: u32,
+    // Peer to client
+    pub data_indications: u32,
+    pub channel_data_out: u32,
+    // Peer packets without a permission or allocation
+    pub dropped: u32,
+}
+
+struct Allocation {
+    relay: Arc<UdpSocket>,
+    expires: Instant,
+    permissions: HashMap<IpAddr, Instant>,
+    channels: HashMap<u16, SocketAddr>,
+    task: JoinHandle<()>,
+}
+
+struct State {
+    lifetime: u32,
+    nonce: u32,
+    // Requests the current nonce has left before it goes stale
+    nonce_uses: u32,
+    allocations: HashMap<SocketAddr, Allocation>,
+    // Off to answer like someone spoofing the server would, without MESSAGE-INTEGRITY
+    signing: bool,
+    stats: TurnStats,
+}
+
+pub struct TurnServer {
+    pub addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    task: JoinHandle<()>,
+}
+
+// A nonce lasts this many authenticated requests
+const NONCE_USES: u32 = 2;
+const PERMISSION_LIFETIME: Duration = Duration::from_secs(300);
+
+impl TurnServer {
+    // Allocations last `lifetime` seconds unless refreshed
+    pub async fn start(lifetime: u32) -> TurnServer {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr = socket.local_addr().unwrap();
+        let state = State { lifetime, nonce: 1, nonce_uses: NONCE_USES, allocations: HashMap::new(), signing: true, stats: TurnStats::default() };
+        let state = Arc::new(Mutex::new(state));
+        let task = tokio::spawn(serve(socket.clone(), state.clone()));
+        TurnServer { addr, state, task }
+    }
+
+    pub fn config(&self, password: &str) -> p2p_rust::turn::TurnConfig {
+        p2p_rust::turn::TurnConfig { server: self.addr, username: TURN_USERNAME.to_string(), password: password.to_string() }
+    }
+
+    pub fn set_signing(&self, signing: bool) {
+        self.state.lock().unwrap().signing = signing;
+    }
+
+    pub fn stats(&self) -> TurnStats {
+        self.state.lock().unwrap().stats
+    }
+
+    // The addresses peers see relayed packets come from
+    pub fn relayed_addrs(&self) -> Vec<SocketAddr> {
+        let state = self.state.lock().unwrap();
+        state.allocations.values().map(|allocation| allocation.relay.local_addr().unwrap()).collect()
+    }
+}
+
+impl Drop for TurnServer {
+    fn drop(&mut self) {
+        self.task.abort();
+        for allocation in self.state.lock().unwrap().allocations.values() {
+            allocation.task.abort();
+        }
+    }
+}
+
+struct Message {
+    method: u16,
+    class: u16,
+    transaction_id: [u8; 12],
+    attributes: Vec<(u16, Vec<u8>)>,
+}
+
+impl Message {
+    fn decode(raw: &[u8]) -> Option<Message> {
+        if raw.len() < 20 || raw[0] & 0xC0 != 0 || raw[4..8] != MAGIC_COOKIE.to_be_bytes() {
+            return None;
+        }
+        let msg_type = u16::from_be_bytes([raw[0], raw[1]]);
+        let len = u16::from_be_bytes([raw[2], raw[3]]) as usize;
+        let body = raw.get(20..20 + len)?;
+        let mut attributes = Vec::new();
+        let mut i = 0;
+        while i + 4 <= body.len() {
+            let attr_type = u16::from_be_bytes([body[i], body[i + 1]]);
+            let attr_len = u16::from_be_bytes([body[i + 2], body[i + 3]]) as usize;
+            attributes.push((attr_type, body.get(i + 4..i + 4 + attr_len)?.to_vec()));
+            i += 4 + attr_len.div_ceil(4) * 4;
+        }
+        Some(Message {
+            method: msg_type & !0x0110,
+            class: msg_type & 0x0110,
+            transaction_id: raw[8..20].try_into().unwrap(),
+            attributes,
+        })
+    }
+
+    fn reply(&self, class: u16) -> Message {
+        Message { method: self.method, class, transaction_id: self.transaction_id, attributes: Vec::new() }
+    }
+
+    fn get(&self, attr_type: u16) -> Option<&[u8]> {
+        self.attributes.iter().find(|(t, _)| *t == attr_type).map(|(_, v)| v.as_slice())
+    }
+
+    fn add(&mut self, attr_type: u16, value: Vec<u8>) -> &mut Message {
+        self.attributes.push((attr_type, value));
+        self
+    }
+
+    fn add_address(&mut self, attr_type: u16, addr: SocketAddr) -> &mut Message {
+        let value = xor_address(addr);
+        self.add(attr_type, value)
+    }
+
+    fn address(&self, attr_type: u16) -> Option<SocketAddr> {
+        let value = self.get(attr_type)?;
+        if value.len() != 8 || value[1] != 0x01 {
+            return None;
+        }
+        let port = u16::from_be_bytes([value[2], value[3]]) ^ (MAGIC_COOKIE >> 16) as u16;
+        let ip = u32::from_be_bytes(value[4..8].try_into().unwrap()) ^ MAGIC_COOKIE;
+        Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), port))
+    }
+
+    fn encode(&self, key: Option<&[u8]>) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(self.method | self.class).to_be_bytes());
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf.extend_from_slice(&self.transaction_id);
+        for (attr_type, value) in &self.attributes {
+            push_attribute(&mut buf, *attr_type, value);
+        }
+        if let Some(key) = key {
+            let len = (buf.len() - 20 + 24) as u16;
+            buf[2..4].copy_from_slice(&len.to_be_bytes());
+            let digest = hmac(key, &buf);
+            push_attribute(&mut buf, ATTR_MESSAGE_INTEGRITY, &digest);
+        }
+        let len = (buf.len() - 20) as u16;
+        buf[2..4].copy_from_slice(&len.to_be_bytes());
+        buf
+    }
+}
+
+fn push_attribute(buf: &mut Vec<u8>, attr_type: u16, value: &[u8]) {
+    buf.extend_from_slice(&attr_type.to_be_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+    buf.resize(buf.len() + (4 - value.len() % 4) % 4, 0);
+}
+
+fn xor_address(addr: SocketAddr) -> Vec<u8> {
+    let IpAddr::V4(ip) = addr.ip() else { panic!("the test TURN server is IPv4 only") };
+    let mut value = vec![0, 0x01];
+    value.extend_from_slice(&(addr.port() ^ (MAGIC_COOKIE >> 16) as u16).to_be_bytes());
+    value.extend_from_slice(&(u32::from(ip) ^ MAGIC_COOKIE).to_be_bytes());
+    value
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn long_term_key(password: &str) -> Vec<u8> {
+    Md5::digest(format!("{}:{}:{}", TURN_USERNAME, REALM, password)).to_vec()
+}
+
+// Whether MESSAGE-INTEGRITY of `raw` was made with `key`
+fn integrity_ok(raw: &[u8], key: &[u8]) -> bool {
+    let mut i = 20;
+    while i + 4 <= raw.len() {
+        let attr_type = u16::from_be_bytes([raw[i], raw[i + 1]]);
+        let attr_len = u16::from_be_bytes([raw[i + 2], raw[i + 3]]) as usize;
+        if attr_type == ATTR_MESSAGE_INTEGRITY {
+            let mut covered = raw[..i].to_vec();
+            covered[2..4].copy_from_slice(&((i - 20 + 24) as u16).to_be_bytes());
+            return raw.get(i + 4..i + 24) == Some(&hmac(key, &covered)[..]);
+        }
+        i += 4 + attr_len.div_ceil(4) * 4;
+    }
+    false
+}
+
+fn error(request: &Message, code: u16, reason: &str) -> Message {
+    let mut value = vec![0, 0, (code / 100) as u8, (code % 100) as u8];
+    value.extend_from_slice(reason.as_bytes());
+    let mut reply = request.reply(ERROR);
+    reply.add(ATTR_ERROR_CODE, value);
+    reply
+}
+
+async fn serve(socket: Arc<UdpSocket>, state: Arc<Mutex<State>>) {
+    let mut buf = vec![0u8; 65536];
+    let key = long_term_key(TURN_PASSWORD);
+    loop {
+        let Ok((len, client)) = socket.recv_from(&mut buf).await else { return };
+        let raw = &buf[..len];
+        if raw.len() >= 4 && (0x40..=0x7F).contains(&raw[0]) {
+            channel_data(&state, client, raw).await;
+            continue;
+        }
+        let Some(msg) = Message::decode(raw) else { continue };
+        if msg.class == INDICATION && msg.method == SEND {
+            send_indication(&state, client, &msg).await;
+            continue;
+        }
+        if msg.class != REQUEST {
+            continue;
+        }
+        let reply = match authenticate(&state, &msg, raw, &key) {
+            Err(reply) => reply,
+            Ok(()) => handle(&socket, &state, client, &msg).await,
+        };
+        let signed = (reply.class == SUCCESS && state.lock().unwrap().signing).then_some(key.as_slice());
+        let _ = socket.send_to(&reply.encode(signed), client).await;
+    }
+}
+
+// The long-term credential dance: a challenge first, then a stale nonce every
+// NONCE_USES requests
+fn authenticate(state: &Mutex<State>, msg: &Message, raw: &[u8], key: &[u8]) -> Result<(), Message> {
+    let mut state = state.lock().unwrap();
+    let nonce = state.nonce.to_string();
+    let challenge = |state: &State, code: u16, reason: &str| {
+        let mut reply = error(msg, code, reason);
+        reply.add(ATTR_REALM, REALM.as_bytes().to_vec()).add(ATTR_NONCE, state.nonce.to_string().into_bytes());
+        reply
+    };
+    if msg.get(ATTR_MESSAGE_INTEGRITY).is_none() {
+        state.stats.challenges += 1;
+        return Err(challenge(&state, 401, "Unauthorized"));
+    }
+    if msg.get(ATTR_USERNAME) != Some(TURN_USERNAME.as_bytes()) || msg.get(ATTR_REALM) != Some(REALM.as_bytes()) || !integrity_ok(raw, key) {
+        state.stats.rejected += 1;
+        return Err(challenge(&state, 401, "Unauthorized"));
+    }
+    if msg.get(ATTR_NONCE) != Some(nonce.as_bytes()) || state.nonce_uses == 0 {
+        state.stats.stale_nonces += 1;
+        state.nonce += 1;
+        state.nonce_uses = NONCE_USES;
+        return Err(challenge(&state, 438, "Stale Nonce"));
+    }
+    state.nonce_uses -= 1;
+    Ok(())
+}
+
+async fn handle(socket: &Arc<UdpSocket>, state: &Arc<Mutex<State>>, client: SocketAddr, msg: &Message) -> Message {
+    if msg.method == ALLOCATE {
+        return allocate(socket, state, client, msg).await;
+    }
+    let mut guard = state.lock().unwrap();
+    let state = &mut *guard;
+    let lifetime = state.lifetime;
+    let Some(allocation) = state.allocations.get_mut(&client).filter(|a| a.expires > Instant::now()) else {
+        return error(msg, 437, "Allocation Mismatch");
+    };
+    match msg.method {
+        CREATE_PERMISSION => {
+            let Some(peer) = msg.address(ATTR_XOR_PEER_ADDRESS) else { return error(msg, 400, "Bad Request") };
+            allocation.permissions.insert(peer.ip(), Instant::now() + PERMISSION_LIFETIME);
+            state.stats.permissions += 1;
+            msg.reply(SUCCESS)
+        }
+        CHANNEL_BIND => {
+            let channel = msg.get(ATTR_CHANNEL_NUMBER).map(|v| u16::from_be_bytes([v[0], v[1]]));
+            let (Some(channel @ 0x4000..=0x7FFE), Some(peer)) = (channel, msg.address(ATTR_XOR_PEER_ADDRESS)) else {
+                return error(msg, 400, "Bad Request");
+            };
+            if allocation.channels.iter().any(|(c, p)| (*c == channel) != (*p == peer)) {
+                return error(msg, 400, "Channel taken");
+            }
+            allocation.channels.insert(channel, peer);
+            allocation.permissions.insert(peer.ip(), Instant::now() + PERMISSION_LIFETIME);
+            state.stats.channel_binds += 1;
+            msg.reply(SUCCESS)
+        }
+        REFRESH => {
+            let requested = msg.get(ATTR_LIFETIME).map_or(lifetime, |v| u32::from_be_bytes(v[..4].try_into().unwrap()));
+            let mut reply = msg.reply(SUCCESS);
+            reply.add(ATTR_LIFETIME, requested.min(lifetime).to_be_bytes().to_vec());
+            if requested == 0 {
+                let allocation = state.allocations.remove(&client).unwrap();
+                allocation.task.abort();
+                state.stats.deallocations += 1;
+            } else {
+                allocation.expires = Instant::now() + Duration::from_secs(requested.min(lifetime) as u64);
+                state.stats.refreshes += 1;
+            }
+            reply
+        }
+        _ => error(msg, 400, "Bad Request"),
+    }
+}
+
+async fn allocate(socket: &Arc<UdpSocket>, state: &Arc<Mutex<State>>, client: SocketAddr, msg: &Message) -> Message {
+    if msg.get(ATTR_REQUESTED_TRANSPORT).and_then(|v| v.first()) != Some(&17) {
+        return error(msg, 442, "Unsupported Transport Protocol");
+    }
+    if state.lock().unwrap().allocations.get(&client).is_some_and(|a| a.expires > Instant::now()) {
+        return error(msg, 437, "Allocation Mismatch");
+    }
+    let relay = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let relayed = relay.local_addr().unwrap();
+    let task = tokio::spawn(relay_to_client(socket.clone(), relay.clone(), state.clone(), client));
+    let mut state = state.lock().unwrap();
+    let lifetime = state.lifetime;
+    let expires = Instant::now() + Duration::from_secs(lifetime as u64);
+    let allocation = Allocation { relay, expires, permissions: HashMap::new(), channels: HashMap::new(), task };
+    if let Some(previous) = state.allocations.insert(client, allocation) {
+        previous.task.abort();
+    }
+    state.stats.allocations += 1;
+    let mut reply = msg.reply(SUCCESS);
+    reply
+        .add_address(ATTR_XOR_RELAYED_ADDRESS, relayed)
+        .add_address(ATTR_XOR_MAPPED_ADDRESS, client)
+        .add(ATTR_LIFETIME, lifetime.to_be_bytes().to_vec());
+    reply
+}
+
+// The relay socket if `client` may send to `peer` from it
+fn relay_for(state: &Mutex<State>, client: SocketAddr, peer: SocketAddr) -> Option<Arc<UdpSocket>> {
+    let mut state = state.lock().unwrap();
+    let allocation = state.allocations.get(&client)?;
+    if allocation.permissions.get(&peer.ip()).is_some_and(|until| *until > Instant::now()) {
+        return Some(allocation.relay.clone());
+    }
+    state.stats.dropped += 1;
+    None
+}
+
+async fn send_indication(state: &Mutex<State>, client: SocketAddr, msg: &Message) {
+    let (Some(peer), Some(data)) = (msg.address(ATTR_XOR_PEER_ADDRESS), msg.get(ATTR_DATA)) else { return };
+    let Some(relay) = relay_for(state, client, peer) else { return };
+    state.lock().unwrap().stats.send_indications += 1;
+    let _ = relay.send_to(data, peer).await;
+}
+
+async fn channel_data(state: &Mutex<State>, client: SocketAddr, raw: &[u8]) {
+    let channel = u16::from_be_bytes([raw[0], raw[1]]);
+    let len = u16::from_be_bytes([raw[2], raw[3]]) as usize;
+    let Some(data) = raw.get(4..4 + len) else { return };
+    let peer = state.lock().unwrap().allocations.get(&client).and_then(|a| a.channels.get(&channel).copied());
+    let Some(peer) = peer else { return };
+    let Some(relay) = relay_for(state, client, peer) else { return };
+    state.lock().unwrap().stats.channel_data_in += 1;
+    let _ = relay.send_to(data, peer).await;
+}
+
+// What peers send to the relayed address goes to the client, over its channel when
+// the peer has one
+async fn relay_to_client(socket: Arc<UdpSocket>, relay: Arc<UdpSocket>, state: Arc<Mutex<State>>, client: SocketAddr) {
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let Ok((len, peer)) = relay.recv_from(&mut buf).await else { return };
+        let data = &buf[..len];
+        let packet = {
+            let mut state = state.lock().unwrap();
+            let state = &mut *state;
+            let Some(allocation) = state.allocations.get(&client) else { return };
+            // An expired allocation relays nothing
+            let expired = allocation.expires <= Instant::now();
+            if expired || allocation.permissions.get(&peer.ip()).is_none_or(|until| *until <= Instant::now()) {
+                state.stats.dropped += 1;
+                continue;
+            }
+            match allocation.channels.iter().find(|(_, p)| **p == peer) {
+                Some((channel, _)) => {
+                    state.stats.channel_data_out += 1;
+                    let mut packet = channel.to_be_bytes().to_vec();
+                    packet.extend_from_slice(&(len as u16).to_be_bytes());
+                    packet.extend_from_slice(data);
+                    packet
+                }
+                None => {
+                    state.stats.data_indications += 1;
+                    let mut indication = Message { method: DATA, class: INDICATION, transaction_id: rand::random(), attributes: Vec::new() };
+                    indication.add_address(ATTR_XOR_PEER_ADDRESS, peer).add(ATTR_DATA, data.to_vec());
+                    indication.encode(None)
+                }
+            }
+        };
+        let _ = socket.send_to(&packet, client).await;
+    }
+}
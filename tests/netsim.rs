@@ -0,0 +1,151 @@
+// The UDP transfer on a simulated bad network (see netsim.rs), both with the sockets
+// wrapped in-process and through the proxy. Seeds keep the impaired packets the same
+// every run.
+mod common;
+
+use common::{random_bytes, test_data_dir};
+use p2p_rust::netsim::{run_proxy, Impaired, Impairment};
+use p2p_rust::udp::{receive_file_udp, send_file_to, send_file_udp, Datagram};
+use std::fs;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+async fn impaired(spec: &str) -> Impaired<UdpSocket> {
+    Impaired::new(UdpSocket::bind("127.0.0.1:0").await.unwrap(), Impairment::parse(spec).unwrap())
+}
+
+// Sends `count` numbered datagrams through `link`, gives the numbers in the order they came out
+async fn numbers_through(link: &Impaired<UdpSocket>, count: u8) -> Vec<u8> {
+    let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let target = link.get_ref().local_addr().unwrap();
+    for n in 0..count {
+        sender.send_to(&[n], target).await.unwrap();
+    }
+    let mut numbers = Vec::new();
+    let mut buf = [0u8; 16];
+    while let Ok(Ok((len, _))) = tokio::time::timeout(Duration::from_millis(300), link.recv_from(&mut buf)).await {
+        numbers.extend_from_slice(&buf[..len]);
+    }
+    numbers
+}
+
+#[test]
+fn impairment_spec() {
+    let impairment = Impairment::parse("loss=5% dup=0.01 reorder=2% delay=40ms jitter=10 rate=256 seed=7").unwrap();
+    assert_eq!(
+        impairment,
+        Impairment {
+            loss: 0.05,
+            duplicate: 0.01,
+            reorder: 0.02,
+            delay: Duration::from_millis(40),
+            jitter: Duration::from_millis(10),
+            rate: 256,
+            seed: Some(7),
+        }
+    );
+    assert_eq!(Impairment::parse(""), Ok(Impairment::default()));
+    assert!(Impairment::parse("loss=150%").is_err());
+    assert!(Impairment::parse("delay=soon").is_err());
+    assert!(Impairment::parse("speed=1").is_err());
+}
+
+#[tokio::test]
+async fn perfect_network_changes_nothing() {
+    let link = impaired("").await;
+    assert_eq!(numbers_through(&link, 50).await, (0..50).collect::<Vec<_>>());
+    let stats = link.stats();
+    assert_eq!((stats.received, stats.delivered, stats.lost), (50, 50, 0));
+}
+
+#[tokio::test]
+async fn loss_duplication_and_reordering() {
+    let lossy = impaired("loss=30% seed=1").await;
+    let numbers = numbers_through(&lossy, 100).await;
+    assert_eq!(numbers.len() as u64, 100 - lossy.stats().lost);
+    assert!((15..=45).contains(&lossy.stats().lost));
+    assert!(numbers.is_sorted());
+
+    let doubled = impaired("dup=100%").await;
+    assert_eq!(numbers_through(&doubled, 20).await, (0..20).flat_map(|n| [n, n]).collect::<Vec<_>>());
+
+    let shuffled = impaired("reorder=30% seed=2").await;
+    let mut numbers = numbers_through(&shuffled, 50).await;
+    assert!(shuffled.stats().reordered > 0);
+    assert!(!numbers.is_sorted());
+    numbers.sort();
+    assert_eq!(numbers, (0..50).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn delay_and_bandwidth() {
+    let slow = impaired("delay=100ms").await;
+    let started = Instant::now();
+    assert_eq!(numbers_through(&slow, 1).await, vec![0]);
+    assert!(started.elapsed() >= Duration::from_millis(100));
+
+    // 20 x 1 KB at 40 KB/s take half a second to come through
+    let capped = impaired("rate=40").await;
+    let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let target = capped.get_ref().local_addr().unwrap();
+    let started = Instant::now();
+    for _ in 0..20 {
+        sender.send_to(&[0u8; 1024], target).await.unwrap();
+    }
+    let mut buf = [0u8; 2048];
+    for _ in 0..20 {
+        capped.recv_from(&mut buf).await.unwrap();
+    }
+    assert!(started.elapsed() >= Duration::from_millis(450));
+}
+
+// Data packets get lost, doubled and reordered on the way there, acks on the way back
+#[tokio::test]
+async fn send_with_ack_on_a_bad_network() {
+    let dir = tempfile::tempdir_in(test_data_dir()).unwrap();
+    let receiver = impaired("loss=10% dup=10% reorder=10% delay=2ms jitter=5ms seed=3").await;
+    let sender = impaired("loss=10% dup=10% reorder=10% delay=2ms jitter=5ms seed=4").await;
+    let peer = receiver.get_ref().local_addr().unwrap();
+
+    let content = random_bytes(40 * 1024 + 7);
+    let path = dir.path().join("rough.bin");
+    fs::write(&path, &content).unwrap();
+    let inbox = dir.path().join("inbox");
+    let (sent, received) = tokio::join!(send_file_to(&sender, peer, &path), receive_file_udp(&receiver, &inbox));
+    sent.unwrap();
+    received.unwrap();
+
+    assert_eq!(fs::read(inbox.join("rough.bin")).unwrap(), content);
+    for stats in [receiver.stats(), sender.stats()] {
+        assert!(stats.lost > 0 && stats.duplicated > 0 && stats.reordered > 0, "{:?}", stats);
+    }
+}
+
+// send_file_udp only knows the proxy's address, like a program would
+#[tokio::test]
+async fn transfer_through_the_proxy() {
+    let dir = tempfile::tempdir_in(test_data_dir()).unwrap();
+    let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let listen = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = listen.local_addr().unwrap().to_string();
+    let forward = Impairment::parse("loss=5% delay=5ms rate=64 seed=5").unwrap();
+    let back = Impairment::parse("delay=5ms").unwrap();
+    let proxy = run_proxy(listen, receiver.local_addr().unwrap(), forward, back);
+
+    let content = random_bytes(32 * 1024);
+    let path = dir.path().join("proxied.bin");
+    fs::write(&path, &content).unwrap();
+    let inbox = dir.path().join("inbox");
+    let started = Instant::now();
+    let transfer = async { tokio::join!(send_file_udp(&path, &proxy_addr), receive_file_udp(&receiver, &inbox)) };
+    let (sent, received) = tokio::select! {
+        result = proxy => panic!("proxy stopped: {:?}", result),
+        results = transfer => results,
+    };
+    sent.unwrap();
+    received.unwrap();
+
+    assert_eq!(fs::read(inbox.join("proxied.bin")).unwrap(), content);
+    // One packet at a time: every one waits for its ack, 10 ms there and back at least
+    assert!(started.elapsed() >= Duration::from_millis(33 * 10));
+}
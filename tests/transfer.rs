@@ -0,0 +1,285 @@
+// The whole send/receive flow against the in-process server (see common/mod.rs):
+// register, list peers, initiate a relay session and transfer, then check what arrived.
+// The relay goes through websockets to the server; the UDP path sends straight to the
+// address the peer registered from STUN and the TCP path straight to a listening peer.
+mod common;
+
+use common::{random_bytes, relay, Harness, PASSWORD};
+use futures::channel::mpsc::UnboundedReceiver;
+use p2p_rust::history::Direction;
+use p2p_rust::receive::Destination;
+use p2p_rust::signaling::{get_pip_port_json_and_sockets_with, get_users, register, server_request};
+use p2p_rust::tcp::{receive_file_tcp, send_file_tcp};
+use p2p_rust::tls::connect_signaling;
+use p2p_rust::udp::{receive_file_udp, send_file_udp};
+use p2p_rust::{Source, TransferEvent};
+use serde_json::json;
+use std::fs;
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+#[tokio::test]
+async fn peers_are_listed_once_registered() {
+    let harness = Harness::start().await;
+    let alice = harness.client("alice").await;
+    let _bob = harness.client("bob").await;
+
+    assert_eq!(alice.username().as_deref(), Some("alice"));
+    assert_eq!(alice.list_peers().await.unwrap(), vec!["alice", "bob"]);
+}
+
+// What the keep-alive sends when the NAT mapping changes, from a connection of its own
+#[tokio::test]
+async fn address_update_keeps_the_registration() {
+    let harness = Harness::start().await;
+    let bob_ws = Arc::new(Mutex::new(connect_signaling(&harness.config()).await.unwrap()));
+    let payload = json!({"type": "register", "username": "bob", "password": PASSWORD, "ipv4_ip": "192.0.2.1", "ipv4_port": 1000});
+    let token = register(&payload, bob_ws.clone()).await.unwrap();
+
+    let keepalive_ws = Arc::new(Mutex::new(connect_signaling(&harness.config()).await.unwrap()));
+    let update = json!({"type": "update_address", "token": token, "ipv4_ip": "192.0.2.2", "ipv4_port": 2000});
+    server_request(update, "address_updated", keepalive_ws.clone()).await.unwrap();
+    drop(keepalive_ws);
+
+    let users = get_users(Arc::new(Mutex::new(connect_signaling(&harness.config()).await.unwrap()))).await.unwrap();
+    assert_eq!(users["bob"]["ipv4_ip"], "192.0.2.2");
+    assert_eq!(users["bob"]["ipv4_port"], 2000);
+
+    let update = json!({"type": "update_address", "token": "forged", "ipv4_ip": "192.0.2.3", "ipv4_port": 3000});
+    let stranger_ws = Arc::new(Mutex::new(connect_signaling(&harness.config()).await.unwrap()));
+    assert!(server_request(update, "address_updated", stranger_ws).await.is_err());
+    drop(bob_ws);
+}
+
+#[tokio::test]
+async fn relay_file() {
+    let harness = Harness::start().await;
+    let alice = harness.client("alice").await;
+    let bob = harness.client("bob").await;
+    let mut sent = alice.events();
+    let mut received = bob.events();
+
+    let content = random_bytes(300 * 1024);
+    let path = harness.folder("outbox").join("report.bin");
+    fs::write(&path, &content).unwrap();
+    let inbox = harness.folder("inbox");
+    relay(&bob, &inbox, alice.send("bob", Source::File(path))).await.unwrap();
+
+    assert_eq!(fs::read(inbox.join("report.bin")).unwrap(), content);
+    // Each client hears about its own side only
+    assert_eq!(
+        finished(&mut sent),
+        vec![(Direction::Sent, "bob".to_string(), "report.bin".to_string(), content.len() as u64, None)]
+    );
+    assert_eq!(
+        finished(&mut received),
+        vec![(Direction::Received, "alice".to_string(), "report.bin".to_string(), content.len() as u64, Some(inbox.join("report.bin")))]
+    );
+}
+
+fn finished(events: &mut UnboundedReceiver<TransferEvent>) -> Vec<(Direction, String, String, u64, Option<PathBuf>)> {
+    let mut finished = Vec::new();
+    while let Ok(Some(event)) = events.try_next() {
+        if let TransferEvent::Finished { direction, peer, name, bytes, path } = event {
+            finished.push((direction, peer, name, bytes, path));
+        }
+    }
+    finished
+}
+
+// Bob has an older copy, so only what changed goes out as literal data (see delta.rs)
+#[tokio::test]
+async fn relay_delta_update() {
+    let harness = Harness::start().await;
+    let alice = harness.client("alice").await;
+    let bob = harness.client("bob").await;
+
+    let old = random_bytes(2 * 1024 * 1024);
+    let mut new = old.clone();
+    new[1_000_000..1_000_100].copy_from_slice(&random_bytes(100));
+    new.extend_from_slice(&random_bytes(5000));
+    let inbox = harness.folder("inbox");
+    fs::write(inbox.join("notes.bin"), &old).unwrap();
+    let path = harness.folder("outbox").join("notes.bin");
+    fs::write(&path, &new).unwrap();
+    relay(&bob, &inbox, alice.send("bob", Source::File(path))).await.unwrap();
+
+    assert!(fs::read(inbox.join("notes.bin")).unwrap() == new);
+}
+
+#[tokio::test]
+async fn relay_stream() {
+    let harness = Harness::start().await;
+    let alice = harness.client("alice").await;
+    let bob = harness.client("bob").await;
+
+    let content = random_bytes(200 * 1024);
+    let source = Source::Stream { name: "piped.bin".to_string(), reader: Box::new(Cursor::new(content.clone())) };
+    let inbox = harness.folder("inbox");
+    relay(&bob, &inbox, alice.send("bob", source)).await.unwrap();
+
+    assert_eq!(fs::read(inbox.join("piped.bin")).unwrap(), content);
+}
+
+// Large files go over parallel relay streams (see multistream.rs)
+#[tokio::test]
+async fn relay_parallel_streams() {
+    let harness = Harness::start().await;
+    let alice = harness.client("alice").await;
+    let bob = harness.client("bob").await;
+
+    let content = random_bytes(20 * 1024 * 1024 + 12345);
+    let path = harness.folder("outbox").join("large.bin");
+    fs::write(&path, &content).unwrap();
+    let inbox = harness.folder("inbox");
+    relay(&bob, &inbox, alice.send("bob", Source::File(path))).await.unwrap();
+
+    assert!(fs::read(inbox.join("large.bin")).unwrap() == content);
+}
+
+#[tokio::test]
+async fn relay_missing_file_fails() {
+    let harness = Harness::start().await;
+    let alice = harness.client("alice").await;
+    let bob = harness.client("bob").await;
+
+    let path = harness.dir.path().join("missing.bin");
+    let inbox = harness.folder("inbox");
+    assert!(relay(&bob, &inbox, alice.send("bob", Source::File(path))).await.is_err());
+    assert_eq!(fs::read_dir(&inbox).unwrap().count(), 0);
+}
+
+// Nothing to name the file after, refused before bob is asked
+#[tokio::test]
+async fn relay_directory_fails() {
+    let harness = Harness::start().await;
+    let alice = harness.client("alice").await;
+    let _bob = harness.client("bob").await;
+
+    for path in [PathBuf::from(".."), PathBuf::from("/"), harness.folder("outbox")] {
+        let error = alice.send("bob", Source::File(path.clone())).await.unwrap_err();
+        assert_eq!(error, format!("{} is not a file", path.display()));
+    }
+}
+
+#[tokio::test]
+async fn relay_declined_file_fails() {
+    let harness = Harness::start().await;
+    let alice = harness.client("alice").await;
+    let bob = harness.client("bob").await;
+
+    let path = harness.folder("outbox").join("unwanted.bin");
+    fs::write(&path, random_bytes(1024)).unwrap();
+    let inbox = harness.folder("inbox");
+    let offered = std::cell::RefCell::new(Vec::new());
+    let on_offer = |sender: &str, metadata: &serde_json::Value| {
+        offered.borrow_mut().push((sender.to_string(), metadata["name"].as_str().unwrap_or_default().to_string()));
+        async { false }
+    };
+    let result = tokio::select! {
+        result = bob.receive_with(Destination::Folder(inbox.clone()), |_| {}, on_offer, |_, _| {}) => panic!("receive ended first: {:?}", result),
+        result = alice.send("bob", Source::File(path)) => result,
+    };
+
+    assert_eq!(result, Err("bob doesn't take files from alice".to_string()));
+    assert_eq!(*offered.borrow(), vec![("alice".to_string(), "unwanted.bin".to_string())]);
+    assert_eq!(fs::read_dir(&inbox).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn send_needs_registration() {
+    let harness = Harness::start().await;
+    let identity = p2p_rust::identity::Identity::load_or_create().unwrap();
+    let client = p2p_rust::Client::connect(harness.config(), identity).await.unwrap();
+
+    let path = harness.dir.path().join("anything.bin");
+    assert_eq!(client.send("bob", Source::File(path)).await, Err("Not registered".to_string()));
+}
+
+#[tokio::test]
+async fn udp_file() {
+    let harness = Harness::start().await;
+    let alice = harness.client("alice").await;
+
+    // Bob registers the address the STUN responder saw and listens on that same socket
+    let stun = harness.stun_addr.to_string();
+    let (payload, mut sockets) = get_pip_port_json_and_sockets_with(&stun, 0, 0, "bob", PASSWORD);
+    let (socket, _) = sockets.pop().expect("no address from STUN");
+    let bob_ws = Arc::new(Mutex::new(connect_signaling(&harness.config()).await.unwrap()));
+    register(&payload, bob_ws.clone()).await.unwrap();
+    socket.set_nonblocking(true).unwrap();
+    let socket = tokio::net::UdpSocket::from_std(socket).unwrap();
+
+    // Alice looks bob up on the server
+    let alice_ws = Arc::new(Mutex::new(connect_signaling(&harness.config()).await.unwrap()));
+    let users = get_users(alice_ws).await.unwrap();
+    let bob = &users["bob"];
+    let target = format!("{}:{}", bob["ipv4_ip"].as_str().unwrap(), bob["ipv4_port"]);
+    assert_eq!(bob["ipv4_port"].as_u64(), Some(socket.local_addr().unwrap().port() as u64));
+
+    let content = random_bytes(64 * 1024 + 100);
+    let path = harness.folder("outbox").join("datagrams.bin");
+    fs::write(&path, &content).unwrap();
+    let inbox = harness.folder("inbox");
+    let (sent, received) = tokio::join!(send_file_udp(&path, &target), receive_file_udp(&socket, &inbox));
+    sent.unwrap();
+    received.unwrap();
+
+    assert_eq!(fs::read(inbox.join("datagrams.bin")).unwrap(), content);
+    assert!(alice.list_peers().await.unwrap().contains(&"bob".to_string()));
+}
+
+#[tokio::test]
+async fn udp_unreadable_file_fails() {
+    let harness = Harness::start().await;
+    let missing = harness.dir.path().join("missing.bin");
+    let error = send_file_udp(&missing, "127.0.0.1:9").await.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+
+    let error = send_file_udp(&harness.folder(".."), "127.0.0.1:9").await.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[tokio::test]
+async fn tcp_file() {
+    let harness = Harness::start().await;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = listener.local_addr().unwrap().to_string();
+
+    let content = random_bytes(300 * 1024 + 5);
+    let path = harness.folder("outbox").join("stream.bin");
+    fs::write(&path, &content).unwrap();
+    let inbox = harness.folder("inbox");
+    let (sent, received) = tokio::join!(send_file_tcp(&path, &target), receive_file_tcp(&listener, &inbox));
+    sent.unwrap();
+
+    assert_eq!(received.unwrap(), inbox.join("stream.bin"));
+    assert_eq!(fs::read(inbox.join("stream.bin")).unwrap(), content);
+}
+
+#[tokio::test]
+async fn tcp_cut_short_fails() {
+    let harness = Harness::start().await;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = listener.local_addr().unwrap();
+    let inbox = harness.folder("inbox");
+    fs::write(inbox.join("short.bin"), b"the good copy").unwrap();
+
+    // A sender that promises more than it sends, then hangs up
+    let sender = async {
+        let mut stream = tokio::net::TcpStream::connect(target).await.unwrap();
+        let header = json!({"name": "../short.bin", "size": 1000, "sha256": "00"});
+        stream.write_all(format!("{}\n", header).as_bytes()).await.unwrap();
+        stream.write_all(&[0u8; 10]).await.unwrap();
+    };
+    let ((), received) = tokio::join!(sender, receive_file_tcp(&listener, &inbox));
+
+    assert!(received.unwrap_err().to_string().contains("ended after 10 of 1000 bytes"));
+    // The copy that was there survives, and no .part is left behind
+    assert_eq!(fs::read(inbox.join("short.bin")).unwrap(), b"the good copy");
+    assert_eq!(fs::read_dir(&inbox).unwrap().count(), 1);
+    assert!(!harness.dir.path().join("short.bin").exists());
+}
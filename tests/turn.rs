@@ -0,0 +1,229 @@
+// The TURN client and the UDP transfer's fallback to it, against the TURN server in
+// common/turn.rs: the 401 challenge and a stale nonce, permissions, channels,
+// refreshing the allocation and a whole file relayed to a peer that only lets the
+// relay in.
+mod common;
+
+use common::turn::{TurnServer, TurnStats, TURN_PASSWORD};
+use common::{random_bytes, Harness, PASSWORD};
+use p2p_rust::signaling::{get_pip_port_json_and_sockets_with, register};
+use p2p_rust::tls::connect_signaling;
+use p2p_rust::turn::TurnClient;
+use p2p_rust::udp::{receive_file_udp, Datagram};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+
+// A peer behind a firewall that drops everything but what comes from the TURN
+// server's relayed addresses
+struct Firewalled<'a> {
+    socket: UdpSocket,
+    turn: &'a TurnServer,
+}
+
+impl Datagram for Firewalled<'_> {
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        self.socket.send_to(buf, target).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let (len, from) = self.socket.recv_from(buf).await?;
+            if self.turn.relayed_addrs().contains(&from) {
+                return Ok((len, from));
+            }
+        }
+    }
+}
+
+async fn recv(client: &mut TurnClient) -> (Vec<u8>, SocketAddr) {
+    let mut buf = [0u8; 1500];
+    let (len, from) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await.unwrap().unwrap();
+    (buf[..len].to_vec(), from)
+}
+
+async fn peer_recv(peer: &UdpSocket) -> (Vec<u8>, SocketAddr) {
+    let mut buf = [0u8; 1500];
+    let (len, from) = tokio::time::timeout(Duration::from_secs(5), peer.recv_from(&mut buf)).await.unwrap().unwrap();
+    (buf[..len].to_vec(), from)
+}
+
+#[tokio::test]
+async fn relays_only_to_permitted_peers() {
+    let turn = TurnServer::start(600).await;
+    let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+    let mut client = TurnClient::allocate(turn.config(TURN_PASSWORD)).await.unwrap();
+    let relayed = client.relayed_addr();
+    assert_eq!(turn.relayed_addrs(), vec![relayed]);
+    assert!(client.mapped_addr().is_some());
+
+    // Neither way gets through before a permission
+    peer.send_to(b"too early", relayed).await.unwrap();
+    client.send_to(b"too early", peer_addr).await.unwrap();
+
+    // Send and Data indications
+    client.create_permission(peer_addr).await.unwrap();
+    client.send_to(b"hello", peer_addr).await.unwrap();
+    assert_eq!(peer_recv(&peer).await, (b"hello".to_vec(), relayed));
+    peer.send_to(b"hi", relayed).await.unwrap();
+    assert_eq!(recv(&mut client).await, (b"hi".to_vec(), peer_addr));
+
+    // ChannelData both ways once a channel is bound
+    client.channel_bind(peer_addr).await.unwrap();
+    client.send_to(b"over the channel", peer_addr).await.unwrap();
+    assert_eq!(peer_recv(&peer).await, (b"over the channel".to_vec(), relayed));
+    peer.send_to(b"back", relayed).await.unwrap();
+    assert_eq!(recv(&mut client).await, (b"back".to_vec(), peer_addr));
+
+    client.deallocate().await.unwrap();
+    assert!(turn.relayed_addrs().is_empty());
+    let stats = turn.stats();
+    assert_eq!(stats.challenges, 1);
+    assert_eq!(stats.allocations, 1);
+    assert_eq!(stats.permissions, 1);
+    assert_eq!(stats.channel_binds, 1);
+    assert_eq!(stats.deallocations, 1);
+    assert_eq!((stats.send_indications, stats.data_indications), (1, 1));
+    assert_eq!((stats.channel_data_in, stats.channel_data_out), (1, 1));
+    assert_eq!(stats.dropped, 2);
+    // Allocate and CreatePermission use up the first nonce, so ChannelBind finds it stale
+    assert_eq!(stats.stale_nonces, 1);
+}
+
+#[tokio::test]
+async fn refreshes_before_the_allocation_expires() {
+    let turn = TurnServer::start(2).await;
+    let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+    let mut client = TurnClient::allocate(turn.config(TURN_PASSWORD)).await.unwrap();
+    client.create_permission(peer_addr).await.unwrap();
+
+    // Past half the lifetime sending refreshes first, so the allocation outlives the 2s
+    // it was granted
+    for round in 0..3 {
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        client.send_to(format!("round {}", round).as_bytes(), peer_addr).await.unwrap();
+        assert_eq!(peer_recv(&peer).await.0, format!("round {}", round).into_bytes());
+    }
+
+    let stats = turn.stats();
+    assert_eq!(stats.refreshes, 3);
+    assert_eq!(stats.allocations, 1);
+    assert_eq!(stats.stale_nonces, 2);
+    client.deallocate().await.unwrap();
+    assert_eq!(turn.stats().deallocations, 1);
+}
+
+// Waiting for data refreshes too, a client that only receives keeps its allocation
+#[tokio::test]
+async fn receiving_keeps_the_allocation() {
+    let turn = TurnServer::start(2).await;
+    let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+    let mut client = TurnClient::allocate(turn.config(TURN_PASSWORD)).await.unwrap();
+    client.create_permission(peer_addr).await.unwrap();
+    let relayed = client.relayed_addr();
+
+    let late_peer = async {
+        tokio::time::sleep(Duration::from_millis(3500)).await;
+        peer.send_to(b"still there?", relayed).await.unwrap();
+    };
+    let mut buf = [0u8; 64];
+    let ((), received) = tokio::join!(late_peer, tokio::time::timeout(Duration::from_secs(10), client.recv_from(&mut buf)));
+    let (len, from) = received.unwrap().unwrap();
+
+    assert_eq!((&buf[..len], from), (&b"still there?"[..], peer_addr));
+    assert!(turn.stats().refreshes >= 3, "{:?}", turn.stats());
+    assert_eq!(turn.stats().dropped, 0);
+}
+
+#[tokio::test]
+async fn wrong_password_is_rejected() {
+    let turn = TurnServer::start(600).await;
+    let error = TurnClient::allocate(turn.config("not the password")).await.err().expect("allocated with a wrong password");
+
+    assert!(error.to_string().contains("rejected the credentials"), "{}", error);
+    let stats = turn.stats();
+    assert_eq!(stats, TurnStats { challenges: 1, rejected: 1, ..TurnStats::default() });
+    assert!(turn.relayed_addrs().is_empty());
+}
+
+// A success without MESSAGE-INTEGRITY could be anyone's, so the relayed address in it
+// isn't taken
+#[tokio::test]
+async fn unsigned_success_is_refused() {
+    let turn = TurnServer::start(600).await;
+    turn.set_signing(false);
+    let error = TurnClient::allocate(turn.config(TURN_PASSWORD)).await.err().expect("took an unsigned allocation");
+
+    assert!(error.to_string().contains("integrity"), "{}", error);
+    assert_eq!(turn.stats().challenges, 1);
+}
+
+// Bob's firewall drops alice's packets, so send_udp gives up on the direct path and
+// relays the file through an allocation
+#[tokio::test]
+async fn client_falls_back_to_turn() {
+    let harness = Harness::start().await;
+    let turn = TurnServer::start(600).await;
+    let alice = harness.client("alice").await;
+    alice.set_turn(Some(turn.config(TURN_PASSWORD)));
+
+    let stun = harness.stun_addr.to_string();
+    let (payload, mut sockets) = get_pip_port_json_and_sockets_with(&stun, 0, 0, "bob", PASSWORD);
+    let (socket, _) = sockets.pop().expect("no address from STUN");
+    let bob_ws = Arc::new(Mutex::new(connect_signaling(&harness.config()).await.unwrap()));
+    register(&payload, bob_ws.clone()).await.unwrap();
+    socket.set_nonblocking(true).unwrap();
+    let bob = Firewalled { socket: UdpSocket::from_std(socket).unwrap(), turn: &turn };
+
+    let content = random_bytes(20 * 1024 + 7);
+    let path = harness.folder("outbox").join("relayed.bin");
+    fs::write(&path, &content).unwrap();
+    let inbox = harness.folder("inbox");
+    let transfer = async { tokio::join!(alice.send_udp("bob", &path), receive_file_udp(&bob, &inbox)) };
+    let (sent, received) = tokio::time::timeout(Duration::from_secs(60), transfer).await.expect("transfer timed out");
+    sent.unwrap();
+    received.unwrap();
+
+    assert_eq!(fs::read(inbox.join("relayed.bin")).unwrap(), content);
+    let stats = turn.stats();
+    assert_eq!((stats.challenges, stats.stale_nonces), (1, 1));
+    assert_eq!((stats.allocations, stats.channel_binds, stats.deallocations), (1, 1, 1));
+    assert!(stats.channel_data_in > 20 && stats.channel_data_out > 20);
+    assert_eq!(stats.send_indications + stats.data_indications, 0);
+    assert!(turn.relayed_addrs().is_empty());
+}
+
+// Without a TURN server the firewall wins
+#[tokio::test]
+async fn direct_only_fails_behind_a_firewall() {
+    let harness = Harness::start().await;
+    let turn = TurnServer::start(600).await;
+    let alice = harness.client("alice").await;
+    alice.set_turn(None);
+
+    let stun = harness.stun_addr.to_string();
+    let (payload, mut sockets) = get_pip_port_json_and_sockets_with(&stun, 0, 0, "bob", PASSWORD);
+    let (socket, _) = sockets.pop().expect("no address from STUN");
+    let bob_ws = Arc::new(Mutex::new(connect_signaling(&harness.config()).await.unwrap()));
+    register(&payload, bob_ws.clone()).await.unwrap();
+    socket.set_nonblocking(true).unwrap();
+    let bob = Firewalled { socket: UdpSocket::from_std(socket).unwrap(), turn: &turn };
+
+    let path = harness.folder("outbox").join("blocked.bin");
+    fs::write(&path, random_bytes(100)).unwrap();
+    let inbox = harness.folder("inbox");
+    let sent = tokio::select! {
+        sent = alice.send_udp("bob", &path) => sent,
+        received = receive_file_udp(&bob, &inbox) => panic!("bob got a file through the firewall: {:?}", received),
+    };
+
+    assert!(sent.unwrap_err().contains("No ACK"));
+    assert_eq!(turn.stats(), TurnStats::default());
+}